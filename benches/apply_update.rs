@@ -0,0 +1,166 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How long updates from the core take to turn into typed updates and
+//! apply to the line cache, on a big file, with a check that applying many
+//! small updates takes time in proportion to how many there are. Run with
+//! `cargo bench`.
+
+// The modules are shared with the app, which uses more of them.
+#![allow(dead_code)]
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+#[path = "../src/linecache.rs"]
+mod linecache;
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use linecache::LineCache;
+use protocol::Update;
+
+const FILE_LINES: usize = 100_000;
+const ITERATIONS: u32 = 10;
+
+// Small updates in the stress test, and how much longer than in proportion
+// the bigger run may take before it counts as superlinear.
+const STRESS_UPDATES: usize = 200;
+const STRESS_SLACK: f64 = 2.0;
+
+fn main() {
+    let load = load_update(FILE_LINES);
+    bench_update("initial load of 100k lines", LineCache::new, &load);
+    bench_update("one character edit in the middle", loaded_cache,
+        &edit_update(FILE_LINES / 2, FILE_LINES));
+    bench_update("scroll invalidating 60 lines", loaded_cache,
+        &scroll_update(FILE_LINES / 2, 60, FILE_LINES));
+    bench_update("select all restyling every line", loaded_cache,
+        &restyle_update(FILE_LINES));
+    stress();
+}
+
+// Time the typed deserialization and applying separately, each on fresh
+// inputs.
+fn bench_update<F: Fn() -> LineCache>(name: &str, cache: F, update: &Value) {
+    bench(&format!("{}: deserialize", name), || (), |_| {
+        Update::deserialize(update).unwrap();
+    });
+    bench(&format!("{}: apply", name), || (cache(), Update::deserialize(update).unwrap()),
+        |(mut cache, update)| {
+            cache.apply_update(update);
+        });
+}
+
+fn bench<T, S, R>(name: &str, mut setup: S, mut run: R)
+    where S: FnMut() -> T, R: FnMut(T)
+{
+    let mut total = Duration::new(0, 0);
+    let mut best = None;
+    for _ in 0..ITERATIONS {
+        let input = setup();
+        let start = Instant::now();
+        run(input);
+        let elapsed = start.elapsed();
+        total += elapsed;
+        best = Some(best.map_or(elapsed, |best: Duration| best.min(elapsed)));
+    }
+    println!("{:<50} {:>9.3} ms mean {:>9.3} ms best", name, ms(total) / ITERATIONS as f64,
+        best.map_or(0.0, ms));
+}
+
+// Apply small edits all over a big cache, twice as many the second time;
+// the second run should take about twice as long.
+fn stress() {
+    let run = |n: usize| {
+        let mut cache = loaded_cache();
+        let updates: Vec<Value> = (0..n)
+            .map(|ix| edit_update(ix * 7919 % FILE_LINES, FILE_LINES))
+            .collect();
+        let start = Instant::now();
+        for update in &updates {
+            cache.apply_update(Update::deserialize(update).unwrap());
+        }
+        ms(start.elapsed())
+    };
+    let small = run(STRESS_UPDATES);
+    let big = run(STRESS_UPDATES * 2);
+    println!("{:<50} {:>9.3} ms for {}, {:.3} ms for {}", "stress: repeated small edits",
+        small, STRESS_UPDATES, big, STRESS_UPDATES * 2);
+    assert!(big < small * 2.0 * STRESS_SLACK,
+        "twice the updates took {:.1} times as long", big / small);
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn loaded_cache() -> LineCache {
+    let mut cache = LineCache::new();
+    cache.apply_update(Update::deserialize(&load_update(FILE_LINES)).unwrap());
+    cache
+}
+
+fn line(ix: usize) -> Value {
+    json!({
+        "text": format!("line {} of a file for benchmarking, with some text on it\n", ix),
+        "cursor": [],
+        "styles": [],
+    })
+}
+
+fn load_update(n: usize) -> Value {
+    json!({"ops": [{"op": "ins", "n": n, "lines": (0..n).map(line).collect::<Vec<_>>()}]})
+}
+
+// A character typed on one line: the others are copied, that one is
+// replaced.
+fn edit_update(line_num: usize, height: usize) -> Value {
+    let mut edited = line(line_num);
+    edited["text"] = json!(format!("x{}", edited["text"].as_str().unwrap()));
+    edited["cursor"] = json!([1]);
+    json!({"ops": [
+        {"op": "copy", "n": line_num},
+        {"op": "skip", "n": 1},
+        {"op": "ins", "n": 1, "lines": [edited]},
+        {"op": "copy", "n": height - line_num - 1},
+    ]})
+}
+
+// Lines scrolled into view, sent again in place of the old ones.
+fn scroll_update(first: usize, n: usize, height: usize) -> Value {
+    json!({"ops": [
+        {"op": "copy", "n": first},
+        {"op": "skip", "n": n},
+        {"op": "ins", "n": n, "lines": (first..first + n).map(line).collect::<Vec<_>>()},
+        {"op": "copy", "n": height - first - n},
+    ]})
+}
+
+// New styles on every line, with the text left alone.
+fn restyle_update(n: usize) -> Value {
+    let lines: Vec<Value> = (0..n).map(|ix| {
+        let len = line(ix)["text"].as_str().unwrap().len();
+        json!({"cursor": [], "styles": [0, len, 0]})
+    }).collect();
+    json!({"ops": [{"op": "update", "n": n, "lines": lines}]})
+}
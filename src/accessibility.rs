@@ -0,0 +1,1035 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The edit view for screen readers, through UI Automation. The window
+//! answers WM_GETOBJECT with a provider for a document with the text
+//! pattern, so clients can read the text by character, word and line, and
+//! follow the caret.
+//!
+//! Clients call in on other threads, so the provider serves a snapshot of
+//! what the view shows, which the window replaces after updates. Selecting
+//! and scrolling from a client go back to the window as requests, run on
+//! its thread.
+
+#![allow(non_upper_case_globals)]
+
+use std::cmp::min;
+use std::ops::Range;
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi::Interface;
+use winapi::ctypes::{c_int, c_long, c_void};
+use winapi::shared::guiddef::{IsEqualGUID, REFIID};
+use winapi::shared::minwindef::{BOOL, FALSE, LPARAM, LRESULT, TRUE, ULONG, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::{E_INVALIDARG, E_NOINTERFACE, E_NOTIMPL, E_POINTER, HRESULT, S_OK};
+use winapi::shared::wtypes::{BSTR, VARTYPE, VARIANT_FALSE, VARIANT_TRUE};
+use winapi::shared::wtypes::{VT_BOOL, VT_BSTR, VT_EMPTY, VT_I4, VT_R8, VT_UNKNOWN};
+use winapi::um::oaidl::{SAFEARRAY, VARIANT};
+use winapi::um::oleauto::{SafeArrayCreateVector, SafeArrayPutElement, SysAllocStringLen};
+use winapi::um::oleauto::SysStringLen;
+use winapi::um::unknwnbase::IUnknown;
+
+// TODO: remove these when they get added to winapi
+DEFINE_GUID!{IID_IRawElementProviderSimple,
+    0xd6dd68d1, 0x86fd, 0x4332, 0x86, 0x66, 0x9a, 0xbe, 0xde, 0xa2, 0xd2, 0x4c}
+DEFINE_GUID!{IID_ITextProvider,
+    0x3589c92c, 0x63f3, 0x4367, 0x99, 0xbb, 0xad, 0xa6, 0x53, 0xb7, 0x7c, 0xf2}
+DEFINE_GUID!{IID_ITextRangeProvider,
+    0x5347ad7b, 0xc355, 0x46f8, 0xaf, 0xf5, 0x90, 0x90, 0x33, 0x58, 0x2f, 0x63}
+
+const UiaRootObjectId: i32 = -25;
+const ProviderOptions_ServerSideProvider: c_int = 0x2;
+const SupportedTextSelection_Single: c_int = 1;
+
+const UIA_TextPatternId: c_int = 10014;
+const UIA_Text_TextSelectionChangedEventId: c_int = 20014;
+const UIA_Text_TextChangedEventId: c_int = 20015;
+const UIA_ControlTypePropertyId: c_int = 30003;
+const UIA_NamePropertyId: c_int = 30005;
+const UIA_HasKeyboardFocusPropertyId: c_int = 30008;
+const UIA_IsKeyboardFocusablePropertyId: c_int = 30009;
+const UIA_DocumentControlTypeId: c_int = 50030;
+
+const UIA_E_ELEMENTNOTAVAILABLE: HRESULT = 0x8004_0201u32 as HRESULT;
+const UIA_E_INVALIDOPERATION: HRESULT = 0x8013_1509u32 as HRESULT;
+
+// TextUnit, how far a range moves or expands. Formats and pages aren't
+// tracked, so they're the whole document.
+const TextUnit_Character: c_int = 0;
+const TextUnit_Word: c_int = 2;
+const TextUnit_Line: c_int = 3;
+const TextUnit_Paragraph: c_int = 4;
+
+// TextPatternRangeEndpoint
+const TextPatternRangeEndpoint_Start: c_int = 0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)] // Passed in by clients, but not used yet
+struct UiaPoint {
+    x: f64,
+    y: f64,
+}
+
+#[link(name = "uiautomationcore")]
+extern "system" {
+    fn UiaReturnRawElementProvider(hwnd: HWND, wparam: WPARAM, lparam: LPARAM,
+        provider: *mut c_void) -> LRESULT;
+    fn UiaHostProviderFromHwnd(hwnd: HWND, provider: *mut *mut c_void) -> HRESULT;
+    fn UiaRaiseAutomationEvent(provider: *mut c_void, id: c_int) -> HRESULT;
+    fn UiaClientsAreListening() -> BOOL;
+    fn UiaGetReservedNotSupportedValue(value: *mut *mut IUnknown) -> HRESULT;
+}
+
+/// What a client asks the window to do, in the core's lines and byte
+/// columns.
+pub enum Request {
+    /// Select from one point to the other, or put the caret at the point
+    /// if they're the same.
+    Select((usize, usize), (usize, usize)),
+    ScrollTo(usize),
+}
+
+/// The text of the view as clients see it, in UTF-16 offsets.
+pub struct Snapshot {
+    name: String,
+    text: Vec<u16>,
+    // Where each line starts in text, ascending.
+    line_starts: Vec<usize>,
+    selection: (usize, usize),
+    visible: (usize, usize),
+    has_focus: bool,
+}
+
+impl Snapshot {
+    /// Take the lines of the view, None for lines the core hasn't sent,
+    /// which show as empty lines, the first selection, and which lines are
+    /// visible.
+    pub fn new(name: &str, lines: &[Option<&str>],
+        selection: Option<((usize, usize), (usize, usize))>, visible: Range<usize>,
+        has_focus: bool) -> Snapshot
+    {
+        let mut text = Vec::new();
+        let mut line_starts = Vec::with_capacity(lines.len());
+        for line in lines {
+            line_starts.push(text.len());
+            match *line {
+                Some(line) => text.extend(line.encode_utf16()),
+                None => text.push('\n' as u16),
+            }
+        }
+        let (selection, visible) = {
+            let offset = |(line_num, col): (usize, usize)| match line_starts.get(line_num) {
+                Some(&start) => start + lines[line_num].and_then(|line| line.get(..col))
+                    .map_or(0, |s| s.encode_utf16().count()),
+                None => text.len(),
+            };
+            let selection = selection.map_or((0, 0), |(start, end)| (offset(start), offset(end)));
+            (selection, (offset((visible.start, 0)), offset((visible.end, 0))))
+        };
+        Snapshot {
+            name: name.to_owned(),
+            text,
+            line_starts,
+            selection,
+            visible,
+            has_focus,
+        }
+    }
+
+    fn empty() -> Snapshot {
+        Snapshot::new("", &[], None, 0..0, false)
+    }
+
+    fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    fn line_of(&self, pos: usize) -> usize {
+        match self.line_starts.binary_search(&pos) {
+            Ok(ix) => ix,
+            Err(ix) => ix.saturating_sub(1),
+        }
+    }
+
+    // The core's line and byte column for an offset.
+    fn point(&self, pos: usize) -> (usize, usize) {
+        let line_num = self.line_of(pos);
+        let start = self.line_starts.get(line_num).cloned().unwrap_or(0);
+        let col = String::from_utf16_lossy(&self.text[start..pos]).len();
+        (line_num, col)
+    }
+
+    fn is_word_start(&self, pos: usize) -> bool {
+        if pos == 0 || pos >= self.len() {
+            return true;
+        }
+        let (prev, this) = (self.text[pos - 1], self.text[pos]);
+        prev == '\n' as u16 || (char_class(this) != CharClass::Space
+            && char_class(this) != char_class(prev))
+    }
+
+    // The start of the unit that the offset is in.
+    fn unit_start(&self, pos: usize, unit: c_int) -> usize {
+        let pos = min(pos, self.len());
+        match unit {
+            TextUnit_Character => {
+                if pos > 0 && pos < self.len() && is_low_surrogate(self.text[pos]) {
+                    pos - 1
+                } else {
+                    pos
+                }
+            }
+            TextUnit_Word => {
+                let mut pos = pos;
+                while !self.is_word_start(pos) {
+                    pos -= 1;
+                }
+                pos
+            }
+            TextUnit_Line | TextUnit_Paragraph => {
+                self.line_starts.get(self.line_of(pos)).cloned().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    // The start of the next unit after the offset, or the end.
+    fn next_boundary(&self, pos: usize, unit: c_int) -> usize {
+        if pos >= self.len() {
+            return self.len();
+        }
+        match unit {
+            TextUnit_Character => {
+                let step = if is_high_surrogate(self.text[pos]) { 2 } else { 1 };
+                min(pos + step, self.len())
+            }
+            TextUnit_Word => {
+                let mut pos = pos + 1;
+                while !self.is_word_start(pos) {
+                    pos += 1;
+                }
+                pos
+            }
+            TextUnit_Line | TextUnit_Paragraph => {
+                self.line_starts.iter().cloned().find(|&start| start > pos)
+                    .unwrap_or(self.len())
+            }
+            _ => self.len(),
+        }
+    }
+
+    // The start of the unit before the offset, or of the one it's in if
+    // it's inside one.
+    fn prev_boundary(&self, pos: usize, unit: c_int) -> usize {
+        if pos == 0 { 0 } else { self.unit_start(pos - 1, unit) }
+    }
+
+    fn find(&self, range: (usize, usize), needle: &[u16], backward: bool, ignore_case: bool)
+        -> Option<usize>
+    {
+        let hay = &self.text[range.0..range.1];
+        if needle.is_empty() || needle.len() > hay.len() {
+            return None;
+        }
+        let fold = |c: u16| if ignore_case && c < 0x80 {
+            (c as u8).to_ascii_lowercase() as u16
+        } else {
+            c
+        };
+        let matches = |start: &usize| hay[*start..*start + needle.len()].iter()
+            .zip(needle).all(|(&a, &b)| fold(a) == fold(b));
+        let starts = 0..hay.len() - needle.len() + 1;
+        let found = if backward { starts.rev().find(matches) } else { starts.find(matches) };
+        found.map(|start| range.0 + start)
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum CharClass {
+    Space,
+    Word,
+    Punctuation,
+}
+
+fn char_class(c: u16) -> CharClass {
+    match ::std::char::from_u32(c as u32) {
+        Some(c) if c.is_whitespace() => CharClass::Space,
+        Some(c) if c.is_alphanumeric() || c == '_' => CharClass::Word,
+        // Half of a surrogate pair, off the basic plane.
+        None => CharClass::Word,
+        Some(_) => CharClass::Punctuation,
+    }
+}
+
+fn is_high_surrogate(c: u16) -> bool {
+    c >= 0xd800 && c < 0xdc00
+}
+
+fn is_low_surrogate(c: u16) -> bool {
+    c >= 0xdc00 && c < 0xe000
+}
+
+struct State {
+    snapshot: Snapshot,
+    // Cleared when the window goes away; clients may still hold on.
+    connected: bool,
+}
+
+struct Shared {
+    hwnd: usize,
+    state: Mutex<State>,
+    requests: Mutex<Box<Fn(Request) + Send>>,
+}
+
+/// The provider of a window's edit view.
+pub struct Accessibility {
+    provider: *mut Provider,
+}
+
+impl Accessibility {
+    /// Requests from clients are passed to the callback, which should run
+    /// them on the window's thread.
+    pub fn new(hwnd: HWND, requests: Box<Fn(Request) + Send>) -> Accessibility {
+        let shared = Arc::new(Shared {
+            hwnd: hwnd as usize,
+            state: Mutex::new(State { snapshot: Snapshot::empty(), connected: true }),
+            requests: Mutex::new(requests),
+        });
+        let provider = Box::new(Provider {
+            simple: &SIMPLE_VTBL,
+            text: &TEXT_VTBL,
+            refs: AtomicUsize::new(1),
+            shared,
+        });
+        Accessibility { provider: Box::into_raw(provider) }
+    }
+
+    /// The answer to WM_GETOBJECT, for the root object.
+    pub fn get_object(&self, wparam: WPARAM, lparam: LPARAM) -> Option<LRESULT> {
+        if lparam as i32 != UiaRootObjectId {
+            return None;
+        }
+        unsafe {
+            let hwnd = (*self.provider).shared.hwnd as HWND;
+            Some(UiaReturnRawElementProvider(hwnd, wparam, lparam, simple_ptr(self.provider)))
+        }
+    }
+
+    /// Replace what clients see, telling them about changes to the text
+    /// and selection, if any are listening.
+    pub fn update(&self, snapshot: Snapshot) {
+        let (text_changed, selection_changed) = {
+            let mut state = unsafe { (*self.provider).shared.state.lock().unwrap() };
+            let text_changed = state.snapshot.text != snapshot.text;
+            let selection_changed = state.snapshot.selection != snapshot.selection;
+            state.snapshot = snapshot;
+            (text_changed, selection_changed)
+        };
+        // Clients can call back in while the event is raised, so the lock
+        // isn't held.
+        unsafe {
+            if UiaClientsAreListening() == FALSE {
+                return;
+            }
+            let provider = simple_ptr(self.provider);
+            if text_changed {
+                UiaRaiseAutomationEvent(provider, UIA_Text_TextChangedEventId);
+            }
+            if selection_changed {
+                UiaRaiseAutomationEvent(provider, UIA_Text_TextSelectionChangedEventId);
+            }
+        }
+    }
+
+    /// Call when the window is being destroyed. Clients still holding the
+    /// provider get errors from then on.
+    pub fn disconnect(&self) {
+        unsafe {
+            let shared = &(*self.provider).shared;
+            shared.state.lock().unwrap().connected = false;
+            UiaReturnRawElementProvider(shared.hwnd as HWND, 0, 0, null_mut());
+        }
+    }
+}
+
+impl Drop for Accessibility {
+    fn drop(&mut self) {
+        unsafe {
+            provider_release(self.provider);
+        }
+    }
+}
+
+// The COM objects. The vtables are laid out as in UIAutomationCore.h,
+// after the three methods of IUnknown. Clients do the reading of them.
+
+#[repr(C)]
+#[allow(dead_code)]
+struct SimpleVtbl {
+    query_interface: unsafe extern "system" fn(*mut c_void, REFIID, *mut *mut c_void) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut c_void) -> ULONG,
+    release: unsafe extern "system" fn(*mut c_void) -> ULONG,
+    get_provider_options: unsafe extern "system" fn(*mut c_void, *mut c_int) -> HRESULT,
+    get_pattern_provider: unsafe extern "system" fn(*mut c_void, c_int, *mut *mut c_void)
+        -> HRESULT,
+    get_property_value: unsafe extern "system" fn(*mut c_void, c_int, *mut VARIANT) -> HRESULT,
+    get_host_raw_element_provider: unsafe extern "system" fn(*mut c_void, *mut *mut c_void)
+        -> HRESULT,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct TextVtbl {
+    query_interface: unsafe extern "system" fn(*mut c_void, REFIID, *mut *mut c_void) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut c_void) -> ULONG,
+    release: unsafe extern "system" fn(*mut c_void) -> ULONG,
+    get_selection: unsafe extern "system" fn(*mut c_void, *mut *mut SAFEARRAY) -> HRESULT,
+    get_visible_ranges: unsafe extern "system" fn(*mut c_void, *mut *mut SAFEARRAY) -> HRESULT,
+    range_from_child: unsafe extern "system" fn(*mut c_void, *mut c_void, *mut *mut c_void)
+        -> HRESULT,
+    range_from_point: unsafe extern "system" fn(*mut c_void, UiaPoint, *mut *mut c_void)
+        -> HRESULT,
+    get_document_range: unsafe extern "system" fn(*mut c_void, *mut *mut c_void) -> HRESULT,
+    get_supported_text_selection: unsafe extern "system" fn(*mut c_void, *mut c_int) -> HRESULT,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct RangeVtbl {
+    query_interface: unsafe extern "system" fn(*mut c_void, REFIID, *mut *mut c_void) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut c_void) -> ULONG,
+    release: unsafe extern "system" fn(*mut c_void) -> ULONG,
+    clone: unsafe extern "system" fn(*mut c_void, *mut *mut c_void) -> HRESULT,
+    compare: unsafe extern "system" fn(*mut c_void, *mut c_void, *mut BOOL) -> HRESULT,
+    compare_endpoints: unsafe extern "system" fn(*mut c_void, c_int, *mut c_void, c_int,
+        *mut c_int) -> HRESULT,
+    expand_to_enclosing_unit: unsafe extern "system" fn(*mut c_void, c_int) -> HRESULT,
+    find_attribute: unsafe extern "system" fn(*mut c_void, c_int, VARIANT, BOOL,
+        *mut *mut c_void) -> HRESULT,
+    find_text: unsafe extern "system" fn(*mut c_void, BSTR, BOOL, BOOL, *mut *mut c_void)
+        -> HRESULT,
+    get_attribute_value: unsafe extern "system" fn(*mut c_void, c_int, *mut VARIANT) -> HRESULT,
+    get_bounding_rectangles: unsafe extern "system" fn(*mut c_void, *mut *mut SAFEARRAY)
+        -> HRESULT,
+    get_enclosing_element: unsafe extern "system" fn(*mut c_void, *mut *mut c_void) -> HRESULT,
+    get_text: unsafe extern "system" fn(*mut c_void, c_int, *mut BSTR) -> HRESULT,
+    move_by_unit: unsafe extern "system" fn(*mut c_void, c_int, c_int, *mut c_int) -> HRESULT,
+    move_endpoint_by_unit: unsafe extern "system" fn(*mut c_void, c_int, c_int, c_int,
+        *mut c_int) -> HRESULT,
+    move_endpoint_by_range: unsafe extern "system" fn(*mut c_void, c_int, *mut c_void, c_int)
+        -> HRESULT,
+    select: unsafe extern "system" fn(*mut c_void) -> HRESULT,
+    add_to_selection: unsafe extern "system" fn(*mut c_void) -> HRESULT,
+    remove_from_selection: unsafe extern "system" fn(*mut c_void) -> HRESULT,
+    scroll_into_view: unsafe extern "system" fn(*mut c_void, BOOL) -> HRESULT,
+    get_children: unsafe extern "system" fn(*mut c_void, *mut *mut SAFEARRAY) -> HRESULT,
+}
+
+// One object for both interfaces of the element, each with its own vtable
+// pointer. The IRawElementProviderSimple pointer is the object itself.
+#[repr(C)]
+#[allow(dead_code)]
+struct Provider {
+    simple: *const SimpleVtbl,
+    text: *const TextVtbl,
+    refs: AtomicUsize,
+    shared: Arc<Shared>,
+}
+
+#[repr(C)]
+struct TextRange {
+    vtbl: *const RangeVtbl,
+    refs: AtomicUsize,
+    // Holds a reference, so the text it ranges over stays around.
+    provider: *mut Provider,
+    start: usize,
+    end: usize,
+}
+
+static SIMPLE_VTBL: SimpleVtbl = SimpleVtbl {
+    query_interface: simple_query_interface,
+    add_ref: simple_add_ref,
+    release: simple_release,
+    get_provider_options,
+    get_pattern_provider,
+    get_property_value,
+    get_host_raw_element_provider,
+};
+
+static TEXT_VTBL: TextVtbl = TextVtbl {
+    query_interface: text_query_interface,
+    add_ref: text_add_ref,
+    release: text_release,
+    get_selection,
+    get_visible_ranges,
+    range_from_child,
+    range_from_point,
+    get_document_range,
+    get_supported_text_selection,
+};
+
+static RANGE_VTBL: RangeVtbl = RangeVtbl {
+    query_interface: range_query_interface,
+    add_ref: range_add_ref,
+    release: range_release,
+    clone: range_clone,
+    compare: range_compare,
+    compare_endpoints: range_compare_endpoints,
+    expand_to_enclosing_unit: range_expand_to_enclosing_unit,
+    find_attribute: range_find_attribute,
+    find_text: range_find_text,
+    get_attribute_value: range_get_attribute_value,
+    get_bounding_rectangles: range_get_bounding_rectangles,
+    get_enclosing_element: range_get_enclosing_element,
+    get_text: range_get_text,
+    move_by_unit: range_move,
+    move_endpoint_by_unit: range_move_endpoint_by_unit,
+    move_endpoint_by_range: range_move_endpoint_by_range,
+    select: range_select,
+    add_to_selection: range_add_to_selection,
+    remove_from_selection: range_remove_from_selection,
+    scroll_into_view: range_scroll_into_view,
+    get_children: range_get_children,
+};
+
+fn simple_ptr(provider: *mut Provider) -> *mut c_void {
+    provider as *mut c_void
+}
+
+fn text_ptr(provider: *mut Provider) -> *mut c_void {
+    unsafe { &mut (*provider).text as *mut *const TextVtbl as *mut c_void }
+}
+
+unsafe fn from_text(this: *mut c_void) -> *mut Provider {
+    (this as *mut *const TextVtbl).offset(-1) as *mut Provider
+}
+
+unsafe fn provider_query(provider: *mut Provider, riid: REFIID, out: *mut *mut c_void)
+    -> HRESULT
+{
+    if out.is_null() {
+        return E_POINTER;
+    }
+    let iid = &*riid;
+    let ptr = if IsEqualGUID(iid, &IUnknown::uuidof())
+        || IsEqualGUID(iid, &IID_IRawElementProviderSimple)
+    {
+        simple_ptr(provider)
+    } else if IsEqualGUID(iid, &IID_ITextProvider) {
+        text_ptr(provider)
+    } else {
+        *out = null_mut();
+        return E_NOINTERFACE;
+    };
+    provider_add_ref(provider);
+    *out = ptr;
+    S_OK
+}
+
+unsafe fn provider_add_ref(provider: *mut Provider) -> ULONG {
+    ((*provider).refs.fetch_add(1, Ordering::Relaxed) + 1) as ULONG
+}
+
+unsafe fn provider_release(provider: *mut Provider) -> ULONG {
+    let refs = (*provider).refs.fetch_sub(1, Ordering::AcqRel) - 1;
+    if refs == 0 {
+        drop(Box::from_raw(provider));
+    }
+    refs as ULONG
+}
+
+// Run f on the snapshot, unless the window has gone away.
+unsafe fn with_snapshot<F>(provider: *mut Provider, f: F) -> HRESULT
+    where F: FnOnce(&Snapshot) -> HRESULT
+{
+    let state = (*provider).shared.state.lock().unwrap();
+    if !state.connected {
+        return UIA_E_ELEMENTNOTAVAILABLE;
+    }
+    f(&state.snapshot)
+}
+
+unsafe fn set_i4(v: *mut VARIANT, value: i32) {
+    let v = (*v).n1.n2_mut();
+    v.vt = VT_I4 as VARTYPE;
+    *v.n3.lVal_mut() = value;
+}
+
+unsafe fn set_bool(v: *mut VARIANT, value: bool) {
+    let v = (*v).n1.n2_mut();
+    v.vt = VT_BOOL as VARTYPE;
+    *v.n3.boolVal_mut() = if value { VARIANT_TRUE } else { VARIANT_FALSE };
+}
+
+unsafe fn set_bstr(v: *mut VARIANT, value: &str) {
+    let wide: Vec<u16> = value.encode_utf16().collect();
+    let v = (*v).n1.n2_mut();
+    v.vt = VT_BSTR as VARTYPE;
+    *v.n3.bstrVal_mut() = SysAllocStringLen(wide.as_ptr(), wide.len() as u32);
+}
+
+unsafe fn set_empty(v: *mut VARIANT) {
+    (*v).n1.n2_mut().vt = VT_EMPTY as VARTYPE;
+}
+
+// A safe array of range objects, taking over the references.
+unsafe fn range_array(ranges: &[*mut c_void]) -> *mut SAFEARRAY {
+    let array = SafeArrayCreateVector(VT_UNKNOWN as VARTYPE, 0, ranges.len() as ULONG);
+    if array.is_null() {
+        return array;
+    }
+    for (ix, &range) in ranges.iter().enumerate() {
+        let ix = ix as c_long;
+        // The array takes its own reference.
+        SafeArrayPutElement(array, &ix, range);
+        range_release(range);
+    }
+    array
+}
+
+unsafe fn new_range(provider: *mut Provider, start: usize, end: usize) -> *mut c_void {
+    provider_add_ref(provider);
+    let range = Box::new(TextRange {
+        vtbl: &RANGE_VTBL,
+        refs: AtomicUsize::new(1),
+        provider,
+        start,
+        end,
+    });
+    Box::into_raw(range) as *mut c_void
+}
+
+// IRawElementProviderSimple
+
+unsafe extern "system" fn simple_query_interface(this: *mut c_void, riid: REFIID,
+    out: *mut *mut c_void) -> HRESULT
+{
+    provider_query(this as *mut Provider, riid, out)
+}
+
+unsafe extern "system" fn simple_add_ref(this: *mut c_void) -> ULONG {
+    provider_add_ref(this as *mut Provider)
+}
+
+unsafe extern "system" fn simple_release(this: *mut c_void) -> ULONG {
+    provider_release(this as *mut Provider)
+}
+
+unsafe extern "system" fn get_provider_options(_this: *mut c_void, out: *mut c_int) -> HRESULT {
+    *out = ProviderOptions_ServerSideProvider;
+    S_OK
+}
+
+unsafe extern "system" fn get_pattern_provider(this: *mut c_void, pattern: c_int,
+    out: *mut *mut c_void) -> HRESULT
+{
+    let provider = this as *mut Provider;
+    if pattern == UIA_TextPatternId {
+        provider_add_ref(provider);
+        *out = text_ptr(provider);
+    } else {
+        *out = null_mut();
+    }
+    S_OK
+}
+
+unsafe extern "system" fn get_property_value(this: *mut c_void, property: c_int,
+    out: *mut VARIANT) -> HRESULT
+{
+    with_snapshot(this as *mut Provider, |snapshot| {
+        match property {
+            UIA_ControlTypePropertyId => set_i4(out, UIA_DocumentControlTypeId),
+            UIA_NamePropertyId => set_bstr(out, &snapshot.name),
+            UIA_IsKeyboardFocusablePropertyId => set_bool(out, true),
+            UIA_HasKeyboardFocusPropertyId => set_bool(out, snapshot.has_focus),
+            // The host provider has the rest.
+            _ => set_empty(out),
+        }
+        S_OK
+    })
+}
+
+unsafe extern "system" fn get_host_raw_element_provider(this: *mut c_void,
+    out: *mut *mut c_void) -> HRESULT
+{
+    let hwnd = (*(this as *mut Provider)).shared.hwnd as HWND;
+    UiaHostProviderFromHwnd(hwnd, out)
+}
+
+// ITextProvider
+
+unsafe extern "system" fn text_query_interface(this: *mut c_void, riid: REFIID,
+    out: *mut *mut c_void) -> HRESULT
+{
+    provider_query(from_text(this), riid, out)
+}
+
+unsafe extern "system" fn text_add_ref(this: *mut c_void) -> ULONG {
+    provider_add_ref(from_text(this))
+}
+
+unsafe extern "system" fn text_release(this: *mut c_void) -> ULONG {
+    provider_release(from_text(this))
+}
+
+unsafe extern "system" fn get_selection(this: *mut c_void, out: *mut *mut SAFEARRAY)
+    -> HRESULT
+{
+    let provider = from_text(this);
+    with_snapshot(provider, |snapshot| {
+        let (start, end) = snapshot.selection;
+        *out = range_array(&[new_range(provider, start, end)]);
+        S_OK
+    })
+}
+
+unsafe extern "system" fn get_visible_ranges(this: *mut c_void, out: *mut *mut SAFEARRAY)
+    -> HRESULT
+{
+    let provider = from_text(this);
+    with_snapshot(provider, |snapshot| {
+        let (start, end) = snapshot.visible;
+        *out = range_array(&[new_range(provider, start, end)]);
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_from_child(_this: *mut c_void, _child: *mut c_void,
+    out: *mut *mut c_void) -> HRESULT
+{
+    // There are no child elements.
+    *out = null_mut();
+    E_INVALIDARG
+}
+
+unsafe extern "system" fn range_from_point(_this: *mut c_void, _point: UiaPoint,
+    out: *mut *mut c_void) -> HRESULT
+{
+    *out = null_mut();
+    E_NOTIMPL
+}
+
+unsafe extern "system" fn get_document_range(this: *mut c_void, out: *mut *mut c_void)
+    -> HRESULT
+{
+    let provider = from_text(this);
+    with_snapshot(provider, |snapshot| {
+        *out = new_range(provider, 0, snapshot.len());
+        S_OK
+    })
+}
+
+unsafe extern "system" fn get_supported_text_selection(_this: *mut c_void, out: *mut c_int)
+    -> HRESULT
+{
+    *out = SupportedTextSelection_Single;
+    S_OK
+}
+
+// ITextRangeProvider
+
+unsafe fn as_range<'a>(ptr: *mut c_void) -> Option<&'a mut TextRange> {
+    if ptr.is_null() {
+        return None;
+    }
+    let range = ptr as *mut TextRange;
+    // Any COM object starts with its vtable pointer, so this tells ours
+    // apart from other providers' ranges.
+    if (*range).vtbl as *const RangeVtbl == &RANGE_VTBL as *const RangeVtbl {
+        Some(&mut *range)
+    } else {
+        None
+    }
+}
+
+// Run f on the range with the snapshot, its ends kept within the text.
+unsafe fn with_range<F>(this: *mut c_void, f: F) -> HRESULT
+    where F: FnOnce(&mut TextRange, &Snapshot) -> HRESULT
+{
+    let range = &mut *(this as *mut TextRange);
+    let provider = range.provider;
+    with_snapshot(provider, |snapshot| {
+        range.end = min(range.end, snapshot.len());
+        range.start = min(range.start, range.end);
+        f(range, snapshot)
+    })
+}
+
+fn endpoint(range: &TextRange, endpoint: c_int) -> usize {
+    if endpoint == TextPatternRangeEndpoint_Start { range.start } else { range.end }
+}
+
+// Move one end, pushing the other along if they'd cross.
+fn set_endpoint(range: &mut TextRange, endpoint: c_int, pos: usize) {
+    if endpoint == TextPatternRangeEndpoint_Start {
+        range.start = pos;
+        if range.end < pos {
+            range.end = pos;
+        }
+    } else {
+        range.end = pos;
+        if range.start > pos {
+            range.start = pos;
+        }
+    }
+}
+
+unsafe extern "system" fn range_query_interface(this: *mut c_void, riid: REFIID,
+    out: *mut *mut c_void) -> HRESULT
+{
+    if out.is_null() {
+        return E_POINTER;
+    }
+    let iid = &*riid;
+    if IsEqualGUID(iid, &IUnknown::uuidof()) || IsEqualGUID(iid, &IID_ITextRangeProvider) {
+        range_add_ref(this);
+        *out = this;
+        S_OK
+    } else {
+        *out = null_mut();
+        E_NOINTERFACE
+    }
+}
+
+unsafe extern "system" fn range_add_ref(this: *mut c_void) -> ULONG {
+    let range = this as *mut TextRange;
+    ((*range).refs.fetch_add(1, Ordering::Relaxed) + 1) as ULONG
+}
+
+unsafe extern "system" fn range_release(this: *mut c_void) -> ULONG {
+    let range = this as *mut TextRange;
+    let refs = (*range).refs.fetch_sub(1, Ordering::AcqRel) - 1;
+    if refs == 0 {
+        let range = Box::from_raw(range);
+        provider_release(range.provider);
+    }
+    refs as ULONG
+}
+
+unsafe extern "system" fn range_clone(this: *mut c_void, out: *mut *mut c_void) -> HRESULT {
+    let range = &*(this as *mut TextRange);
+    *out = new_range(range.provider, range.start, range.end);
+    S_OK
+}
+
+unsafe extern "system" fn range_compare(this: *mut c_void, other: *mut c_void, out: *mut BOOL)
+    -> HRESULT
+{
+    let range = &*(this as *mut TextRange);
+    let same = match as_range(other) {
+        Some(other) => other.provider == range.provider && other.start == range.start
+            && other.end == range.end,
+        None => false,
+    };
+    *out = if same { TRUE } else { FALSE };
+    S_OK
+}
+
+unsafe extern "system" fn range_compare_endpoints(this: *mut c_void, this_endpoint: c_int,
+    other: *mut c_void, other_endpoint: c_int, out: *mut c_int) -> HRESULT
+{
+    let range = &*(this as *mut TextRange);
+    let theirs = match as_range(other) {
+        Some(ref other) if other.provider == range.provider => endpoint(other, other_endpoint),
+        _ => return E_INVALIDARG,
+    };
+    let ours = endpoint(range, this_endpoint);
+    *out = if ours < theirs { -1 } else if ours > theirs { 1 } else { 0 };
+    S_OK
+}
+
+unsafe extern "system" fn range_expand_to_enclosing_unit(this: *mut c_void, unit: c_int)
+    -> HRESULT
+{
+    with_range(this, |range, snapshot| {
+        range.start = snapshot.unit_start(range.start, unit);
+        range.end = snapshot.next_boundary(range.start, unit);
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_find_attribute(_this: *mut c_void, _attribute: c_int,
+    _value: VARIANT, _backward: BOOL, out: *mut *mut c_void) -> HRESULT
+{
+    // No attributes are exposed, so none is ever found.
+    *out = null_mut();
+    S_OK
+}
+
+unsafe extern "system" fn range_find_text(this: *mut c_void, text: BSTR, backward: BOOL,
+    ignore_case: BOOL, out: *mut *mut c_void) -> HRESULT
+{
+    *out = null_mut();
+    if text.is_null() {
+        return E_INVALIDARG;
+    }
+    let needle = ::std::slice::from_raw_parts(text, SysStringLen(text) as usize);
+    with_range(this, |range, snapshot| {
+        let found = snapshot.find((range.start, range.end), needle, backward != FALSE,
+            ignore_case != FALSE);
+        if let Some(start) = found {
+            *out = new_range(range.provider, start, start + needle.len());
+        }
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_get_attribute_value(_this: *mut c_void, _attribute: c_int,
+    out: *mut VARIANT) -> HRESULT
+{
+    let mut not_supported = null_mut();
+    let hr = UiaGetReservedNotSupportedValue(&mut not_supported);
+    if hr != S_OK {
+        return hr;
+    }
+    let v = (*out).n1.n2_mut();
+    v.vt = VT_UNKNOWN as VARTYPE;
+    *v.n3.punkVal_mut() = not_supported;
+    S_OK
+}
+
+unsafe extern "system" fn range_get_bounding_rectangles(_this: *mut c_void,
+    out: *mut *mut SAFEARRAY) -> HRESULT
+{
+    // TODO: the rectangles of the lines, from the layouts of the view.
+    *out = SafeArrayCreateVector(VT_R8 as VARTYPE, 0, 0);
+    S_OK
+}
+
+unsafe extern "system" fn range_get_enclosing_element(this: *mut c_void,
+    out: *mut *mut c_void) -> HRESULT
+{
+    let provider = (*(this as *mut TextRange)).provider;
+    provider_add_ref(provider);
+    *out = simple_ptr(provider);
+    S_OK
+}
+
+unsafe extern "system" fn range_get_text(this: *mut c_void, max_length: c_int, out: *mut BSTR)
+    -> HRESULT
+{
+    with_range(this, |range, snapshot| {
+        let mut text = &snapshot.text[range.start..range.end];
+        if max_length >= 0 && text.len() > max_length as usize {
+            text = &text[..max_length as usize];
+        }
+        *out = SysAllocStringLen(text.as_ptr(), text.len() as u32);
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_move(this: *mut c_void, unit: c_int, count: c_int,
+    moved: *mut c_int) -> HRESULT
+{
+    with_range(this, |range, snapshot| {
+        let degenerate = range.start == range.end;
+        let mut n = 0;
+        let mut pos = snapshot.unit_start(range.start, unit);
+        if count > 0 {
+            while n < count {
+                let next = snapshot.next_boundary(pos, unit);
+                // The end of the text doesn't start a unit.
+                if next >= snapshot.len() {
+                    break;
+                }
+                pos = next;
+                n += 1;
+            }
+        } else if count < 0 {
+            pos = range.start;
+            while n > count && pos > 0 {
+                pos = snapshot.prev_boundary(pos, unit);
+                n -= 1;
+            }
+        } else {
+            pos = range.start;
+        }
+        range.start = pos;
+        range.end = if degenerate { pos } else { snapshot.next_boundary(pos, unit) };
+        *moved = n;
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_move_endpoint_by_unit(this: *mut c_void, which: c_int,
+    unit: c_int, count: c_int, moved: *mut c_int) -> HRESULT
+{
+    with_range(this, |range, snapshot| {
+        let mut pos = endpoint(range, which);
+        let mut n = 0;
+        while n < count && pos < snapshot.len() {
+            pos = snapshot.next_boundary(pos, unit);
+            n += 1;
+        }
+        while n > count && pos > 0 {
+            pos = snapshot.prev_boundary(pos, unit);
+            n -= 1;
+        }
+        set_endpoint(range, which, pos);
+        *moved = n;
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_move_endpoint_by_range(this: *mut c_void, which: c_int,
+    other: *mut c_void, other_endpoint: c_int) -> HRESULT
+{
+    let pos = match as_range(other) {
+        Some(ref other) if other.provider == (*(this as *mut TextRange)).provider => {
+            endpoint(other, other_endpoint)
+        }
+        _ => return E_INVALIDARG,
+    };
+    with_range(this, |range, snapshot| {
+        set_endpoint(range, which, min(pos, snapshot.len()));
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_select(this: *mut c_void) -> HRESULT {
+    with_range(this, |range, snapshot| {
+        let request = Request::Select(snapshot.point(range.start), snapshot.point(range.end));
+        (*(*range.provider).shared.requests.lock().unwrap())(request);
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_add_to_selection(_this: *mut c_void) -> HRESULT {
+    UIA_E_INVALIDOPERATION
+}
+
+unsafe extern "system" fn range_remove_from_selection(_this: *mut c_void) -> HRESULT {
+    UIA_E_INVALIDOPERATION
+}
+
+unsafe extern "system" fn range_scroll_into_view(this: *mut c_void, _align_to_top: BOOL)
+    -> HRESULT
+{
+    with_range(this, |range, snapshot| {
+        let request = Request::ScrollTo(snapshot.line_of(range.start));
+        (*(*range.provider).shared.requests.lock().unwrap())(request);
+        S_OK
+    })
+}
+
+unsafe extern "system" fn range_get_children(_this: *mut c_void, out: *mut *mut SAFEARRAY)
+    -> HRESULT
+{
+    *out = SafeArrayCreateVector(VT_UNKNOWN as VARTYPE, 0, 0);
+    S_OK
+}
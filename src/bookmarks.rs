@@ -0,0 +1,137 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bookmarks on lines of a view. The core doesn't know about them, so
+//! they're moved along with their lines by the ops of each update.
+
+use std::collections::BTreeSet;
+use std::collections::btree_set;
+use std::mem;
+use std::ops::Range;
+
+use linecache::{walk_ops, LineCache, Run};
+use protocol::Op;
+
+pub struct Bookmarks {
+    lines: BTreeSet<usize>,
+    // Whether the lines have changed since take_changed.
+    changed: bool,
+}
+
+impl Bookmarks {
+    pub fn new() -> Bookmarks {
+        Bookmarks { lines: BTreeSet::new(), changed: false }
+    }
+
+    pub fn toggle(&mut self, line: usize) {
+        if !self.lines.remove(&line) {
+            self.lines.insert(line);
+        }
+        self.changed = true;
+    }
+
+    /// Remove them all. Returns whether there were any.
+    pub fn clear(&mut self) -> bool {
+        let had_any = !self.lines.is_empty();
+        self.lines.clear();
+        self.changed |= had_any;
+        had_any
+    }
+
+    pub fn take_changed(&mut self) -> bool {
+        mem::replace(&mut self.changed, false)
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        self.lines.contains(&line)
+    }
+
+    /// The bookmarked lines, in order.
+    pub fn iter(&self) -> btree_set::Iter<usize> {
+        self.lines.iter()
+    }
+
+    pub fn lines(&self) -> &BTreeSet<usize> {
+        &self.lines
+    }
+
+    /// The first bookmark after a line, wrapping around to the first.
+    pub fn next(&self, line: usize) -> Option<usize> {
+        self.lines.range(line + 1..).next().or_else(|| self.lines.iter().next()).cloned()
+    }
+
+    /// The last bookmark before a line, wrapping around to the last.
+    pub fn prev(&self, line: usize) -> Option<usize> {
+        self.lines.range(..line).next_back().or_else(|| self.lines.iter().next_back()).cloned()
+    }
+
+    /// Move the bookmarks with their lines through the ops of an update,
+    /// before it's applied to the cache, which still has the old lines.
+    /// Bookmarks on lines that were deleted are dropped.
+    pub fn apply_ops(&mut self, ops: &[Op], cache: &LineCache) {
+        if self.lines.is_empty() {
+            return;
+        }
+        let mut moved = BTreeSet::new();
+        walk_ops(ops, |run| match run {
+            Run::Copied { old, new_start } => {
+                for line in self.lines.range(old.clone()) {
+                    moved.insert(line - old.start + new_start);
+                }
+            }
+            Run::Replaced { old, new_start, added } => {
+                self.move_replaced(old, new_start, added, cache, &mut moved);
+            }
+        });
+        if moved != self.lines {
+            self.lines = moved;
+            self.changed = true;
+        }
+    }
+
+    // Find where the bookmarks in a run of replaced old lines went among
+    // the new lines starting at new_start: to a line with the same text,
+    // the same line when the run was edited in place, or the first new
+    // line when a line was split or joined onto.
+    fn move_replaced(&self, replaced: Range<usize>, new_start: usize, added: &[Option<&str>],
+        old: &LineCache, moved: &mut BTreeSet<usize>)
+    {
+        let in_place = replaced.end - replaced.start == added.len();
+        for &line in self.lines.range(replaced.clone()) {
+            let k = line - replaced.start;
+            let old_text = old.get_line(line).map(|line| trim_eol(line.text()));
+            let same_text = old_text.and_then(|text| {
+                added.iter().position(|added| added.map(trim_eol) == Some(text))
+            });
+            let target = same_text.or_else(|| if in_place {
+                Some(k)
+            } else if k == 0 {
+                match (old_text, added.first().and_then(|&text| text.map(trim_eol))) {
+                    (Some(old), Some(new)) if !old.is_empty() && !new.is_empty()
+                        && (old.starts_with(new) || new.starts_with(old)) => Some(0),
+                    _ => None,
+                }
+            } else {
+                None
+            });
+            if let Some(target) = target {
+                moved.insert(new_start + target);
+            }
+        }
+    }
+}
+
+fn trim_eol(text: &str) -> &str {
+    text.trim_end_matches(|c| c == '\n' || c == '\r')
+}
@@ -0,0 +1,178 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where the edit view's chrome goes either side of its text: the line
+//! number gutter and the bookmark margin on one side, the minimap and the
+//! scrollbar on the other. For Arabic and Hebrew UIs it's mirrored, with
+//! the gutter at the right and the scrollbar at the left; the text itself
+//! still reads the way it's written.
+
+use winapi::shared::minwindef::DWORD;
+use winapi::um::wingdi::LAYOUT_RTL;
+use winapi::um::winnls::GetUserDefaultUILanguage;
+use winapi::um::winnt::{LANG_ARABIC, LANG_FARSI, LANG_HEBREW, LANG_URDU, PRIMARYLANGID};
+use winapi::um::winuser::GetProcessDefaultLayout;
+
+/// A part of the view across its width.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    Gutter,
+    /// The text, with the margins beside it.
+    Text,
+    Minimap,
+    Scrollbar,
+}
+
+/// The widths of the view's parts, and which side they go on. Spans are
+/// (left, right), in px units from the view's left edge.
+#[derive(Clone, Copy)]
+pub struct Chrome {
+    pub width: f32,
+    pub gutter_width: f32,
+    /// Between the gutter and the text, where bookmarks are marked. A
+    /// mirrored view has as much again between the minimap and the text,
+    /// so the text doesn't touch it.
+    pub margin: f32,
+    pub minimap_width: f32,
+    pub scrollbar_width: f32,
+    pub mirrored: bool,
+}
+
+impl Chrome {
+    pub fn gutter(&self) -> (f32, f32) {
+        self.flip(0.0, self.gutter_width)
+    }
+
+    /// The margin beside the gutter.
+    pub fn margin(&self) -> (f32, f32) {
+        self.flip(self.gutter_width, self.gutter_width + self.margin)
+    }
+
+    /// Where lines are laid out, as if the view weren't scrolled sideways.
+    pub fn text(&self) -> (f32, f32) {
+        if self.mirrored {
+            (self.scrollbar_width + self.minimap_width + self.margin,
+                self.width - self.gutter_width - self.margin)
+        } else {
+            (self.gutter_width + self.margin,
+                self.width - self.scrollbar_width - self.minimap_width)
+        }
+    }
+
+    pub fn minimap(&self) -> (f32, f32) {
+        let right = self.width - self.scrollbar_width;
+        self.flip(right - self.minimap_width, right)
+    }
+
+    pub fn scrollbar(&self) -> (f32, f32) {
+        self.flip(self.width - self.scrollbar_width, self.width)
+    }
+
+    /// The part of the view at x. The margins count as text, so a click
+    /// there puts the caret at the start or end of the line.
+    pub fn region_at(&self, x: f32) -> Region {
+        let within = |(x0, x1): (f32, f32)| x >= x0 && x < x1;
+        if within(self.scrollbar()) {
+            Region::Scrollbar
+        } else if within(self.minimap()) {
+            Region::Minimap
+        } else if within(self.gutter()) {
+            Region::Gutter
+        } else {
+            Region::Text
+        }
+    }
+
+    // A span as it'd be without mirroring, placed for the view.
+    fn flip(&self, x0: f32, x1: f32) -> (f32, f32) {
+        if self.mirrored {
+            (self.width - x1, self.width - x0)
+        } else {
+            (x0, x1)
+        }
+    }
+}
+
+/// Whether the UI should be mirrored: if the process was started with a
+/// right-to-left default layout, or the user's UI language is written
+/// right to left.
+pub fn detect_mirrored() -> bool {
+    let mut layout: DWORD = 0;
+    if unsafe { GetProcessDefaultLayout(&mut layout) } != 0 && (layout & LAYOUT_RTL) != 0 {
+        return true;
+    }
+    let lang = PRIMARYLANGID(unsafe { GetUserDefaultUILanguage() });
+    [LANG_ARABIC, LANG_HEBREW, LANG_FARSI, LANG_URDU].contains(&lang)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chrome(mirrored: bool) -> Chrome {
+        Chrome {
+            width: 500.0,
+            gutter_width: 40.0,
+            margin: 6.0,
+            minimap_width: 100.0,
+            scrollbar_width: 12.0,
+            mirrored,
+        }
+    }
+
+    #[test]
+    fn left_to_right() {
+        let c = chrome(false);
+        assert_eq!(c.gutter(), (0.0, 40.0));
+        assert_eq!(c.margin(), (40.0, 46.0));
+        assert_eq!(c.text(), (46.0, 388.0));
+        assert_eq!(c.minimap(), (388.0, 488.0));
+        assert_eq!(c.scrollbar(), (488.0, 500.0));
+    }
+
+    #[test]
+    fn mirrored() {
+        let c = chrome(true);
+        assert_eq!(c.scrollbar(), (0.0, 12.0));
+        assert_eq!(c.minimap(), (12.0, 112.0));
+        assert_eq!(c.text(), (118.0, 454.0));
+        assert_eq!(c.margin(), (454.0, 460.0));
+        assert_eq!(c.gutter(), (460.0, 500.0));
+    }
+
+    #[test]
+    fn hidden_parts() {
+        let c = Chrome { gutter_width: 0.0, minimap_width: 0.0, ..chrome(true) };
+        assert_eq!(c.text(), (18.0, 494.0));
+        assert_eq!(c.region_at(13.0), Region::Text);
+        assert_eq!(c.region_at(499.0), Region::Text);
+        let c = Chrome { mirrored: false, ..c };
+        assert_eq!(c.text(), (6.0, 488.0));
+        assert_eq!(c.region_at(0.0), Region::Text);
+    }
+
+    #[test]
+    fn hit_testing() {
+        let regions = |c: Chrome| -> Vec<Region> {
+            [0.0, 11.9, 12.0, 111.0, 115.0, 300.0, 455.0, 460.0, 499.0].iter()
+                .map(|&x| c.region_at(x))
+                .collect()
+        };
+        use self::Region::*;
+        assert_eq!(regions(chrome(true)),
+            vec![Scrollbar, Scrollbar, Minimap, Minimap, Text, Text, Text, Gutter, Gutter]);
+        assert_eq!(regions(chrome(false)),
+            vec![Gutter, Gutter, Gutter, Text, Text, Text, Minimap, Minimap, Scrollbar]);
+    }
+}
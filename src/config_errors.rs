@@ -0,0 +1,133 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What's wrong with the front end's own configuration files, settings.json
+//! and keymap.json, and where, so the user can be told and taken there.
+//!
+//! A file that can't be read as a whole is replaced by the last copy of it
+//! that could, kept next to it as a .last-good.json file.
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json;
+
+use settings::{config_path, read_file};
+
+/// A problem in a configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    /// The file's name, as the user knows it.
+    pub file: String,
+    /// The line it's on, from 1, if it's known.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new<S: Into<String>>(file: &str, line: Option<usize>, message: S) -> ConfigError {
+        ConfigError { file: file.to_owned(), line, message: message.into() }
+    }
+
+    /// A JSON syntax error, at the line it says it's on.
+    pub fn from_json(file: &str, e: &serde_json::Error) -> ConfigError {
+        // The line goes in front, as for other errors, so it isn't
+        // repeated at the end.
+        let text = e.to_string();
+        let position = format!(" at line {} column {}", e.line(), e.column());
+        let message = text.trim_end_matches(position.as_str());
+        ConfigError::new(file, Some(e.line()), message)
+    }
+
+    /// Where the file is, to open it at the error. The built-in defaults
+    /// are in no file.
+    pub fn path(&self) -> Option<PathBuf> {
+        config_path(&self.file).filter(|path| path.exists())
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}, line {}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+// The copy of a file kept from the last time it could be read, like
+// settings.last-good.json.
+fn last_good_path(file: &str) -> Option<PathBuf> {
+    config_path(&file.replace(".json", ".last-good.json"))
+}
+
+/// The text of a file the last time it could be read, if it's been kept.
+pub fn read_last_good(file: &str) -> Option<String> {
+    last_good_path(file).and_then(|path| read_file(&path).ok())
+}
+
+/// Keep the text of a file that could be read, to fall back on if a later
+/// edit breaks it.
+pub fn write_last_good(file: &str, text: &str) {
+    let path = match last_good_path(file) {
+        Some(path) => path,
+        None => return,
+    };
+    match read_file(&path) {
+        Ok(ref kept) if kept == text => return,
+        _ => (),
+    }
+    if let Err(e) = fs::write(&path, text) {
+        println!("failed to keep a copy of {}: {}", file, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn json_error(text: &str) -> ConfigError {
+        ConfigError::from_json("keymap.json", &serde_json::from_str::<Value>(text).unwrap_err())
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let e = json_error("[\n  {\"keys\": \"ctrl+k\",\n]");
+        assert_eq!(e.line, Some(3));
+        assert!(!e.message.contains("line"), "{}", e.message);
+        assert!(e.to_string().starts_with("keymap.json, line 3: "), "{}", e);
+        // A corpus of broken files, each with the line of its mistake.
+        let broken = [
+            ("{\n  \"line_numbers\": \"off\"\n  \"word_wrap\": true\n}", 3),
+            ("{\n  \"tab_size\": 4,\n}", 3),
+            ("{\n  'tab_size': 4\n}", 2),
+            ("[\n  {\"keys\": \"ctrl+k\"}\n  {\"keys\": \"ctrl+j\"}\n]", 3),
+            ("[\n  {\"keys\": \"ctrl+k\", \"command\": \"undo\"\n]", 3),
+            ("", 1),
+        ];
+        for &(text, line) in broken.iter() {
+            assert_eq!(json_error(text).line, Some(line), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(ConfigError::new("keymap.json", Some(4), "unknown keys").to_string(),
+            "keymap.json, line 4: unknown keys");
+        assert_eq!(ConfigError::new("settings.json", None, "expected an object").to_string(),
+            "settings.json: expected an object");
+    }
+}
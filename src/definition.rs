@@ -0,0 +1,222 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Jump to definition: Ctrl+click on an identifier asks the running
+//! plugins where it's defined, through the core's plugin_rpc, as a
+//! find_definition notification with a request id and the position. A
+//! plugin that knows answers with a show_definition notification carrying
+//! the same id and a result of a file_path, line and col, or null if it
+//! found nothing.
+//!
+//! Where each jump came from goes on a back stack shared by the windows,
+//! for going back and forward with Alt+Left and Alt+Right, along with
+//! where the caret was before other jumps far enough to lose one's place,
+//! like going to a line or a find result. The places move with their
+//! lines as the file is edited.
+
+use std::cmp::{max, min};
+use std::fs;
+use std::mem;
+
+use serde_json::Value;
+
+use linecache::{walk_ops, Run};
+use protocol::Op;
+
+/// How long to wait for a plugin before the click does what a Ctrl+click
+/// otherwise does, and adds a caret, in ms.
+pub const DEFINITION_TIMEOUT_MS: u32 = 1000;
+
+// Older places are forgotten past this many.
+const MAX_HISTORY: usize = 100;
+// A caret move of more than this many lines at once is a jump, and
+// places within this many lines of each other are the same place.
+const JUMP_LINES: usize = 10;
+
+/// A place in a file to go to.
+#[derive(Clone, PartialEq)]
+pub struct Location {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Location {
+    /// The location in a show_definition result, if there is one.
+    pub fn from_result(result: &Value) -> Option<Location> {
+        Some(Location {
+            path: result["file_path"].as_str()?.to_owned(),
+            line: result["line"].as_u64()? as usize,
+            col: result["col"].as_u64().unwrap_or(0) as usize,
+        })
+    }
+
+    fn is_near(&self, other: &Location) -> bool {
+        self.path == other.path && max(self.line, other.line) - min(self.line, other.line)
+            <= JUMP_LINES
+    }
+
+    /// Whether it's in this file. The plugin may spell the path
+    /// differently, with the other slashes or case, say.
+    pub fn is_in(&self, path: &str) -> bool {
+        if self.path == path {
+            return true;
+        }
+        match (fs::canonicalize(&self.path), fs::canonicalize(path)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// The places jumped from, to go back to, and the ones gone back from, to
+/// go forward to again.
+pub struct Navigation {
+    back: Vec<Location>,
+    forward: Vec<Location>,
+}
+
+impl Navigation {
+    pub fn new() -> Navigation {
+        Navigation { back: Vec::new(), forward: Vec::new() }
+    }
+
+    /// Remember where a jump is from. Jumping somewhere new forgets the
+    /// way forward, like in a browser.
+    pub fn push(&mut self, from: Location) {
+        if !self.back.last().map_or(false, |last| last.is_near(&from)) {
+            if self.back.len() == MAX_HISTORY {
+                self.back.remove(0);
+            }
+            self.back.push(from);
+        }
+        self.forward.clear();
+    }
+
+    /// Where to go back to, if anywhere. The current place, if known, is
+    /// kept for going forward to.
+    pub fn back(&mut self, current: Option<Location>) -> Option<Location> {
+        let to = self.back.pop()?;
+        self.forward.extend(current);
+        Some(to)
+    }
+
+    /// Where to go forward to, if anywhere, keeping the current place for
+    /// going back to.
+    pub fn forward(&mut self, current: Option<Location>) -> Option<Location> {
+        let to = self.forward.pop()?;
+        self.back.extend(current);
+        Some(to)
+    }
+
+    /// Move the places in a file with their lines through the ops of an
+    /// update to it. A place on a line that was deleted goes to the line
+    /// after.
+    pub fn apply_ops(&mut self, path: &str, ops: &[Op]) {
+        let mut lines: Vec<usize> = self.back.iter().chain(self.forward.iter())
+            .filter(|location| location.path == path)
+            .map(|location| location.line)
+            .collect();
+        if lines.is_empty() {
+            return;
+        }
+        let old = lines.clone();
+        walk_ops(ops, |run| {
+            for (ix, &line) in old.iter().enumerate() {
+                if let Some(moved) = moved_line(line, &run) {
+                    lines[ix] = moved;
+                }
+            }
+        });
+        let in_file = self.back.iter_mut().chain(self.forward.iter_mut())
+            .filter(|location| location.path == path);
+        for (location, line) in in_file.zip(lines) {
+            location.line = line;
+        }
+    }
+
+    /// Forget the places in a file that's been closed.
+    pub fn forget(&mut self, path: &str) {
+        self.back.retain(|location| location.path != path);
+        self.forward.retain(|location| location.path != path);
+    }
+}
+
+// Where an old line is after a run of an update's ops, if it's in it.
+fn moved_line(line: usize, run: &Run) -> Option<usize> {
+    match *run {
+        Run::Copied { ref old, new_start } if old.start <= line && line < old.end => {
+            Some(line - old.start + new_start)
+        }
+        Run::Replaced { ref old, new_start, added } if old.start <= line && line < old.end => {
+            let k = line - old.start;
+            if old.end - old.start == added.len() {
+                Some(new_start + k)
+            } else {
+                Some(new_start + min(k, added.len().saturating_sub(1)))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Watches a view's caret for jumps: moves of more than JUMP_LINES lines
+/// at once that didn't come with an edit, like typing or pasting does.
+pub struct CaretJumps {
+    // Where the caret was last seen. It isn't while its line isn't in the
+    // cache, as just after a jump.
+    last: Option<(usize, usize)>,
+    // Whether the text has changed since.
+    edited: bool,
+    // Set when the next move goes back or forward through the places,
+    // rather than being a new one.
+    skip_next: bool,
+    jumped_from: Option<(usize, usize)>,
+}
+
+impl CaretJumps {
+    pub fn new() -> CaretJumps {
+        CaretJumps { last: None, edited: false, skip_next: false, jumped_from: None }
+    }
+
+    /// Follow the caret through an update, with where it is afterwards, if
+    /// its line is in the cache, and whether the update changed the text.
+    pub fn update(&mut self, caret: Option<(usize, usize)>, text_changed: bool) {
+        self.edited |= text_changed;
+        let caret = match caret {
+            Some(caret) => caret,
+            None => return,
+        };
+        let last = mem::replace(&mut self.last, Some(caret));
+        let edited = mem::replace(&mut self.edited, false);
+        let (line, col) = match last {
+            Some(last) if last.0 != caret.0 => last,
+            _ => return,
+        };
+        let skip = mem::replace(&mut self.skip_next, false);
+        if max(line, caret.0) - min(line, caret.0) > JUMP_LINES && !edited && !skip {
+            self.jumped_from = Some((line, col));
+        }
+    }
+
+    /// Don't count the next move as a jump.
+    pub fn skip_next(&mut self) {
+        self.skip_next = true;
+    }
+
+    /// Where the caret last jumped from, once.
+    pub fn take(&mut self) -> Option<(usize, usize)> {
+        self.jumped_from.take()
+    }
+}
@@ -9,9 +9,35 @@ use winapi::shared::wtypesbase::*;
 use winapi::um::combaseapi::*;
 use winapi::um::shobjidl::*;
 use winapi::um::shobjidl_core::*;
+use winapi::um::winuser::*;
 
 use std::ptr::null_mut;
-use xi_win_shell::util::FromWide;
+use xi_win_shell::util::{FromWide, ToWide};
+
+/// Show a message to the user in a modal message box.
+pub unsafe fn show_alert(hwnd_owner: HWND, msg: &str) {
+  MessageBoxW(hwnd_owner, msg.to_wide().as_ptr(), "xi-editor".to_wide().as_ptr(),
+      MB_OK | MB_ICONWARNING);
+}
+
+/// What to do with unsaved changes, from `ask_save_changes`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SaveChoice {
+  Save,
+  Discard,
+  Cancel,
+}
+
+/// Ask whether to save changes before they'd be lost, in a modal message
+/// box with Yes, No and Cancel.
+pub unsafe fn ask_save_changes(hwnd_owner: HWND, msg: &str) -> SaveChoice {
+  match MessageBoxW(hwnd_owner, msg.to_wide().as_ptr(), "xi-editor".to_wide().as_ptr(),
+      MB_YESNOCANCEL | MB_ICONWARNING) {
+    IDYES => SaveChoice::Save,
+    IDNO => SaveChoice::Discard,
+    _ => SaveChoice::Cancel,
+  }
+}
 
 pub unsafe fn get_open_file_dialog_path(hwnd_owner: HWND) -> Option<String> {
   get_file_dialog_path(hwnd_owner, true)
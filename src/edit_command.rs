@@ -0,0 +1,344 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The edit commands the core takes for a view, as a typed vocabulary for
+//! key bindings, menus and the edit view to share.
+
+use serde_json::Value;
+
+// The commands that take no parameters, with the core's names for them.
+macro_rules! edit_commands {
+    ($($variant:ident => $name:expr,)*) => {
+        #[derive(Clone, Debug, PartialEq)]
+        pub enum EditCommand {
+            $($variant,)*
+            Insert { chars: String },
+            /// Like insert, but with a line for each caret, each caret gets
+            /// its own line.
+            Paste { chars: String },
+            /// Tell the core which lines are visible.
+            Scroll { first: usize, last: usize },
+            Gesture { line: usize, col: usize, ty: GestureType },
+            /// Extend the selection being dragged out to a point.
+            Drag { line: usize, col: usize },
+            GotoLine { line: usize },
+            /// Search for the selection, or the word at each caret.
+            SelectionForFind { case_sensitive: bool },
+            FindNext { wrap_around: bool, allow_same: bool, modify_selection: ModifySelection },
+            /// Requests, answered with the text taken.
+            Copy,
+            Cut,
+            /// Ask the plugins about a point, answered with show_hover.
+            RequestHover { request_id: usize, line: usize, col: usize },
+        }
+
+        impl EditCommand {
+            /// The core's name for the command.
+            pub fn method(&self) -> &str {
+                match *self {
+                    $(EditCommand::$variant => $name,)*
+                    EditCommand::Insert { .. } => "insert",
+                    EditCommand::Paste { .. } => "paste",
+                    EditCommand::Scroll { .. } => "scroll",
+                    EditCommand::Gesture { .. } => "gesture",
+                    EditCommand::Drag { .. } => "drag",
+                    EditCommand::GotoLine { .. } => "goto_line",
+                    EditCommand::SelectionForFind { .. } => "selection_for_find",
+                    EditCommand::FindNext { .. } => "find_next",
+                    EditCommand::Copy => "copy",
+                    EditCommand::Cut => "cut",
+                    EditCommand::RequestHover { .. } => "request_hover",
+                }
+            }
+
+            /// The command without parameters that the core knows by this
+            /// name, for key bindings.
+            pub fn from_name(name: &str) -> Option<EditCommand> {
+                match name {
+                    $($name => Some(EditCommand::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Each command without parameters, with its name.
+            #[cfg(test)]
+            fn all_named() -> Vec<(EditCommand, &'static str)> {
+                vec![$((EditCommand::$variant, $name),)*]
+            }
+        }
+    }
+}
+
+edit_commands! {
+    InsertNewline => "insert_newline",
+    InsertTab => "insert_tab",
+    Indent => "indent",
+    Outdent => "outdent",
+    MoveUp => "move_up",
+    MoveUpAndModifySelection => "move_up_and_modify_selection",
+    MoveDown => "move_down",
+    MoveDownAndModifySelection => "move_down_and_modify_selection",
+    MoveLeft => "move_left",
+    MoveLeftAndModifySelection => "move_left_and_modify_selection",
+    MoveRight => "move_right",
+    MoveRightAndModifySelection => "move_right_and_modify_selection",
+    MoveWordLeft => "move_word_left",
+    MoveWordLeftAndModifySelection => "move_word_left_and_modify_selection",
+    MoveWordRight => "move_word_right",
+    MoveWordRightAndModifySelection => "move_word_right_and_modify_selection",
+    MoveToLeftEndOfLine => "move_to_left_end_of_line",
+    MoveToLeftEndOfLineAndModifySelection => "move_to_left_end_of_line_and_modify_selection",
+    MoveToRightEndOfLine => "move_to_right_end_of_line",
+    MoveToRightEndOfLineAndModifySelection => "move_to_right_end_of_line_and_modify_selection",
+    MoveToBeginningOfDocument => "move_to_beginning_of_document",
+    MoveToBeginningOfDocumentAndModifySelection =>
+        "move_to_beginning_of_document_and_modify_selection",
+    MoveToEndOfDocument => "move_to_end_of_document",
+    MoveToEndOfDocumentAndModifySelection => "move_to_end_of_document_and_modify_selection",
+    ScrollPageUp => "scroll_page_up",
+    PageUpAndModifySelection => "page_up_and_modify_selection",
+    ScrollPageDown => "scroll_page_down",
+    PageDownAndModifySelection => "page_down_and_modify_selection",
+    AddSelectionAbove => "add_selection_above",
+    AddSelectionBelow => "add_selection_below",
+    SelectAll => "select_all",
+    CancelOperation => "cancel_operation",
+    DeleteBackward => "delete_backward",
+    DeleteForward => "delete_forward",
+    DeleteWordBackward => "delete_word_backward",
+    DeleteWordForward => "delete_word_forward",
+    DeleteToBeginningOfLine => "delete_to_beginning_of_line",
+    DeleteToEndOfParagraph => "delete_to_end_of_paragraph",
+    Undo => "undo",
+    Redo => "redo",
+    Uppercase => "uppercase",
+    Lowercase => "lowercase",
+    Transpose => "transpose",
+    DuplicateLine => "duplicate_line",
+    DebugToggleComment => "debug_toggle_comment",
+}
+
+/// How a gesture sets the selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GestureType {
+    PointSelect,
+    RangeSelect,
+    LineSelect,
+    WordSelect,
+    /// Add a line or word to the selections, for a click with Ctrl.
+    MultiLineSelect,
+    MultiWordSelect,
+    /// Add a caret, or remove the one already there.
+    ToggleSel,
+}
+
+impl GestureType {
+    fn name(self) -> &'static str {
+        match self {
+            GestureType::PointSelect => "point_select",
+            GestureType::RangeSelect => "range_select",
+            GestureType::LineSelect => "line_select",
+            GestureType::WordSelect => "word_select",
+            GestureType::MultiLineSelect => "multi_line_select",
+            GestureType::MultiWordSelect => "multi_word_select",
+            GestureType::ToggleSel => "toggle_sel",
+        }
+    }
+}
+
+/// How find_next changes the selections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModifySelection {
+    /// Add the match to the selections.
+    Add,
+    /// Add the match, and drop the selection it was found from.
+    AddRemovingCurrent,
+}
+
+impl ModifySelection {
+    fn name(self) -> &'static str {
+        match self {
+            ModifySelection::Add => "add",
+            ModifySelection::AddRemovingCurrent => "add_removing_current",
+        }
+    }
+}
+
+impl EditCommand {
+    /// The params to send with the command.
+    pub fn params(&self) -> Value {
+        match *self {
+            EditCommand::Insert { ref chars } | EditCommand::Paste { ref chars } => {
+                json!({"chars": chars})
+            }
+            EditCommand::Scroll { first, last } => json!([first, last]),
+            EditCommand::Gesture { line, col, ty } => {
+                json!({"line": line, "col": col, "ty": ty.name()})
+            }
+            EditCommand::Drag { line, col } => json!([line, col, 0]),
+            EditCommand::GotoLine { line } => json!({"line": line}),
+            EditCommand::SelectionForFind { case_sensitive } => {
+                json!({"case_sensitive": case_sensitive})
+            }
+            EditCommand::FindNext { wrap_around, allow_same, modify_selection } => json!({
+                "wrap_around": wrap_around,
+                "allow_same": allow_same,
+                "modify_selection": modify_selection.name(),
+            }),
+            EditCommand::RequestHover { request_id, line, col } => json!({
+                "request_id": request_id,
+                "position": {"line": line, "col": col},
+            }),
+            _ => json!([]),
+        }
+    }
+
+    /// The params of the "edit" notification, or request, that sends the
+    /// command for a view.
+    pub fn edit_params(&self, view_id: &str) -> Value {
+        json!({
+            "method": self.method(),
+            "params": self.params(),
+            "view_id": view_id,
+        })
+    }
+
+    /// Whether the command changes the buffer, so the core answers it with
+    /// an update.
+    pub fn is_mutating(&self) -> bool {
+        match *self {
+            EditCommand::Insert { .. } | EditCommand::Paste { .. } | EditCommand::Cut |
+                EditCommand::InsertNewline | EditCommand::InsertTab | EditCommand::Indent |
+                EditCommand::Outdent | EditCommand::Uppercase | EditCommand::Lowercase |
+                EditCommand::Transpose | EditCommand::DuplicateLine |
+                EditCommand::DebugToggleComment | EditCommand::Undo |
+                EditCommand::Redo | EditCommand::DeleteBackward | EditCommand::DeleteForward |
+                EditCommand::DeleteWordBackward | EditCommand::DeleteWordForward |
+                EditCommand::DeleteToBeginningOfLine |
+                EditCommand::DeleteToEndOfParagraph => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_commands() {
+        let named = EditCommand::all_named();
+        assert!(!named.is_empty());
+        for (command, name) in named {
+            assert_eq!(command.method(), name);
+            assert_eq!(command.params(), json!([]));
+            assert_eq!(EditCommand::from_name(name), Some(command.clone()));
+            assert_eq!(EditCommand::from_name(command.method()), Some(command));
+        }
+    }
+
+    #[test]
+    fn commands_with_params_have_no_name() {
+        for name in &["insert", "paste", "scroll", "gesture", "drag", "goto_line",
+            "selection_for_find", "find_next", "copy", "cut", "request_hover", "", "nonsense"]
+        {
+            assert_eq!(EditCommand::from_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn commands_with_params() {
+        let chars = || "a\n".to_owned();
+        let cases = vec![
+            (EditCommand::Insert { chars: chars() }, "insert", json!({"chars": "a\n"})),
+            (EditCommand::Paste { chars: chars() }, "paste", json!({"chars": "a\n"})),
+            (EditCommand::Scroll { first: 3, last: 40 }, "scroll", json!([3, 40])),
+            (EditCommand::Gesture { line: 2, col: 5, ty: GestureType::PointSelect }, "gesture",
+                json!({"line": 2, "col": 5, "ty": "point_select"})),
+            (EditCommand::Drag { line: 7, col: 1 }, "drag", json!([7, 1, 0])),
+            (EditCommand::GotoLine { line: 9 }, "goto_line", json!({"line": 9})),
+            (EditCommand::SelectionForFind { case_sensitive: true }, "selection_for_find",
+                json!({"case_sensitive": true})),
+            (EditCommand::FindNext {
+                wrap_around: true,
+                allow_same: false,
+                modify_selection: ModifySelection::AddRemovingCurrent,
+            }, "find_next", json!({
+                "wrap_around": true,
+                "allow_same": false,
+                "modify_selection": "add_removing_current",
+            })),
+            (EditCommand::Copy, "copy", json!([])),
+            (EditCommand::Cut, "cut", json!([])),
+            (EditCommand::RequestHover { request_id: 4, line: 1, col: 2 }, "request_hover",
+                json!({"request_id": 4, "position": {"line": 1, "col": 2}})),
+        ];
+        for (command, method, params) in cases {
+            assert_eq!(command.method(), method);
+            assert_eq!(command.params(), params, "{}", method);
+        }
+    }
+
+    #[test]
+    fn gesture_types() {
+        let cases = [
+            (GestureType::PointSelect, "point_select"),
+            (GestureType::RangeSelect, "range_select"),
+            (GestureType::LineSelect, "line_select"),
+            (GestureType::WordSelect, "word_select"),
+            (GestureType::MultiLineSelect, "multi_line_select"),
+            (GestureType::MultiWordSelect, "multi_word_select"),
+            (GestureType::ToggleSel, "toggle_sel"),
+        ];
+        for &(ty, name) in &cases {
+            let params = EditCommand::Gesture { line: 0, col: 0, ty }.params();
+            assert_eq!(params["ty"], json!(name));
+        }
+        let params = EditCommand::FindNext {
+            wrap_around: false,
+            allow_same: true,
+            modify_selection: ModifySelection::Add,
+        }.params();
+        assert_eq!(params["modify_selection"], json!("add"));
+    }
+
+    #[test]
+    fn mutating() {
+        let mutating = [
+            EditCommand::Insert { chars: "x".to_owned() },
+            EditCommand::Paste { chars: "x".to_owned() },
+            EditCommand::Cut,
+            EditCommand::InsertNewline,
+            EditCommand::DeleteBackward,
+            EditCommand::DeleteWordForward,
+            EditCommand::DeleteToEndOfParagraph,
+            EditCommand::Undo,
+            EditCommand::DuplicateLine,
+        ];
+        for command in &mutating {
+            assert!(command.is_mutating(), "{:?}", command);
+        }
+        let not_mutating = [
+            EditCommand::MoveLeft,
+            EditCommand::SelectAll,
+            EditCommand::Copy,
+            EditCommand::Scroll { first: 0, last: 1 },
+            EditCommand::Gesture { line: 0, col: 0, ty: GestureType::WordSelect },
+            EditCommand::RequestHover { request_id: 0, line: 0, col: 0 },
+        ];
+        for command in &not_mutating {
+            assert!(!command.is_mutating(), "{:?}", command);
+        }
+    }
+}
@@ -14,26 +14,71 @@
 
 //! The main edit view.
 
-use std::cmp::min;
+use std::collections::BTreeMap;
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+#[cfg(debug_assertions)]
+use std::collections::HashSet;
+use std::mem;
 use std::ops::Range;
+use std::ptr::null_mut;
+use std::time::{Duration, Instant};
 
 use serde_json::Value;
 
+use winapi::Interface;
+use winapi::ctypes::c_void;
+use winapi::shared::winerror::SUCCEEDED;
+use winapi::um::dwrite::{DWRITE_LINE_METRICS, DWRITE_LINE_SPACING_METHOD_UNIFORM};
+use winapi::um::dwrite_2::{IDWriteFactory2, IDWriteFontFallback, IDWriteTextFormat1};
+use winapi::um::winbase::INFINITE;
 use winapi::um::winuser::*;
 
 use direct2d::brush;
 use direct2d::math::*;
+use direct2d::render_target::RenderTarget;
 use directwrite::{self, TextFormat, TextLayout};
+use directwrite::enums::FontWeight;
 use directwrite::text_format;
 use directwrite::text_layout;
 
+use xi_win_shell::clipboard;
 use xi_win_shell::paint::PaintCtx;
-use xi_win_shell::util::default_text_options;
-use xi_win_shell::window::{M_ALT, M_CTRL, M_SHIFT};
+use xi_win_shell::util::{default_text_options, Error};
+use xi_win_shell::window::{MouseButton, TouchType, M_ALT, M_CTRL, M_SHIFT};
 
-use MainWin;
+use {MainWin, MainWinHandler, Timers};
 
-use linecache::LineCache;
+use chrome::{Chrome, Region};
+use edit_command::{EditCommand, GestureType, ModifySelection};
+use bookmarks::Bookmarks;
+use definition::{CaretJumps, DEFINITION_TIMEOUT_MS};
+use encoding::{Encoding, TempCopy};
+use hover::{Hover, HOVER_DELAY_MS};
+use keymap::{is_modifier, Binding, ViewAction};
+use linecache::{HighlightKind, Line, LineCache};
+use paint_stats::PaintStats;
+use minimap::{Minimap, MINIMAP_WIDTH};
+use protocol::Update;
+use scrollbar::{MarkKind, Scrollbar, SCROLLBAR_WIDTH};
+use selection_count::{Counts, SelectionCounts};
+use utf16::{utf16_to_utf8, utf8_to_utf16};
+use viewport::{self, Viewport};
+use word_boundary::{find_whole_word, is_word_char, word_end, word_start};
+
+/// Where a view's edit commands go: through the main window to the core,
+/// or in tests to a stand-in that keeps them.
+pub trait EditSink {
+    fn send_edit_cmd(&self, cmd: &EditCommand, view_id: &str);
+
+    /// Start polling check_core_stall, as an edit is waiting for the core.
+    fn watch_core_stall(&self);
+
+    /// Send the core a request for the view that changes nothing, and call
+    /// stall_probe_answered when it's answered.
+    fn send_stall_probe(&self, view_id: &str);
+}
 
 /// State and behavior for one editor view.
 pub struct EditView {
@@ -44,18 +89,564 @@ pub struct EditView {
     dwrite_factory: directwrite::Factory,
     resources: Option<Resources>,
     scroll_offset: f32,
+    // How far the text is scrolled left, in px units. Only the core's
+    // scroll_to moves it, to follow the caret along lines too long to
+    // show; it stays at zero while wrapping.
+    h_scroll_offset: f32,
+    // Set when the user scrolls, so a scroll_to from the core doesn't
+    // yank the view back to the caret; cleared by the next edit command.
+    scroll_pinned: bool,
+    // Where the last Ctrl+L put the caret's line, so pressing it again
+    // moves on; cleared by the next edit command, as the caret may move.
+    recenter: Option<Recenter>,
     size: (f32, f32),  // in px units
-    viewport: Range<usize>,
+    // The chrome laid out from the right, for right-to-left UIs.
+    mirrored: bool,
+    // How far down the window the view is, when it shares the window
+    // with another pane.
+    top: f32,
+    // The lines told to the core as visible, held back so a burst of
+    // scrolling sends one scroll a frame.
+    viewport: Viewport,
+    has_focus: bool,
+    // Blink phase; the caret is only drawn in the "on" phase while focused.
+    caret_visible: bool,
+    // Caret rectangles from the last paint, so blinking can invalidate
+    // just those.
+    caret_rects: Vec<(f32, f32, f32, f32)>,
+    stall_watch: StallWatch,
+    // Set after Ctrl+K, the first half of a chord.
+    ctrl_k_pending: bool,
+    last_click: Option<Click>,
+    word_wrap: bool,
+    // The wrap width last sent to the core, in columns; 0 is no wrapping.
+    wrap_cols: usize,
+    // Typed characters replace the one after the caret, toggled by Insert.
+    overwrite: bool,
+    // Edits aren't sent to the core, only moves and selections.
+    read_only: bool,
+    // Set while the left button is held after a click in the view.
+    drag: Option<Drag>,
+    // The rows of the box last sent while dragging out a column
+    // selection, as the line and the columns at the anchor's and the
+    // pointer's side.
+    column_rows: Vec<(usize, usize, usize)>,
+    // Whether the autoscroll timer is running, while dragging outside
+    // the view.
+    autoscrolling: bool,
+    middle_click: MiddleClick,
+    // Detected when the file is opened; the core is told to use it for
+    // new lines.
+    line_ending: LineEnding,
+    // Of the file; one that isn't UTF-8 is open as a UTF-8 copy, deleted
+    // when it's dropped.
+    encoding: Encoding,
+    temp_copy: Option<TempCopy>,
+    // Set while scrolling with the middle button.
+    pan: Option<Pan>,
+    // Set while a finger is on the view, and while the view glides on
+    // after it's lifted, at this speed in px units per ms.
+    touch: Option<Touch>,
+    glide: Option<(f32, f32)>,
+    plugins: Vec<Plugin>,
+    language: Option<String>,
+    tab_size: usize,
+    translate_tabs_to_spaces: bool,
+    show_whitespace: ShowWhitespace,
+    indent_guides: bool,
+    show_rulers: bool,
+    // Columns to draw rulers at.
+    rulers: Vec<usize>,
+    // Whether the view can scroll on past the last line, and how far, in
+    // lines; None is the height of the view less one line.
+    scroll_past_end: bool,
+    overscroll_lines: Option<usize>,
+    // A scroll offset and caret line to go back to once the first update
+    // arrives, for a file reopened from the last session.
+    pending_scroll: Option<(f32, usize)>,
+    // A line and column to put the caret at once the first update
+    // arrives, for a file opened to jump to a definition in it.
+    pending_jump: Option<(usize, usize)>,
+    // Whether the buffer matches the file, as of the last update.
+    pristine: bool,
+    // Widths answered to the core's measure_width, which asks about the
+    // same strings over and over. Cleared when the font changes.
+    width_cache: HashMap<String, f32>,
+    layouts: LayoutCache,
+    // How many undo and redo steps there are, as counted from the edits
+    // sent. The core groups edits into undo steps its own way, so these
+    // can be high but not low; an undo or redo that turns out to change
+    // nothing sets its count to zero.
+    undo_steps: usize,
+    redo_steps: usize,
+    // An undo or redo whose update hasn't arrived.
+    pending_history: Option<History>,
+    // Set when an undo or redo had nothing to do, or an edit was turned
+    // down, for the status bar.
+    note: Option<&'static str>,
+    // The other occurrences of the word at the caret in the visible
+    // lines, as line numbers and ranges.
+    word_matches: Vec<(usize, Range<usize>)>,
+    // The line and offset of the bracket at the caret, and of the one it
+    // pairs with.
+    bracket_match: Option<((usize, usize), (usize, usize))>,
+    counts: SelectionCounts,
+    stats: PaintStats,
+    hover: Hover,
+    // The identifier under the mouse, as a line and range, underlined like
+    // a link while Ctrl is held and a plugin could find its definition.
+    link: Option<(usize, Range<usize>)>,
+    definition_request: Option<DefinitionRequest>,
+    bookmarks: Bookmarks,
+    scrollbar: Scrollbar,
+    show_minimap: bool,
+    minimap: Minimap,
+    line_numbers: LineNumbers,
+    gutter_colors: GutterColors,
+    // The row whose line number stands out, the caret's or, when it's on
+    // the continuation of a wrapped line, the row starting the line.
+    current_number_row: Option<usize>,
+    // Set while the core takes long enough opening a file to say so.
+    loading: bool,
+    jumps: CaretJumps,
+}
+
+#[derive(Clone, Copy)]
+enum History {
+    Undo,
+    Redo,
+}
+
+/// Where recentering puts the caret's line in the view.
+#[derive(Clone, Copy)]
+enum Recenter {
+    Middle,
+    Top,
+    Bottom,
+}
+
+/// Which whitespace characters are marked in the view.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum ShowWhitespace {
+    Off,
+    All,
+    // Only whitespace at the end of a line, which is usually unwanted.
+    Trailing,
+}
+
+/// What clicking the middle button in the view does.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum MiddleClick {
+    /// Scroll towards the mouse until the next click, as elsewhere in
+    /// Windows.
+    Autoscroll,
+    /// Paste at the click point, as on X11.
+    Paste,
+}
+
+/// What the gutter shows beside each line.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LineNumbers {
+    /// No gutter.
+    Off,
+    Absolute,
+    /// How many lines away from the caret's each line is, for the counts
+    /// of vim-style motions; the caret's line shows 0.
+    Relative,
+    /// Relative, except that the caret's line shows its own number.
+    Hybrid,
+}
+
+/// The gutter's colors from the core's theme, as 0xRRGGBB, where it has
+/// them.
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
+pub struct GutterColors {
+    pub background: Option<u32>,
+    pub foreground: Option<u32>,
+}
+
+impl GutterColors {
+    /// The colors from the theme of a theme_changed.
+    pub fn from_theme(theme: &Value) -> GutterColors {
+        GutterColors {
+            background: theme_color(&theme["gutter"]),
+            foreground: theme_color(&theme["gutter_foreground"]),
+        }
+    }
+}
+
+/// A theme color, sent as {"r", "g", "b", "a"}. Transparent and missing
+/// colors are left to the defaults.
+pub fn theme_color(color: &Value) -> Option<u32> {
+    let channel = |name| color[name].as_u64().map(|c| c.min(255) as u32);
+    match (channel("r"), channel("g"), channel("b"), channel("a")) {
+        (_, _, _, Some(0)) => None,
+        (Some(r), Some(g), Some(b), _) => Some(r << 16 | g << 8 | b),
+        _ => None,
+    }
+}
+
+/// How the document's lines end.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// Some lines one way and some the other, until converted.
+    Mixed,
+}
+
+impl LineEnding {
+    /// How the lines of a file end, from its contents; None if it has no
+    /// line breaks to tell by.
+    pub fn detect(bytes: &[u8]) -> Option<LineEnding> {
+        let mut crlf = 0;
+        let mut lf = 0;
+        for (ix, &b) in bytes.iter().enumerate() {
+            if b == b'\n' {
+                if ix > 0 && bytes[ix - 1] == b'\r' { crlf += 1 } else { lf += 1 }
+            }
+        }
+        match (crlf, lf) {
+            (0, 0) => None,
+            (_, 0) => Some(LineEnding::Crlf),
+            (0, _) => Some(LineEnding::Lf),
+            _ => Some(LineEnding::Mixed),
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+            LineEnding::Mixed => "Mixed",
+        }
+    }
+
+    // The line break itself, for new lines; mixed endings don't have
+    // one.
+    fn newline(self) -> Option<&'static str> {
+        match self {
+            LineEnding::Lf => Some("\n"),
+            LineEnding::Crlf => Some("\r\n"),
+            LineEnding::Mixed => None,
+        }
+    }
+}
+
+// Scrolling with the middle button. The view scrolls towards the mouse,
+// faster the farther it is from where the button was pressed, until the
+// next click or key. Holding the button down and moving instead stops at
+// the release.
+#[derive(Clone, Copy)]
+struct Pan {
+    // Where the button was pressed, and where the mouse is, in px units
+    // from the view's top left.
+    origin: (f32, f32),
+    pointer: (f32, f32),
+    // Whether the mouse has left the dead zone while the button was held.
+    moved: bool,
+    held: bool,
+}
+
+// Panning with a finger on a touchscreen. The view follows the finger
+// and glides on after it's lifted; a touch that doesn't move is a tap,
+// which puts the caret there.
+#[derive(Clone, Copy)]
+struct Touch {
+    id: u32,
+    // Where the finger went down and where it is now, in px units from
+    // the view's top left, and when it got there.
+    origin: (f32, f32),
+    pointer: (f32, f32),
+    time: Instant,
+    // Smoothed over the last few moves, in px units per ms.
+    velocity: (f32, f32),
+    moved: bool,
+}
+
+/// A plugin the core can run for this view.
+pub struct Plugin {
+    pub name: String,
+    pub running: bool,
+}
+
+// A left click, kept for counting double and triple clicks; the window
+// doesn't get Windows' own double-click processing.
+struct Click {
+    time: Instant,
+    x: f32,
+    y: f32,
+    count: u32,
+}
+
+/// Whether a drag selects by character, or whole words or lines, as set
+/// by the number of clicks that started it.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Granularity {
+    Char,
+    Word,
+    Line,
+    // A box, from the anchor's x to the pointer's on every line between
+    // them, for a drag with Alt held.
+    Column,
+}
+
+// A Ctrl+click waiting on the plugins to find a definition.
+struct DefinitionRequest {
+    id: usize,
+    // The click point, where the caret goes if no plugin finds anything.
+    point: (usize, usize),
+    // How many plugins were asked and haven't answered.
+    waiting: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Drag {
+    granularity: Granularity,
+    // The line and column of the click that started the drag, and the
+    // word or line there, snapped as in snap_span, which stays selected
+    // whichever way the drag goes.
+    anchor: (usize, usize),
+    anchor_span: ((usize, usize), (usize, usize)),
+    // Whether the drag adds to the selections, from a click with Ctrl.
+    multi: bool,
+    // And its x in px units, as if the view weren't scrolled sideways,
+    // for column selections, as the text can scroll under it.
+    anchor_x: f32,
+    // The last point sent to the core, so moves within the same word
+    // don't resend it.
+    last: (usize, usize),
+    // Where the mouse is, in px units from the view's top left; it can be
+    // outside the view.
+    pointer: (f32, f32),
+}
+
+impl Drag {
+    // Snap a point to the drag's granularity, given the text of its line,
+    // if it's cached, and the document's height. Words and lines are
+    // selected whole, so the point moves to the end of its word or line
+    // when it's past the anchor and to the start when it's before it.
+    fn snap(&self, text: Option<&str>, height: usize, line: usize, col: usize) -> (usize, usize) {
+        let (start, end) = snap_span(self.granularity, text, height, line, col);
+        if (line, col) >= self.anchor { end } else { start }
+    }
+
+    // The pointer has moved to a point: the commands to send for it, if
+    // it snaps somewhere other than the last one sent. A word or line drag
+    // selects from the far end of the anchor's word or line, so that stays
+    // selected when the drag turns back past it: the core would extend
+    // from the click point. A Ctrl drag can't start again there without
+    // dropping the other selections, so it only drags.
+    fn move_to(&mut self, text: Option<&str>, height: usize, line: usize, col: usize)
+        -> Vec<EditCommand>
+    {
+        let point = self.snap(text, height, line, col);
+        if point == self.last {
+            return Vec::new();
+        }
+        self.last = point;
+        let mut cmds = Vec::new();
+        let whole = self.granularity == Granularity::Word || self.granularity == Granularity::Line;
+        if whole && !self.multi {
+            let from = if point >= self.anchor { self.anchor_span.0 } else { self.anchor_span.1 };
+            cmds.push(EditCommand::Gesture { line: from.0, col: from.1,
+                ty: GestureType::PointSelect });
+        }
+        cmds.push(EditCommand::Drag { line: point.0, col: point.1 });
+        cmds
+    }
+}
+
+// The start and end of the word or line at a point, for a drag of that
+// granularity, given the text of the line, if it's cached, and the
+// document's height. A line runs to the start of the next one, except for
+// the last. Otherwise, and on lines that aren't cached, it's the point.
+fn snap_span(granularity: Granularity, text: Option<&str>, height: usize, line: usize,
+    col: usize) -> ((usize, usize), (usize, usize))
+{
+    let text = match text {
+        Some(text) => text.trim_end_matches(|c| c == '\n' || c == '\r'),
+        None => return ((line, col), (line, col)),
+    };
+    match granularity {
+        Granularity::Word => ((line, word_start(text, col)), (line, word_end(text, col))),
+        Granularity::Line if line + 1 < height => ((line, 0), (line + 1, 0)),
+        Granularity::Line => ((line, 0), (line, text.len())),
+        Granularity::Char | Granularity::Column => ((line, col), (line, col)),
+    }
+}
+
+/// Correlates edits sent to the core with the updates that answer them,
+/// so we can tell the user when the core has stopped responding.
+///
+/// Not every edit produces an update (deleting backward at the start of
+/// the document, say), so an edit that goes unanswered for a while is
+/// followed by a probe request; the core handles messages in order, so a
+/// reply to the probe means the earlier edits were processed.
+#[derive(Default)]
+struct StallWatch {
+    // When the oldest unanswered edit was sent.
+    oldest: Option<Instant>,
+    // Whether a probe is outstanding, and when the first edit after it
+    // was sent.
+    probing: bool,
+    after_probe: Option<Instant>,
+    // Whether the user has been told in a toast that the core isn't
+    // responding, which is only done once a stall.
+    toasted: bool,
+}
+
+/// How long the core has kept a view's edits waiting, for the window to
+/// show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoreStall {
+    /// Every edit has been answered.
+    Answered,
+    /// An edit is waiting, but not for long enough to mention.
+    Pending,
+    /// Long enough to say in the status bar that we're waiting.
+    Waiting,
+    /// So long that the core doesn't seem to be responding. The first time
+    /// in a stall, it's said in a toast as well.
+    NotResponding { toast: bool },
+}
+
+impl StallWatch {
+    // An edit that changes the buffer was sent. Returns whether it's the
+    // only one waiting, so polling should start.
+    fn edit_sent(&mut self, now: Instant) -> bool {
+        let first = self.oldest.is_none();
+        if first {
+            self.oldest = Some(now);
+        }
+        if self.probing && self.after_probe.is_none() {
+            self.after_probe = Some(now);
+        }
+        first
+    }
+
+    // Whether a pending edit has waited long enough that the core should
+    // be probed, and no probe is outstanding. Marks the probe as sent.
+    fn start_probe(&mut self, now: Instant) -> bool {
+        let probe_due = match self.oldest {
+            Some(oldest) => now.duration_since(oldest) >= Duration::from_millis(STALL_PROBE_MS),
+            None => false,
+        };
+        if probe_due && !self.probing {
+            self.probing = true;
+            self.after_probe = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    // The core answered the probe, so every edit sent before it has been
+    // processed. Returns whether that was every edit.
+    fn probe_answered(&mut self) -> bool {
+        if !self.probing {
+            return false;
+        }
+        self.oldest = self.after_probe.take();
+        self.probing = false;
+        // It's responding, so if it stops again, that's a new stall.
+        self.toasted = false;
+        self.oldest.is_none()
+    }
+
+    fn check(&mut self, now: Instant) -> CoreStall {
+        let age = match self.oldest {
+            Some(oldest) => now.duration_since(oldest),
+            None => return CoreStall::Answered,
+        };
+        if age >= Duration::from_millis(STALL_ALERT_MS) {
+            let toast = !self.toasted;
+            self.toasted = true;
+            CoreStall::NotResponding { toast }
+        } else if age >= Duration::from_millis(STALL_WARN_MS) {
+            CoreStall::Waiting
+        } else {
+            CoreStall::Pending
+        }
+    }
 }
 
 struct Resources {
     fg: brush::SolidColor,
     bg: brush::SolidColor,
+    // Caret color while the window doesn't have focus.
+    caret_unfocused: brush::SolidColor,
+    selection: brush::SolidColor,
+    // Selections while the window doesn't have focus.
+    selection_unfocused: brush::SolidColor,
+    find_result: brush::SolidColor,
+    // Other occurrences of the word at the caret; faint, as they're only
+    // a hint.
+    word_match: brush::SolidColor,
+    // The box around the bracket at the caret and its partner.
+    bracket_match: brush::SolidColor,
+    // The block caret of overwrite mode, drawn behind the text.
+    block_caret: brush::SolidColor,
+    whitespace: brush::SolidColor,
+    trailing_whitespace: brush::SolidColor,
+    indent_guide: brush::SolidColor,
+    ruler: brush::SolidColor,
+    // The marks of bookmarked lines in the left margin, and on the
+    // scrollbar.
+    bookmark: brush::SolidColor,
+    gutter: brush::SolidColor,
+    line_number: brush::SolidColor,
+    scrollbar_track: brush::SolidColor,
+    scrollbar_thumb: brush::SolidColor,
+    // The scrollbar's marks of find results and changed lines.
+    find_mark: brush::SolidColor,
+    changed_mark: brush::SolidColor,
+    // The placeholder for lines the core hasn't sent.
+    missing_line: brush::SolidColor,
+    // The minimap's band where the view is, and its bars of text.
+    minimap_band: brush::SolidColor,
+    minimap_bar: brush::SolidColor,
     text_format: TextFormat,
+    // The caret's line number, in bold.
+    number_format: TextFormat,
+    // Advance width of the (monospace) font.
+    char_width: f32,
+}
+
+// Text layouts of lines, by line number, built when drawing or hit
+// testing first needs them and kept until the line changes or the font
+// does. Clearing the whole cache starts a new generation.
+struct LayoutCache {
+    layouts: HashMap<usize, TextLayout>,
+    // The lines laid out this generation, to check that an unchanged line
+    // is only laid out once.
+    #[cfg(debug_assertions)]
+    built: HashSet<usize>,
+    // Layouts built ever, for the paint statistics.
+    built_count: usize,
 }
 
+// Past this many layouts, the cache starts over rather than keep lines
+// that have long scrolled out of view.
+const MAX_CACHED_LAYOUTS: usize = 1000;
+
+// The text color, and the gutter's when the theme doesn't say.
+const DEFAULT_FG: u32 = 0xf0f0ea;
+const DEFAULT_GUTTER: u32 = 0x2f302a;
+
 const TOP_PAD: f32 = 6.0;
+// The margin between the gutter and the text, where bookmarks are marked.
+const LEFT_PAD: f32 = 6.0;
+// The room either side of the line numbers, and the fewest digits the
+// gutter has room for, so it doesn't widen while a file is short.
+const GUTTER_PAD: f32 = 6.0;
+const MIN_GUTTER_DIGITS: usize = 3;
 const LINE_SPACE: f32 = 17.0;
+// Width of the overwrite caret where there's no character under it.
+const BLOCK_CARET_WIDTH: f32 = 8.0;
+
+// The text font, and the monospace fonts to try in its place, in order.
+pub const FONT_FAMILIES: &[&str] = &["Consolas", "Courier New"];
 
 impl EditView {
     pub fn new() -> EditView {
@@ -66,27 +657,180 @@ impl EditView {
             dwrite_factory: directwrite::Factory::new().unwrap(),
             resources: None,
             scroll_offset: 0.0,
+            h_scroll_offset: 0.0,
+            scroll_pinned: false,
+            recenter: None,
             size: (0.0, 0.0),
-            viewport: 0..0,
+            mirrored: false,
+            top: 0.0,
+            viewport: Viewport::new(),
+            has_focus: false,
+            caret_visible: true,
+            caret_rects: Vec::new(),
+            stall_watch: Default::default(),
+            ctrl_k_pending: false,
+            last_click: None,
+            word_wrap: false,
+            wrap_cols: 0,
+            overwrite: false,
+            read_only: false,
+            drag: None,
+            column_rows: Vec::new(),
+            autoscrolling: false,
+            middle_click: MiddleClick::Autoscroll,
+            line_ending: LineEnding::Crlf,
+            encoding: Encoding::Utf8,
+            temp_copy: None,
+            pan: None,
+            touch: None,
+            glide: None,
+            plugins: Vec::new(),
+            language: None,
+            tab_size: 4,
+            translate_tabs_to_spaces: false,
+            show_whitespace: ShowWhitespace::Off,
+            indent_guides: false,
+            show_rulers: false,
+            rulers: vec![80],
+            scroll_past_end: true,
+            overscroll_lines: None,
+            pending_scroll: None,
+            pending_jump: None,
+            pristine: true,
+            width_cache: HashMap::new(),
+            layouts: LayoutCache::new(),
+            undo_steps: 0,
+            redo_steps: 0,
+            pending_history: None,
+            note: None,
+            word_matches: Vec::new(),
+            bracket_match: None,
+            counts: SelectionCounts::new(),
+            stats: PaintStats::new(),
+            hover: Hover::new(),
+            link: None,
+            definition_request: None,
+            bookmarks: Bookmarks::new(),
+            scrollbar: Scrollbar::new(),
+            show_minimap: false,
+            minimap: Minimap::new(),
+            line_numbers: LineNumbers::Absolute,
+            gutter_colors: GutterColors::default(),
+            current_number_row: None,
+            loading: false,
+            jumps: CaretJumps::new(),
         }
     }
 
-    fn create_resources(&mut self, p: &mut PaintCtx) -> Resources {
-        let rt = p.render_target();
-        let text_format_params = text_format::ParamBuilder::new()
-            .size(15.0)
-            .family("Consolas")
-            .build().unwrap();
-        let text_format = self.dwrite_factory.create(text_format_params).unwrap();
-        Resources {
-            fg: rt.create_solid_color_brush(0xf0f0ea, &BrushProperties::default()).unwrap(),
-            bg: rt.create_solid_color_brush(0x272822, &BrushProperties::default()).unwrap(),
+    fn create_resources(&mut self, p: &mut PaintCtx) -> Result<Resources, Error> {
+        let char_width = self.char_width();
+        let tab_width = self.tab_size as f32 * char_width;
+        let text_format = self.create_text_format(tab_width)?;
+        let number_format = create_text_format(&self.dwrite_factory, FontWeight::Bold)?;
+        let gutter = self.gutter_colors.background.unwrap_or(DEFAULT_GUTTER);
+        let line_number = self.gutter_colors.foreground
+            .unwrap_or_else(|| blend(DEFAULT_FG, gutter, 0.5));
+        Ok(Resources {
+            fg: p.solid_brush(DEFAULT_FG)?,
+            bg: p.solid_brush(0x272822)?,
+            caret_unfocused: p.solid_brush(0x75715e)?,
+            selection: p.solid_brush(0x49483e)?,
+            selection_unfocused: p.solid_brush(0x38382f)?,
+            find_result: p.solid_brush(0x5c5526)?,
+            word_match: p.solid_brush(0x3e3d32)?,
+            bracket_match: p.solid_brush(0x75715e)?,
+            block_caret: p.solid_brush(0x75715e)?,
+            whitespace: p.solid_brush(0x5b5a4f)?,
+            trailing_whitespace: p.solid_brush(0x8c4040)?,
+            indent_guide: p.solid_brush(0x3e3d32)?,
+            ruler: p.solid_brush(0x3b3a32)?,
+            bookmark: p.solid_brush(0x66d9ef)?,
+            gutter: p.solid_brush(gutter)?,
+            line_number: p.solid_brush(line_number)?,
+            scrollbar_track: p.solid_brush(0x2f302a)?,
+            scrollbar_thumb: p.solid_brush(0x49483e)?,
+            find_mark: p.solid_brush(0xe6db74)?,
+            changed_mark: p.solid_brush(0xfd971f)?,
+            missing_line: p.solid_brush(0x5b5a4f)?,
+            minimap_band: p.solid_brush(0x3e3d32)?,
+            minimap_bar: p.solid_brush(0x8f908a)?,
             text_format: text_format,
-        }
+            number_format: number_format,
+            char_width: char_width,
+        })
     }
 
     pub fn rebuild_resources(&mut self) {
         self.resources = None;
+        self.width_cache.clear();
+        self.layouts.clear();
+        self.minimap.clear();
+    }
+
+    /// The layout of a line, from the cache if it's been laid out since it
+    /// last changed. None if the line isn't in the line cache, or there's
+    /// no font yet.
+    fn get_layout(&mut self, line_num: usize) -> Option<&TextLayout> {
+        let resources = self.resources.as_ref()?;
+        self.layouts.get(line_num, &self.line_cache, resources, &self.dwrite_factory)
+    }
+
+    // The text format with tab stops for the current tab size.
+    fn create_text_format(&self, tab_width: f32) -> Result<TextFormat, Error> {
+        let text_format = create_text_format(&self.dwrite_factory, FontWeight::Normal)?;
+        unsafe {
+            (*text_format.get_raw()).SetIncrementalTabStop(tab_width);
+        }
+        Ok(text_format)
+    }
+
+    /// Answer the core's measure_width request: for each group of strings
+    /// (by style id, which doesn't change the font here), their widths in
+    /// px units.
+    pub fn measure_widths(&mut self, params: &Value) -> Value {
+        let empty = Vec::new();
+        let groups = params.as_array().unwrap_or(&empty);
+        let widths: Vec<Vec<f32>> = groups.iter().map(|group| {
+            group["strings"].as_array().unwrap_or(&empty).iter()
+                .map(|s| self.measure_width(s.as_str().unwrap_or("")))
+                .collect()
+        }).collect();
+        json!(widths)
+    }
+
+    fn measure_width(&mut self, text: &str) -> f32 {
+        if let Some(&width) = self.width_cache.get(text) {
+            return width;
+        }
+        let char_width = match self.resources {
+            Some(ref resources) => resources.char_width,
+            None => self.char_width(),
+        };
+        let width = if text.bytes().all(|b| b >= 0x20 && b < 0x7f) {
+            // The font is monospace, so plain ASCII needs no layout.
+            text.len() as f32 * char_width
+        } else {
+            let text_format = match self.resources {
+                Some(ref resources) => Ok(resources.text_format.clone()),
+                None => self.create_text_format(self.tab_size as f32 * char_width),
+            };
+            match text_format {
+                Ok(text_format) => {
+                    let params = text_layout::ParamBuilder::new()
+                        .text(text)
+                        .font(text_format)
+                        .width(1e6)
+                        .height(1e6)
+                        .build().unwrap();
+                    let layout: TextLayout = self.dwrite_factory.create(params).unwrap();
+                    offset_to_x(&layout, text, text.len())
+                }
+                // Without a font, a guess the core can wrap with.
+                Err(_) => text.chars().count() as f32 * char_width,
+            }
+        };
+        self.width_cache.insert(text.to_owned(), width);
+        width
     }
 
     pub fn size(&mut self, x: f32, y: f32) {
@@ -94,318 +838,4153 @@ impl EditView {
         self.constrain_scroll();
     }
 
+    /// Lay the chrome out from the right.
+    pub fn set_mirrored(&mut self, mirrored: bool) {
+        self.mirrored = mirrored;
+    }
+
+    // Where the gutter, the text, the minimap and the scrollbar go.
+    fn chrome(&self) -> Chrome {
+        Chrome {
+            width: self.size.0,
+            gutter_width: self.gutter_width(),
+            margin: LEFT_PAD,
+            minimap_width: if self.show_minimap { MINIMAP_WIDTH } else { 0.0 },
+            scrollbar_width: SCROLLBAR_WIDTH,
+            mirrored: self.mirrored,
+        }
+    }
+
+    /// Place the view this far down the window (in px units). Mouse
+    /// positions are in window coordinates; the caller translates the
+    /// drawing.
+    pub fn set_top(&mut self, top: f32) {
+        self.top = top;
+    }
+
+    /// The area of the window the view occupies, in px units.
+    pub fn bounds(&self) -> (f32, f32, f32, f32) {
+        (0.0, self.top, self.size.0, self.top + self.size.1)
+    }
+
+    fn scrollbar_bounds(&self) -> (f32, f32, f32, f32) {
+        let (x0, x1) = self.chrome().scrollbar();
+        (x0, self.top, x1, self.top + self.size.1)
+    }
+
+    // Where the text stops, at the minimap or scrollbar, or in a mirrored
+    // view at the margin beside the gutter.
+    fn text_right(&self) -> f32 {
+        self.chrome().text().1
+    }
+
+    // The width lines are laid out in.
+    fn text_width(&self) -> f32 {
+        self.text_right() - self.text_left()
+    }
+
+    // The number of digits the gutter has room for, or 0 when line numbers
+    // are off.
+    fn gutter_digits(&self) -> usize {
+        if self.line_numbers == LineNumbers::Off {
+            return 0;
+        }
+        self.line_cache.height().to_string().len().max(MIN_GUTTER_DIGITS)
+    }
+
+    // The width of the line number gutter at the left of the view, or the
+    // right of a mirrored one.
+    fn gutter_width(&self) -> f32 {
+        let digits = self.gutter_digits();
+        if digits == 0 {
+            return 0.0;
+        }
+        let char_width = match self.resources {
+            Some(ref resources) => resources.char_width,
+            None => self.char_width(),
+        };
+        (digits as f32 * char_width + 2.0 * GUTTER_PAD).ceil()
+    }
+
+    // Where the text starts, as if the view weren't scrolled sideways.
+    fn text_left(&self) -> f32 {
+        self.chrome().text().0
+    }
+
+    fn minimap_layout(&self) -> (usize, f32) {
+        Minimap::layout(self.size.1, self.line_cache.height(), self.scroll_offset,
+            self.max_scroll())
+    }
+
+    /// A view for another pane onto the same file, shown the same way.
+    /// It has no view id until the core gives it one.
+    pub fn new_pane(&self) -> EditView {
+        let mut pane = EditView::new();
+        pane.filename = self.filename.clone();
+        pane.word_wrap = self.word_wrap;
+        pane.tab_size = self.tab_size;
+        pane.translate_tabs_to_spaces = self.translate_tabs_to_spaces;
+        pane.show_whitespace = self.show_whitespace;
+        pane.indent_guides = self.indent_guides;
+        pane.show_rulers = self.show_rulers;
+        pane.rulers = self.rulers.clone();
+        pane.scroll_past_end = self.scroll_past_end;
+        pane.mirrored = self.mirrored;
+        pane.show_minimap = self.show_minimap;
+        pane.line_numbers = self.line_numbers;
+        pane.gutter_colors = self.gutter_colors;
+        pane.overscroll_lines = self.overscroll_lines;
+        pane.middle_click = self.middle_click;
+        pane.line_ending = self.line_ending;
+        pane.encoding = self.encoding;
+        pane.read_only = self.read_only;
+        pane.language = self.language.clone();
+        pane.stats.set_enabled(self.stats.is_enabled());
+        pane
+    }
+
+    /// Show that the file is loading, in place of its lines, or stop.
+    pub fn set_loading(&mut self, loading: bool) {
+        self.loading = loading;
+    }
+
     pub fn clear_line_cache(&mut self) {
         self.line_cache = LineCache::new();
+        self.layouts.clear();
+        self.counts.clear();
+        self.bookmarks.clear();
+        self.scrollbar.clear();
+        self.minimap.clear();
+        self.jumps = CaretJumps::new();
+        self.current_number_row = None;
     }
 
-    pub fn render(&mut self, p: &mut PaintCtx) {
+    /// Draw the view. Fails if the resources for drawing can't be made,
+    /// as when the device has been lost; the caller retries later.
+    pub fn render(&mut self, p: &mut PaintCtx) -> Result<(), Error> {
         if self.resources.is_none() {
-            self.resources = Some(self.create_resources(p));
+            self.resources = Some(self.create_resources(p)?);
         }
+        self.stats.start_frame();
+        let built_before = self.layouts.built_count;
         let resources = &self.resources.as_ref().unwrap();
+        // Device pixels per px unit.
+        let scale = p.dpi() / 96.0;
         let rt = p.render_target();
         let rect = RectF::from((0.0, 0.0, self.size.0, self.size.1));
         rt.fill_rectangle(&rect, &resources.bg);
+        let (chrome, text_left) = (self.chrome(), self.text_left());
+
+        if self.show_rulers {
+            for &col in &self.rulers {
+                let (x, width) = crisp_line(text_left + col as f32 * resources.char_width
+                    - self.h_scroll_offset, scale);
+                rt.draw_line(&Point2F::from((x, 0.0)), &Point2F::from((x, self.size.1)),
+                    &resources.ruler, width, None);
+            }
+        }
 
         let first_line = self.y_to_line(0.0);
         let last_line = min(self.y_to_line(self.size.1) + 1, self.line_cache.height());
 
-        let x0 = 6.0;
+        let draw_carets = self.caret_visible;
+        self.caret_rects.clear();
+
+        self.layouts.limit();
+        for line_num in first_line..last_line {
+            self.layouts.get(line_num, &self.line_cache, resources, &self.dwrite_factory);
+        }
+        let x0 = text_left - self.h_scroll_offset;
         let mut y = self.line_to_content_y(first_line) - self.scroll_offset;
         for line_num in first_line..last_line {
-            if let Some(line) = self.line_cache.get_line(line_num) {
-                let layout = resources.create_text_layout(&self.dwrite_factory, line.text());
+            if let (Some(line), Some(layout)) =
+                (self.line_cache.get_line(line_num), self.layouts.cached(line_num))
+            {
+                self.stats.line_painted();
+                for &(_, ref range) in self.word_matches.iter().filter(|m| m.0 == line_num) {
+                    let match_x0 = x0 + offset_to_x(&layout, line.text(), range.start);
+                    let match_x1 = x0 + offset_to_x(&layout, line.text(), range.end);
+                    let match_rect = RectF::from((match_x0, y, match_x1, y + LINE_SPACE));
+                    rt.fill_rectangle(&match_rect, &resources.word_match);
+                }
+                for highlight in line.highlights() {
+                    let brush = match highlight.kind {
+                        HighlightKind::Selection if self.has_focus => &resources.selection,
+                        HighlightKind::Selection => &resources.selection_unfocused,
+                        HighlightKind::Find => &resources.find_result,
+                    };
+                    let sel_x0 = x0 + offset_to_x(&layout, line.text(), highlight.start);
+                    let sel_x1 = x0 + offset_to_x(&layout, line.text(), highlight.end);
+                    let sel_rect = RectF::from((sel_x0, y, sel_x1, y + LINE_SPACE));
+                    rt.fill_rectangle(&sel_rect, brush);
+                }
+                if let Some((a, b)) = self.bracket_match {
+                    for &(_, offset) in [a, b].iter().filter(|pos| pos.0 == line_num) {
+                        let bracket_x0 = x0 + offset_to_x(&layout, line.text(), offset);
+                        // Brackets are all one byte.
+                        let bracket_x1 = x0 + offset_to_x(&layout, line.text(), offset + 1);
+                        let box_rect = RectF::from((bracket_x0.round() + 0.5, y + 0.5,
+                            bracket_x1.round() - 0.5, y + LINE_SPACE - 0.5));
+                        rt.draw_rectangle(&box_rect, &resources.bracket_match, 1.0, None);
+                    }
+                }
+                if self.indent_guides {
+                    let indent = self.guide_indent(line_num);
+                    let tab_size = self.tab_size.max(1);
+                    let guide_width = tab_size as f32 * resources.char_width;
+                    for level in 0..(indent + tab_size - 1) / tab_size {
+                        let (x, width) = crisp_line(x0 + level as f32 * guide_width, scale);
+                        rt.draw_line(&Point2F::from((x, y)), &Point2F::from((x, y + LINE_SPACE)),
+                            &resources.indent_guide, width, None);
+                    }
+                }
+                if self.show_whitespace != ShowWhitespace::Off {
+                    draw_whitespace(rt, resources, &layout, line.text(), (x0, y),
+                        self.show_whitespace == ShowWhitespace::Trailing);
+                }
+                if self.overwrite && self.has_focus {
+                    for hit in caret_hits(line) {
+                        let (x, width) = caret_pos(&layout, hit);
+                        let x = x0 + x;
+                        // At the end of the line there's no character to cover.
+                        let width = if width > 0.0 { width } else { BLOCK_CARET_WIDTH };
+                        if draw_carets {
+                            let block_rect = RectF::from((x, y, x + width, y + LINE_SPACE));
+                            rt.fill_rectangle(&block_rect, &resources.block_caret);
+                        }
+                        self.caret_rects.push((x, y, x + width, y + LINE_SPACE));
+                    }
+                }
                 rt.draw_text_layout(
                     &Point2F::from((x0, y)),
                     &layout,
                     &resources.fg,
                     default_text_options()
                 );
-                for &offset in line.cursor() {
-                    if let Some(pos) = layout.hit_test_text_position(offset as u32, true) {
-                        let x = x0 + pos.point_x;
-                        rt.draw_line(&Point2F::from((x, y)),
-                            &Point2F::from((x, y + 17.0)),
-                            &resources.fg, 1.0, None);
+                match self.link {
+                    Some((link_line, ref range)) if link_line == line_num => {
+                        let link_x0 = x0 + offset_to_x(&layout, line.text(), range.start);
+                        let link_x1 = x0 + offset_to_x(&layout, line.text(), range.end);
+                        let link_y = y + LINE_SPACE - 1.5;
+                        rt.draw_line(&Point2F::from((link_x0, link_y)),
+                            &Point2F::from((link_x1, link_y)), &resources.fg, 1.0, None);
+                    }
+                    _ => (),
+                }
+                if !self.has_focus {
+                    // Carets are hollow boxes around the next character
+                    // while another window has focus.
+                    for hit in caret_hits(line) {
+                        let (x, width) = caret_pos(&layout, hit);
+                        let x = x0 + x;
+                        let width = if width > 0.0 { width } else { BLOCK_CARET_WIDTH };
+                        let box_rect = RectF::from((x.round() + 0.5, y + 0.5,
+                            (x + width).round() - 0.5, y + LINE_SPACE - 0.5));
+                        rt.draw_rectangle(&box_rect, &resources.caret_unfocused, 1.0, None);
+                        self.caret_rects.push((x, y, x + width, y + LINE_SPACE));
+                    }
+                } else if !self.overwrite {
+                    for hit in caret_hits(line) {
+                        let (x, width) = crisp_line(x0 + caret_pos(&layout, hit).0, scale);
+                        if draw_carets {
+                            rt.draw_line(&Point2F::from((x, y)),
+                                &Point2F::from((x, y + LINE_SPACE)),
+                                &resources.fg, width, None);
+                        }
+                        self.caret_rects.push((x - 1.0, y, x + 1.0, y + LINE_SPACE));
                     }
                 }
+            } else if self.line_cache.get_line(line_num).is_none() {
+                draw_missing_line(rt, (x0, y), resources);
             }
             y += LINE_SPACE;
         }
+        // Over any text scrolled sideways under it.
+        self.draw_gutter(rt, resources, first_line..last_line, &chrome);
+        if self.loading {
+            let layout = resources.create_text_layout(&self.dwrite_factory, "Loading\u{2026}");
+            rt.draw_text_layout(&Point2F::from((text_left, TOP_PAD)), &layout, &resources.fg,
+                default_text_options());
+        }
+        if let Some(pan) = self.pan {
+            draw_pan_origin(rt, pan.origin, !self.word_wrap, &resources.fg);
+        }
+        if self.show_minimap {
+            let (x0, layout) = (chrome.minimap().0, self.minimap_layout());
+            self.minimap.render(rt, x0, self.size.1, layout, first_line..last_line,
+                &self.line_cache, self.tab_size, &resources.bg, &resources.minimap_band,
+                &resources.minimap_bar);
+        }
+        self.draw_scrollbar(rt, resources);
+        self.hover.render(rt, &self.dwrite_factory, &resources.text_format, &resources.bg,
+            &resources.fg, self.size, self.mirrored);
+        if self.stats.is_enabled() {
+            self.stats.layouts_built(self.layouts.built_count - built_before);
+            self.stats.end_frame();
+            draw_paint_stats(rt, resources, &self.dwrite_factory, &self.stats.summary(),
+                self.size.0, self.mirrored);
+        }
+        Ok(())
     }
 
-    pub fn set_view_id(&mut self, view_id: &str) {
-        self.view_id = view_id.into();
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_ref().map(|s| s.as_str())
     }
 
-    pub fn apply_update(&mut self, update: &Value) {
-        self.line_cache.apply_update(update);
-        self.constrain_scroll();
+    /// Record the language the core has chosen for the view.
+    pub fn set_language(&mut self, language: &str) {
+        self.language = Some(language.to_owned());
     }
 
-    pub fn char(&self, ch: u32, _mods: u32, win: &MainWin) {
-        let view_id = &self.view_id;
-        if let Some(c) = ::std::char::from_u32(ch) {
-            if ch >= 0x20 {
-                // Don't insert control characters
-                let params = json!({"chars": c.to_string()});
-                win.send_edit_cmd("insert", &params, view_id);
-            }
-        }
+    /// Ask the core to switch the view to a language; it answers with
+    /// language_changed.
+    pub fn request_language(&mut self, language: &str, win: &MainWin) {
+        win.send_notification("set_language", &json!({
+            "view_id": self.view_id,
+            "language_id": language,
+        }));
     }
 
-    /// Sends a simple action with no parameters
-    fn send_action(&self, method: &str, win: &MainWin) {
-        win.send_edit_cmd(method, &json!([]), &self.view_id);
+    pub fn is_word_wrap(&self) -> bool {
+        self.word_wrap
     }
 
-    pub fn keydown(&mut self, vk_code: i32, mods: u32, win: &MainWin) -> bool {
-        // Handle special keys here
-        match vk_code {
-            VK_RETURN => {
-                // TODO: modifiers are variants of open
-                self.send_action("insert_newline", win);
-            }
-            VK_TAB => {
-                // TODO: modified versions
-                self.send_action("insert_tab", win);
-            }
-            VK_UP => {
-                if mods == M_CTRL {
-                    self.scroll_offset -= LINE_SPACE;
-                    self.constrain_scroll();
-                    self.update_viewport(win);
-                    win.invalidate();
-                } else {
-                    let action = if mods == M_CTRL | M_ALT {
-                        "add_selection_above"
-                    } else {
-                        s(mods, "move_up", "move_up_and_modify_selection")
-                    };
-                    // TODO: swap line up is ctrl + shift
-                    self.send_action(action, win);
-                }
-            }
-            VK_DOWN => {
-                if mods == M_CTRL {
-                    self.scroll_offset += LINE_SPACE;
-                    self.constrain_scroll();
-                    self.update_viewport(win);
-                    win.invalidate();
-                } else {
-                    let action = if mods == M_CTRL | M_ALT {
-                        "add_selection_below"
-                    } else {
-                        s(mods, "move_down", "move_down_and_modify_selection")
-                    };
-                    self.send_action(action, win);
-                }
-            }
-            VK_LEFT => {
-                // TODO: there is a subtle distinction between alt and ctrl
-                let action = if (mods & (M_ALT | M_CTRL)) != 0 {
-                    s(mods, "move_word_left", "move_word_left_and_modify_selection")
-                } else {
-                    s(mods, "move_left", "move_left_and_modify_selection")
-                };
-                self.send_action(action, win);
-            }
-            VK_RIGHT => {
-                // TODO: there is a subtle distinction between alt and ctrl
-                let action = if (mods & (M_ALT | M_CTRL)) != 0 {
-                    s(mods, "move_word_right", "move_word_right_and_modify_selection")
-                } else {
-                    s(mods, "move_right", "move_right_and_modify_selection")
-                };
-                self.send_action(action, win);
-            }
-            VK_PRIOR => {
-                self.send_action(s(mods, "scroll_page_up",
-                    "page_up_and_modify_selection"), win);
-            }
-            VK_NEXT => {
-                self.send_action(s(mods, "scroll_page_down",
-                    "page_down_and_modify_selection"), win);
-            }
-            VK_HOME => {
-                let action = if (mods & M_CTRL) != 0 {
-                    s(mods, "move_to_beginning_of_document",
-                        "move_to_beginning_of_document_and_modify_selection")
-                } else {
-                    s(mods, "move_to_left_end_of_line",
-                        "move_to_left_end_of_line_and_modify_selection")
-                };
-                self.send_action(action, win);
-            }
-            VK_END => {
-                let action = if (mods & M_CTRL) != 0 {
-                    s(mods, "move_to_end_of_document",
-                        "move_to_end_of_document_and_modify_selection")
-                } else {
-                    s(mods, "move_to_right_end_of_line",
-                        "move_to_right_end_of_line_and_modify_selection")
-                };
-                self.send_action(action, win);
-            }
-            VK_ESCAPE => {
-                self.send_action("cancel_operation", win);
-            }
-            VK_BACK => {
-                let action = if (mods & M_CTRL) != 0 {
-                    // should be "delete to beginning of paragraph" but not supported
-                    s(mods, "delete_word_backward", "delete_to_beginning_of_line")
-                } else {
-                    "delete_backward"
-                };
-                self.send_action(action, win);
-                self.send_action("delete_forward", win);
-            }
-            VK_DELETE => {
-                let action = if (mods & M_CTRL) != 0 {
-                    s(mods, "delete_word_forward", "delete_to_end_of_paragraph")
-                } else {
-                    // TODO: shift-delete should be "delete line"
-                    "delete_forward"
-                };
-                self.send_action(action, win);
-                self.send_action("delete_forward", win);
-            }
-            VK_OEM_4 => {
-                // generally '[' key, but might vary on non-US keyboards
-                if mods == M_CTRL {
-                    self.send_action("outdent", win);
-                } else {
-                    return false
-                }
-            }
-            VK_OEM_6 => {
-                // generally ']' key, but might vary on non-US keyboards
-                if mods == M_CTRL {
-                    self.send_action("indent", win);
-                } else {
-                    return false
-                }
-            }
-            _ => {
-                return false
-            }
+    /// Turn soft wrapping at the width of the view on or off.
+    pub fn set_word_wrap(&mut self, word_wrap: bool, win: &MainWin) {
+        self.word_wrap = word_wrap;
+        if word_wrap && self.h_scroll_offset != 0.0 {
+            self.h_scroll_offset = 0.0;
+            win.invalidate();
         }
-        true
+        self.update_wrap_width(win);
     }
 
-    // Commands
+    /// Tell the core how many columns fit in the view, if wrapping is on.
+    /// Call after the size changes. The core does the wrapping, and sends
+    /// each visual line as a line of its own.
+    pub fn update_wrap_width(&mut self, win: &MainWin) {
+        let cols = if self.word_wrap {
+            let width = self.text_width();
+            ((width / self.char_width()).floor() as usize).max(1)
+        } else {
+            0
+        };
+        if cols != self.wrap_cols && !self.view_id.is_empty() {
+            self.wrap_cols = cols;
+            self.modify_config(json!({"wrap_width": cols}), win);
+        }
+    }
 
-    pub fn undo(&mut self, win: &MainWin) {
-        self.send_action("undo", win);
+    // Change config settings for this view only.
+    fn modify_config(&self, changes: Value, win: &MainWin) {
+        win.send_notification("modify_user_config", &json!({
+            "domain": {"user_override": self.view_id},
+            "changes": changes,
+        }));
     }
 
-    pub fn redo(&mut self, win: &MainWin) {
-        self.send_action("redo", win);
+    /// Take in changed settings from the core's config_changed.
+    pub fn apply_config(&mut self, changes: &Value) {
+        if let Some(tab_size) = changes["tab_size"].as_u64() {
+            if tab_size as usize != self.tab_size {
+                self.tab_size = tab_size as usize;
+                // The tab stops are set on the text format.
+                self.rebuild_resources();
+            }
+        }
+        if let Some(spaces) = changes["translate_tabs_to_spaces"].as_bool() {
+            self.translate_tabs_to_spaces = spaces;
+        }
     }
 
-    pub fn upper_case(&mut self, win: &MainWin) {
-        self.send_action("uppercase", win);
+    pub fn show_whitespace(&self) -> ShowWhitespace {
+        self.show_whitespace
     }
 
-    pub fn lower_case(&mut self, win: &MainWin) {
-        self.send_action("lowercase", win);
+    pub fn set_show_whitespace(&mut self, show_whitespace: ShowWhitespace, win: &MainWin) {
+        self.show_whitespace = show_whitespace;
+        win.invalidate();
     }
 
-    pub fn transpose(&mut self, win: &MainWin) {
-        self.send_action("transpose", win);
+    pub fn show_rulers(&self) -> bool {
+        self.show_rulers
     }
 
-    pub fn add_cursor_above(&mut self, win: &MainWin) {
-        // Note: some subtlety around find, the escape key cancels it, but the menu
-        // shouldn't.
-        self.send_action("add_selection_above", win);
+    pub fn set_show_rulers(&mut self, show_rulers: bool, win: &MainWin) {
+        self.show_rulers = show_rulers;
+        win.invalidate();
     }
 
-    pub fn add_cursor_below(&mut self, win: &MainWin) {
-        // Note: some subtlety around find, the escape key cancels it, but the menu
-        // shouldn't.
-        self.send_action("add_selection_below", win);
+    /// Set the columns rulers are drawn at.
+    pub fn set_rulers(&mut self, rulers: Vec<usize>) {
+        self.rulers = rulers;
     }
 
-    pub fn single_selection(&mut self, win: &MainWin) {
-        // Note: some subtlety around find, the escape key cancels it, but the menu
-        // shouldn't.
-        self.send_action("cancel_operation", win);
+    pub fn scroll_past_end(&self) -> bool {
+        self.scroll_past_end
     }
 
-    pub fn select_all(&mut self, win: &MainWin) {
-        // Note: some subtlety around find, the escape key cancels it, but the menu
-        // shouldn't.
-        self.send_action("select_all", win);
+    pub fn show_minimap(&self) -> bool {
+        self.show_minimap
     }
 
-    pub fn mouse_wheel(&mut self, delta: i32, _mods: u32, win: &MainWin) {
-        // TODO: scale properly, taking SPI_GETWHEELSCROLLLINES into account
-        let scroll_scaling = 0.5;
-        self.scroll_offset -= (delta as f32) * scroll_scaling;
+    /// Show or hide the minimap. The text gets narrower or wider, and the
+    /// core is asked for the lines the minimap shows.
+    pub fn set_show_minimap(&mut self, show_minimap: bool, win: &MainWin) {
+        self.show_minimap = show_minimap;
+        self.update_wrap_width(win);
+        if !self.view_id.is_empty() {
+            self.viewport.refresh();
+            self.flush_viewport(win);
+        }
+        win.invalidate();
+    }
+
+    pub fn set_scroll_past_end(&mut self, scroll_past_end: bool, win: &MainWin) {
+        self.scroll_past_end = scroll_past_end;
         self.constrain_scroll();
         self.update_viewport(win);
-        win.handle.borrow().invalidate();
+        win.invalidate();
     }
 
-    fn constrain_scroll(&mut self) {
-        let max_scroll = TOP_PAD + LINE_SPACE *
-            (self.line_cache.height().saturating_sub(1)) as f32;
-        if self.scroll_offset < 0.0 {
-            self.scroll_offset = 0.0;
-        } else if self.scroll_offset > max_scroll {
-            self.scroll_offset = max_scroll;
-        }
+    /// Set how many lines the view can scroll past the end, or `None` for
+    /// the height of the view less one line.
+    pub fn set_overscroll_lines(&mut self, lines: Option<usize>) {
+        self.overscroll_lines = lines;
     }
 
-    // Takes y in screen-space px.
-    fn y_to_line(&self, y: f32) -> usize {
-        let mut line = (y + self.scroll_offset - TOP_PAD) / LINE_SPACE;
-        if line < 0.0 { line = 0.0; }
-        let line = line.floor() as usize;
-        min(line, self.line_cache.height())
+    pub fn set_middle_click(&mut self, middle_click: MiddleClick) {
+        self.middle_click = middle_click;
     }
 
-    /// Convert line number to y coordinate in content space.
-    fn line_to_content_y(&self, line: usize) -> f32 {
-        TOP_PAD + (line as f32) * LINE_SPACE
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
     }
 
-    fn update_viewport(&mut self, win: &MainWin) {
-        let first_line = self.y_to_line(0.0);
-        let last_line = first_line + ((self.size.1 / LINE_SPACE).floor() as usize) + 1;
-        let viewport = first_line..last_line;
-        if viewport != self.viewport {
-            self.viewport = viewport;
-            let view_id = &self.view_id;
-            win.send_edit_cmd("scroll", &json!([first_line, last_line]), view_id);
-        }
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
     }
 
-    pub fn scroll_to(&mut self, line: usize) {
-        let y = self.line_to_content_y(line);
-        let bottom_slop = 20.0;
-        if y < self.scroll_offset {
-            self.scroll_offset = y;
-        } else if y > self.scroll_offset + self.size.1 - bottom_slop {
-            self.scroll_offset = y - (self.size.1 - bottom_slop)
-        }
+    /// Keep the copy the core opened in place of the file, deleting the
+    /// one before, as its view has been closed.
+    pub fn set_temp_copy(&mut self, copy: Option<TempCopy>) {
+        self.temp_copy = copy;
     }
-}
 
-// Helper function for choosing between normal and shifted action
-fn s<'a>(mods: u32, normal: &'a str, shifted: &'a str) -> &'a str {
-    if (mods & M_SHIFT) != 0 { shifted } else { normal }
-}
+    pub fn temp_copy_path(&self) -> Option<String> {
+        self.temp_copy.as_ref().map(|copy| copy.path())
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    /// Record how the lines of the view's file end; `send_line_ending`
+    /// passes it on once the core has made the view.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    /// Ask the core for every line of the document, as for printing, and
+    /// keep asking for all of them, however the view scrolls, until
+    /// release_all_lines. Asks again if the document has grown since.
+    pub fn hold_all_lines(&mut self, win: &MainWin) {
+        if let Some((first, last)) = self.viewport.hold_all(self.line_cache.height()) {
+            self.send_edit_cmd(EditCommand::Scroll { first, last }, win);
+        }
+    }
+
+    /// Go back to asking for just the visible lines.
+    pub fn release_all_lines(&mut self, win: &MainWin) {
+        if self.viewport.release_all() {
+            self.update_viewport(win);
+        }
+    }
+
+    /// Whether every line of the document is in the line cache.
+    pub fn has_all_lines(&self) -> bool {
+        self.line_cache.cached_count() == self.line_cache.height()
+    }
+
+    /// The text of every line without its newline, once the core has sent
+    /// them all.
+    pub fn all_lines(&self) -> Option<Vec<String>> {
+        (0..self.line_cache.height()).map(|ix| {
+            self.line_cache.get_line(ix)
+                .map(|line| line.text().trim_end_matches(|c| c == '\n' || c == '\r').to_owned())
+        }).collect()
+    }
+
+    /// Tell the core which line break to use for new lines.
+    pub fn send_line_ending(&self, win: &MainWin) {
+        if let Some(newline) = self.line_ending.newline() {
+            if !self.view_id.is_empty() {
+                self.modify_config(json!({"line_ending": newline}), win);
+            }
+        }
+    }
+
+    /// Make every line end the same way. The core can't convert, so the
+    /// whole text is copied, converted and put back in place of itself,
+    /// as one edit. The carets are put back where they were.
+    pub fn convert_line_endings(&mut self, line_ending: LineEnding, win: &MainWin) {
+        let newline = match line_ending.newline() {
+            Some(newline) => newline,
+            None => return,
+        };
+        let carets = self.carets();
+        let cmds = vec![EditCommand::SelectAll];
+        self.send_edit_request(cmds, EditCommand::Copy, win, move |handler, result| {
+            let edit_view = &mut handler.win.state.borrow_mut().edit_view;
+            let win = &handler.win;
+            if let Some(text) = result.as_str() {
+                let converted = text.replace("\r\n", "\n");
+                let converted = if newline == "\n" {
+                    converted
+                } else {
+                    converted.replace('\n', newline)
+                };
+                // A read-only view keeps its line endings, and the setting.
+                let cmds = vec![EditCommand::Insert { chars: converted.clone() }];
+                if converted == text || edit_view.send_edit_cmds(cmds, win) {
+                    edit_view.line_ending = line_ending;
+                    edit_view.send_line_ending(win);
+                }
+            }
+            edit_view.restore_carets(&carets, win);
+        });
+    }
+
+    pub fn line_numbers(&self) -> LineNumbers {
+        self.line_numbers
+    }
+
+    /// Set what the gutter shows. Turning it on or off makes the text
+    /// narrower or wider, so the wrap width needs updating after.
+    pub fn set_line_numbers(&mut self, line_numbers: LineNumbers) {
+        self.line_numbers = line_numbers;
+    }
+
+    /// Take the gutter's colors from a new theme.
+    pub fn set_gutter_colors(&mut self, gutter_colors: GutterColors) {
+        if gutter_colors != self.gutter_colors {
+            self.gutter_colors = gutter_colors;
+            self.rebuild_resources();
+        }
+    }
+
+    // The row of the line number that stands out: the caret's, or the one
+    // starting its line if the caret's on the continuation of a wrapped
+    // line.
+    fn find_current_number_row(&self) -> Option<usize> {
+        let mut row = self.caret_line()?;
+        if self.word_wrap {
+            while row > 0 && self.line_cache.get_line(row).map_or(false, |l| l.ln().is_none()) {
+                row -= 1;
+            }
+        }
+        Some(row)
+    }
+
+    // Repaint the gutter beside a row, if it's in view.
+    fn invalidate_gutter_row(&self, row: usize, win: &MainWin) {
+        let (x0, x1) = self.chrome().gutter();
+        let y = self.line_to_content_y(row) - self.scroll_offset;
+        if x1 > x0 && y + LINE_SPACE > 0.0 && y < self.size.1 {
+            win.handle.borrow().invalidate_rect(x0, self.top + y.max(0.0), x1,
+                self.top + (y + LINE_SPACE).min(self.size.1));
+        }
+    }
+
+    pub fn indent_guides(&self) -> bool {
+        self.indent_guides
+    }
+
+    pub fn set_indent_guides(&mut self, indent_guides: bool, win: &MainWin) {
+        self.indent_guides = indent_guides;
+        win.invalidate();
+    }
+
+    // The indentation, in columns, to draw guides for on a line. A blank
+    // line inside an indented block continues its guides, so it takes the
+    // lesser indentation of the nearest non-blank lines around it.
+    fn guide_indent(&self, line_num: usize) -> usize {
+        let indent_of = |ix| self.line_cache.get_line(ix)
+            .and_then(|line| indent_columns(line.text(), self.tab_size));
+        if let Some(indent) = indent_of(line_num) {
+            return indent;
+        }
+        let limit = GUIDE_SEARCH_LINES;
+        let above = (1..limit).take_while(|&d| d <= line_num)
+            .filter_map(|d| indent_of(line_num - d)).next();
+        let below = (1..limit).filter_map(|d| indent_of(line_num + d)).next();
+        min(above.unwrap_or(0), below.unwrap_or(0))
+    }
+
+    pub fn tab_size(&self) -> usize {
+        self.tab_size
+    }
+
+    pub fn translate_tabs_to_spaces(&self) -> bool {
+        self.translate_tabs_to_spaces
+    }
+
+    /// Ask the core for a new tab size; the view follows once the core
+    /// confirms it with config_changed.
+    pub fn set_tab_size(&mut self, tab_size: usize, win: &MainWin) {
+        self.modify_config(json!({"tab_size": tab_size}), win);
+    }
+
+    /// Ask the core to indent with spaces or with tabs.
+    pub fn set_translate_tabs_to_spaces(&mut self, spaces: bool, win: &MainWin) {
+        self.modify_config(json!({"translate_tabs_to_spaces": spaces}), win);
+    }
+
+    // The advance width of a character in the (monospace) text font.
+    fn char_width(&self) -> f32 {
+        let text_format = match create_text_format(&self.dwrite_factory, FontWeight::Normal) {
+            Ok(text_format) => text_format,
+            Err(_) => return 8.0,
+        };
+        let params = text_layout::ParamBuilder::new()
+            .text("0")
+            .font(text_format)
+            .width(1e6)
+            .height(1e6)
+            .build().unwrap();
+        let layout: TextLayout = self.dwrite_factory.create(params).unwrap();
+        layout.hit_test_text_position(1, false).map(|pos| pos.point_x).unwrap_or(8.0)
+    }
+
+    pub fn plugins(&self) -> &[Plugin] {
+        &self.plugins
+    }
+
+    /// Replace the plugin list from the core's available_plugins.
+    pub fn set_available_plugins(&mut self, plugins: &Value) {
+        self.plugins = plugins.as_array().map(|plugins| plugins.iter().map(|plugin| Plugin {
+            name: plugin["name"].as_str().unwrap_or("").to_owned(),
+            running: plugin["running"].as_bool().unwrap_or(false),
+        }).collect()).unwrap_or_default();
+    }
+
+    pub fn set_plugin_running(&mut self, name: &str, running: bool) {
+        for plugin in &mut self.plugins {
+            if plugin.name == name {
+                plugin.running = running;
+            }
+        }
+    }
+
+    /// Ask the core to start the plugin if it's stopped, or stop it. The
+    /// list is updated when the core says it has started or stopped.
+    pub fn toggle_plugin(&mut self, index: usize, win: &MainWin) {
+        if let Some(plugin) = self.plugins.get(index) {
+            let command = if plugin.running { "stop" } else { "start" };
+            win.send_notification("plugin", &json!({
+                "command": command,
+                "view_id": self.view_id,
+                "plugin_name": plugin.name,
+            }));
+        }
+    }
+
+    pub fn set_view_id(&mut self, view_id: &str) {
+        self.view_id = view_id.into();
+        // The core doesn't know what the new view shows yet.
+        self.viewport.reset();
+        // A new view has no history.
+        self.undo_steps = 0;
+        self.redo_steps = 0;
+        self.pending_history = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.undo_steps > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.redo_steps > 0
+    }
+
+    /// Whether anything is selected, for the commands that need it.
+    pub fn has_selection(&self) -> bool {
+        self.line_cache.has_selection()
+    }
+
+    /// A note to show if the last undo or redo had nothing to do, or an
+    /// edit wasn't made because the view is read-only.
+    pub fn take_note(&mut self) -> Option<&'static str> {
+        self.note.take()
+    }
+
+    // Count an edit that the core can undo.
+    fn count_edit(&mut self) {
+        self.undo_steps += 1;
+        self.redo_steps = 0;
+        self.pending_history = None;
+    }
+
+    // The answer to an undo or redo has arrived; `changed` is whether it
+    // changed the text.
+    fn history_done(&mut self, history: History, changed: bool) {
+        match (history, changed) {
+            (History::Undo, true) => {
+                self.undo_steps = self.undo_steps.saturating_sub(1);
+                self.redo_steps += 1;
+            }
+            (History::Redo, true) => {
+                self.redo_steps = self.redo_steps.saturating_sub(1);
+                self.undo_steps += 1;
+            }
+            (History::Undo, false) => {
+                self.undo_steps = 0;
+                self.note = Some("Nothing to undo");
+            }
+            (History::Redo, false) => {
+                self.redo_steps = 0;
+                self.note = Some("Nothing to redo");
+            }
+        }
+    }
+
+    /// Scroll to an offset (in px units) and put the caret on a line once
+    /// the file's first update has arrived.
+    pub fn set_pending_scroll(&mut self, scroll_offset: f32, caret_line: usize) {
+        self.pending_scroll = Some((scroll_offset, caret_line));
+    }
+
+    pub fn set_pending_jump(&mut self, line: usize, col: usize) {
+        self.pending_jump = Some((line, col));
+    }
+
+    /// Whether the buffer has no unsaved changes.
+    pub fn is_pristine(&self) -> bool {
+        self.pristine
+    }
+
+    pub fn scroll_offset(&self) -> f32 {
+        self.scroll_offset
+    }
+
+    /// The line of the first caret, if the core has sent it.
+    pub fn caret_line(&self) -> Option<usize> {
+        self.line_cache.first_cursor().map(|(line, _)| line)
+    }
+
+    /// The line and column of the first caret, if the core has sent it.
+    pub fn caret(&self) -> Option<(usize, usize)> {
+        self.line_cache.first_cursor()
+    }
+
+    pub fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+
+    /// The text of each line, None for lines the core hasn't sent.
+    pub fn cached_lines(&self) -> Vec<Option<&str>> {
+        (0..self.line_cache.height())
+            .map(|ix| self.line_cache.get_line(ix).map(|line| line.text()))
+            .collect()
+    }
+
+    /// The ends of the first selection in the cached lines, or the first
+    /// caret at both ends if nothing is selected.
+    pub fn first_selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let mut span: Option<((usize, usize), (usize, usize))> = None;
+        for ix in 0..self.line_cache.height() {
+            let line = match self.line_cache.get_line(ix) {
+                Some(line) => line,
+                None if span.is_some() => break,
+                None => continue,
+            };
+            let selected = line.selections().find(|s| s.end > s.start);
+            let (start, end) = match (span, selected) {
+                (None, Some(s)) => ((ix, s.start), (ix, s.end)),
+                // A selection across lines goes on from the start of the
+                // next one.
+                (Some((start, _)), Some(s)) if s.start == 0 => (start, (ix, s.end)),
+                (None, None) => continue,
+                _ => break,
+            };
+            span = Some((start, end));
+            if end.1 < line.text().len() {
+                break;
+            }
+        }
+        span.or_else(|| self.line_cache.first_cursor().map(|caret| (caret, caret)))
+    }
+
+    /// The lines in view, even if the core hasn't been told yet.
+    pub fn visible_lines(&self) -> Range<usize> {
+        self.viewport.visible()
+    }
+
+    /// What the view knows, for working out where it and the core have
+    /// come to disagree. Only the lines in view are listed one by one, so
+    /// it's quick however big the file is.
+    pub fn debug_state(&self) -> Value {
+        let (height, cached) = (self.line_cache.height(), self.line_cache.cached_count());
+        let visible = self.visible_lines();
+        let lines: Vec<Value> = (visible.start..min(visible.end, height)).map(|ix| {
+            match self.line_cache.get_line(ix) {
+                Some(line) => json!({
+                    "line": ix,
+                    "ln": line.ln(),
+                    "len": line.text().len(),
+                    "cursor": line.cursor(),
+                    "highlights": line.highlights().len(),
+                }),
+                None => json!({"line": ix, "missing": true}),
+            }
+        }).collect();
+        let font = self.resources.as_ref().map(|resources| unsafe {
+            let text_format = &*resources.text_format.get_raw();
+            let mut family = [0u16; 64];
+            let family = if SUCCEEDED(text_format.GetFontFamilyName(family.as_mut_ptr(),
+                family.len() as u32))
+            {
+                let len = family.iter().position(|&c| c == 0).unwrap_or(family.len());
+                String::from_utf16_lossy(&family[..len])
+            } else {
+                String::new()
+            };
+            json!({
+                "family": family,
+                "size": text_format.GetFontSize(),
+                "char_width": resources.char_width,
+            })
+        });
+        json!({
+            "view_id": self.view_id,
+            "filename": self.filename,
+            "size": [self.size.0, self.size.1],
+            "scroll_offset": self.scroll_offset,
+            "h_scroll_offset": self.h_scroll_offset,
+            "max_scroll": self.max_scroll(),
+            "scroll_pinned": self.scroll_pinned,
+            "viewport": [self.viewport.sent().start, self.viewport.sent().end],
+            "pending_viewport": self.viewport.pending().map(|r| [r.start, r.end]),
+            "word_wrap": self.word_wrap,
+            "wrap_cols": self.wrap_cols,
+            "line_cache": {
+                "height": height,
+                "cached": cached,
+                "invalid": height - cached,
+                "cursors": self.line_cache.cursor_count(),
+                "first_cursor": self.line_cache.first_cursor(),
+            },
+            "pristine": self.pristine,
+            "read_only": self.read_only,
+            "encoding": self.encoding.label(),
+            "language": self.language,
+            "font": font,
+            "gutter_colors": {
+                "background": self.gutter_colors.background.map(|c| format!("#{:06x}", c)),
+                "foreground": self.gutter_colors.foreground.map(|c| format!("#{:06x}", c)),
+            },
+            "lines": lines,
+        })
+    }
+
+    /// Apply an update from the core. Returns the part of the window (in px
+    /// units) that needs repainting, which may be none of the view.
+    pub fn apply_update(&mut self, update: Update, win: &MainWin)
+        -> Option<(f32, f32, f32, f32)>
+    {
+        let pristine = update.pristine;
+        let gutter_digits = self.gutter_digits();
+        self.bookmarks.apply_ops(&update.ops, &self.line_cache);
+        self.scrollbar.apply_ops(&update.ops, &self.line_cache);
+        let changed = self.line_cache.apply_update(update);
+        if let Some(ref lines) = changed {
+            self.layouts.invalidate(lines.clone());
+            self.counts.invalidate(lines.clone(), self.line_cache.height());
+            // Marks anywhere in the document can have moved.
+            self.scrollbar.update_lines(&self.line_cache, lines.clone());
+            let (x0, y0, x1, y1) = self.scrollbar_bounds();
+            win.handle.borrow().invalidate_rect(x0, y0, x1, y1);
+            self.minimap.invalidate(lines.clone());
+            if self.show_minimap {
+                let (first, line_height) = self.minimap_layout();
+                if let Some((y0, y1)) = Minimap::lines_y(lines.clone(), first, line_height) {
+                    let (x0, x1) = self.chrome().minimap();
+                    win.handle.borrow().invalidate_rect(x0, self.top + y0, x1,
+                        self.top + y1.min(self.size.1));
+                }
+            }
+        }
+        if self.gutter_digits() != gutter_digits {
+            // The line count has gained or lost a digit.
+            self.update_wrap_width(win);
+            win.invalidate();
+        }
+        let current_number_row = self.find_current_number_row();
+        if current_number_row != self.current_number_row {
+            match self.line_numbers {
+                // Every relative number changes with the caret's line.
+                LineNumbers::Relative | LineNumbers::Hybrid => {
+                    let ((x0, x1), bottom) = (self.chrome().gutter(), self.top + self.size.1);
+                    win.handle.borrow().invalidate_rect(x0, self.top, x1, bottom);
+                }
+                _ => {
+                    for &row in self.current_number_row.iter().chain(current_number_row.iter()) {
+                        self.invalidate_gutter_row(row, win);
+                    }
+                }
+            }
+            self.current_number_row = current_number_row;
+        }
+        self.stall_watch = Default::default();
+        self.jumps.update(self.line_cache.first_cursor(), self.line_cache.text_changed());
+        if let Some(history) = self.pending_history.take() {
+            let text_changed = self.line_cache.text_changed();
+            self.history_done(history, text_changed);
+        }
+        // Matches found before an edit may have moved.
+        if self.line_cache.text_changed() {
+            self.word_matches.clear();
+        }
+        self.schedule_word_matches(win);
+        self.count_selection(win);
+        let bracket_match = self.find_bracket_match();
+        if bracket_match != self.bracket_match {
+            self.bracket_match = bracket_match;
+            win.invalidate();
+        }
+        if let Some(pristine) = pristine {
+            self.pristine = pristine;
+        }
+        if self.line_cache.height() > 0 {
+            if let Some((line, col)) = self.pending_jump.take() {
+                // The core scrolls to the caret.
+                self.gesture(line, col, GestureType::PointSelect, win);
+            }
+            if let Some((scroll_offset, caret_line)) = self.pending_scroll.take() {
+                self.send_edit_cmd(EditCommand::GotoLine { line: caret_line }, win);
+                // Ahead of the core's scroll_to, which only scrolls if the
+                // caret is out of view anyway.
+                self.scroll_offset = scroll_offset;
+                self.constrain_scroll();
+                self.update_viewport(win);
+                return Some(self.bounds());
+            }
+        }
+        let old_scroll = self.scroll_offset;
+        self.constrain_scroll();
+        if self.scroll_offset != old_scroll {
+            return Some(self.bounds());
+        }
+        let lines = changed?;
+        let y0 = self.line_to_content_y(lines.start) - self.scroll_offset;
+        let y1 = self.line_to_content_y(lines.end) - self.scroll_offset;
+        if y1 <= 0.0 || y0 >= self.size.1 {
+            return None;
+        }
+        Some((0.0, self.top + y0.max(0.0), self.size.0, self.top + y1.min(self.size.1)))
+    }
+
+    /// Type a character, one the window's CharFilter let through.
+    pub fn char(&mut self, ch: u32, win: &MainWin) {
+        self.reset_blink(win);
+        self.dismiss_hover(win);
+        self.end_pan(win);
+        if let Some(c) = ::std::char::from_u32(ch) {
+            let mut cmds = if self.overwrite && !self.line_cache.has_selection() {
+                self.overwrite_cmds()
+            } else {
+                Vec::new()
+            };
+            cmds.push(EditCommand::Insert { chars: c.to_string() });
+            self.send_edit_cmds(cmds, win);
+        }
+    }
+
+    // The commands selecting what a character typed in overwrite mode
+    // replaces, for every caret.
+    fn overwrite_cmds(&self) -> Vec<EditCommand> {
+        let carets: Vec<(usize, usize, Option<usize>)> = self.carets().into_iter()
+            .map(|(line_num, col)| {
+                let end = self.line_cache.get_line(line_num)
+                    .and_then(|line| next_char_end(line.text(), col));
+                (line_num, col, end)
+            })
+            .collect();
+        overwrite_cmds(&carets)
+    }
+
+    pub fn is_overwrite(&self) -> bool {
+        self.overwrite
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    // Whether commands can be sent: a read-only view stops any that would
+    // change the buffer, and leaves a note for the status bar. This is the
+    // only place that's checked; every command comes through here, from
+    // send_edit_cmds or send_edit_request.
+    fn writable_for(&mut self, cmds: &[EditCommand]) -> bool {
+        let writable = !self.read_only || !cmds.iter().any(|cmd| cmd.is_mutating());
+        if !writable {
+            self.note = Some("The view is read-only");
+        }
+        writable
+    }
+
+    /// Sends an edit command for this view. Commands that change the
+    /// buffer are tracked until the core answers them with an update.
+    fn send_edit_cmd(&mut self, cmd: EditCommand, sink: &EditSink) {
+        self.send_edit_cmds(vec![cmd], sink);
+    }
+
+    /// Sends the commands of one edit, such as the gestures selecting what
+    /// an insert replaces. If the view is read-only and any would change
+    /// the buffer, none are sent, so the selection doesn't move for an edit
+    /// that doesn't happen. Returns whether they were sent.
+    fn send_edit_cmds(&mut self, cmds: Vec<EditCommand>, sink: &EditSink) -> bool {
+        if !self.writable_for(&cmds) {
+            return false;
+        }
+        for cmd in cmds {
+            self.track_edit_cmd(&cmd, sink);
+            sink.send_edit_cmd(&cmd, &self.view_id);
+        }
+        true
+    }
+
+    /// Sends commands and then a request, such as a cut, as one edit, the
+    /// way send_edit_cmds does. The callback gets the request's result.
+    fn send_edit_request<F>(&mut self, mut cmds: Vec<EditCommand>, request: EditCommand,
+        win: &MainWin, callback: F) -> bool
+        where F: FnOnce(&MainWinHandler, &Value) + Send + 'static
+    {
+        cmds.push(request);
+        if !self.writable_for(&cmds) {
+            return false;
+        }
+        let request = cmds.pop().unwrap();
+        self.send_edit_cmds(cmds, win);
+        self.track_edit_cmd(&request, win);
+        win.send_edit_request(&request, &self.view_id, callback);
+        true
+    }
+
+    // What sending a command does to the view's own state: the scroll
+    // unpins, and edits are counted and watched until the core answers.
+    fn track_edit_cmd(&mut self, cmd: &EditCommand, sink: &EditSink) {
+        match *cmd {
+            EditCommand::Scroll { .. } | EditCommand::RequestHover { .. } => (),
+            _ => {
+                self.scroll_pinned = false;
+                self.recenter = None;
+            }
+        }
+        match *cmd {
+            EditCommand::Undo => self.pending_history = Some(History::Undo),
+            EditCommand::Redo => self.pending_history = Some(History::Redo),
+            _ if cmd.is_mutating() => self.count_edit(),
+            _ => (),
+        }
+        if cmd.is_mutating() && self.stall_watch.edit_sent(Instant::now()) {
+            sink.watch_core_stall();
+        }
+    }
+
+    fn gesture(&mut self, line: usize, col: usize, ty: GestureType, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::Gesture { line, col, ty }, win);
+    }
+
+    /// Polled while an edit is waiting for the core: probes the core once
+    /// the edit has waited a while, and says how long it's been waiting.
+    pub fn check_core_stall(&mut self, now: Instant, sink: &EditSink) -> CoreStall {
+        if self.stall_watch.start_probe(now) {
+            sink.send_stall_probe(&self.view_id);
+        }
+        self.stall_watch.check(now)
+    }
+
+    /// The core answered the probe, so every edit sent before it has been
+    /// processed.
+    pub fn stall_probe_answered(&mut self) {
+        // An undo with nothing to do doesn't get an update.
+        if self.stall_watch.probe_answered() {
+            if let Some(history) = self.pending_history.take() {
+                self.history_done(history, false);
+            }
+        }
+    }
+
+    /// Handle a key, given what the keymap binds it to. Returns whether
+    /// the key did anything.
+    pub fn keydown(&mut self, vk_code: i32, binding: Option<Binding>, win: &MainWin) -> bool {
+        self.reset_blink(win);
+        self.dismiss_hover(win);
+        if vk_code == VK_CONTROL {
+            self.update_link(true, win);
+        }
+        // Any key but a bare modifier ends a Ctrl+K chord.
+        let ctrl_k = if is_modifier(vk_code) {
+            self.ctrl_k_pending
+        } else {
+            mem::replace(&mut self.ctrl_k_pending, false)
+        };
+        match binding {
+            Some(Binding::Edit(cmd)) => self.send_edit_cmd(cmd, win),
+            Some(Binding::View(action)) => self.run_view_action(action, ctrl_k, win),
+            // Window commands are run by the window.
+            Some(Binding::Menu(_)) | None => return false,
+        }
+        true
+    }
+
+    fn run_view_action(&mut self, action: ViewAction, ctrl_k: bool, win: &MainWin) {
+        match action {
+            ViewAction::IndentOrInsertTab => {
+                // Tab indents the lines of a selection that spans several
+                // lines instead of replacing it.
+                let cmd = if self.line_cache.has_multi_line_selection() {
+                    EditCommand::Indent
+                } else {
+                    EditCommand::InsertTab
+                };
+                self.send_edit_cmd(cmd, win);
+            }
+            ViewAction::SmartHome => {
+                if !self.smart_home(false, win) {
+                    self.send_edit_cmd(EditCommand::MoveToLeftEndOfLine, win);
+                }
+            }
+            ViewAction::SmartHomeAndModifySelection => {
+                if !self.smart_home(true, win) {
+                    self.send_edit_cmd(EditCommand::MoveToLeftEndOfLineAndModifySelection, win);
+                }
+            }
+            ViewAction::ScrollLineUp => self.scroll_by_line(-LINE_SPACE, win),
+            ViewAction::ScrollLineDown => self.scroll_by_line(LINE_SPACE, win),
+            ViewAction::MoveLinesUp => self.move_lines(true, win),
+            ViewAction::MoveLinesDown => self.move_lines(false, win),
+            ViewAction::ToggleOverwrite => {
+                self.overwrite = !self.overwrite;
+                win.invalidate();
+            }
+            ViewAction::DeleteLine => self.delete_line(win),
+            ViewAction::ToggleComment => self.toggle_comment(win),
+            ViewAction::GotoMatchingBracket => self.goto_matching_bracket(win),
+            ViewAction::StartChord => self.ctrl_k_pending = true,
+            ViewAction::Recenter => self.recenter(win),
+            // Ctrl+K, Ctrl+D skips the current occurrence
+            ViewAction::SelectNextOccurrence => self.select_next_occurrence(ctrl_k, win),
+            ViewAction::TogglePaintStats => {
+                let enabled = self.stats.is_enabled();
+                self.stats.set_enabled(!enabled);
+                win.invalidate();
+            }
+        }
+    }
+
+    // Scroll the view by a line, without moving the caret.
+    fn scroll_by_line(&mut self, delta: f32, win: &MainWin) {
+        self.scroll_offset += delta;
+        self.scroll_pinned = true;
+        self.constrain_scroll();
+        self.update_viewport(win);
+        win.invalidate();
+    }
+
+    // Commands
+
+    pub fn undo(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::Undo, win);
+    }
+
+    pub fn redo(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::Redo, win);
+    }
+
+    pub fn upper_case(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::Uppercase, win);
+    }
+
+    pub fn lower_case(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::Lowercase, win);
+    }
+
+    /// Capitalize each word of the selections. The core has no command
+    /// for it, so each selection's text, taken from the cached lines, is
+    /// replaced by itself capitalized, all in one edit, and they stay
+    /// selected.
+    pub fn title_case(&mut self, win: &MainWin) {
+        let selections = self.line_cache.selections();
+        let mut regions = Vec::new();
+        // Whether every selection keeps its length, so it can be put back.
+        let mut same_lengths = true;
+        for &(anchor, caret) in &selections {
+            let (start, end) = (min(anchor, caret), max(anchor, caret));
+            if start == end {
+                continue;
+            }
+            let text = match self.range_text(start, end) {
+                Some(text) => text,
+                None => {
+                    self.note = Some("Not all the selected lines are loaded");
+                    return;
+                }
+            };
+            let chars = title_case(&text);
+            if chars != text {
+                same_lengths = same_lengths && chars.len() == text.len();
+                regions.push((start, end, chars));
+            }
+        }
+        if regions.is_empty() {
+            return;
+        }
+        if self.send_edit_cmds(replace_cmds(regions), win) && same_lengths {
+            self.restore_selections(&selections, win);
+        }
+    }
+
+    pub fn transpose(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::Transpose, win);
+    }
+
+    /// Copy the line at each caret, or each selected region, directly
+    /// below it.
+    pub fn duplicate_line(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::DuplicateLine, win);
+    }
+
+    /// Comment or uncomment the lines with carets or selections on them,
+    /// using the comment syntax of the view's language.
+    pub fn toggle_comment(&mut self, win: &MainWin) {
+        self.send_edit_cmd(EditCommand::DebugToggleComment, win);
+    }
+
+    /// Delete the spaces and tabs at the ends of lines, except on lines
+    /// where a caret is in them, as while typing. Only lines the core has
+    /// sent can be trimmed; saving asks for them all first. The selections
+    /// are put back where they were, and the view doesn't scroll.
+    pub fn trim_trailing_whitespace(&mut self, win: &MainWin) {
+        let runs = self.trailing_whitespace_runs();
+        if runs.is_empty() {
+            return;
+        }
+        let trims: BTreeMap<usize, usize> =
+            runs.iter().map(|&(line, start, _)| (line, start)).collect();
+        let selections = selections_after_trims(self.line_cache.selections(), &trims);
+        // Select every run, then delete them all in one edit, which undoes
+        // as one.
+        let mut cmds = Vec::new();
+        for (ix, &(line, start, end)) in runs.iter().enumerate() {
+            let ty = if ix == 0 { GestureType::PointSelect } else { GestureType::ToggleSel };
+            cmds.push(EditCommand::Gesture { line, col: start, ty });
+            cmds.push(EditCommand::Drag { line, col: end });
+        }
+        cmds.push(EditCommand::DeleteBackward);
+        if self.send_edit_cmds(cmds, win) {
+            self.restore_selections(&selections, win);
+        }
+    }
+
+    // The line, start and end of each run of trailing whitespace that a
+    // trim deletes: those in the cached lines with no caret in them.
+    fn trailing_whitespace_runs(&self) -> Vec<(usize, usize, usize)> {
+        let mut runs = Vec::new();
+        for line_num in 0..self.line_cache.height() {
+            if let Some(line) = self.line_cache.get_line(line_num) {
+                let (start, end) = trailing_whitespace(line.text());
+                if start < end && !line.cursor().iter().any(|&col| col > start && col <= end) {
+                    runs.push((line_num, start, end));
+                }
+            }
+        }
+        runs
+    }
+
+    /// Join each line with a caret or selection on it to the next, or the
+    /// lines a selection covers to each other, with a space in place of
+    /// the whitespace around each line break. The core has no command for
+    /// it, so the breaks are all selected and replaced in one edit.
+    pub fn join_lines(&mut self, win: &MainWin) {
+        let mut joins = BTreeSet::new();
+        for (first, last) in self.selected_line_spans() {
+            joins.extend(first..max(last, first + 1));
+        }
+        let mut breaks: Vec<((usize, usize), (usize, usize))> = Vec::new();
+        for line_num in joins {
+            let (line, next) = match (self.line_cache.get_line(line_num),
+                self.line_cache.get_line(line_num + 1))
+            {
+                (Some(line), Some(next)) => (line, next),
+                _ => continue,
+            };
+            let start = (line_num, trailing_whitespace(line.text()).0);
+            let indent = next.text().len() -
+                next.text().trim_start_matches(|c| c == ' ' || c == '\t').len();
+            let end = (line_num + 1, indent);
+            // The core would merge selections that touch, and toggling at
+            // the end of one would drop it, so they're merged here.
+            if let Some(last) = breaks.last_mut() {
+                if start <= last.1 {
+                    last.1 = max(last.1, end);
+                    continue;
+                }
+            }
+            breaks.push((start, end));
+        }
+        if breaks.is_empty() {
+            return;
+        }
+        let mut cmds = Vec::new();
+        for (ix, &(start, end)) in breaks.iter().enumerate() {
+            let ty = if ix == 0 { GestureType::PointSelect } else { GestureType::ToggleSel };
+            cmds.push(EditCommand::Gesture { line: start.0, col: start.1, ty });
+            cmds.push(EditCommand::Drag { line: end.0, col: end.1 });
+        }
+        cmds.push(EditCommand::Insert { chars: " ".to_owned() });
+        self.send_edit_cmds(cmds, win);
+    }
+
+    /// Sort the lines of each selection across lines, from the start of
+    /// its first line to the end of its last, keeping equal lines in
+    /// order. They're put back in one edit, which undoes as one, and stay
+    /// selected.
+    pub fn sort_lines(&mut self, case_sensitive: bool, win: &MainWin) {
+        let spans: Vec<(usize, usize)> = self.selected_line_spans().into_iter()
+            .filter(|&(first, last)| last > first)
+            .collect();
+        if spans.is_empty() {
+            self.note = Some("Select the lines to sort");
+            return;
+        }
+        // Sorting leaves each line where it was, so the spans after one
+        // don't move.
+        let mut cmds = Vec::new();
+        let mut selections = Vec::new();
+        for &(first, last) in &spans {
+            let sorted = {
+                let lines: Option<Vec<&str>> = (first..last + 1)
+                    .map(|ix| self.line_cache.get_line(ix).map(|line| line.text()))
+                    .collect();
+                lines.map(|lines| {
+                    let last_len = lines[lines.len() - 1]
+                        .trim_end_matches(|c| c == '\n' || c == '\r').len();
+                    ((last, last_len), sorted_lines(&lines, case_sensitive))
+                })
+            };
+            let (old_end, chars) = match sorted {
+                Some(sorted) => sorted,
+                None => {
+                    self.note = Some("Not all the selected lines are loaded");
+                    return;
+                }
+            };
+            let end = (last, chars.len() - chars.rfind('\n').map_or(0, |ix| ix + 1));
+            cmds.extend(range_cmds((first, 0), old_end));
+            cmds.push(EditCommand::Insert { chars });
+            selections.push(((first, 0), end));
+        }
+        cmds.extend(selections_cmds(&selections));
+        self.send_edit_cmds(cmds, win);
+    }
+
+    /// Make the document end in exactly one newline, adding it or deleting
+    /// the blank lines after it, if the end is in the line cache; saving
+    /// asks for every line first. With `trimmed`, a trim was sent just
+    /// before, which the cache doesn't show yet, so the lines are taken as
+    /// that trim leaves them. The selections are put back, and the view
+    /// doesn't scroll, though the edit is at the end.
+    pub fn ensure_final_newline(&mut self, trimmed: bool, win: &MainWin) {
+        let height = self.line_cache.height();
+        // Where each line's whitespace starts, on the lines the trim
+        // empties of it.
+        let trims: BTreeMap<usize, usize> = if trimmed {
+            self.trailing_whitespace_runs().into_iter()
+                .map(|(line, start, _)| (line, start))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+        // The length of a line after the trim, without its newline.
+        let text_len = |line_num: usize, text: &str| match trims.get(&line_num) {
+            Some(&start) => start,
+            None => text.trim_end_matches(|c| c == '\n' || c == '\r').len(),
+        };
+        // The last line with text on it, and whether it ends in a newline.
+        let mut last_text = None;
+        for line_num in (0..height).rev() {
+            let line = match self.line_cache.get_line(line_num) {
+                Some(line) => line,
+                // Nothing can be done without knowing the end.
+                None => return,
+            };
+            if text_len(line_num, line.text()) > 0 {
+                last_text = Some((line_num, line.text().ends_with('\n')));
+                break;
+            }
+        }
+        // An empty document stays empty.
+        let (last, ends_in_newline) = match last_text {
+            Some(last_text) => last_text,
+            None => return,
+        };
+        // After the final newline the core sends an empty line to put the
+        // caret on, so the document ends where that line does. Anything
+        // between the end of the last text and there is blank lines.
+        let end_line = height - 1;
+        let end_col = match self.line_cache.get_line(end_line) {
+            Some(line) if end_line > last => text_len(end_line, line.text()),
+            _ => 0,
+        };
+        let blank_lines = end_line > last + 1 || end_col > 0;
+        let mut selections = selections_after_trims(self.line_cache.selections(), &trims);
+        let cmds = if !ends_in_newline {
+            vec![EditCommand::MoveToEndOfDocument, EditCommand::Insert { chars: "\n".to_owned() }]
+        } else if blank_lines {
+            let mut cmds = range_cmds((last + 1, 0), (end_line, end_col));
+            cmds.push(EditCommand::DeleteBackward);
+            // Selections on the lines deleted end up after the newline.
+            let after = |point: (usize, usize)| if point.0 > last { (last + 1, 0) } else { point };
+            for selection in &mut selections {
+                *selection = (after(selection.0), after(selection.1));
+            }
+            selections.dedup();
+            cmds
+        } else {
+            return;
+        };
+        if self.send_edit_cmds(cmds, win) {
+            self.restore_selections(&selections, win);
+        }
+    }
+
+    // The line and offset of every caret in the cached lines, in order.
+    fn carets(&self) -> Vec<(usize, usize)> {
+        let mut carets = Vec::new();
+        for line_num in 0..self.line_cache.height() {
+            if let Some(line) = self.line_cache.get_line(line_num) {
+                carets.extend(line.cursor().iter().map(|&col| (line_num, col)));
+            }
+        }
+        carets
+    }
+
+    // The text from one point to another, if all its lines are cached.
+    fn range_text(&self, start: (usize, usize), end: (usize, usize)) -> Option<String> {
+        let mut text = String::new();
+        for ix in start.0..end.0 + 1 {
+            let from = if ix == start.0 { start.1 } else { 0 };
+            if ix == end.0 && end.1 == from {
+                break;
+            }
+            let line = self.line_cache.get_line(ix)?.text();
+            let to = if ix == end.0 { end.1 } else { line.len() };
+            text.push_str(line.get(from..to)?);
+        }
+        Some(text)
+    }
+
+    // The first and last lines of each selection, and the line of each
+    // caret on its own, in order and merged where they overlap. A
+    // selection ending at the start of a line doesn't take that line in.
+    fn selected_line_spans(&self) -> Vec<(usize, usize)> {
+        let mut spans: Vec<(usize, usize)> = Vec::new();
+        // Whether the last span's selection goes on past its line's end.
+        let mut open = false;
+        for ix in 0..self.line_cache.height() {
+            let line = match self.line_cache.get_line(ix) {
+                Some(line) => line,
+                None => {
+                    open = false;
+                    continue;
+                }
+            };
+            let len = line.text().trim_end_matches(|c| c == '\n' || c == '\r').len();
+            let continued = open;
+            open = false;
+            let selected: Vec<Range<usize>> = line.selections()
+                .filter(|s| s.end > s.start)
+                .map(|s| s.start..s.end)
+                .collect();
+            for s in &selected {
+                if continued && s.start == 0 {
+                    spans.last_mut().unwrap().1 = ix;
+                } else {
+                    spans.push((ix, ix));
+                }
+                open = s.end > len;
+            }
+            for &col in line.cursor() {
+                let in_selection = selected.iter().any(|s| s.start <= col && col <= s.end) ||
+                    (continued && col == 0);
+                if !in_selection {
+                    spans.push((ix, ix));
+                }
+            }
+        }
+        spans.sort();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (first, last) in spans {
+            if let Some(span) = merged.last_mut() {
+                if first <= span.1 {
+                    span.1 = max(span.1, last);
+                    continue;
+                }
+            }
+            merged.push((first, last));
+        }
+        merged
+    }
+
+    // Put the selections back after an edit that left their lines and the
+    // text before them alone, and keep the view where it is, though the
+    // edit and the selections moved the carets.
+    fn restore_selections(&mut self, selections: &[((usize, usize), (usize, usize))],
+        win: &MainWin)
+    {
+        self.send_edit_cmds(selections_cmds(selections), win);
+        self.scroll_pinned = true;
+    }
+
+    // Put the carets back after an edit that left their lines and the text
+    // before them alone.
+    fn restore_carets(&mut self, carets: &[(usize, usize)], win: &MainWin) {
+        for (ix, &(line, col)) in carets.iter().enumerate() {
+            let ty = if ix == 0 { GestureType::PointSelect } else { GestureType::ToggleSel };
+            self.gesture(line, col, ty, win);
+        }
+    }
+
+    /// Select from one point to another, or put the caret at the point if
+    /// they're the same.
+    pub fn select_range(&mut self, start: (usize, usize), end: (usize, usize), win: &MainWin) {
+        self.send_edit_cmds(range_cmds(start, end), win);
+    }
+
+    pub fn add_cursor_above(&mut self, win: &MainWin) {
+        // Note: some subtlety around find, the escape key cancels it, but the menu
+        // shouldn't.
+        self.send_edit_cmd(EditCommand::AddSelectionAbove, win);
+    }
+
+    pub fn add_cursor_below(&mut self, win: &MainWin) {
+        // Note: some subtlety around find, the escape key cancels it, but the menu
+        // shouldn't.
+        self.send_edit_cmd(EditCommand::AddSelectionBelow, win);
+    }
+
+    pub fn single_selection(&mut self, win: &MainWin) {
+        // Note: some subtlety around find, the escape key cancels it, but the menu
+        // shouldn't.
+        self.send_edit_cmd(EditCommand::CancelOperation, win);
+    }
+
+    pub fn select_all(&mut self, win: &MainWin) {
+        // Note: some subtlety around find, the escape key cancels it, but the menu
+        // shouldn't.
+        self.send_edit_cmd(EditCommand::SelectAll, win);
+    }
+
+    /// Select the word at the caret. If there's already a selection, add
+    /// the next occurrence of it as another selection, optionally dropping
+    /// the current one.
+    pub fn select_next_occurrence(&mut self, skip_current: bool, win: &MainWin) {
+        if !self.line_cache.has_selection() {
+            if let Some((line, col)) = self.line_cache.first_cursor() {
+                self.gesture(line, col, GestureType::WordSelect, win);
+            }
+            return;
+        }
+        self.send_edit_cmd(EditCommand::SelectionForFind { case_sensitive: true }, win);
+        let modify_selection = if skip_current {
+            ModifySelection::AddRemovingCurrent
+        } else {
+            ModifySelection::Add
+        };
+        self.send_edit_cmd(EditCommand::FindNext {
+            wrap_around: true,
+            allow_same: false,
+            modify_selection,
+        }, win);
+    }
+
+    // Move the caret to the first non-blank character of its line, or to
+    // the start of the line if it's already there; shift extends the
+    // selection. Returns false if the core's plain Home should be used
+    // instead, as with several carets, which the gestures would collapse.
+    fn smart_home(&mut self, extend: bool, win: &MainWin) -> bool {
+        if self.line_cache.cursor_count() != 1 {
+            return false;
+        }
+        let (line_num, col) = match self.line_cache.first_cursor() {
+            Some(cursor) => cursor,
+            None => return false,
+        };
+        let indent = match self.line_cache.get_line(line_num) {
+            Some(line) => {
+                let text = line.text();
+                text.len() - text.trim_start_matches(|c| c == ' ' || c == '\t').len()
+            }
+            None => return false,
+        };
+        let target = if col == indent { 0 } else { indent };
+        let ty = if extend { GestureType::RangeSelect } else { GestureType::PointSelect };
+        self.gesture(line_num, target, ty, win);
+        true
+    }
+
+    /// Delete the line containing each caret, leaving each caret on the
+    /// following line at the same column where it's long enough.
+    pub fn delete_line(&mut self, win: &MainWin) {
+        let carets = self.carets();
+        let deleted = self.caret_lines();
+        let height = self.line_cache.height();
+        let last_has_newline = self.line_cache.get_line(height.saturating_sub(1))
+            .map_or(true, |line| line.text().ends_with('\n'));
+        let cmds = delete_lines_cmds(&deleted, height, last_has_newline);
+        if self.send_edit_cmds(cmds, win) {
+            self.restore_carets(&carets_after_deleting(&carets, &deleted, height), win);
+        }
+    }
+
+    /// Move the lines with carets or selections on them up or down past
+    /// the neighboring line. Each run of them moves on its own, and every
+    /// selection moves with its lines.
+    pub fn move_lines(&mut self, up: bool, win: &MainWin) {
+        let blocks = line_blocks(self.selected_line_spans());
+        let height = self.line_cache.height();
+        // If one run can't move, none do.
+        match (blocks.first(), blocks.last()) {
+            (Some(&(first, _)), _) if up && first == 0 => return,
+            (_, Some(&(_, last))) if !up && last + 1 >= height => return,
+            (None, _) => return,
+            _ => (),
+        }
+        // The core has no command for this, so each run and the line it
+        // moves past are replaced by the same lines in their new order, all
+        // as one edit. No line changes, and none moves more than one, so
+        // the runs still to go are where the cache has them.
+        let mut cmds = Vec::new();
+        for &(first, last) in &blocks {
+            let (start, end) = if up { (first - 1, last) } else { (first, last + 1) };
+            let texts: Option<Vec<&str>> = (start..end + 1)
+                .map(|ix| self.line_cache.get_line(ix).map(|line| line.text()))
+                .collect();
+            let texts = match texts {
+                Some(texts) => texts,
+                None => return,
+            };
+            let (above, below) = if up {
+                (texts[0].to_owned(), texts[1..].concat())
+            } else {
+                (texts[..texts.len() - 1].concat(), texts[texts.len() - 1].to_owned())
+            };
+            let span_end = if below.ends_with('\n') {
+                (end + 1, 0)
+            } else {
+                (end, texts[texts.len() - 1].len())
+            };
+            cmds.extend(range_cmds((start, 0), span_end));
+            cmds.push(EditCommand::Insert { chars: lines_swapped(&above, &below) });
+        }
+        let selections = selections_after_moving(self.line_cache.selections(), &blocks, up);
+        cmds.extend(selections_cmds(&selections));
+        self.send_edit_cmds(cmds, win);
+    }
+
+    pub fn cut(&mut self, win: &MainWin) {
+        if self.line_cache.has_selection() {
+            self.clipboard_request(EditCommand::Cut, win);
+        } else if let Some(text) = self.caret_lines_text() {
+            // Take the whole lines, newlines included.
+            let lines = self.caret_lines();
+            let mut cmds = Vec::new();
+            for (i, &line) in lines.iter().enumerate() {
+                let ty = if i == 0 {
+                    GestureType::LineSelect
+                } else {
+                    GestureType::MultiLineSelect
+                };
+                cmds.push(EditCommand::Gesture { line, col: 0, ty });
+            }
+            cmds.push(EditCommand::DeleteForward);
+            if self.send_edit_cmds(cmds, win) {
+                set_line_copy(win, &text);
+            }
+        }
+    }
+
+    pub fn copy(&mut self, win: &MainWin) {
+        if self.line_cache.has_selection() {
+            self.clipboard_request(EditCommand::Copy, win);
+        } else if let Some(text) = self.caret_lines_text() {
+            set_line_copy(win, &text);
+        }
+    }
+
+    // The lines with carets on them, each once.
+    fn caret_lines(&self) -> Vec<usize> {
+        (0..self.line_cache.height()).filter(|&ix| {
+            self.line_cache.get_line(ix).map_or(false, |line| !line.cursor().is_empty())
+        }).collect()
+    }
+
+    // The text of the lines with carets on them, each ending in a newline,
+    // for copying when nothing is selected.
+    fn caret_lines_text(&self) -> Option<String> {
+        let mut text = String::new();
+        for ix in self.caret_lines() {
+            if let Some(line) = self.line_cache.get_line(ix) {
+                text.push_str(line.text());
+                if !line.text().ends_with('\n') {
+                    text.push('\n');
+                }
+            }
+        }
+        if text.is_empty() { None } else { Some(text) }
+    }
+
+    // Cut and copy are requests; the result is the selected text, or null if
+    // the selection is empty.
+    fn clipboard_request(&mut self, cmd: EditCommand, win: &MainWin) {
+        self.send_edit_request(Vec::new(), cmd, win, |handler, result| {
+            if let Some(text) = result.as_str() {
+                let text = text.replace('\n', "\r\n");
+                if let Err(e) = clipboard::set_text(&handler.win.handle.borrow(), &text) {
+                    println!("error setting clipboard: {:?}", e);
+                }
+            }
+        });
+    }
+
+    pub fn paste(&mut self, win: &MainWin) {
+        if let Some(text) = clipboard::get_text() {
+            let cmds = paste_cmds(text.replace("\r\n", "\n"),
+                clipboard::has_format(line_copy_format()), self.line_cache.cursor_count(),
+                self.line_cache.first_cursor(), self.line_cache.has_selection());
+            self.send_edit_cmds(cmds, win);
+        }
+    }
+
+    /// Whether the point (in px units) is inside the edit area.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= 0.0 && y >= self.top && x < self.size.0 && y < self.top + self.size.1
+    }
+
+    pub fn mouse_down(&mut self, x: f32, y: f32, mods: u32, which: MouseButton, win: &MainWin) {
+        self.reset_blink(win);
+        self.stop_glide(win);
+        self.dismiss_hover(win);
+        let in_selection = self.is_in_selection(x, y);
+        // From here on, in the view's own coordinates.
+        let y = y - self.top;
+        match self.chrome().region_at(x) {
+            Region::Scrollbar => {
+                if which == MouseButton::Left {
+                    self.scrollbar_mouse_down(y, win);
+                }
+                return;
+            }
+            Region::Minimap => {
+                if which == MouseButton::Left {
+                    self.minimap.start_drag();
+                    self.minimap_scroll(y, win);
+                }
+                return;
+            }
+            Region::Gutter if which == MouseButton::Left => {
+                return self.gutter_mouse_down(x, y, mods, win);
+            }
+            _ => (),
+        }
+        let (line, col) = self.xy_to_line_col(x, y);
+        match which {
+            MouseButton::Left => {
+                let ctrl = (mods & M_CTRL) != 0;
+                let alt = (mods & M_ALT) != 0;
+                let count = self.click_count(x, y);
+                let plain = !alt && (mods & M_SHIFT) == 0;
+                if count == 1 && ctrl && plain && self.request_definition(x, y, (line, col), win) {
+                    return;
+                }
+                let (ty, granularity) = click_gesture(count, mods);
+                self.gesture(line, col, ty, win);
+                self.drag = Some(Drag {
+                    granularity: granularity,
+                    anchor: (line, col),
+                    anchor_span: self.drag_span(granularity, line, col),
+                    multi: ctrl,
+                    anchor_x: x + self.h_scroll_offset,
+                    last: (line, col),
+                    pointer: (x, y),
+                });
+                self.column_rows = vec![(line, col, col)];
+            }
+            MouseButton::Right => {
+                // Move the caret first unless the click is on the selection,
+                // so the context menu acts on the click point.
+                if !in_selection {
+                    self.gesture(line, col, GestureType::PointSelect, win);
+                }
+            }
+            MouseButton::Middle => match self.middle_click {
+                MiddleClick::Autoscroll => {
+                    self.pan = Some(Pan {
+                        origin: (x, y),
+                        pointer: (x, y),
+                        moved: false,
+                        held: true,
+                    });
+                    win.handle.borrow().set_timer(Timers::Autoscroll as usize, AUTOSCROLL_MS);
+                    win.invalidate();
+                }
+                MiddleClick::Paste => self.paste_at(line, col, win),
+            },
+            _ => (),
+        }
+    }
+
+    pub fn mouse_up(&mut self, which: MouseButton, win: &MainWin) {
+        match which {
+            MouseButton::Left if self.scrollbar.end_drag() => (),
+            MouseButton::Left if self.minimap.end_drag() => (),
+            MouseButton::Left => self.end_drag(win),
+            MouseButton::Middle => {
+                // A click keeps scrolling until the next one; dragging
+                // with the button held scrolls until it's let go.
+                let moved = match self.pan {
+                    Some(ref mut pan) => {
+                        pan.held = false;
+                        pan.moved
+                    }
+                    None => false,
+                };
+                if moved {
+                    self.end_pan(win);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Stop scrolling with the middle button. Any click, key or scroll
+    /// does this. Returns whether it was scrolling, in which case the
+    /// click that stopped it does nothing else.
+    pub fn end_pan(&mut self, win: &MainWin) -> bool {
+        if self.pan.take().is_none() {
+            return false;
+        }
+        if !self.autoscrolling {
+            win.handle.borrow().kill_timer(Timers::Autoscroll as usize);
+        }
+        win.invalidate();
+        true
+    }
+
+    // Put the caret at the click point and paste there, without replacing
+    // the selection. Lines copied whole go in above the clicked line, as
+    // they would from paste.
+    fn paste_at(&mut self, line: usize, col: usize, win: &MainWin) {
+        if let Some(text) = clipboard::get_text() {
+            let mut cmds = vec![EditCommand::Gesture { line, col, ty: GestureType::PointSelect }];
+            cmds.extend(paste_cmds(text.replace("\r\n", "\n"),
+                clipboard::has_format(line_copy_format()), 1, Some((line, col)), false));
+            self.send_edit_cmds(cmds, win);
+        }
+    }
+
+    // Select the clicked line whole, and start a drag that goes on
+    // selecting whole lines. Shift extends the selection to the clicked
+    // line instead, and Ctrl adds the line as another selection.
+    fn gutter_mouse_down(&mut self, x: f32, y: f32, mods: u32, win: &MainWin) {
+        let line = min(self.y_to_line(y), self.line_cache.height().saturating_sub(1));
+        let mut drag = Drag {
+            granularity: Granularity::Line,
+            anchor: (line, 0),
+            anchor_span: self.drag_span(Granularity::Line, line, 0),
+            multi: (mods & M_CTRL) != 0,
+            anchor_x: 0.0,
+            last: (line, 0),
+            pointer: (x, y),
+        };
+        if (mods & M_SHIFT) != 0 {
+            if let Some(caret) = self.caret() {
+                drag.anchor = (caret.0, 0);
+                drag.anchor_span = self.drag_span(Granularity::Line, caret.0, 0);
+            }
+            drag.last = self.snap_drag_point(&drag, line, 0);
+            self.gesture(drag.last.0, drag.last.1, GestureType::RangeSelect, win);
+        } else {
+            let ty = if (mods & M_CTRL) != 0 {
+                GestureType::MultiLineSelect
+            } else {
+                GestureType::LineSelect
+            };
+            self.gesture(line, 0, ty, win);
+        }
+        self.drag = Some(drag);
+    }
+
+    /// Stop dragging, also when the mouse capture is lost.
+    pub fn end_drag(&mut self, win: &MainWin) {
+        self.drag = None;
+        self.scrollbar.end_drag();
+        self.minimap.end_drag();
+        self.column_rows.clear();
+        if self.autoscrolling {
+            self.autoscrolling = false;
+            // Scrolling with the middle button shares the timer.
+            if self.pan.is_none() {
+                win.handle.borrow().kill_timer(Timers::Autoscroll as usize);
+            }
+        }
+    }
+
+    /// Extend the selection while dragging with the left button held.
+    pub fn mouse_move(&mut self, x: f32, y: f32, mods: u32, win: &MainWin) {
+        let y = y - self.top;
+        let shown = self.hover.is_shown();
+        if self.hover.mouse_moved((x, y)) {
+            if shown {
+                win.handle.borrow().invalidate();
+            }
+            if self.drag.is_none() && self.plugins.iter().any(|plugin| plugin.running) {
+                win.handle.borrow().set_timer(Timers::Hover as usize, HOVER_DELAY_MS);
+            }
+            self.update_link((mods & M_CTRL) != 0, win);
+        }
+        let max_scroll = self.max_scroll();
+        let drag = self.scrollbar.drag_to(y, self.size.1, self.scroll_offset, max_scroll);
+        if let Some(offset) = drag {
+            self.scroll_to_offset(offset, win);
+            return;
+        }
+        if self.minimap.is_dragging() {
+            self.minimap_scroll(y, win);
+            return;
+        }
+        if let Some(ref mut pan) = self.pan {
+            pan.pointer = (x, y);
+            let (dx, dy) = (x - pan.origin.0, y - pan.origin.1);
+            if pan.held && (dx.abs() > PAN_DEAD_ZONE || dy.abs() > PAN_DEAD_ZONE) {
+                pan.moved = true;
+            }
+            return;
+        }
+        if let Some(ref mut drag) = self.drag {
+            drag.pointer = (x, y);
+        } else {
+            return;
+        }
+        self.extend_drag(win);
+        // Scroll while the mouse is above or below the view.
+        let outside = y < 0.0 || y >= self.size.1;
+        if outside != self.autoscrolling {
+            self.autoscrolling = outside;
+            if outside {
+                win.handle.borrow().set_timer(Timers::Autoscroll as usize, AUTOSCROLL_MS);
+            } else {
+                win.handle.borrow().kill_timer(Timers::Autoscroll as usize);
+            }
+        }
+    }
+
+    /// Scroll towards the mouse while it's dragged outside the view, called
+    /// from the autoscroll timer. The farther out it is, the faster.
+    pub fn autoscroll(&mut self, win: &MainWin) {
+        if self.pan.is_some() {
+            return self.pan_scroll(win);
+        }
+        let (_, y) = match self.drag {
+            Some(drag) => drag.pointer,
+            None => return self.end_drag(win),
+        };
+        let distance = if y < 0.0 { y } else { (y - self.size.1).max(0.0) };
+        self.scroll_offset += (distance * AUTOSCROLL_RATE).max(-AUTOSCROLL_MAX).min(AUTOSCROLL_MAX);
+        self.constrain_scroll();
+        self.update_viewport(win);
+        self.extend_drag(win);
+        win.invalidate();
+    }
+
+    // Scroll towards the mouse while scrolling with the middle button,
+    // sideways too unless lines wrap.
+    fn pan_scroll(&mut self, win: &MainWin) {
+        let pan = match self.pan {
+            Some(pan) => pan,
+            None => return,
+        };
+        let speed = |d: f32| {
+            let d = if d < 0.0 {
+                (d + PAN_DEAD_ZONE).min(0.0)
+            } else {
+                (d - PAN_DEAD_ZONE).max(0.0)
+            };
+            (d * PAN_RATE).max(-AUTOSCROLL_MAX).min(AUTOSCROLL_MAX)
+        };
+        let dy = speed(pan.pointer.1 - pan.origin.1);
+        let dx = if self.word_wrap { 0.0 } else { speed(pan.pointer.0 - pan.origin.0) };
+        if dx == 0.0 && dy == 0.0 {
+            return;
+        }
+        self.scroll_offset += dy;
+        self.h_scroll_offset = (self.h_scroll_offset + dx).max(0.0).round();
+        self.scroll_pinned = true;
+        self.constrain_scroll();
+        self.update_viewport(win);
+        win.invalidate();
+    }
+
+    /// Follow a finger on the touchscreen (x, y in px units in the
+    /// window). Dragging pans the view, sideways too unless lines wrap,
+    /// rather than selecting; a tap puts the caret there. Returns whether
+    /// the touch is the view's, so other fingers are left alone.
+    pub fn touch(&mut self, id: u32, x: f32, y: f32, ty: TouchType, win: &MainWin) -> bool {
+        let point = (x, y - self.top);
+        let now = Instant::now();
+        let mut touch = match (ty, self.touch) {
+            (TouchType::Down, None) => {
+                self.end_pan(win);
+                self.stop_glide(win);
+                self.touch = Some(Touch {
+                    id,
+                    origin: point,
+                    pointer: point,
+                    time: now,
+                    velocity: (0.0, 0.0),
+                    moved: false,
+                });
+                return true;
+            }
+            (TouchType::Down, Some(_)) => return false,
+            (_, Some(touch)) if touch.id == id => touch,
+            _ => return false,
+        };
+        if ty == TouchType::Up {
+            self.touch = None;
+            if !touch.moved {
+                let (line, col) = self.xy_to_line_col(point.0, point.1);
+                self.gesture(line, col, GestureType::PointSelect, win);
+                self.reset_blink(win);
+            } else if now.duration_since(touch.time) < GLIDE_HOLD {
+                // A finger that stopped before it was lifted doesn't glide.
+                self.glide = Some(touch.velocity);
+                win.handle.borrow().set_timer(Timers::TouchGlide as usize, GLIDE_MS);
+            }
+            return true;
+        }
+        let slop = |a: f32, b: f32| (a - b).abs() > TOUCH_SLOP;
+        if !touch.moved && !slop(point.0, touch.origin.0) && !slop(point.1, touch.origin.1) {
+            return true;
+        }
+        let (dx, dy) = (touch.pointer.0 - point.0, touch.pointer.1 - point.1);
+        let elapsed = now.duration_since(touch.time);
+        let ms = (elapsed.as_secs() as f32 * 1000.0 + elapsed.subsec_nanos() as f32 / 1e6).max(1.0);
+        let smooth = |v: f32, d: f32| v * (1.0 - GLIDE_SMOOTHING) + d / ms * GLIDE_SMOOTHING;
+        touch.velocity = (smooth(touch.velocity.0, dx), smooth(touch.velocity.1, dy));
+        touch.pointer = point;
+        touch.time = now;
+        touch.moved = true;
+        self.touch = Some(touch);
+        self.scroll_by_px(dx, dy, win);
+        true
+    }
+
+    /// Keep scrolling after a touch, slower each time, from the glide
+    /// timer.
+    pub fn glide(&mut self, win: &MainWin) {
+        let (vx, vy) = match self.glide {
+            Some(velocity) => velocity,
+            None => return self.stop_glide(win),
+        };
+        let ms = GLIDE_MS as f32;
+        self.scroll_by_px(vx * ms, vy * ms, win);
+        let velocity = (vx * GLIDE_DECAY, vy * GLIDE_DECAY);
+        if velocity.0.abs().max(velocity.1.abs()) < GLIDE_MIN_SPEED {
+            self.stop_glide(win);
+        } else {
+            self.glide = Some(velocity);
+        }
+    }
+
+    fn stop_glide(&mut self, win: &MainWin) {
+        if self.glide.take().is_some() {
+            win.handle.borrow().kill_timer(Timers::TouchGlide as usize);
+        }
+    }
+
+    fn scroll_by_px(&mut self, dx: f32, dy: f32, win: &MainWin) {
+        self.scroll_offset += dy;
+        if !self.word_wrap {
+            self.h_scroll_offset = (self.h_scroll_offset + dx).max(0.0).round();
+        }
+        self.scroll_pinned = true;
+        self.constrain_scroll();
+        self.update_viewport(win);
+        win.invalidate();
+    }
+
+    // Send the drag gesture for the point under the mouse, if it moved.
+    fn extend_drag(&mut self, win: &MainWin) {
+        let mut drag = match self.drag {
+            Some(drag) => drag,
+            None => return,
+        };
+        if drag.granularity == Granularity::Column {
+            return self.extend_column_drag(&drag, win);
+        }
+        let (x, y) = drag.pointer;
+        let (line, col) = self.xy_to_line_col(x, y);
+        let cmds = {
+            let text = self.line_cache.get_line(line).map(|line| line.text());
+            drag.move_to(text, self.line_cache.height(), line, col)
+        };
+        if !cmds.is_empty() {
+            self.drag = Some(drag);
+            self.send_edit_cmds(cmds, win);
+        }
+    }
+
+    // Select the box between the anchor and the pointer, a selection for
+    // each line it covers, at the columns each line has at the box's left
+    // and right edges. Lines that end before the left edge get a caret at
+    // their end. The whole box is sent again whenever it changes.
+    fn extend_column_drag(&mut self, drag: &Drag, win: &MainWin) {
+        let (x, y) = drag.pointer;
+        let pointer_line = min(self.y_to_line(y), self.line_cache.height().saturating_sub(1));
+        let anchor_line = drag.anchor.0;
+        let (first, last) = (min(anchor_line, pointer_line), max(anchor_line, pointer_line));
+        let anchor_x = drag.anchor_x - self.h_scroll_offset;
+        let rows: Vec<_> = (first..last + 1).map(|line| {
+            (line, self.line_x_to_col(line, anchor_x), self.line_x_to_col(line, x))
+        }).collect();
+        if rows == self.column_rows {
+            return;
+        }
+        // The first region replaces the selection, the others are added
+        // to it. Each is anchored on the side the drag started from, so
+        // extending it afterwards moves the pointer's side.
+        for (ix, &(line, anchor_col, pointer_col)) in rows.iter().enumerate() {
+            let ty = if ix == 0 { GestureType::PointSelect } else { GestureType::ToggleSel };
+            self.gesture(line, anchor_col, ty, win);
+            if pointer_col != anchor_col {
+                self.send_edit_cmd(EditCommand::Drag { line, col: pointer_col }, win);
+            }
+        }
+        self.column_rows = rows;
+    }
+
+    // Snap a drag point to the drag's granularity, on the cached text.
+    fn snap_drag_point(&self, drag: &Drag, line_num: usize, col: usize) -> (usize, usize) {
+        let text = self.line_cache.get_line(line_num).map(|line| line.text());
+        drag.snap(text, self.line_cache.height(), line_num, col)
+    }
+
+    // The word or line at a drag's anchor, as it's cached now.
+    fn drag_span(&self, granularity: Granularity, line_num: usize, col: usize)
+        -> ((usize, usize), (usize, usize))
+    {
+        let text = self.line_cache.get_line(line_num).map(|line| line.text());
+        snap_span(granularity, text, self.line_cache.height(), line_num, col)
+    }
+
+    // Count successive clicks at about the same place, cycling through
+    // single, double and triple.
+    fn click_count(&mut self, x: f32, y: f32) -> u32 {
+        let (max_ms, max_dx, max_dy) = unsafe {
+            (GetDoubleClickTime(), GetSystemMetrics(SM_CXDOUBLECLK),
+                GetSystemMetrics(SM_CYDOUBLECLK))
+        };
+        let now = Instant::now();
+        let count = match self.last_click {
+            // The double-click metrics are the size of a rectangle centered
+            // on the first click.
+            Some(ref last) if now - last.time <= Duration::from_millis(max_ms as u64)
+                && (x - last.x).abs() * 2.0 <= max_dx as f32
+                && (y - last.y).abs() * 2.0 <= max_dy as f32 => last.count % 3 + 1,
+            _ => 1,
+        };
+        self.last_click = Some(Click { time: now, x: x, y: y, count: count });
+        count
+    }
+
+    /// Whether the point (in px units) is inside a selected range.
+    pub fn is_in_selection(&mut self, x: f32, y: f32) -> bool {
+        let (line_num, col) = self.xy_to_line_col(x, y - self.top);
+        match self.line_cache.get_line(line_num) {
+            Some(line) => line.selections().any(|span|
+                span.end > span.start && col >= span.start && col <= span.end),
+            None => false,
+        }
+    }
+
+    /// Convert a point in px units to a line number and offset within the
+    /// line, clamped to the document.
+    fn xy_to_line_col(&mut self, x: f32, y: f32) -> (usize, usize) {
+        let line_num = min(self.y_to_line(y), self.line_cache.height().saturating_sub(1));
+        (line_num, self.line_x_to_col(line_num, x))
+    }
+
+    // The column of a line nearest to x (in view px units); past the end
+    // of the line, its end.
+    fn line_x_to_col(&mut self, line_num: usize, x: f32) -> usize {
+        let (text_left, h_scroll_offset) = (self.text_left(), self.h_scroll_offset);
+        let pos = match self.get_layout(line_num) {
+            Some(layout) => {
+                let hit = layout.hit_test_point(x - text_left + h_scroll_offset, 0.0);
+                let mut pos = hit.metrics.text_position as usize;
+                if hit.is_trailing_hit {
+                    pos += hit.metrics.length as usize;
+                }
+                pos
+            }
+            None => return 0,
+        };
+        self.line_cache.get_line(line_num).map_or(0, |line| utf16_to_utf8(line.text(), pos))
+    }
+
+    /// Restart the caret blink cycle in the visible phase, so the caret
+    /// doesn't disappear right as the user types or clicks.
+    pub fn reset_blink(&mut self, win: &MainWin) {
+        let was_visible = self.caret_visible;
+        self.caret_visible = true;
+        if self.has_focus {
+            let blink_time = unsafe { GetCaretBlinkTime() };
+            // INFINITE means the user has turned blinking off.
+            if blink_time != INFINITE {
+                win.handle.borrow().set_timer(Timers::CaretBlink as usize, blink_time);
+            }
+        }
+        if !was_visible {
+            self.invalidate_carets(win);
+        }
+    }
+
+    /// Toggle the blink phase, called from the blink timer.
+    pub fn blink(&mut self, win: &MainWin) {
+        self.caret_visible = !self.caret_visible;
+        self.invalidate_carets(win);
+    }
+
+    pub fn set_focus(&mut self, has_focus: bool, win: &MainWin) {
+        self.has_focus = has_focus;
+        if has_focus {
+            self.reset_blink(win);
+        } else {
+            win.handle.borrow().kill_timer(Timers::CaretBlink as usize);
+            self.caret_visible = true;
+            self.end_pan(win);
+            self.stop_glide(win);
+        }
+        // The carets change shape and the selections color, but the lines
+        // don't change, so there's no need to touch the viewport.
+        win.invalidate();
+    }
+
+    fn invalidate_carets(&self, win: &MainWin) {
+        let handle = win.handle.borrow();
+        for &(x0, y0, x1, y1) in &self.caret_rects {
+            handle.invalidate_rect(x0, y0 + self.top, x1, y1 + self.top);
+        }
+    }
+
+    pub fn mouse_wheel(&mut self, delta: i32, _mods: u32, win: &MainWin) {
+        self.end_pan(win);
+        self.stop_glide(win);
+        self.dismiss_hover(win);
+        // TODO: scale properly, taking SPI_GETWHEELSCROLLLINES into account
+        let scroll_scaling = 0.5;
+        self.scroll_offset -= (delta as f32) * scroll_scaling;
+        self.scroll_pinned = true;
+        self.constrain_scroll();
+        self.update_viewport(win);
+        win.handle.borrow().invalidate();
+    }
+
+    fn constrain_scroll(&mut self) {
+        let max_scroll = self.max_scroll();
+        if self.scroll_offset < 0.0 {
+            self.scroll_offset = 0.0;
+        } else if self.scroll_offset > max_scroll {
+            self.scroll_offset = max_scroll;
+        }
+    }
+
+    // How far the view can scroll: until the last line is at the bottom,
+    // with padding below it, and then the overscroll if that's on.
+    fn max_scroll(&self) -> f32 {
+        let content_height = TOP_PAD + LINE_SPACE * self.line_cache.height() as f32 + TOP_PAD;
+        let overscroll = match (self.scroll_past_end, self.overscroll_lines) {
+            (false, _) => 0.0,
+            (true, Some(lines)) => lines as f32 * LINE_SPACE,
+            (true, None) => (self.size.1 - LINE_SPACE).max(0.0),
+        };
+        (content_height - self.size.1 + overscroll).max(0.0)
+    }
+
+    // Takes y in screen-space px. Past the last line, as when scrolled
+    // past the end, gives the line count.
+    fn y_to_line(&self, y: f32) -> usize {
+        let mut line = (y + self.scroll_offset - TOP_PAD) / LINE_SPACE;
+        if line < 0.0 { line = 0.0; }
+        let line = line.floor() as usize;
+        min(line, self.line_cache.height())
+    }
+
+    /// Convert line number to y coordinate in content space.
+    fn line_to_content_y(&self, line: usize) -> f32 {
+        TOP_PAD + (line as f32) * LINE_SPACE
+    }
+
+    /// Tell the core which lines are visible, if that has changed since
+    /// the last time. Call after scrolling or resizing. The scroll is sent
+    /// on the viewport timer, so scrolling by many wheel ticks at once
+    /// sends the lines the view comes to rest on, not every step. The core
+    /// is asked for more lines than are visible, ahead of the scroll.
+    pub fn update_viewport(&mut self, win: &MainWin) {
+        let first_line = self.y_to_line(0.0);
+        let last_line = first_line + ((self.size.1 / LINE_SPACE).floor() as usize) + 1;
+        if self.viewport.update(first_line..last_line) {
+            win.handle.borrow().set_timer(Timers::Viewport as usize, VIEWPORT_FLUSH_MS);
+        }
+    }
+
+    /// Ask the core what's under the mouse, from the hover timer, once it
+    /// has rested over text.
+    pub fn request_hover(&mut self, win: &MainWin) {
+        let ((x, y), request_id) = match self.hover.rest() {
+            Some(rest) => rest,
+            None => return,
+        };
+        if self.pan.is_some() || y < 0.0 || y >= self.size.1 ||
+            self.chrome().region_at(x) != Region::Text
+        {
+            return;
+        }
+        let line_num = self.y_to_line(y);
+        let len = match self.line_cache.get_line(line_num) {
+            Some(line) => line.text().trim_end_matches(|c| c == '\n' || c == '\r').len(),
+            None => return,
+        };
+        let col = self.line_x_to_col(line_num, x);
+        // Past the end of the line there's nothing to ask about.
+        if col < len {
+            self.send_edit_cmd(EditCommand::RequestHover { request_id, line: line_num, col },
+                win);
+        }
+    }
+
+    /// Show a plugin's answer to request_hover.
+    pub fn show_hover(&mut self, request_id: usize, text: &str, win: &MainWin) {
+        if self.hover.show(request_id, text) {
+            win.handle.borrow().invalidate();
+        }
+    }
+
+    pub fn keyup(&mut self, vk_code: i32, win: &MainWin) {
+        if vk_code == VK_CONTROL {
+            self.update_link(false, win);
+        }
+    }
+
+    // Underline the identifier under the mouse while Ctrl is held, if a
+    // plugin is running to find its definition.
+    fn update_link(&mut self, ctrl: bool, win: &MainWin) {
+        let running = self.plugins.iter().any(|plugin| plugin.running);
+        let link = match self.hover.point() {
+            Some((x, y)) if ctrl && running && self.drag.is_none() => self.identifier_at(x, y),
+            _ => None,
+        };
+        if link != self.link {
+            self.link = link;
+            win.invalidate();
+        }
+    }
+
+    // The identifier at a point (in view px units), as a line and range.
+    fn identifier_at(&mut self, x: f32, y: f32) -> Option<(usize, Range<usize>)> {
+        if y < 0.0 || y >= self.size.1 || self.chrome().region_at(x) != Region::Text {
+            return None;
+        }
+        let line_num = self.y_to_line(y);
+        let col = self.line_x_to_col(line_num, x);
+        let text = self.line_cache.get_line(line_num)?.text();
+        let range = word_start(text, col)..word_end(text, col);
+        if range.start < range.end && text[range.clone()].chars().all(is_word_char) {
+            Some((line_num, range))
+        } else {
+            None
+        }
+    }
+
+    // Ask the running plugins where the identifier at a Ctrl+click point
+    // is defined. Returns whether it asked; the click does what it usually
+    // does if nothing is found.
+    fn request_definition(&mut self, x: f32, y: f32, point: (usize, usize), win: &MainWin)
+        -> bool
+    {
+        let (line, range) = match self.identifier_at(x, y) {
+            Some(identifier) => identifier,
+            None => return false,
+        };
+        let plugins: Vec<String> = self.plugins.iter().filter(|plugin| plugin.running)
+            .map(|plugin| plugin.name.clone())
+            .collect();
+        if plugins.is_empty() {
+            return false;
+        }
+        let id = win.definition_requests.get() + 1;
+        win.definition_requests.set(id);
+        for plugin in &plugins {
+            win.send_notification("plugin_rpc", &json!({
+                "view_id": self.view_id,
+                "receiver": plugin,
+                "rpc": {
+                    "rpc_type": "notification",
+                    "method": "find_definition",
+                    "params": {
+                        "request_id": id,
+                        "position": {"line": line, "col": range.start},
+                    },
+                },
+            }));
+        }
+        self.definition_request = Some(DefinitionRequest { id, point, waiting: plugins.len() });
+        win.handle.borrow().set_timer(Timers::Definition as usize, DEFINITION_TIMEOUT_MS);
+        self.link = None;
+        win.invalidate();
+        true
+    }
+
+    /// A plugin's answer to request_definition. Returns whether to jump to
+    /// what it found: answers to earlier clicks are dropped, and when every
+    /// plugin has found nothing, the click adds a caret after all.
+    pub fn definition_answered(&mut self, request_id: usize, found: bool, win: &MainWin)
+        -> bool
+    {
+        let waiting = match self.definition_request {
+            Some(ref mut request) => {
+                if request.id != request_id {
+                    return false;
+                }
+                request.waiting -= 1;
+                request.waiting
+            }
+            None => return false,
+        };
+        if found || waiting == 0 {
+            win.handle.borrow().kill_timer(Timers::Definition as usize);
+            let request = self.definition_request.take().unwrap();
+            if !found {
+                self.gesture(request.point.0, request.point.1, GestureType::ToggleSel, win);
+            }
+        }
+        found
+    }
+
+    /// Whether the view is waiting on the plugins for this request.
+    pub fn awaits_definition(&self, request_id: usize) -> bool {
+        self.definition_request.as_ref().map_or(false, |request| request.id == request_id)
+    }
+
+    /// No plugin answered in time, so the Ctrl+click adds a caret.
+    pub fn definition_timed_out(&mut self, win: &MainWin) {
+        if let Some(request) = self.definition_request.take() {
+            self.gesture(request.point.0, request.point.1, GestureType::ToggleSel, win);
+        }
+    }
+
+    /// Bookmark the line of the first caret, or remove its bookmark.
+    pub fn toggle_bookmark(&mut self, win: &MainWin) {
+        if let Some(line) = self.caret_line() {
+            self.bookmarks.toggle(line);
+            win.invalidate();
+        }
+    }
+
+    pub fn clear_bookmarks(&mut self, win: &MainWin) {
+        if self.bookmarks.clear() {
+            win.invalidate();
+        }
+    }
+
+    /// Move the caret to the next bookmark after its line, or the one
+    /// before, wrapping around.
+    pub fn goto_bookmark(&mut self, next: bool, win: &MainWin) {
+        let line = self.caret_line().unwrap_or(0);
+        let target = if next { self.bookmarks.next(line) } else { self.bookmarks.prev(line) };
+        if let Some(target) = target {
+            self.gesture(target, 0, GestureType::PointSelect, win);
+        }
+    }
+
+    /// The bookmarked lines, with their text if the core has sent it.
+    pub fn bookmarks(&self) -> Vec<(usize, Option<&str>)> {
+        self.bookmarks.iter()
+            .map(|&line| (line, self.line_cache.get_line(line).map(|line| line.text())))
+            .collect()
+    }
+
+    /// Whether bookmarks were added, removed or moved by an update since
+    /// the last call, so the menu listing them is out of date.
+    pub fn take_bookmarks_changed(&mut self) -> bool {
+        self.bookmarks.take_changed()
+    }
+
+    /// Where the caret jumped from, if it's jumped far since the last call,
+    /// to remember for going back to.
+    pub fn take_caret_jump(&mut self) -> Option<(usize, usize)> {
+        self.jumps.take()
+    }
+
+    /// Don't remember the caret's next jump, as it goes back or forward
+    /// to a place that's remembered already.
+    pub fn skip_caret_jump(&mut self) {
+        self.jumps.skip_next();
+    }
+
+    /// Put the caret at a line and column, which the core scrolls to.
+    pub fn jump_to(&mut self, line: usize, col: usize, win: &MainWin) {
+        if self.line_cache.height() == 0 {
+            self.pending_jump = Some((line, col));
+        } else {
+            self.gesture(line, col, GestureType::PointSelect, win);
+        }
+    }
+
+    fn dismiss_hover(&mut self, win: &MainWin) {
+        if self.hover.dismiss() {
+            win.handle.borrow().invalidate();
+        }
+    }
+
+    /// Hide the view, as when its window is minimized, or show it again.
+    /// The core isn't told of the lines visible while it's hidden; once
+    /// it's shown, it's told again, before the view is painted, in case it
+    /// dropped some meanwhile.
+    pub fn set_hidden(&mut self, hidden: bool, win: &MainWin) {
+        if hidden {
+            self.viewport.suspend();
+        } else if self.viewport.resume() && !self.view_id.is_empty() {
+            self.flush_viewport(win);
+        }
+    }
+
+    /// Send the scroll held back by update_viewport, from the viewport
+    /// timer.
+    pub fn flush_viewport(&mut self, win: &MainWin) {
+        // The minimap shows more lines than the view, around them.
+        let shown = if self.show_minimap {
+            Some(Minimap::lines(self.size.1, self.line_cache.height(), self.scroll_offset,
+                self.max_scroll()))
+        } else {
+            None
+        };
+        if let Some((first, last)) = self.viewport.flush(shown) {
+            self.send_edit_cmd(EditCommand::Scroll { first, last }, win);
+            self.schedule_word_matches(win);
+        }
+    }
+
+    // The bracket right after the only caret, or else right before it,
+    // and its partner.
+    fn find_bracket_match(&self) -> Option<((usize, usize), (usize, usize))> {
+        if self.line_cache.cursor_count() != 1 {
+            return None;
+        }
+        let (line_num, col) = self.line_cache.first_cursor()?;
+        let text = self.line_cache.get_line(line_num)?.text();
+        let after = text.get(col..).and_then(|rest| rest.chars().next()).map(|c| (col, c));
+        let before = text.get(..col).and_then(|start| start.char_indices().next_back());
+        after.iter().chain(before.iter()).filter_map(|&(offset, c)| {
+            self.find_partner(line_num, offset, c).map(|partner| ((line_num, offset), partner))
+        }).next()
+    }
+
+    // The bracket that pairs with the one at a line and offset, skipping
+    // nested pairs. Only cached lines, and only so far, are searched.
+    // Brackets in strings and comments count like any others.
+    fn find_partner(&self, line_num: usize, offset: usize, c: char) -> Option<(usize, usize)> {
+        let (partner, forward) = match c {
+            '(' => (')', true),
+            '[' => (']', true),
+            '{' => ('}', true),
+            ')' => ('(', false),
+            ']' => ('[', false),
+            '}' => ('{', false),
+            _ => return None,
+        };
+        let mut depth = 0;
+        let mut budget = BRACKET_SCAN_LIMIT;
+        let mut ix = line_num;
+        loop {
+            let text = self.line_cache.get_line(ix)?.text();
+            let mut chars: Vec<(usize, char)> = text.char_indices()
+                .filter(|&(i, _)| ix != line_num || if forward { i > offset } else { i < offset })
+                .collect();
+            if !forward {
+                chars.reverse();
+            }
+            for (i, ch) in chars {
+                if budget == 0 {
+                    return None;
+                }
+                budget -= 1;
+                if ch == c {
+                    depth += 1;
+                } else if ch == partner {
+                    if depth == 0 {
+                        return Some((ix, i));
+                    }
+                    depth -= 1;
+                }
+            }
+            if forward && ix + 1 < self.line_cache.height() {
+                ix += 1;
+            } else if !forward && ix > 0 {
+                ix -= 1;
+            } else {
+                return None;
+            }
+        }
+    }
+
+    /// Move the caret to the bracket that pairs with the one at the caret:
+    /// after a closing bracket, or before an opening one, so going again
+    /// comes back.
+    pub fn goto_matching_bracket(&mut self, win: &MainWin) {
+        if let Some((bracket, (line, col))) = self.bracket_match {
+            let col = if (line, col) > bracket { col + 1 } else { col };
+            self.gesture(line, col, GestureType::PointSelect, win);
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_cache.height()
+    }
+
+    /// The selected characters and lines; None while they're still being
+    /// counted.
+    pub fn selection_counts(&self) -> Option<Counts> {
+        self.counts.counts()
+    }
+
+    /// Count the next lines of the selection waiting since an update,
+    /// leaving the rest to the count timer if there are many.
+    pub fn count_selection(&mut self, win: &MainWin) {
+        if self.counts.step(&self.line_cache, COUNT_STEP_LINES) {
+            win.handle.borrow().set_timer(Timers::CountSelection as usize, COUNT_STEP_MS);
+        }
+    }
+
+    // Look for word matches once the caret and view have stayed put for a
+    // moment, so holding down an arrow key doesn't rescan every time.
+    fn schedule_word_matches(&self, win: &MainWin) {
+        win.handle.borrow().set_timer(Timers::WordMatches as usize, WORD_MATCH_DELAY_MS);
+    }
+
+    /// Find the other occurrences of the word at the caret, or of a
+    /// selected word, in the visible lines, for highlighting.
+    pub fn find_word_matches(&mut self, win: &MainWin) {
+        win.handle.borrow().kill_timer(Timers::WordMatches as usize);
+        let mut matches = Vec::new();
+        if let Some((caret_line, caret_start, word)) = self.caret_word() {
+            let first_line = self.y_to_line(0.0);
+            let last_line = min(self.y_to_line(self.size.1) + 1, self.line_cache.height());
+            for line_num in first_line..last_line {
+                if let Some(line) = self.line_cache.get_line(line_num) {
+                    for start in find_whole_word(line.text(), &word) {
+                        if line_num != caret_line || start != caret_start {
+                            matches.push((line_num, start..start + word.len()));
+                        }
+                    }
+                }
+            }
+        }
+        if matches != self.word_matches {
+            self.word_matches = matches;
+            win.invalidate();
+        }
+    }
+
+    // The line, start offset and text of the word at the caret, or of the
+    // selection if it's exactly one word. None with more than one caret,
+    // or when the caret isn't on a word.
+    fn caret_word(&self) -> Option<(usize, usize, String)> {
+        if self.line_cache.cursor_count() != 1 || self.line_cache.has_multi_line_selection() {
+            return None;
+        }
+        let (line_num, col) = self.line_cache.first_cursor()?;
+        let text = self.line_cache.get_line(line_num)?.text();
+        let is_word = |range: &Range<usize>| {
+            range.start < range.end && text[range.clone()].chars().all(is_word_char)
+        };
+        let selection = self.line_cache.get_line(line_num)?.selections()
+            .find(|span| span.end > span.start)
+            .map(|span| span.start..span.end);
+        let range = match selection {
+            Some(range) => {
+                if word_start(text, range.start) != range.start ||
+                    word_end(text, range.start) != range.end
+                {
+                    return None;
+                }
+                range
+            }
+            None => {
+                let range = word_start(text, col)..word_end(text, col);
+                // Right after the end of a word counts as on it.
+                if !is_word(&range) && col > 0 {
+                    word_start(text, col - 1)..word_end(text, col - 1)
+                } else {
+                    range
+                }
+            }
+        };
+        if is_word(&range) {
+            Some((line_num, range.start, text[range].to_owned()))
+        } else {
+            None
+        }
+    }
+
+    /// Scroll the caret's line to the middle of the view, without moving
+    /// the caret. Doing it again moves the line to the top, then to the
+    /// bottom, then back to the middle, like Emacs' recenter.
+    pub fn recenter(&mut self, win: &MainWin) {
+        let line = match self.line_cache.first_cursor() {
+            Some((line, _)) => line,
+            None => return,
+        };
+        let recenter = match self.recenter {
+            None | Some(Recenter::Bottom) => Recenter::Middle,
+            Some(Recenter::Middle) => Recenter::Top,
+            Some(Recenter::Top) => Recenter::Bottom,
+        };
+        let y = self.line_to_content_y(line);
+        self.scroll_offset = match recenter {
+            Recenter::Middle => y + (LINE_SPACE - self.size.1) / 2.0,
+            Recenter::Top => y - TOP_PAD,
+            Recenter::Bottom => y + LINE_SPACE + TOP_PAD - self.size.1,
+        };
+        self.scroll_pinned = true;
+        self.constrain_scroll();
+        self.update_viewport(win);
+        self.recenter = Some(recenter);
+        win.invalidate();
+    }
+
+    // A click on the scrollbar: on a mark, scroll so its line is in the
+    // middle; on the thumb, start dragging it; elsewhere on the track,
+    // jump the thumb's middle there and drag from there.
+    fn scrollbar_mouse_down(&mut self, y: f32, win: &MainWin) {
+        let n_lines = self.line_cache.height();
+        if let Some(line) = self.scrollbar.mark_at(y, n_lines, self.size.1, self.bookmarks.lines())
+        {
+            let offset = self.line_to_content_y(line) + (LINE_SPACE - self.size.1) / 2.0;
+            self.scroll_to_offset(offset, win);
+            return;
+        }
+        let max_scroll = self.max_scroll();
+        let (top, bottom) = Scrollbar::thumb(self.size.1, self.scroll_offset, max_scroll);
+        if y >= top && y < bottom {
+            self.scrollbar.start_drag(y - top);
+        } else {
+            self.scrollbar.start_drag((bottom - top) / 2.0);
+            if let Some(offset) = self.scrollbar.drag_to(y, self.size.1, self.scroll_offset,
+                max_scroll)
+            {
+                self.scroll_to_offset(offset, win);
+            }
+        }
+    }
+
+    // Scroll in proportion to how far down the minimap a click or drag is.
+    fn minimap_scroll(&mut self, y: f32, win: &MainWin) {
+        let (_, line_height) = self.minimap_layout();
+        let offset = Minimap::scroll_for(y, self.size.1, self.line_cache.height(), line_height,
+            self.max_scroll());
+        self.scroll_to_offset(offset, win);
+    }
+
+    fn scroll_to_offset(&mut self, scroll_offset: f32, win: &MainWin) {
+        self.scroll_offset = scroll_offset;
+        self.scroll_pinned = true;
+        self.constrain_scroll();
+        self.update_viewport(win);
+        win.invalidate();
+    }
+
+    // The line numbers of the visible lines, right-aligned in the gutter
+    // with the caret's in bold, and the bookmark marks in the margin beside
+    // them; a mirrored gutter still right-aligns them, as digits read left
+    // to right. A line the core sent without a number continues a wrapped
+    // line, and gets none; older cores don't send numbers, but without
+    // wrapping every line has one. In the relative modes, the numbers are
+    // counted from the caret's line.
+    fn draw_gutter(&self, rt: &mut RenderTarget, resources: &Resources, lines: Range<usize>,
+        chrome: &Chrome)
+    {
+        let ((gutter_x0, gutter_x1), (margin_x0, margin_x1)) = (chrome.gutter(), chrome.margin());
+        let gutter_width = chrome.gutter_width;
+        rt.fill_rectangle(&RectF::from((margin_x0, 0.0, margin_x1, self.size.1)), &resources.bg);
+        if chrome.mirrored {
+            // The margin between the minimap and the text.
+            let (text_x0, _) = chrome.text();
+            rt.fill_rectangle(&RectF::from((text_x0 - chrome.margin, 0.0, text_x0, self.size.1)),
+                &resources.bg);
+        }
+        if gutter_width > 0.0 {
+            rt.fill_rectangle(&RectF::from((gutter_x0, 0.0, gutter_x1, self.size.1)),
+                &resources.gutter);
+        }
+        let current_number = self.current_number_row.and_then(|row| self.row_number(row));
+        let mut y = self.line_to_content_y(lines.start) - self.scroll_offset;
+        for line_num in lines {
+            match self.row_number(line_num) {
+                Some(number) if gutter_width > 0.0 => {
+                    let current = Some(line_num) == self.current_number_row;
+                    let number = match (self.line_numbers, current_number) {
+                        (LineNumbers::Hybrid, _) if current => number,
+                        (LineNumbers::Relative, Some(from)) | (LineNumbers::Hybrid, Some(from)) => {
+                            max(number, from) - min(number, from)
+                        }
+                        _ => number,
+                    };
+                    let number = number.to_string();
+                    let x = gutter_x1 - GUTTER_PAD
+                        - number.len() as f32 * resources.char_width;
+                    let (format, brush) = if current {
+                        (&resources.number_format, &resources.fg)
+                    } else {
+                        (&resources.text_format, &resources.line_number)
+                    };
+                    let layout = resources.create_layout_in(&self.dwrite_factory, &number,
+                        format);
+                    rt.draw_text_layout(&Point2F::from((x, y)), &layout, brush,
+                        default_text_options());
+                }
+                _ => (),
+            }
+            if self.bookmarks.contains(line_num) {
+                let mark_rect = RectF::from((margin_x0 + 1.0, y + 3.0, margin_x1 - 2.0,
+                    y + LINE_SPACE - 3.0));
+                rt.fill_rectangle(&mark_rect, &resources.bookmark);
+            }
+            y += LINE_SPACE;
+        }
+    }
+
+    // The line number shown beside a row, if it starts a line.
+    fn row_number(&self, row: usize) -> Option<usize> {
+        let line = self.line_cache.get_line(row);
+        match line.and_then(|line| line.ln()) {
+            Some(ln) => Some(ln),
+            None if !self.word_wrap && line.is_some() => Some(row + 1),
+            None => None,
+        }
+    }
+
+    // The track and thumb, with the marks over them, each kind in its own
+    // column so they don't hide each other.
+    fn draw_scrollbar(&self, rt: &mut RenderTarget, resources: &Resources) {
+        let ((x0, x1), height) = (self.chrome().scrollbar(), self.size.1);
+        rt.fill_rectangle(&RectF::from((x0, 0.0, x1, height)), &resources.scrollbar_track);
+        let (top, bottom) = Scrollbar::thumb(height, self.scroll_offset, self.max_scroll());
+        rt.fill_rectangle(&RectF::from((x0 + 2.0, top, x1 - 2.0, bottom)),
+            &resources.scrollbar_thumb);
+        let n_lines = self.line_cache.height();
+        for &(kind, lines) in self.scrollbar.marks(self.bookmarks.lines()).iter() {
+            let (brush, mark_x0, mark_x1) = match kind {
+                MarkKind::Changed => (&resources.changed_mark, x0, x0 + 3.0),
+                MarkKind::Find => (&resources.find_mark, x0 + 3.0, x1),
+                MarkKind::Bookmark => (&resources.bookmark, x1 - 4.0, x1),
+                MarkKind::Caret => (&resources.fg, x0 + 3.0, x1 - 4.0),
+            };
+            // One mark per pixel row, skipping the lines that would go on
+            // the same one.
+            let mut from = 0;
+            while let Some(&line) = lines.range(from..).next() {
+                let y = Scrollbar::line_y(line, n_lines, height);
+                rt.fill_rectangle(&RectF::from((mark_x0, y, mark_x1, y + 2.0)), brush);
+                let next_row = ((y + 1.0) / height * n_lines as f32).ceil() as usize;
+                from = max(line + 1, next_row);
+            }
+        }
+    }
+
+    /// Scroll so the line is visible, with padding above and below, and
+    /// the column too, with room to its right. This is the core's
+    /// scroll_to, which follows the caret after edits and searches; it's
+    /// ignored if the user has scrolled since the last edit command.
+    pub fn scroll_to(&mut self, line: usize, col: usize, win: &MainWin) {
+        if self.scroll_pinned {
+            return;
+        }
+        let y = self.line_to_content_y(line);
+        let max_scroll = self.max_scroll();
+        self.scroll_offset = viewport::scroll_into_view(self.scroll_offset, y - TOP_PAD,
+            y + LINE_SPACE + TOP_PAD, self.size.1, max_scroll);
+        self.update_viewport(win);
+        self.scroll_to_col(line, col, win);
+    }
+
+    // Scroll horizontally as little as needed to show the column, with
+    // room to its right. A column already in view leaves it alone.
+    fn scroll_to_col(&mut self, line: usize, col: usize, win: &MainWin) {
+        if self.word_wrap {
+            return;
+        }
+        let text = match self.line_cache.get_line(line) {
+            Some(line) => line.text().to_owned(),
+            None => return,
+        };
+        let x = match self.get_layout(line) {
+            Some(layout) => offset_to_x(layout, &text, col),
+            // Not laid out yet; the next scroll_to catches up.
+            None => return,
+        };
+        let width = self.text_width();
+        let mut h_scroll_offset = self.h_scroll_offset;
+        if x < h_scroll_offset {
+            h_scroll_offset = x;
+        } else if x + H_SCROLL_SLOP > h_scroll_offset + width {
+            h_scroll_offset = x + H_SCROLL_SLOP - width;
+        }
+        let h_scroll_offset = h_scroll_offset.max(0.0).round();
+        if h_scroll_offset != self.h_scroll_offset {
+            self.h_scroll_offset = h_scroll_offset;
+            win.invalidate();
+        }
+    }
+}
+
+// Autoscroll ticks, and how far each one scrolls per px the mouse is
+// outside the view, up to a limit (in px units).
+const AUTOSCROLL_MS: u32 = 30;
+const AUTOSCROLL_RATE: f32 = 0.5;
+const AUTOSCROLL_MAX: f32 = 10.0 * LINE_SPACE;
+
+// How far the mouse can be from where the middle button was pressed
+// without scrolling, and how far each autoscroll tick scrolls per px
+// beyond that (in px units).
+const PAN_DEAD_ZONE: f32 = 8.0;
+const PAN_RATE: f32 = 0.25;
+
+// The box of the paint statistics, with room for its lines of text, and
+// its padding (in px units).
+const STATS_WIDTH: f32 = 360.0;
+const STATS_LINES: f32 = 2.0;
+const STATS_PAD: f32 = 6.0;
+
+// How long a change of the visible lines waits before it's sent to the
+// core, about a frame.
+const VIEWPORT_FLUSH_MS: u32 = 16;
+
+// How far a finger can move before a touch pans instead of being a tap
+// (in px units).
+const TOUCH_SLOP: f32 = 10.0;
+
+// Glide ticks after a touch, how much of the speed each keeps, and the
+// speed it stops at, in px units per ms. A finger held still this long
+// before it's lifted doesn't glide. Each move weighs in this much on the
+// speed.
+const GLIDE_MS: u32 = 16;
+const GLIDE_DECAY: f32 = 0.95;
+const GLIDE_MIN_SPEED: f32 = 0.02;
+const GLIDE_HOLD: Duration = Duration::from_millis(60);
+const GLIDE_SMOOTHING: f32 = 0.5;
+
+// How much room to keep right of the caret when scrolling to follow it
+// along a line, in px units.
+const H_SCROLL_SLOP: f32 = 40.0;
+
+// How far to look for a matching bracket, in characters.
+const BRACKET_SCAN_LIMIT: usize = 10_000;
+
+// How many lines of the selection to count at a time, and how long to
+// leave between steps for other messages.
+const COUNT_STEP_LINES: usize = 20_000;
+const COUNT_STEP_MS: u32 = 10;
+
+// How long the caret and view have to stay put before looking for
+// occurrences of the word at the caret.
+const WORD_MATCH_DELAY_MS: u32 = 150;
+
+// Interval for polling an unanswered edit, and how long before it's
+// worth probing the core. Then how long before we tell the user we're
+// waiting, and before we say the core isn't responding.
+pub const STALL_POLL_MS: u32 = 250;
+const STALL_PROBE_MS: u64 = 1000;
+const STALL_WARN_MS: u64 = 2000;
+const STALL_ALERT_MS: u64 = 10_000;
+
+// The private clipboard format marking text copied as whole lines, with
+// nothing selected.
+const LINE_COPY_FORMAT: &str = "XiWinLineCopy";
+
+fn line_copy_format() -> u32 {
+    clipboard::register_format(LINE_COPY_FORMAT)
+}
+
+// Capitalize the first letter of each word and lower the rest. An
+// apostrophe inside a word, as in "don't", doesn't end it.
+fn title_case(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_word = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            if in_word {
+                result.extend(c.to_lowercase());
+            } else {
+                result.extend(c.to_uppercase());
+            }
+            in_word = true;
+        } else {
+            result.push(c);
+            in_word = in_word && c == '\'';
+        }
+    }
+    result
+}
+
+// The commands to paste text at the carets, the first of them at `caret`.
+// Lines copied whole go in above the caret's line rather than at it,
+// unless there's a selection to replace.
+fn paste_cmds(text: String, whole_lines: bool, carets: usize, caret: Option<(usize, usize)>,
+    has_selection: bool) -> Vec<EditCommand>
+{
+    if whole_lines && !has_selection {
+        // The caret stays on the same text, now further down.
+        let n_lines = text.matches('\n').count();
+        let mut cmds = vec![EditCommand::MoveToLeftEndOfLine, EditCommand::Paste { chars: text }];
+        if let (Some((line, col)), 1) = (caret, carets) {
+            cmds.push(EditCommand::Gesture { line: line + n_lines, col,
+                ty: GestureType::PointSelect });
+        }
+        cmds
+    } else if carets > 1 && paste_line_count(&text) == carets {
+        // With a line for each caret, the core's paste gives each caret
+        // its own line; otherwise every caret gets all of it.
+        vec![EditCommand::Paste { chars: text }]
+    } else {
+        vec![EditCommand::Insert { chars: text }]
+    }
+}
+
+// The number of lines in text to paste. A newline at the end finishes the
+// last line rather than starting an empty one.
+fn paste_line_count(text: &str) -> usize {
+    let text = if text.ends_with('\n') { &text[..text.len() - 1] } else { text };
+    text.split('\n').count()
+}
+
+// The end of the character after an offset in a line, if it isn't at the
+// end of the line.
+fn next_char_end(text: &str, offset: usize) -> Option<usize> {
+    let text = text.trim_end_matches(|c| c == '\n' || c == '\r');
+    text.get(offset..)?.chars().next().map(|c| offset + c.len_utf8())
+}
+
+// The commands selecting, at each caret, the character that overwrite
+// mode types over, given each caret with the end of the character after
+// it. At the end of a line there's none, as lines shouldn't be joined.
+// When every caret has one, or none do, the core's own commands do; when
+// only some do, each caret is put back with or without its character.
+fn overwrite_cmds(carets: &[(usize, usize, Option<usize>)]) -> Vec<EditCommand> {
+    if carets.iter().all(|&(_, _, end)| end.is_none()) {
+        Vec::new()
+    } else if carets.iter().all(|&(_, _, end)| end.is_some()) {
+        vec![EditCommand::MoveRightAndModifySelection]
+    } else {
+        let mut cmds = Vec::new();
+        for (ix, &(line, col, end)) in carets.iter().enumerate() {
+            let ty = if ix == 0 { GestureType::PointSelect } else { GestureType::ToggleSel };
+            cmds.push(EditCommand::Gesture { line, col, ty });
+            if let Some(end) = end {
+                cmds.push(EditCommand::Drag { line, col: end });
+            }
+        }
+        cmds
+    }
+}
+
+// The commands deleting the lines with carets on them, given in order, of
+// a document this many lines high. The core has no single command for
+// this. Selecting from the start of each line down one line takes in the
+// newline too, and on the last line selects to the end of the document.
+fn delete_lines_cmds(lines: &[usize], height: usize, last_has_newline: bool)
+    -> Vec<EditCommand>
+{
+    let mut cmds = vec![EditCommand::MoveToLeftEndOfLine,
+        EditCommand::MoveDownAndModifySelection, EditCommand::DeleteForward];
+    match lines.last() {
+        Some(&last) if last > 0 && last + 1 == height && !last_has_newline => {
+            // Without a newline of its own, the last line is left empty.
+            // Take the newline before it instead, at its caret alone.
+            let line = last + 1 - lines.len();
+            cmds.push(EditCommand::Gesture { line, col: 0, ty: GestureType::PointSelect });
+            cmds.push(EditCommand::DeleteBackward);
+        }
+        _ => (),
+    }
+    cmds
+}
+
+// Where carets go when the lines they're on are deleted, given in order,
+// from a document this many lines high: lines after a deleted line move
+// up, and carets on one go to the line that follows it, keeping their
+// columns, or to the new last line if there's none.
+fn carets_after_deleting(carets: &[(usize, usize)], deleted: &[usize], height: usize)
+    -> Vec<(usize, usize)>
+{
+    let last = height.saturating_sub(deleted.len() + 1);
+    let mut moved: Vec<(usize, usize)> = carets.iter().map(|&(line, col)| {
+        let before = deleted.iter().take_while(|&&deleted| deleted < line).count();
+        (min(line - before, last), col)
+    }).collect();
+    moved.sort();
+    moved.dedup();
+    moved
+}
+
+// Selections as deleting the trailing whitespace of lines leaves them,
+// given where each line's whitespace starts: points in it move to its
+// start.
+fn selections_after_trims(selections: Vec<((usize, usize), (usize, usize))>,
+    trims: &BTreeMap<usize, usize>) -> Vec<((usize, usize), (usize, usize))>
+{
+    let trim = |point: (usize, usize)| match trims.get(&point.0) {
+        Some(&start) if point.1 > start => (point.0, start),
+        _ => point,
+    };
+    selections.into_iter().map(|(anchor, caret)| (trim(anchor), trim(caret))).collect()
+}
+
+// Lines sorted, stably, and joined with the newline the first ends in,
+// without one at the end, as they go back in place of the same lines
+// without their last newline, which the last may not have.
+fn sorted_lines(lines: &[&str], case_sensitive: bool) -> String {
+    let newline = if lines.first().map_or(false, |line| line.ends_with("\r\n")) {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut sorted: Vec<&str> = lines.iter()
+        .map(|text| text.trim_end_matches(|c| c == '\n' || c == '\r'))
+        .collect();
+    if case_sensitive {
+        sorted.sort();
+    } else {
+        sorted.sort_by_key(|text| text.to_lowercase());
+    }
+    sorted.join(newline)
+}
+
+// Two runs of whole lines, one above the other, the other way round. Only
+// the last line of the document goes without a newline, so if the lower
+// run ends the document, the newline moves from the upper one to it.
+fn lines_swapped(above: &str, below: &str) -> String {
+    if below.ends_with('\n') {
+        return format!("{}{}", below, above);
+    }
+    let newline = if above.ends_with("\r\n") { "\r\n" } else { "\n" };
+    let above = &above[..above.len() - newline.len()];
+    format!("{}{}{}", below, newline, above)
+}
+
+// Runs of whole lines, from spans of them in order, with spans that
+// touch joined.
+fn line_blocks(spans: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    let mut blocks: Vec<(usize, usize)> = Vec::new();
+    for (first, last) in spans {
+        if let Some(block) = blocks.last_mut() {
+            if first <= block.1 + 1 {
+                block.1 = max(block.1, last);
+                continue;
+            }
+        }
+        blocks.push((first, last));
+    }
+    blocks
+}
+
+// Selections as moving these runs of lines up or down one line leaves
+// them. Points on a run's lines go with them, and so does the end of a
+// selection taking in a run's last newline, at the start of the line
+// after it.
+fn selections_after_moving(selections: Vec<((usize, usize), (usize, usize))>,
+    blocks: &[(usize, usize)], up: bool) -> Vec<((usize, usize), (usize, usize))>
+{
+    let in_block = |line: usize| blocks.iter().any(|&(first, last)| first <= line && line <= last);
+    let moved = |point: (usize, usize), is_end: bool| {
+        let follows_block = is_end && point.1 == 0 && point.0 > 0 && in_block(point.0 - 1);
+        if !in_block(point.0) && !follows_block {
+            point
+        } else if up {
+            (point.0 - 1, point.1)
+        } else {
+            (point.0 + 1, point.1)
+        }
+    };
+    selections.into_iter().map(|(anchor, caret)| {
+        if anchor == caret {
+            let caret = moved(caret, false);
+            (caret, caret)
+        } else {
+            (moved(anchor, anchor > caret), moved(caret, caret > anchor))
+        }
+    }).collect()
+}
+
+// The commands making these the selections, each as its anchor and its
+// caret.
+fn selections_cmds(selections: &[((usize, usize), (usize, usize))]) -> Vec<EditCommand> {
+    let mut cmds = Vec::new();
+    for (ix, &(anchor, caret)) in selections.iter().enumerate() {
+        let ty = if ix == 0 { GestureType::PointSelect } else { GestureType::ToggleSel };
+        cmds.push(EditCommand::Gesture { line: anchor.0, col: anchor.1, ty });
+        if caret != anchor {
+            cmds.push(EditCommand::Drag { line: caret.0, col: caret.1 });
+        }
+    }
+    cmds
+}
+
+// The commands replacing each region, given in order by its start and end
+// with its new text, in one edit. The last goes first, so text changing
+// length doesn't move the regions still to go.
+fn replace_cmds(regions: Vec<((usize, usize), (usize, usize), String)>) -> Vec<EditCommand> {
+    let mut cmds = Vec::new();
+    for (start, end, chars) in regions.into_iter().rev() {
+        cmds.extend(range_cmds(start, end));
+        cmds.push(EditCommand::Insert { chars });
+    }
+    cmds
+}
+
+// The commands selecting from one point to another, or putting the caret
+// at the point if they're the same.
+fn range_cmds(start: (usize, usize), end: (usize, usize)) -> Vec<EditCommand> {
+    let mut cmds = vec![EditCommand::Gesture { line: start.0, col: start.1,
+        ty: GestureType::PointSelect }];
+    if end != start {
+        cmds.push(EditCommand::Drag { line: end.0, col: end.1 });
+    }
+    cmds
+}
+
+fn set_line_copy(win: &MainWin, text: &str) {
+    let text = text.replace('\n', "\r\n");
+    let handle = win.handle.borrow();
+    if let Err(e) = clipboard::set_text_with_markers(&handle, &text, &[line_copy_format()]) {
+        println!("error setting clipboard: {:?}", e);
+    }
+}
+
+// The gesture for a click of the left button, the count-th in a row, and
+// how a drag from it selects.
+fn click_gesture(count: u32, mods: u32) -> (GestureType, Granularity) {
+    let ctrl = (mods & M_CTRL) != 0;
+    match count {
+        2 if ctrl => (GestureType::MultiWordSelect, Granularity::Word),
+        2 => (GestureType::WordSelect, Granularity::Word),
+        3 if ctrl => (GestureType::MultiLineSelect, Granularity::Line),
+        3 => (GestureType::LineSelect, Granularity::Line),
+        _ if (mods & M_ALT) != 0 => (GestureType::PointSelect, Granularity::Column),
+        // Ctrl adds a caret, or removes one already at the click point.
+        // Shift extends the selection from its anchor.
+        _ => (if ctrl {
+            GestureType::ToggleSel
+        } else if (mods & M_SHIFT) != 0 {
+            GestureType::RangeSelect
+        } else {
+            GestureType::PointSelect
+        }, Granularity::Char),
+    }
+}
+
+// How far to look for a non-blank line when extending indent guides
+// through blank lines.
+const GUIDE_SEARCH_LINES: usize = 100;
+
+// The width of a line's leading whitespace in columns, with tabs going to
+// the next tab stop, or None if the line is blank.
+fn indent_columns(text: &str, tab_size: usize) -> Option<usize> {
+    let tab_size = tab_size.max(1);
+    let mut cols = 0;
+    for c in text.chars() {
+        match c {
+            ' ' => cols += 1,
+            '\t' => cols += tab_size - cols % tab_size,
+            '\n' | '\r' => return None,
+            _ => return Some(cols),
+        }
+    }
+    None
+}
+
+// The byte range of the spaces and tabs at the end of a line, before its
+// newline.
+fn trailing_whitespace(text: &str) -> (usize, usize) {
+    let content = text.trim_end_matches(|c| c == '\n' || c == '\r');
+    (content.trim_end_matches(|c| c == ' ' || c == '\t').len(), content.len())
+}
+
+// Draw a dim ellipsis for a line the core hasn't sent yet, as after a
+// fast scroll, so the gap doesn't look like blank lines.
+fn draw_missing_line(rt: &mut RenderTarget, origin: (f32, f32), resources: &Resources) {
+    let (x0, y) = origin;
+    let dot_y = y + LINE_SPACE / 2.0;
+    for i in 0..3 {
+        let dot_x = x0 + (i as f32 + 0.5) * resources.char_width;
+        let dot = RectF::from((dot_x - 1.0, dot_y - 1.0, dot_x + 1.0, dot_y + 1.0));
+        rt.fill_rectangle(&dot, &resources.missing_line);
+    }
+}
+
+// Mark the spaces and tabs of a line with dots and arrows, placed where
+// the layout put the characters. Trailing whitespace gets its own color,
+// and with `trailing_only` it's the only whitespace marked.
+fn draw_whitespace(rt: &mut RenderTarget, resources: &Resources, layout: &TextLayout,
+    text: &str, origin: (f32, f32), trailing_only: bool)
+{
+    let (x0, y) = origin;
+    let content = text.trim_end_matches(|c| c == '\n' || c == '\r');
+    let trailing_start = content.trim_end_matches(|c| c == ' ' || c == '\t').len();
+    let y_mid = y + LINE_SPACE / 2.0;
+    // Layout positions are in utf-16 code units.
+    let mut pos = 0;
+    for (ix, c) in content.char_indices() {
+        let trailing = ix >= trailing_start;
+        if (c == ' ' || c == '\t') && (trailing || !trailing_only) {
+            if let Some(hit) = layout.hit_test_text_position(pos as u32, false) {
+                let brush = if trailing {
+                    &resources.trailing_whitespace
+                } else {
+                    &resources.whitespace
+                };
+                let x = x0 + hit.point_x;
+                let width = hit.metrics.width;
+                if c == ' ' {
+                    let x_mid = x + width / 2.0;
+                    let dot = RectF::from((x_mid - 1.0, y_mid - 1.0, x_mid + 1.0, y_mid + 1.0));
+                    rt.fill_rectangle(&dot, brush);
+                } else {
+                    let (x1, x2) = (x + 2.0, x + width - 2.0);
+                    rt.draw_line(&Point2F::from((x1, y_mid)), &Point2F::from((x2, y_mid)),
+                        brush, 1.0, None);
+                    rt.draw_line(&Point2F::from((x2 - 3.0, y_mid - 3.0)),
+                        &Point2F::from((x2, y_mid)), brush, 1.0, None);
+                    rt.draw_line(&Point2F::from((x2 - 3.0, y_mid + 3.0)),
+                        &Point2F::from((x2, y_mid)), brush, 1.0, None);
+                }
+            }
+        }
+        pos += c.len_utf16();
+    }
+}
+
+// Draw the paint statistics in the top right corner, or the top left of a
+// mirrored view, over everything.
+fn draw_paint_stats(rt: &mut RenderTarget, resources: &Resources, factory: &directwrite::Factory,
+    text: &str, width: f32, mirrored: bool)
+{
+    let layout = resources.create_text_layout(factory, text);
+    let x0 = if mirrored { STATS_PAD } else { (width - STATS_WIDTH - STATS_PAD).max(0.0) };
+    let rect = RectF::from((x0, STATS_PAD, x0 + STATS_WIDTH,
+        STATS_PAD * 3.0 + STATS_LINES * LINE_SPACE));
+    rt.fill_rectangle(&rect, &resources.bg);
+    rt.draw_rectangle(&rect, &resources.fg, 1.0, None);
+    rt.draw_text_layout(&Point2F::from((x0 + STATS_PAD, STATS_PAD * 2.0)), &layout,
+        &resources.fg, default_text_options());
+}
+
+// Mark where the middle button was pressed: a dot, with arrows for the
+// directions the view can scroll in.
+fn draw_pan_origin(rt: &mut RenderTarget, origin: (f32, f32), sideways: bool,
+    brush: &brush::SolidColor)
+{
+    let (x, y) = (origin.0.round() + 0.5, origin.1.round() + 0.5);
+    rt.fill_rectangle(&RectF::from((x - 2.0, y - 2.0, x + 2.0, y + 2.0)), brush);
+    let mut arrows = vec![(0.0, -1.0), (0.0, 1.0)];
+    if sideways {
+        arrows.extend(&[(-1.0, 0.0), (1.0, 0.0)]);
+    }
+    for (dx, dy) in arrows {
+        // An arrowhead pointing away from the dot.
+        let tip = Point2F::from((x + dx * 12.0, y + dy * 12.0));
+        let (bx, by) = (x + dx * 8.0, y + dy * 8.0);
+        rt.draw_line(&Point2F::from((bx - dy * 4.0, by - dx * 4.0)), &tip, brush, 1.0, None);
+        rt.draw_line(&Point2F::from((bx + dy * 4.0, by + dx * 4.0)), &tip, brush, 1.0, None);
+    }
+}
+
+// Mix two 0xRRGGBB colors, a fraction t of the way from a to b.
+fn blend(a: u32, b: u32, t: f32) -> u32 {
+    let mix = |shift: u32| {
+        let (a, b) = (((a >> shift) & 0xff) as f32, ((b >> shift) & 0xff) as f32);
+        ((a + (b - a) * t).round() as u32) << shift
+    };
+    mix(16) | mix(8) | mix(0)
+}
+
+// Where to draw a vertical line near x, and how wide, for it to cover
+// whole device pixels rather than blur across two: as thin as a px unit,
+// so 2 pixels at 200%, and centered on a pixel when it's an odd number of
+// them wide, or between two when it's even. Scale is device pixels per
+// px unit.
+fn crisp_line(x: f32, scale: f32) -> (f32, f32) {
+    let pixels = scale.round().max(1.0);
+    let x = x * scale;
+    let x = if pixels % 2.0 == 1.0 { x.floor() + 0.5 } else { x.round() };
+    (x / scale, pixels / scale)
+}
+
+// Where a caret goes in a line's layout, and the width of the character
+// after it, which is zero at the end of the line.
+fn caret_pos(layout: &TextLayout, hit: CaretHit) -> (f32, f32) {
+    match hit {
+        CaretHit::Start => (0.0, 0.0),
+        CaretHit::Trailing(pos) => {
+            (layout.hit_test_text_position(pos, true).map_or(0.0, |pos| pos.point_x), 0.0)
+        }
+        CaretHit::Leading(pos) => layout.hit_test_text_position(pos, false)
+            .map_or((0.0, 0.0), |pos| (pos.point_x, pos.metrics.width)),
+    }
+}
+
+// What to hit test in a line's layout for a caret, at UTF-16 positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CaretHit {
+    /// The line is empty, and DirectWrite has nothing to hit test on it,
+    /// so the caret is at the start.
+    Start,
+    /// The leading edge of the character at the position.
+    Leading(u32),
+    /// The trailing edge of the character that starts at the position,
+    /// the last before the newline, for a caret at the end of the line:
+    /// past the newline, DirectWrite has the position on the next line.
+    Trailing(u32),
+}
+
+// What to hit test for each of a line's carets, in the order the core
+// sent them.
+fn caret_hits(line: &Line) -> Vec<CaretHit> {
+    line.cursor().iter().map(|&offset| caret_hit(line.text(), offset)).collect()
+}
+
+fn caret_hit(text: &str, offset: usize) -> CaretHit {
+    let text = text.trim_end_matches(|c| c == '\n' || c == '\r');
+    let last = match text.char_indices().next_back() {
+        Some((last, _)) => last,
+        None => return CaretHit::Start,
+    };
+    let pos = utf8_to_utf16(text, offset);
+    if pos >= utf8_to_utf16(text, text.len()) {
+        // The start of the last character, not the unit before the end,
+        // which is the second half of a surrogate pair when the line ends
+        // in an astral character.
+        CaretHit::Trailing(utf8_to_utf16(text, last) as u32)
+    } else {
+        CaretHit::Leading(pos as u32)
+    }
+}
+
+// The x position of the leading edge of the character at a byte offset
+// into the layout's text.
+fn offset_to_x(layout: &TextLayout, text: &str, offset: usize) -> f32 {
+    let offset = utf8_to_utf16(text, offset);
+    layout.hit_test_text_position(offset as u32, false).map(|pos| pos.point_x).unwrap_or(0.0)
+}
+
+// The text font, falling back to one that's on every system if the
+// first choice isn't installed.
+fn create_text_format(factory: &directwrite::Factory, weight: FontWeight)
+    -> Result<TextFormat, Error>
+{
+    let text_format = first_font(FONT_FAMILIES, |family| {
+        let params = text_format::ParamBuilder::new()
+            .size(15.0)
+            .family(family)
+            .weight(weight)
+            .build().ok_or(Error::D2Error)?;
+        factory.create(params).map_err(|e| {
+            println!("can't create font {}: {:?}", family, e);
+            Error::D2Error
+        })
+    })?;
+    unsafe {
+        use_system_fallback(factory, &text_format);
+        set_line_spacing(factory, &text_format);
+    }
+    Ok(text_format)
+}
+
+// The font of the first family that can be made into one, or the last
+// error if none can.
+fn first_font<T, F>(families: &[&str], mut create: F) -> Result<T, Error>
+    where F: FnMut(&str) -> Result<T, Error>
+{
+    let mut result = Err(Error::D2Error);
+    for &family in families {
+        result = create(family);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+// Have the system pick fonts for the characters the text font doesn't
+// have, like CJK and emoji, rather than draw them as boxes. Layouts fall
+// back on their own, but setting the system's fallback makes sure of it
+// where there's one to set, on 8.1 and above.
+unsafe fn use_system_fallback(factory: &directwrite::Factory, text_format: &TextFormat) {
+    let mut factory2: *mut IDWriteFactory2 = null_mut();
+    let hr = (*factory.get_raw()).QueryInterface(&IDWriteFactory2::uuidof(),
+        &mut factory2 as *mut *mut IDWriteFactory2 as *mut *mut c_void);
+    if !SUCCEEDED(hr) {
+        return;
+    }
+    let mut format1: *mut IDWriteTextFormat1 = null_mut();
+    let hr = (*text_format.get_raw()).QueryInterface(&IDWriteTextFormat1::uuidof(),
+        &mut format1 as *mut *mut IDWriteTextFormat1 as *mut *mut c_void);
+    if SUCCEEDED(hr) {
+        let mut fallback: *mut IDWriteFontFallback = null_mut();
+        if SUCCEEDED((*factory2).GetSystemFontFallback(&mut fallback)) {
+            (*format1).SetFontFallback(fallback);
+            (*fallback).Release();
+        }
+        (*format1).Release();
+    }
+    (*factory2).Release();
+}
+
+// Lay every line out LINE_SPACE tall, on the text font's baseline. Left
+// to itself, a layout makes room for the tallest font on the line, so a
+// line with CJK text in a fallback font gets taller, its baseline drops
+// and its text runs into the next row, out of step with the carets and
+// selections, which go by rows.
+unsafe fn set_line_spacing(factory: &directwrite::Factory, text_format: &TextFormat) {
+    let params = text_layout::ParamBuilder::new()
+        .text("x")
+        .font(text_format.clone())
+        .width(1e6)
+        .height(1e6)
+        .build().unwrap();
+    let layout: TextLayout = match factory.create(params) {
+        Ok(layout) => layout,
+        Err(_) => return,
+    };
+    let mut metrics: DWRITE_LINE_METRICS = mem::zeroed();
+    let mut count = 0;
+    if SUCCEEDED((*layout.get_raw()).GetLineMetrics(&mut metrics, 1, &mut count)) && count == 1 {
+        (*text_format.get_raw()).SetLineSpacing(DWRITE_LINE_SPACING_METHOD_UNIFORM, LINE_SPACE,
+            metrics.baseline.min(LINE_SPACE));
+    }
+}
 
 impl Resources {
     fn create_text_layout(&self, factory: &directwrite::Factory, text: &str) -> TextLayout {
+        self.create_layout_in(factory, text, &self.text_format)
+    }
+
+    fn create_layout_in(&self, factory: &directwrite::Factory, text: &str,
+        text_format: &TextFormat) -> TextLayout
+    {
         let params = text_layout::ParamBuilder::new()
             .text(text)
-            .font(self.text_format.clone())
+            .font(text_format.clone())
             .width(1e6)
             .height(1e6)
             .build().unwrap();
         factory.create(params).unwrap()
     }
 }
+
+impl LayoutCache {
+    fn new() -> LayoutCache {
+        LayoutCache {
+            layouts: HashMap::new(),
+            #[cfg(debug_assertions)]
+            built: HashSet::new(),
+            built_count: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.layouts.clear();
+        #[cfg(debug_assertions)]
+        self.built.clear();
+    }
+
+    // Drop the layouts of lines that have changed or moved.
+    fn invalidate(&mut self, lines: Range<usize>) {
+        for line_num in lines {
+            self.layouts.remove(&line_num);
+            #[cfg(debug_assertions)]
+            self.built.remove(&line_num);
+        }
+    }
+
+    // Start over if the cache has grown too big. Only done before drawing,
+    // so the lines being drawn stay cached.
+    fn limit(&mut self) {
+        if self.layouts.len() >= MAX_CACHED_LAYOUTS {
+            self.clear();
+        }
+    }
+
+    fn cached(&self, line_num: usize) -> Option<&TextLayout> {
+        self.layouts.get(&line_num)
+    }
+
+    fn get(&mut self, line_num: usize, line_cache: &LineCache, resources: &Resources,
+        factory: &directwrite::Factory) -> Option<&TextLayout>
+    {
+        if !self.layouts.contains_key(&line_num) {
+            let line = line_cache.get_line(line_num)?;
+            #[cfg(debug_assertions)]
+            {
+                let first_build = self.built.insert(line_num);
+                debug_assert!(first_build, "line {} laid out twice", line_num);
+            }
+            let layout = resources.create_text_layout(factory, line.text());
+            self.layouts.insert(line_num, layout);
+            self.built_count += 1;
+        }
+        self.layouts.get(&line_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use serde::Deserialize;
+    use keymap::Keymap;
+
+    // Stands in for the window and the core, keeping the edit notifications
+    // a view sends, as they'd go to the core. It answers nothing by itself;
+    // tests answer when they like.
+    #[derive(Default)]
+    struct FakeCore {
+        sent: RefCell<Vec<Value>>,
+        watching: Cell<bool>,
+        probes: Cell<usize>,
+    }
+
+    impl FakeCore {
+        // The notifications sent since the last call.
+        fn take(&self) -> Vec<Value> {
+            mem::replace(&mut *self.sent.borrow_mut(), Vec::new())
+        }
+    }
+
+    impl EditSink for FakeCore {
+        fn send_edit_cmd(&self, cmd: &EditCommand, view_id: &str) {
+            let params = cmd.edit_params(view_id);
+            self.sent.borrow_mut().push(json!({"method": "edit", "params": params}));
+        }
+
+        fn watch_core_stall(&self) {
+            self.watching.set(true);
+        }
+
+        fn send_stall_probe(&self, _view_id: &str) {
+            self.probes.set(self.probes.get() + 1);
+        }
+    }
+
+    fn new_view() -> EditView {
+        let mut view = EditView::new();
+        view.view_id = "view-id-1".to_owned();
+        view
+    }
+
+    // Send an edit, and give a clock for the times this many ms after it.
+    fn send_edit(view: &mut EditView, core: &FakeCore) -> impl Fn(u64) -> Instant {
+        view.send_edit_cmd(EditCommand::InsertNewline, core);
+        let sent = view.stall_watch.oldest.expect("the edit isn't watched");
+        move |ms| sent + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn caret_on_empty_lines() {
+        for text in &["", "\n", "\r\n"] {
+            assert_eq!(caret_hit(text, 0), CaretHit::Start, "{:?}", text);
+            assert_eq!(caret_hit(text, 1), CaretHit::Start, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn caret_on_a_line_of_one_char() {
+        assert_eq!(caret_hit("a", 0), CaretHit::Leading(0));
+        assert_eq!(caret_hit("a", 1), CaretHit::Trailing(0));
+        assert_eq!(caret_hit("a\n", 1), CaretHit::Trailing(0));
+        // Past the newline, and past the end, is still the end.
+        assert_eq!(caret_hit("a\r\n", 3), CaretHit::Trailing(0));
+        assert_eq!(caret_hit("a", 5), CaretHit::Trailing(0));
+    }
+
+    #[test]
+    fn caret_on_a_line_of_wide_chars() {
+        // Each takes three bytes and one UTF-16 unit.
+        let text = "\u{65e5}\u{672c}\u{8a9e}\n";
+        assert_eq!(caret_hit(text, 0), CaretHit::Leading(0));
+        assert_eq!(caret_hit(text, 3), CaretHit::Leading(1));
+        assert_eq!(caret_hit(text, 6), CaretHit::Leading(2));
+        assert_eq!(caret_hit(text, 9), CaretHit::Trailing(2));
+    }
+
+    #[test]
+    fn caret_after_an_astral_char() {
+        // The emoji takes two UTF-16 units; the end hit tests its first.
+        assert_eq!(caret_hit("a\u{1f600}", 1), CaretHit::Leading(1));
+        assert_eq!(caret_hit("a\u{1f600}", 5), CaretHit::Trailing(1));
+        assert_eq!(caret_hit("\u{1f600}\n", 4), CaretHit::Trailing(0));
+        assert_eq!(caret_hit("\u{1f600}b", 4), CaretHit::Leading(2));
+        assert_eq!(caret_hit("\u{1f600}b", 5), CaretHit::Trailing(2));
+    }
+
+    #[test]
+    fn every_caret_of_an_update_is_drawn() {
+        // Carets from ctrl+clicks: two on the first line, none on the
+        // second, one on the empty third, and one at the start and one at
+        // the end of the last, after an astral char.
+        let update = Update::deserialize(&json!({"ops": [{"op": "ins", "lines": [
+            {"text": "let x = 1;\n", "cursor": [4, 8]},
+            {"text": "no carets\n"},
+            {"text": "\n", "cursor": [0]},
+            {"text": "\u{1f600} end", "cursor": [0, 8]},
+        ]}]})).unwrap();
+        let mut cache = LineCache::new();
+        cache.apply_update(update);
+        assert_eq!(cache.cursor_count(), 5);
+        let hits: Vec<Vec<CaretHit>> = (0..cache.height())
+            .map(|ix| caret_hits(cache.get_line(ix).unwrap())).collect();
+        assert_eq!(hits, vec![
+            vec![CaretHit::Leading(4), CaretHit::Leading(8)],
+            vec![],
+            vec![CaretHit::Start],
+            vec![CaretHit::Leading(0), CaretHit::Trailing(5)],
+        ]);
+    }
+
+    #[test]
+    fn font_fallback() {
+        // A factory that can't make the first choice, as when Consolas
+        // isn't installed.
+        let mut tried = Vec::new();
+        let font = first_font(FONT_FAMILIES, |family| {
+            tried.push(family.to_owned());
+            if family == "Consolas" { Err(Error::D2Error) } else { Ok(family.to_owned()) }
+        });
+        assert_eq!(font.ok(), Some("Courier New".to_owned()));
+        assert_eq!(tried, vec!["Consolas", "Courier New"]);
+
+        // One that can't make any, as when the device is lost, gives the
+        // last error, and tries each.
+        let mut tries = 0;
+        let font = first_font(FONT_FAMILIES, |_| -> Result<(), Error> {
+            tries += 1;
+            Err(if tries == FONT_FAMILIES.len() { Error::Null } else { Error::D2Error })
+        });
+        match font {
+            Err(Error::Null) => (),
+            _ => panic!("expected the last error"),
+        }
+        assert_eq!(tries, FONT_FAMILIES.len());
+
+        // The first choice is used when it can be made.
+        assert_eq!(first_font(FONT_FAMILIES, |family| Ok::<_, Error>(family.len())).ok(),
+            Some("Consolas".len()));
+    }
+
+    // The commands a left-button press and then a drag send, on these lines,
+    // for a click that's the count-th in a row and the points the pointer
+    // moves to after.
+    fn drag_cmds(lines: &[&str], count: u32, click: (usize, usize), moves: &[(usize, usize)])
+        -> Vec<EditCommand>
+    {
+        let (ty, granularity) = click_gesture(count, 0);
+        let anchor_span = snap_span(granularity, lines.get(click.0).cloned(), lines.len(),
+            click.0, click.1);
+        let mut drag = Drag { granularity, anchor: click, anchor_span, multi: false,
+            anchor_x: 0.0, last: click, pointer: (0.0, 0.0) };
+        let mut cmds = vec![EditCommand::Gesture { line: click.0, col: click.1, ty }];
+        for &(line, col) in moves {
+            cmds.extend(drag.move_to(lines.get(line).cloned(), lines.len(), line, col));
+        }
+        cmds
+    }
+
+    // The commands that select from one point to another: a click at the
+    // first, and a drag to the second.
+    fn range(from: (usize, usize), to: (usize, usize)) -> Vec<EditCommand> {
+        vec![
+            EditCommand::Gesture { line: from.0, col: from.1, ty: GestureType::PointSelect },
+            EditCommand::Drag { line: to.0, col: to.1 },
+        ]
+    }
+
+    #[test]
+    fn word_drag_forward_then_back() {
+        let lines = ["alpha beta gamma\n", "delta epsilon"];
+        // Each point the pointer moves to, after a double-click in "beta",
+        // and the selection it sends, if it sends one. "beta" is 6..10, and
+        // stays selected whichever way the drag goes.
+        let fixture: &[((usize, usize), Option<((usize, usize), (usize, usize))>)] = &[
+            // Forward, from its start to the ends of words, and nothing
+            // within one.
+            ((0, 8), Some(((0, 6), (0, 10)))),
+            ((0, 9), None),
+            ((0, 13), Some(((0, 6), (0, 16)))),
+            ((1, 2), Some(((0, 6), (1, 5)))),
+            // Back to the anchor's word.
+            ((0, 7), Some(((0, 6), (0, 10)))),
+            // Before it, from its end to the starts of words, so the
+            // selection flips and keeps all of it.
+            ((0, 3), Some(((0, 10), (0, 0)))),
+            ((0, 1), None),
+            // And forward again.
+            ((0, 12), Some(((0, 6), (0, 16)))),
+        ];
+        let moves: Vec<_> = fixture.iter().map(|&(point, _)| point).collect();
+        let mut expected = vec![
+            EditCommand::Gesture { line: 0, col: 7, ty: GestureType::WordSelect },
+        ];
+        for &(_, sent) in fixture {
+            if let Some((from, to)) = sent {
+                expected.extend(range(from, to));
+            }
+        }
+        assert_eq!(drag_cmds(&lines, 2, (0, 7), &moves), expected);
+    }
+
+    #[test]
+    fn line_and_char_drags() {
+        let lines = ["one\n", "two\n", "three"];
+        // Whole lines, from the anchor line's start to the next line's
+        // start forward, and from its end to the line's start back; the
+        // last line has no next one.
+        let mut expected = vec![
+            EditCommand::Gesture { line: 1, col: 1, ty: GestureType::LineSelect },
+        ];
+        expected.extend(range((1, 0), (2, 0)));
+        expected.extend(range((1, 0), (2, 5)));
+        expected.extend(range((2, 0), (0, 0)));
+        assert_eq!(drag_cmds(&lines, 3, (1, 1), &[(1, 2), (2, 1), (0, 2)]), expected);
+        // By character, every new point.
+        assert_eq!(drag_cmds(&lines, 1, (0, 1), &[(0, 1), (0, 2), (2, 4)]), vec![
+            EditCommand::Gesture { line: 0, col: 1, ty: GestureType::PointSelect },
+            EditCommand::Drag { line: 0, col: 2 },
+            EditCommand::Drag { line: 2, col: 4 },
+        ]);
+        // Lines that aren't cached aren't snapped.
+        let mut expected = vec![
+            EditCommand::Gesture { line: 0, col: 1, ty: GestureType::WordSelect },
+        ];
+        expected.extend(range((0, 0), (4, 2)));
+        assert_eq!(drag_cmds(&lines[..1], 2, (0, 1), &[(4, 2)]), expected);
+
+        // A Ctrl drag keeps the other selections, so it only drags.
+        let mut drag = Drag { granularity: Granularity::Word, anchor: (0, 1),
+            anchor_span: ((0, 0), (0, 3)), multi: true, anchor_x: 0.0, last: (0, 1),
+            pointer: (0.0, 0.0) };
+        assert_eq!(drag.move_to(Some(lines[1]), lines.len(), 1, 1),
+            vec![EditCommand::Drag { line: 1, col: 3 }]);
+    }
+
+    #[test]
+    fn click_gestures() {
+        assert!(click_gesture(1, M_SHIFT) == (GestureType::RangeSelect, Granularity::Char));
+        assert!(click_gesture(1, M_CTRL) == (GestureType::ToggleSel, Granularity::Char));
+        assert!(click_gesture(1, M_ALT) == (GestureType::PointSelect, Granularity::Column));
+        assert!(click_gesture(2, M_CTRL) == (GestureType::MultiWordSelect, Granularity::Word));
+        assert!(click_gesture(3, 0) == (GestureType::LineSelect, Granularity::Line));
+    }
+
+    #[test]
+    fn pasting() {
+        let paste = |text: &str| EditCommand::Paste { chars: text.to_owned() };
+        let insert = |text: &str| EditCommand::Insert { chars: text.to_owned() };
+        assert_eq!(paste_cmds("ab".to_owned(), false, 1, Some((2, 1)), false), vec![insert("ab")]);
+        // A line for each of the carets.
+        assert_eq!(paste_cmds("a\nb\n".to_owned(), false, 2, Some((0, 0)), false),
+            vec![paste("a\nb\n")]);
+        assert_eq!(paste_cmds("a\nb".to_owned(), false, 3, Some((0, 0)), true),
+            vec![insert("a\nb")]);
+        // Whole lines go above, and the caret follows its text down.
+        assert_eq!(paste_cmds("one\ntwo\n".to_owned(), true, 1, Some((4, 3)), false), vec![
+            EditCommand::MoveToLeftEndOfLine, paste("one\ntwo\n"),
+            EditCommand::Gesture { line: 6, col: 3, ty: GestureType::PointSelect },
+        ]);
+        assert_eq!(paste_cmds("one\n".to_owned(), true, 2, Some((4, 3)), false),
+            vec![EditCommand::MoveToLeftEndOfLine, paste("one\n")]);
+        // Unless they replace a selection.
+        assert_eq!(paste_cmds("one\n".to_owned(), true, 1, Some((4, 3)), true),
+            vec![insert("one\n")]);
+    }
+
+    #[test]
+    fn overwriting_at_several_carets() {
+        assert_eq!(next_char_end("a\u{e9}b\r\n", 1), Some(3));
+        assert_eq!(next_char_end("ab\n", 2), None);
+        assert_eq!(next_char_end("ab", 2), None);
+
+        // All before the end of their lines, or all at it.
+        assert_eq!(overwrite_cmds(&[(0, 1, Some(2)), (3, 0, Some(1))]),
+            vec![EditCommand::MoveRightAndModifySelection]);
+        assert_eq!(overwrite_cmds(&[(0, 4, None), (3, 2, None)]), Vec::new());
+        assert_eq!(overwrite_cmds(&[]), Vec::new());
+
+        // Some at the end: only the others take a character.
+        assert_eq!(overwrite_cmds(&[(0, 4, None), (3, 0, Some(1)), (5, 2, None)]), vec![
+            EditCommand::Gesture { line: 0, col: 4, ty: GestureType::PointSelect },
+            EditCommand::Gesture { line: 3, col: 0, ty: GestureType::ToggleSel },
+            EditCommand::Drag { line: 3, col: 1 },
+            EditCommand::Gesture { line: 5, col: 2, ty: GestureType::ToggleSel },
+        ]);
+    }
+
+    #[test]
+    fn deleting_lines_with_several_carets() {
+        // One caret on each of two lines, and a line untouched between.
+        assert_eq!(carets_after_deleting(&[(1, 3), (3, 0), (5, 2)], &[1, 3], 7),
+            vec![(1, 3), (2, 0), (3, 2)]);
+        // Carets on lines next to each other land on the one line after,
+        // and two at the same place become one.
+        assert_eq!(carets_after_deleting(&[(2, 1), (3, 1), (3, 4)], &[2, 3], 5),
+            vec![(2, 1), (2, 4)]);
+        assert_eq!(carets_after_deleting(&[(0, 0)], &[0], 1), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn deleting_the_last_line() {
+        let down = vec![EditCommand::MoveToLeftEndOfLine,
+            EditCommand::MoveDownAndModifySelection, EditCommand::DeleteForward];
+        let join = |line| vec![
+            EditCommand::Gesture { line, col: 0, ty: GestureType::PointSelect },
+            EditCommand::DeleteBackward,
+        ];
+        // Ending in a newline, it goes like any other line.
+        assert_eq!(delete_lines_cmds(&[2], 3, true), down);
+        // Without one, the newline before it goes too, so no empty line is
+        // left at the end.
+        assert_eq!(delete_lines_cmds(&[2], 3, false), [down.clone(), join(2)].concat());
+        // With a line deleted above it, the empty line is one higher.
+        assert_eq!(delete_lines_cmds(&[0, 2], 3, false), [down.clone(), join(1)].concat());
+        // The only line has no newline before it to take.
+        assert_eq!(delete_lines_cmds(&[0], 1, false), down);
+        // Its caret goes to the line before, now the last.
+        assert_eq!(carets_after_deleting(&[(2, 1)], &[2], 3), vec![(1, 1)]);
+        assert_eq!(carets_after_deleting(&[(0, 1), (2, 1)], &[0, 2], 3), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn selections_after_trimming() {
+        // The whitespace on line 1 starts at 4, and on line 3 at 0.
+        let trims: BTreeMap<usize, usize> = [(1, 4), (3, 0)].iter().cloned().collect();
+        let selections = vec![
+            // Ends in the whitespace move to where it started.
+            ((0, 2), (1, 6)),
+            ((3, 2), (3, 2)),
+            // Before it, or on lines without any, they stay.
+            ((1, 4), (1, 1)),
+            ((2, 5), (4, 0)),
+        ];
+        assert_eq!(selections_after_trims(selections, &trims), vec![
+            ((0, 2), (1, 4)),
+            ((3, 0), (3, 0)),
+            ((1, 4), (1, 1)),
+            ((2, 5), (4, 0)),
+        ]);
+    }
+
+    #[test]
+    fn selecting_a_range() {
+        let click = |line, col| EditCommand::Gesture { line, col, ty: GestureType::PointSelect };
+        assert_eq!(range_cmds((1, 2), (1, 2)), vec![click(1, 2)]);
+        assert_eq!(range_cmds((1, 2), (3, 0)),
+            vec![click(1, 2), EditCommand::Drag { line: 3, col: 0 }]);
+    }
+
+    #[test]
+    fn each_delete_key_sends_one_command() {
+        let keymap = Keymap::defaults();
+        let core = FakeCore::default();
+        let mut view = new_view();
+        let table = [
+            (VK_BACK, 0, "delete_backward"),
+            (VK_DELETE, 0, "delete_forward"),
+            (VK_BACK, M_CTRL, "delete_word_backward"),
+            (VK_DELETE, M_CTRL, "delete_word_forward"),
+        ];
+        for &(vk, mods, method) in &table {
+            // Sent as keydown sends what the key is bound to.
+            match keymap.lookup(vk, mods).cloned() {
+                Some(Binding::Edit(cmd)) => view.send_edit_cmd(cmd, &core),
+                _ => panic!("{:#x} with {:#x} isn't bound to an edit", vk, mods),
+            }
+            assert_eq!(core.take(), vec![json!({"method": "edit", "params": {
+                "method": method,
+                "params": [],
+                "view_id": "view-id-1",
+            }})], "{:#x} with {:#x}", vk, mods);
+        }
+        // And they're watched, in case the core doesn't answer.
+        assert!(core.watching.get());
+    }
+
+    #[test]
+    fn a_short_wait_for_the_core_isnt_shown() {
+        let core = FakeCore::default();
+        let mut view = new_view();
+        let at = send_edit(&mut view, &core);
+        assert!(core.watching.get());
+        assert_eq!(view.check_core_stall(at(250), &core), CoreStall::Pending);
+        assert_eq!(core.probes.get(), 0);
+        // After a second the core is probed, once.
+        assert_eq!(view.check_core_stall(at(1000), &core), CoreStall::Pending);
+        assert_eq!(view.check_core_stall(at(1250), &core), CoreStall::Pending);
+        assert_eq!(core.probes.get(), 1);
+        // An edit that changes nothing gets no update, but the probe is
+        // answered after it.
+        view.stall_probe_answered();
+        assert_eq!(view.check_core_stall(at(1500), &core), CoreStall::Answered);
+        assert_eq!(core.probes.get(), 1);
+    }
+
+    #[test]
+    fn a_long_wait_for_the_core_escalates() {
+        let core = FakeCore::default();
+        let mut view = new_view();
+        let at = send_edit(&mut view, &core);
+        assert_eq!(view.check_core_stall(at(1000), &core), CoreStall::Pending);
+        assert_eq!(view.check_core_stall(at(2000), &core), CoreStall::Waiting);
+        assert_eq!(view.check_core_stall(at(9750), &core), CoreStall::Waiting);
+        // At ten seconds there's a toast, but only the first time.
+        assert_eq!(view.check_core_stall(at(10_000), &core),
+            CoreStall::NotResponding { toast: true });
+        assert_eq!(view.check_core_stall(at(10_250), &core),
+            CoreStall::NotResponding { toast: false });
+        assert_eq!(view.check_core_stall(at(60_000), &core),
+            CoreStall::NotResponding { toast: false });
+        // The probe is still out, so there's no other.
+        assert_eq!(core.probes.get(), 1);
+    }
+
+    #[test]
+    fn the_wait_ends_when_the_core_answers() {
+        let core = FakeCore::default();
+        let mut view = new_view();
+        let at = send_edit(&mut view, &core);
+        view.check_core_stall(at(1000), &core);
+        // Another edit, sent while the probe is out.
+        view.stall_watch.edit_sent(at(9000));
+        assert_eq!(view.check_core_stall(at(10_000), &core),
+            CoreStall::NotResponding { toast: true });
+
+        // The core catches up with the first edit and answers the probe,
+        // so the wait goes on from the second, with a probe of its own.
+        view.stall_probe_answered();
+        assert_eq!(view.check_core_stall(at(10_250), &core), CoreStall::Pending);
+        assert_eq!(core.probes.get(), 2);
+        assert_eq!(view.check_core_stall(at(11_000), &core), CoreStall::Waiting);
+        view.stall_probe_answered();
+        assert_eq!(view.check_core_stall(at(11_250), &core), CoreStall::Answered);
+
+        // A stall after that is a new one, with a toast of its own.
+        let at = send_edit(&mut view, &core);
+        view.check_core_stall(at(1000), &core);
+        assert_eq!(view.check_core_stall(at(10_000), &core),
+            CoreStall::NotResponding { toast: true });
+    }
+
+    #[test]
+    fn swapping_lines() {
+        assert_eq!(lines_swapped("a\n", "b\n"), "b\na\n");
+        assert_eq!(lines_swapped("a\nb\n", "c\n"), "c\na\nb\n");
+        // The last line of the document, without a newline, takes the
+        // other's.
+        assert_eq!(lines_swapped("a\n", "b"), "b\na");
+        assert_eq!(lines_swapped("a\nb\n", "c"), "c\na\nb");
+        assert_eq!(lines_swapped("a\r\n", "b"), "b\r\na");
+    }
+
+    #[test]
+    fn sorting_lines() {
+        assert_eq!(sorted_lines(&["b\n", "c\n", "a\n"], true), "a\nb\nc");
+        // The last line of the document has no newline of its own; the
+        // others keep theirs.
+        assert_eq!(sorted_lines(&["b\n", "a"], true), "a\nb");
+        assert_eq!(sorted_lines(&["b\r\n", "c\r\n", "a"], true), "a\r\nb\r\nc");
+        // Capitals sort first with case, and with the rest without,
+        // keeping equal lines in order.
+        let lines = ["b\n", "B\n", "a\n", "C\n"];
+        assert_eq!(sorted_lines(&lines, true), "B\nC\na\nb");
+        assert_eq!(sorted_lines(&lines, false), "a\nb\nB\nC");
+    }
+
+    #[test]
+    fn title_casing_several_selections() {
+        assert_eq!(title_case("don't STOP\nnow"), "Don't Stop\nNow");
+        // Two selections across lines, each replaced on its own, from the
+        // last.
+        let regions = vec![
+            ((0, 4), (2, 1), "One\nTwo\nT".to_owned()),
+            ((3, 0), (5, 0), "Four\nFive\n".to_owned()),
+        ];
+        assert_eq!(replace_cmds(regions), vec![
+            EditCommand::Gesture { line: 3, col: 0, ty: GestureType::PointSelect },
+            EditCommand::Drag { line: 5, col: 0 },
+            EditCommand::Insert { chars: "Four\nFive\n".to_owned() },
+            EditCommand::Gesture { line: 0, col: 4, ty: GestureType::PointSelect },
+            EditCommand::Drag { line: 2, col: 1 },
+            EditCommand::Insert { chars: "One\nTwo\nT".to_owned() },
+        ]);
+    }
+
+    #[test]
+    fn moving_lines_with_several_selections() {
+        // Runs that touch move as one; those apart move on their own.
+        assert_eq!(line_blocks(vec![(1, 1), (2, 3), (6, 6), (8, 9)]),
+            vec![(1, 3), (6, 6), (8, 9)]);
+        let blocks = [(1, 3), (6, 6)];
+        let selections = vec![
+            // A caret, and a selection going back, on the first run.
+            ((1, 2), (1, 2)),
+            ((3, 4), (2, 0)),
+            // A selection of line 6 with its newline ends at the start of
+            // line 7, which goes with it.
+            ((6, 0), (7, 0)),
+            // A caret on a line that doesn't move.
+            ((9, 1), (9, 1)),
+        ];
+        assert_eq!(selections_after_moving(selections.clone(), &blocks, false), vec![
+            ((2, 2), (2, 2)),
+            ((4, 4), (3, 0)),
+            ((7, 0), (8, 0)),
+            ((9, 1), (9, 1)),
+        ]);
+        assert_eq!(selections_after_moving(selections, &blocks, true), vec![
+            ((0, 2), (0, 2)),
+            ((2, 4), (1, 0)),
+            ((5, 0), (6, 0)),
+            ((9, 1), (9, 1)),
+        ]);
+        assert_eq!(selections_cmds(&[((2, 2), (2, 2)), ((4, 4), (3, 0))]), vec![
+            EditCommand::Gesture { line: 2, col: 2, ty: GestureType::PointSelect },
+            EditCommand::Gesture { line: 4, col: 4, ty: GestureType::ToggleSel },
+            EditCommand::Drag { line: 3, col: 0 },
+        ]);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Telling which encoding a file is in. The core only reads UTF-8, so a
+//! file that starts as anything else is decoded here and the core opens a
+//! UTF-8 copy of it instead, which is encoded back when it's saved. Only
+//! the start of a file is read to tell; it's read whole only when it has
+//! to be decoded. A file that turns out not to be UTF-8 further on is
+//! refused by the core, and then read whole with `read_lossy`.
+
+use std::char;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// How much of a file is read to tell its encoding.
+const SNIFF_LEN: u64 = 64 * 1024;
+
+// The characters of Windows-1252 for the bytes 0x80 to 0x9f, where it
+// differs from Latin-1. The five bytes it leaves undefined keep their
+// Latin-1 control characters.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+// Numbers the copies this process makes, so no two share a file.
+static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Not valid UTF-8 but text, taken as Windows' Western code page, a
+    /// superset of Latin-1. Every byte decodes to a character.
+    Latin1,
+    /// Not valid UTF-8, and with control characters text doesn't have, so
+    /// likely binary, or UTF-8 with invalid bytes past its start. The
+    /// invalid bytes are replaced when it's decoded.
+    Invalid,
+}
+
+impl Encoding {
+    pub fn label(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 with BOM",
+            Encoding::Utf16Le => "UTF-16 LE",
+            Encoding::Utf16Be => "UTF-16 BE",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Invalid => "Not UTF-8",
+        }
+    }
+
+    /// Whether the core can open the file itself.
+    pub fn is_utf8(self) -> bool {
+        self == Encoding::Utf8 || self == Encoding::Utf8Bom
+    }
+
+    /// Whether text decoded from it can be written back the same way.
+    /// Invalid bytes were replaced, so their file can't.
+    pub fn can_encode(self) -> bool {
+        self != Encoding::Invalid
+    }
+}
+
+/// What was read of a file to open it.
+pub struct Contents {
+    pub encoding: Encoding,
+    /// The whole file decoded, when the core can't read it as is.
+    pub decoded: Option<String>,
+    /// The start of the file, when it isn't decoded.
+    pub prefix: Vec<u8>,
+}
+
+impl Contents {
+    /// Text of the file to tell things like its line endings by: all of
+    /// it when it was decoded, else its start.
+    pub fn sample(&self) -> &[u8] {
+        self.decoded.as_ref().map_or(&self.prefix[..], |text| text.as_bytes())
+    }
+}
+
+/// Read a file for opening. A file that starts as UTF-8 is taken to be
+/// UTF-8 without reading the rest, as the core reads it anyway; one that
+/// isn't all UTF-8 after all is refused by the core.
+pub fn read(path: &str) -> io::Result<Contents> {
+    let mut prefix = Vec::new();
+    let mut file = File::open(path)?;
+    file.by_ref().take(SNIFF_LEN).read_to_end(&mut prefix)?;
+    let whole = (prefix.len() as u64) < SNIFF_LEN;
+    if let Some(encoding) = sniff(&prefix, whole) {
+        return Ok(Contents { encoding, decoded: None, prefix });
+    }
+    let mut bytes = prefix;
+    file.read_to_end(&mut bytes)?;
+    let (encoding, decoded) = decode(&bytes);
+    Ok(Contents { encoding, decoded, prefix: Vec::new() })
+}
+
+/// Read a whole file that started as UTF-8, but that the core refused, as
+/// it isn't further on. The rest is taken as UTF-8 too, with the invalid
+/// bytes replaced.
+pub fn read_lossy(path: &str) -> io::Result<Contents> {
+    let bytes = fs::read(path)?;
+    let decoded = String::from_utf8_lossy(&bytes).into_owned();
+    Ok(Contents { encoding: Encoding::Invalid, decoded: Some(decoded), prefix: Vec::new() })
+}
+
+// The encoding of a file the core can read as is, from its start, `whole`
+// if that's all of it. A character cut off at the end of a prefix is
+// left for the rest of the file.
+fn sniff(prefix: &[u8], whole: bool) -> Option<Encoding> {
+    if prefix.starts_with(&[0xff, 0xfe]) || prefix.starts_with(&[0xfe, 0xff]) {
+        return None;
+    }
+    match str::from_utf8(prefix) {
+        Err(e) if whole || e.error_len().is_some() => None,
+        _ if prefix.starts_with(&[0xef, 0xbb, 0xbf]) => Some(Encoding::Utf8Bom),
+        _ => Some(Encoding::Utf8),
+    }
+}
+
+/// Tell a file's encoding from its bytes. For a file the core can't read
+/// as is, also returns it decoded.
+pub fn decode(bytes: &[u8]) -> (Encoding, Option<String>) {
+    if bytes.starts_with(&[0xff, 0xfe]) {
+        return (Encoding::Utf16Le, Some(decode_utf16(&bytes[2..], false)));
+    }
+    if bytes.starts_with(&[0xfe, 0xff]) {
+        return (Encoding::Utf16Be, Some(decode_utf16(&bytes[2..], true)));
+    }
+    match str::from_utf8(bytes) {
+        Ok(_) if bytes.starts_with(&[0xef, 0xbb, 0xbf]) => (Encoding::Utf8Bom, None),
+        Ok(_) => (Encoding::Utf8, None),
+        Err(_) if is_binary(bytes) => {
+            (Encoding::Invalid, Some(String::from_utf8_lossy(bytes).into_owned()))
+        }
+        Err(_) => (Encoding::Latin1, Some(decode_latin1(bytes))),
+    }
+}
+
+/// Encode text back into the encoding its file was decoded from, with the
+/// byte order mark a UTF-16 file starts with. None if it has characters
+/// the encoding doesn't, which Latin-1's Windows code page can't have
+/// many of.
+pub fn encode(text: &str, encoding: Encoding) -> Option<Vec<u8>> {
+    match encoding {
+        Encoding::Utf8 | Encoding::Utf8Bom => Some(text.as_bytes().to_vec()),
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            let big_endian = encoding == Encoding::Utf16Be;
+            let mut bytes = if big_endian { vec![0xfe, 0xff] } else { vec![0xff, 0xfe] };
+            for unit in text.encode_utf16() {
+                let (hi, lo) = ((unit >> 8) as u8, unit as u8);
+                bytes.extend_from_slice(&if big_endian { [hi, lo] } else { [lo, hi] });
+            }
+            Some(bytes)
+        }
+        Encoding::Latin1 => text.chars().map(encode_latin1).collect(),
+        Encoding::Invalid => None,
+    }
+}
+
+fn encode_latin1(c: char) -> Option<u8> {
+    if let Some(ix) = CP1252_HIGH.iter().position(|&high| high == c) {
+        return Some(0x80 + ix as u8);
+    }
+    match c as u32 {
+        0..=0x7f | 0xa0..=0xff => Some(c as u8),
+        _ => None,
+    }
+}
+
+// Whether bytes have control characters that text doesn't: any but tab,
+// the line breaks, form feed and escape.
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().any(|&b| (b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b)) || b == 0x7f)
+}
+
+fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| match b {
+        0x80..=0x9f => CP1252_HIGH[(b - 0x80) as usize],
+        _ => b as char,
+    }).collect()
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> String {
+    let units = bytes.chunks(2).map(|pair| match *pair {
+        [a, b] if big_endian => (a as u16) << 8 | b as u16,
+        [a, b] => (b as u16) << 8 | a as u16,
+        // A byte left over at the end.
+        _ => char::REPLACEMENT_CHARACTER as u16,
+    });
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+/// A UTF-8 copy of a file in the temp directory, for the core to open.
+/// It's deleted when dropped, so it's kept as long as its view is open.
+pub struct TempCopy {
+    path: PathBuf,
+}
+
+impl TempCopy {
+    pub fn path(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Write decoded text to a file in the temp directory for the core to
+/// open, named after the file it's a copy of. Every copy gets a file of
+/// its own, so a reload's copy isn't the one the old view is deleting.
+pub fn write_copy(path: &str, text: &str) -> io::Result<TempCopy> {
+    let name = Path::new(path).file_name()
+        .map_or_else(|| "untitled".to_owned(), |name| name.to_string_lossy().into_owned());
+    let n = COPIES.fetch_add(1, Ordering::Relaxed);
+    let path = env::temp_dir().join(format!("xi-win-{}-{}-{}", process::id(), n, name));
+    fs::write(&path, text)?;
+    Ok(TempCopy { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing_utf8() {
+        assert!(sniff(b"plain", true) == Some(Encoding::Utf8));
+        assert!(sniff(b"\xef\xbb\xbfbom", true) == Some(Encoding::Utf8Bom));
+        assert!(sniff(b"\xff\xfea\0", true) == None);
+        assert!(sniff(b"caf\xe9", true) == None);
+        // An \u{e9} cut in half at the end of a prefix could go on in
+        // the rest of the file, but not at the end of the whole file.
+        assert!(sniff(b"caf\xc3", false) == Some(Encoding::Utf8));
+        assert!(sniff(b"caf\xc3", true) == None);
+        assert!(sniff(b"caf\xc3x", false) == None);
+    }
+
+    #[test]
+    fn decoding() {
+        assert!(decode(b"caf\xc3\xa9").0 == Encoding::Utf8);
+        let (encoding, text) = decode(b"\xff\xfea\0\xe9\0");
+        assert!(encoding == Encoding::Utf16Le);
+        assert_eq!(text.unwrap(), "a\u{e9}");
+        let (encoding, text) = decode(b"\xfe\xff\0a\0");
+        assert!(encoding == Encoding::Utf16Be);
+        assert_eq!(text.unwrap(), "a\u{fffd}");
+    }
+
+    #[test]
+    fn decoding_latin1() {
+        let (encoding, text) = decode(b"caf\xe9\r\n\x93quoted\x94\t\x80\x81");
+        assert!(encoding == Encoding::Latin1);
+        assert_eq!(text.unwrap(), "caf\u{e9}\r\n\u{201c}quoted\u{201d}\t\u{20ac}\u{81}");
+        // Every byte decodes to a character of its own.
+        let all: Vec<u8> = (0x80..0x100).map(|b| b as u8).collect();
+        let text = decode_latin1(&all);
+        assert_eq!(text.chars().count(), 128);
+        assert!(!text.contains('\u{fffd}'));
+    }
+
+    #[test]
+    fn binary_isnt_latin1() {
+        let (encoding, text) = decode(b"\x7fELF\x02\x01\x01\0\xff");
+        assert!(encoding == Encoding::Invalid);
+        assert!(text.unwrap().contains('\u{fffd}'));
+    }
+
+    #[test]
+    fn encoding_back() {
+        // Each decodes back to the bytes it came from.
+        let files: &[&[u8]] = &[
+            b"\xff\xfea\0\xe9\0=\xd8\0\xde",
+            b"\xfe\xff\0a\0\xe9\xd8=\xde\0",
+            b"caf\xe9\r\n\x93quoted\x94\t\x80\x81",
+        ];
+        for &bytes in files {
+            let (encoding, text) = decode(bytes);
+            assert_eq!(encode(&text.unwrap(), encoding).as_ref().map(|b| &b[..]), Some(bytes));
+        }
+        // Every byte of Latin-1 does.
+        let all: Vec<u8> = (0..0x100).map(|b| b as u8).collect();
+        assert_eq!(encode(&decode_latin1(&all), Encoding::Latin1), Some(all));
+        // Characters it doesn't have can't be.
+        assert_eq!(encode("caf\u{e9} \u{65e5}", Encoding::Latin1), None);
+        assert_eq!(encode("\u{fffd}", Encoding::Invalid), None);
+    }
+
+    #[test]
+    fn reading_utf8_thats_invalid_later() {
+        let mut bytes = "\u{e9}t\u{e9}\n".repeat(20_000).into_bytes();
+        bytes.extend_from_slice(b"caf\xe9\n");
+        let copy = write_copy("late.txt", "").unwrap();
+        fs::write(copy.path(), &bytes).unwrap();
+        // Its start is UTF-8, so it's left to the core.
+        assert!(read(&copy.path()).unwrap().encoding == Encoding::Utf8);
+        let contents = read_lossy(&copy.path()).unwrap();
+        assert!(contents.encoding == Encoding::Invalid);
+        let text = contents.decoded.unwrap();
+        assert!(text.starts_with("\u{e9}t\u{e9}\n"));
+        assert!(text.ends_with("caf\u{fffd}\n"));
+    }
+
+    #[test]
+    fn copies_are_separate_and_deleted() {
+        let first = write_copy("C:\\docs\\notes.txt", "one").unwrap();
+        let second = write_copy("C:\\docs\\notes.txt", "two").unwrap();
+        assert!(first.path() != second.path());
+        assert!(first.path().ends_with("notes.txt"));
+        let path = first.path();
+        drop(first);
+        assert!(!Path::new(&path).exists());
+        assert_eq!(fs::read_to_string(second.path()).unwrap(), "two");
+    }
+
+    #[test]
+    fn reading() {
+        let copy = write_copy("big.txt", &"abc\n".repeat(40_000)).unwrap();
+        let contents = read(&copy.path()).unwrap();
+        assert!(contents.encoding == Encoding::Utf8);
+        assert!(contents.decoded.is_none());
+        assert_eq!(contents.prefix.len() as u64, SNIFF_LEN);
+        assert!(read("no such file").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Noticing when another program changes a file.
+
+use std::ffi::OsString;
+use std::mem;
+use std::os::windows::ffi::OsStringExt;
+use std::path::Path;
+use std::ptr::{null, null_mut};
+use std::slice;
+use std::sync::Arc;
+use std::thread;
+
+use winapi::shared::minwindef::{DWORD, FALSE, LPVOID, TRUE};
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::synchapi::{CreateEventW, SetEvent, WaitForMultipleObjects};
+use winapi::um::winbase::{ReadDirectoryChangesW, FILE_FLAG_BACKUP_SEMANTICS,
+    FILE_FLAG_OVERLAPPED, INFINITE, WAIT_OBJECT_0};
+use winapi::um::winnt::*;
+
+use xi_win_shell::util::ToWide;
+
+/// Watches the directory of a file on a background thread, and calls back
+/// (on that thread) when something may have happened to the file. Events
+/// come in bursts, so the caller should debounce them. Watching stops
+/// when this is dropped.
+pub struct FileWatcher {
+    // Set to stop the thread. Shared, so it stays open until both sides
+    // are done with it, however the thread ends.
+    stop: Arc<Handle>,
+}
+
+// A handle that's closed when it's dropped.
+struct Handle(HANDLE);
+
+// Handles can be used from any thread.
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+
+impl Handle {
+    fn new(handle: HANDLE) -> Option<Handle> {
+        if handle.is_null() || handle == INVALID_HANDLE_VALUE {
+            None
+        } else {
+            Some(Handle(handle))
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+impl FileWatcher {
+    pub fn new<F>(path: &Path, callback: F) -> Option<FileWatcher>
+        where F: Fn() + Send + 'static
+    {
+        let dir = path.parent()?;
+        // Names are compared case-insensitively, like the file system does.
+        let file_name = path.file_name()?.to_string_lossy().to_lowercase();
+        // The thread owns the directory and the event its reads signal;
+        // nothing else touches them, so they're only closed once it's done.
+        let dir = Handle::new(unsafe {
+            CreateFileW(dir.to_wide().as_ptr(), FILE_LIST_DIRECTORY,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE, null_mut(),
+                OPEN_EXISTING, FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED, null_mut())
+        })?;
+        let changed = Handle::new(unsafe { CreateEventW(null_mut(), TRUE, FALSE, null()) })?;
+        let stop = Arc::new(Handle::new(unsafe {
+            CreateEventW(null_mut(), TRUE, FALSE, null())
+        })?);
+        let thread_stop = stop.clone();
+        thread::spawn(move || watch(&dir, &changed, &thread_stop, &file_name, callback));
+        Some(FileWatcher { stop })
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        // The thread cancels its read and stops, if it hasn't already.
+        unsafe {
+            SetEvent(self.stop.0);
+        }
+    }
+}
+
+// Read the directory's changes until told to stop, or reading fails.
+// Each read is overlapped, so waiting for it can also wait for the stop.
+fn watch<F: Fn()>(dir: &Handle, changed: &Handle, stop: &Handle, file_name: &str, callback: F) {
+    // u32s for the DWORD alignment the records need.
+    let mut buf = vec![0u32; 4096];
+    loop {
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = changed.0;
+        let mut n_bytes: DWORD = 0;
+        unsafe {
+            let ok = ReadDirectoryChangesW(dir.0, buf.as_mut_ptr() as LPVOID,
+                (buf.len() * 4) as DWORD, FALSE,
+                FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_LAST_WRITE |
+                    FILE_NOTIFY_CHANGE_SIZE,
+                null_mut(), &mut overlapped, None);
+            if ok == 0 {
+                return;
+            }
+            let events = [changed.0, stop.0];
+            let woken = WaitForMultipleObjects(2, events.as_ptr(), FALSE, INFINITE);
+            if woken != WAIT_OBJECT_0 {
+                // Stopped, or the wait failed. The read writes to buf and
+                // overlapped until it's cancelled, so wait for that too.
+                CancelIoEx(dir.0, &mut overlapped);
+                GetOverlappedResult(dir.0, &mut overlapped, &mut n_bytes, TRUE);
+                return;
+            }
+            if GetOverlappedResult(dir.0, &mut overlapped, &mut n_bytes, FALSE) == 0 {
+                return;
+            }
+        }
+        // No bytes means there were too many changes to report.
+        if n_bytes == 0 ||
+            changed_names(&buf).iter().any(|name| name.to_lowercase() == file_name)
+        {
+            callback();
+        }
+    }
+}
+
+// The file names in a buffer of FILE_NOTIFY_INFORMATION records.
+fn changed_names(buf: &[u32]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut offset = 0;
+    unsafe {
+        loop {
+            let info = (buf.as_ptr() as *const u8).add(offset) as *const FILE_NOTIFY_INFORMATION;
+            let len = (*info).FileNameLength as usize / 2;
+            let name = slice::from_raw_parts((*info).FileName.as_ptr(), len);
+            names.push(OsString::from_wide(name).to_string_lossy().into_owned());
+            if (*info).NextEntryOffset == 0 {
+                break;
+            }
+            offset += (*info).NextEntryOffset as usize;
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::time::Duration;
+
+    #[test]
+    fn watching_and_stopping() {
+        let dir = env::temp_dir().join(format!("xi-win-watch-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Watched.txt");
+        let (tx, rx) = channel();
+        let watcher = FileWatcher::new(&path, move || { let _ = tx.send(()); }).unwrap();
+
+        // Another file in the directory isn't reported; this one is, with
+        // its name in another case.
+        fs::write(dir.join("other.txt"), "x").unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(500)), Err(RecvTimeoutError::Timeout));
+        fs::write(dir.join("watched.TXT"), "x").unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // Dropping it stops the thread, which was waiting on a read, and
+        // drops the callback with it.
+        drop(watcher);
+        loop {
+            match rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(()) => continue,
+                Err(e) => {
+                    assert_eq!(e, RecvTimeoutError::Disconnected);
+                    break;
+                }
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hover tooltips: when the mouse rests over text, the edit view asks the
+//! core, which asks the language plugins, what's there, like a type or
+//! docs, and shows the answer in a box by the mouse until it moves or a
+//! key is pressed.
+
+use direct2d::brush;
+use direct2d::math::*;
+use direct2d::render_target::RenderTarget;
+use directwrite::{self, TextFormat, TextLayout};
+use directwrite::text_layout;
+
+use xi_win_shell::util::default_text_options;
+
+/// How long the mouse has to rest before the view asks, in ms.
+pub const HOVER_DELAY_MS: u32 = 500;
+
+// Past this width the text wraps, and the room around it (in px units).
+const MAX_WIDTH: f32 = 480.0;
+const PAD: f32 = 4.0;
+// How far below the mouse the box goes, clear of the pointer.
+const POINTER_CLEARANCE: f32 = 20.0;
+
+pub struct Hover {
+    // Where the mouse is resting, in px units from the view's top left.
+    point: Option<(f32, f32)>,
+    // The id of the latest request. Answers to earlier ones come in after
+    // the mouse has moved on, and are dropped.
+    request_id: usize,
+    shown: Option<String>,
+}
+
+impl Hover {
+    pub fn new() -> Hover {
+        Hover { point: None, request_id: 0, shown: None }
+    }
+
+    /// The mouse moved, so what was asked about is out of date. Returns
+    /// whether it's somewhere new; moves to the same point come in too.
+    pub fn mouse_moved(&mut self, point: (f32, f32)) -> bool {
+        if self.point == Some(point) {
+            return false;
+        }
+        self.point = Some(point);
+        self.dismiss();
+        true
+    }
+
+    /// Where the mouse was last, in px units from the view's top left.
+    pub fn point(&self) -> Option<(f32, f32)> {
+        self.point
+    }
+
+    pub fn is_shown(&self) -> bool {
+        self.shown.is_some()
+    }
+
+    /// Drop the tooltip and any answer on its way. Returns whether a
+    /// tooltip was dismissed.
+    pub fn dismiss(&mut self) -> bool {
+        self.request_id += 1;
+        self.shown.take().is_some()
+    }
+
+    /// The point to ask about, once the mouse has rested, and the id to
+    /// ask with.
+    pub fn rest(&self) -> Option<((f32, f32), usize)> {
+        match (self.point, &self.shown) {
+            (Some(point), &None) => Some((point, self.request_id)),
+            _ => None,
+        }
+    }
+
+    /// Show the answer to a request, unless the mouse has moved since.
+    /// Returns whether anything is shown.
+    pub fn show(&mut self, request_id: usize, text: &str) -> bool {
+        let text = text.trim();
+        if request_id != self.request_id || self.point.is_none() || text.is_empty() {
+            return false;
+        }
+        self.shown = Some(text.to_owned());
+        true
+    }
+
+    /// Draw the tooltip, below the mouse, or above it if there's no room
+    /// below, and kept inside the view. It starts at the mouse and goes
+    /// right, or left in a mirrored view.
+    pub fn render(&self, rt: &mut RenderTarget, factory: &directwrite::Factory,
+        text_format: &TextFormat, bg: &brush::SolidColor, fg: &brush::SolidColor,
+        size: (f32, f32), mirrored: bool)
+    {
+        let (text, (x, y)) = match (&self.shown, self.point) {
+            (&Some(ref text), Some(point)) => (text, point),
+            _ => return,
+        };
+        let layout_width = |width: f32| {
+            let params = text_layout::ParamBuilder::new()
+                .text(text)
+                .font(text_format.clone())
+                .width(width)
+                .height(1e6)
+                .build().unwrap();
+            let layout: TextLayout = factory.create(params).unwrap();
+            layout
+        };
+        let end = text.encode_utf16().count() as u32;
+        // As wide as the widest line, up to where it wraps.
+        let unwrapped = layout_width(1e6);
+        let x_at = |pos: u32| unwrapped.hit_test_text_position(pos, false)
+            .map_or(0.0, |pos| pos.point_x);
+        let width = text.lines().map(|line| {
+            let start = text[..line.as_ptr() as usize - text.as_ptr() as usize]
+                .encode_utf16().count() as u32;
+            x_at(start + line.encode_utf16().count() as u32) - x_at(start)
+        }).fold(0.0, f32::max).min(MAX_WIDTH).ceil();
+        let layout = layout_width(width);
+        let height = layout.hit_test_text_position(end, false)
+            .map_or(0.0, |pos| pos.point_y + pos.metrics.height);
+        let (w, h) = (width + 2.0 * PAD, height + 2.0 * PAD);
+        let x0 = if mirrored { x - w } else { x };
+        let x0 = x0.min(size.0 - w).max(0.0);
+        let y0 = if y + POINTER_CLEARANCE + h <= size.1 || y < h {
+            y + POINTER_CLEARANCE
+        } else {
+            y - h
+        };
+        let rect = RectF::from((x0, y0, x0 + w, y0 + h));
+        rt.fill_rectangle(&rect, bg);
+        rt.draw_rectangle(&RectF::from((x0 + 0.5, y0 + 0.5, x0 + w - 0.5, y0 + h - 0.5)), fg,
+            1.0, None);
+        rt.draw_text_layout(&Point2F::from((x0 + PAD, y0 + PAD)), &layout, fg,
+            default_text_options());
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bar across the top of the edit area telling the user about something
+//! that needs their attention, like the file changing on disk, without
+//! stopping them with a message box. It can offer a couple of actions as
+//! buttons.
+
+use std::collections::VecDeque;
+
+use direct2d::math::*;
+
+use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::util::default_text_options;
+
+use overlay::OverlayResources;
+
+/// Height of the bar while it's shown, in px units. The panes start
+/// below it.
+pub const INFOBAR_HEIGHT: f32 = 24.0;
+
+// The most buttons a message can have, and the most messages waiting
+// behind the one shown; older ones are dropped.
+const MAX_ACTIONS: usize = 2;
+const MAX_QUEUED: usize = 4;
+
+const PAD: f32 = 6.0;
+const BUTTON_WIDTH: f32 = 72.0;
+
+/// What a button of the bar does. Any of them also dismisses the message.
+#[derive(Clone, PartialEq, Eq)]
+pub enum InfoAction {
+    /// Open the file again, discarding unsaved changes.
+    Reload(String),
+    SaveAs,
+    /// List the problems with the configuration files.
+    ShowConfigErrors,
+    Dismiss,
+}
+
+impl InfoAction {
+    fn label(&self) -> &str {
+        match *self {
+            InfoAction::Reload(_) => "Reload",
+            InfoAction::SaveAs => "Save As",
+            InfoAction::ShowConfigErrors => "Details",
+            InfoAction::Dismiss => "Dismiss",
+        }
+    }
+}
+
+struct Info {
+    message: String,
+    actions: Vec<InfoAction>,
+}
+
+pub struct Infobar {
+    width: f32,  // of the edit area, in px units
+    current: Option<Info>,
+    queue: VecDeque<Info>,
+}
+
+impl Infobar {
+    pub fn new() -> Infobar {
+        Infobar {
+            width: 0.0,
+            current: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn is_shown(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// How much of the top of the edit area the bar takes up.
+    pub fn height(&self) -> f32 {
+        if self.is_shown() { INFOBAR_HEIGHT } else { 0.0 }
+    }
+
+    /// Show a message, or queue it behind the one shown. A message that's
+    /// already shown or waiting isn't added again.
+    pub fn show(&mut self, message: String, mut actions: Vec<InfoAction>) {
+        let known = {
+            let same = |info: &Info| info.message == message;
+            self.current.as_ref().map_or(false, &same) || self.queue.iter().any(&same)
+        };
+        if known {
+            return;
+        }
+        actions.truncate(MAX_ACTIONS);
+        let info = Info { message, actions };
+        if self.current.is_none() {
+            self.current = Some(info);
+        } else {
+            if self.queue.len() == MAX_QUEUED {
+                self.queue.pop_front();
+            }
+            self.queue.push_back(info);
+        }
+    }
+
+    /// Dismiss the message shown, showing the next one if there is one.
+    pub fn dismiss(&mut self) {
+        self.current = self.queue.pop_front();
+    }
+
+    /// Take back a message that no longer holds, whether it's shown or
+    /// waiting. Returns whether it was the one shown.
+    pub fn withdraw(&mut self, message: &str) -> bool {
+        self.queue.retain(|info| info.message != message);
+        let shown = self.current.as_ref().map_or(false, |info| info.message == message);
+        if shown {
+            self.dismiss();
+        }
+        shown
+    }
+
+    /// Whether the point (in px units from the top left of the edit area)
+    /// is on the bar.
+    pub fn contains(&self, y: f32) -> bool {
+        self.is_shown() && y >= 0.0 && y < INFOBAR_HEIGHT
+    }
+
+    /// The action of the button at this point, if there's one there.
+    pub fn button_at(&self, x: f32, y: f32) -> Option<InfoAction> {
+        let info = match self.current {
+            Some(ref info) if self.contains(y) => info,
+            _ => return None,
+        };
+        info.actions.iter().enumerate().find(|&(ix, _)| {
+            let (x0, x1) = self.button_span(ix);
+            x >= x0 && x < x1
+        }).map(|(_, action)| action.clone())
+    }
+
+    // The left and right of a button, counting from the right.
+    fn button_span(&self, ix: usize) -> (f32, f32) {
+        let x1 = self.width - PAD - ix as f32 * (BUTTON_WIDTH + PAD);
+        (x1 - BUTTON_WIDTH, x1)
+    }
+
+    pub fn render(&self, p: &mut PaintCtx, resources: &OverlayResources) {
+        let info = match self.current {
+            Some(ref info) => info,
+            None => return,
+        };
+        let rt = p.render_target();
+        rt.fill_rectangle(&RectF::from((0.0, 0.0, self.width, INFOBAR_HEIGHT)), &resources.bg);
+        rt.draw_line(&Point2F::from((0.0, INFOBAR_HEIGHT - 0.5)),
+            &Point2F::from((self.width, INFOBAR_HEIGHT - 0.5)), &resources.border, 1.0, None);
+        let text_right = self.button_span(info.actions.len()).1;
+        rt.draw_text(
+            &info.message,
+            &resources.text_format,
+            &RectF::from((PAD, 3.0, text_right.max(PAD), INFOBAR_HEIGHT)),
+            &resources.text,
+            default_text_options()
+        );
+        for (ix, action) in info.actions.iter().enumerate() {
+            let (x0, x1) = self.button_span(ix);
+            let rect = RectF::from((x0, 3.0, x1, INFOBAR_HEIGHT - 4.0));
+            rt.fill_rectangle(&rect, &resources.selection);
+            rt.draw_text(
+                action.label(),
+                &resources.text_format,
+                &RectF::from((x0 + PAD, 4.0, x1, INFOBAR_HEIGHT - 4.0)),
+                &resources.text,
+                default_text_options()
+            );
+        }
+    }
+}
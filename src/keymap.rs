@@ -0,0 +1,819 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key bindings for the edit area, from %APPDATA%\xi-win\keymap.json on
+//! top of the defaults built in. Each binding is an object with "keys",
+//! like "ctrl+shift+up", and either the "command" of the core to send or
+//! the "action" of the front end to run.
+//!
+//! The defaults are also written out next to it as default_keymap.json,
+//! to copy from. Shortcuts of the menus, like Ctrl+S, go through the
+//! accelerator table before bindings are looked at.
+//!
+//! If keymap.json can't be read as a list of bindings at all, the ones it
+//! had the last time it could are used instead.
+
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+
+use serde_json::{self, Value};
+
+use winapi::um::winuser::*;
+
+use xi_win_shell::window::{M_ALT, M_CTRL, M_SHIFT};
+
+use config_errors::{self, ConfigError};
+use edit_command::EditCommand;
+use menus::MenuEntries;
+use settings::{config_path, read_file};
+
+const DEFAULT_KEYMAP: &str = include_str!("default_keymap.json");
+
+/// What a key does.
+#[derive(Clone)]
+pub enum Binding {
+    /// Sent to the core as is.
+    Edit(EditCommand),
+    /// Run by the edit view, which knows what to send the core.
+    View(ViewAction),
+    /// A command of the window, as from its menus.
+    Menu(u32),
+}
+
+/// The front-end behaviors of the edit view that keys can be bound to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ViewAction {
+    /// Indent the lines of a selection across several lines, or insert a
+    /// tab.
+    IndentOrInsertTab,
+    /// To the first non-blank character of the line, or the start.
+    SmartHome,
+    SmartHomeAndModifySelection,
+    ScrollLineUp,
+    ScrollLineDown,
+    MoveLinesUp,
+    MoveLinesDown,
+    ToggleOverwrite,
+    DeleteLine,
+    ToggleComment,
+    GotoMatchingBracket,
+    /// Make the next select_next_occurrence skip the current one.
+    StartChord,
+    Recenter,
+    SelectNextOccurrence,
+    /// Show or hide the paint statistics, for performance work.
+    TogglePaintStats,
+}
+
+// The front-end actions, by the names bindings use.
+const VIEW_ACTIONS: &[(&str, ViewAction)] = &[
+    ("indent_or_insert_tab", ViewAction::IndentOrInsertTab),
+    ("smart_home", ViewAction::SmartHome),
+    ("smart_home_and_modify_selection", ViewAction::SmartHomeAndModifySelection),
+    ("scroll_line_up", ViewAction::ScrollLineUp),
+    ("scroll_line_down", ViewAction::ScrollLineDown),
+    ("move_lines_up", ViewAction::MoveLinesUp),
+    ("move_lines_down", ViewAction::MoveLinesDown),
+    ("toggle_overwrite", ViewAction::ToggleOverwrite),
+    ("delete_line", ViewAction::DeleteLine),
+    ("toggle_comment", ViewAction::ToggleComment),
+    ("goto_matching_bracket", ViewAction::GotoMatchingBracket),
+    ("start_chord", ViewAction::StartChord),
+    ("recenter", ViewAction::Recenter),
+    ("select_next_occurrence", ViewAction::SelectNextOccurrence),
+    ("toggle_paint_stats", ViewAction::TogglePaintStats),
+];
+
+const MENU_ACTIONS: &[(&str, u32)] = &[
+    ("new_window", MenuEntries::NewWindow as u32),
+    ("open", MenuEntries::Open as u32),
+    ("save", MenuEntries::Save as u32),
+    ("save_as", MenuEntries::SaveAs as u32),
+    ("save_all", MenuEntries::SaveAll as u32),
+    ("print", MenuEntries::Print as u32),
+    ("clear_recent", MenuEntries::ClearRecent as u32),
+    ("reload_keymap", MenuEntries::ReloadKeymap as u32),
+    ("show_config_errors", MenuEntries::ShowConfigErrors as u32),
+    ("exit", MenuEntries::Exit as u32),
+    ("undo", MenuEntries::Undo as u32),
+    ("redo", MenuEntries::Redo as u32),
+    ("cut", MenuEntries::Cut as u32),
+    ("copy", MenuEntries::Copy as u32),
+    ("paste", MenuEntries::Paste as u32),
+    ("uppercase", MenuEntries::UpperCase as u32),
+    ("lowercase", MenuEntries::LowerCase as u32),
+    ("title_case", MenuEntries::TitleCase as u32),
+    ("transpose", MenuEntries::Transpose as u32),
+    ("duplicate_line", MenuEntries::DuplicateLine as u32),
+    ("join_lines", MenuEntries::JoinLines as u32),
+    ("sort_lines", MenuEntries::SortLines as u32),
+    ("sort_lines_case_insensitive", MenuEntries::SortLinesCaseInsensitive as u32),
+    ("trim_trailing_whitespace", MenuEntries::TrimWhitespace as u32),
+    ("line_endings_lf", MenuEntries::LineEndingLf as u32),
+    ("line_endings_crlf", MenuEntries::LineEndingCrlf as u32),
+    ("toggle_read_only", MenuEntries::ReadOnly as u32),
+    ("toggle_trim_on_save", MenuEntries::TrimOnSave as u32),
+    ("toggle_final_newline_on_save", MenuEntries::FinalNewlineOnSave as u32),
+    ("word_wrap", MenuEntries::WordWrap as u32),
+    ("show_whitespace", MenuEntries::ShowWhitespace as u32),
+    ("show_trailing_whitespace", MenuEntries::ShowTrailingWhitespace as u32),
+    ("line_numbers_off", MenuEntries::LineNumbersOff as u32),
+    ("line_numbers_absolute", MenuEntries::LineNumbersAbsolute as u32),
+    ("line_numbers_relative", MenuEntries::LineNumbersRelative as u32),
+    ("line_numbers_hybrid", MenuEntries::LineNumbersHybrid as u32),
+    ("indent_guides", MenuEntries::IndentGuides as u32),
+    ("rulers", MenuEntries::Rulers as u32),
+    ("scroll_past_end", MenuEntries::ScrollPastEnd as u32),
+    ("minimap", MenuEntries::Minimap as u32),
+    ("antialias_cleartype", MenuEntries::AntialiasClearType as u32),
+    ("antialias_grayscale", MenuEntries::AntialiasGrayscale as u32),
+    ("antialias_aliased", MenuEntries::AntialiasAliased as u32),
+    ("split_view", MenuEntries::SplitView as u32),
+    ("full_screen", MenuEntries::FullScreen as u32),
+    ("command_palette", MenuEntries::CommandPalette as u32),
+    ("go_back", MenuEntries::GoBack as u32),
+    ("go_forward", MenuEntries::GoForward as u32),
+    ("single_selection", MenuEntries::SingleSelection as u32),
+    ("add_cursor_above", MenuEntries::AddCursorAbove as u32),
+    ("add_cursor_below", MenuEntries::AddCursorBelow as u32),
+    ("select_all", MenuEntries::SelectAll as u32),
+    ("toggle_bookmark", MenuEntries::ToggleBookmark as u32),
+    ("next_bookmark", MenuEntries::NextBookmark as u32),
+    ("prev_bookmark", MenuEntries::PrevBookmark as u32),
+    ("clear_bookmarks", MenuEntries::ClearBookmarks as u32),
+];
+
+// A key is either a virtual key, for keys that are in the same place on
+// every layout, or the character it types without modifiers, for
+// punctuation, which moves around between layouts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Vk(i32),
+    Char(char),
+}
+
+pub struct Keymap {
+    bindings: HashMap<(Key, u32), Binding>,
+    // The text of keymap.json the last time it could be read, to fall back
+    // on when it's reloaded broken.
+    user_text: Option<String>,
+}
+
+impl Keymap {
+    /// Load the defaults, then the user's bindings over them. Also returns
+    /// what was wrong with the user's file, if anything; bindings that
+    /// can't be understood are skipped, keeping the defaults for their keys.
+    /// The keymap being replaced, if there is one, has the bindings to keep
+    /// if the file is broken as a whole.
+    pub fn load(previous: Option<&Keymap>) -> (Keymap, Vec<ConfigError>) {
+        let mut keymap = Keymap { bindings: HashMap::new(), user_text: None };
+        let mut errors = Vec::new();
+        keymap.add_bindings("the default keymap", DEFAULT_KEYMAP, &mut errors);
+        write_default_keymap();
+        if let Some(text) = config_path("keymap.json").and_then(|path| read_file(&path).ok()) {
+            let last_good = previous.and_then(|keymap| keymap.user_text.clone())
+                .or_else(|| config_errors::read_last_good("keymap.json"));
+            keymap.add_user_bindings(text, last_good, &mut errors);
+            if let Some(ref text) = keymap.user_text {
+                config_errors::write_last_good("keymap.json", text);
+            }
+        }
+        (keymap, errors)
+    }
+
+    /// Just the default bindings, for tests that press keys.
+    #[cfg(test)]
+    pub fn defaults() -> Keymap {
+        let mut keymap = Keymap { bindings: HashMap::new(), user_text: None };
+        keymap.add_bindings("the default keymap", DEFAULT_KEYMAP, &mut Vec::new());
+        keymap
+    }
+
+    // The bindings of keymap.json, or if it isn't a list of them, the
+    // bindings of its last good text.
+    fn add_user_bindings(&mut self, text: String, last_good: Option<String>,
+        errors: &mut Vec<ConfigError>)
+    {
+        if self.add_bindings("keymap.json", &text, errors) {
+            self.user_text = Some(text);
+            return;
+        }
+        if let Some(last_good) = last_good {
+            // What's wrong with it was reported when it was loaded.
+            if self.add_bindings("keymap.json", &last_good, &mut Vec::new()) {
+                errors.push(ConfigError::new("keymap.json", None,
+                    "using the bindings from the last time it could be read"));
+                self.user_text = Some(last_good);
+            }
+        }
+    }
+
+    // Add the bindings in a file, returning whether it was a list of them;
+    // if it wasn't, none are added.
+    fn add_bindings(&mut self, source: &str, text: &str, errors: &mut Vec<ConfigError>)
+        -> bool
+    {
+        let values = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(values)) => values,
+            Ok(_) => {
+                errors.push(ConfigError::new(source, None, "expected a list of bindings"));
+                return false;
+            }
+            Err(e) => {
+                errors.push(ConfigError::from_json(source, &e));
+                return false;
+            }
+        };
+        let lines = element_lines(text);
+        // The line each key was bound on in this file, to catch keys bound
+        // twice. The later binding wins.
+        let mut bound_on = HashMap::new();
+        for (ix, value) in values.iter().enumerate() {
+            let line = lines.get(ix).cloned().unwrap_or(0);
+            match parse_binding(value) {
+                Ok((key, binding)) => {
+                    if let Some(earlier) = bound_on.insert(key, line) {
+                        errors.push(ConfigError::new(source, Some(line),
+                            format!("\"{}\" is already bound on line {}",
+                                value["keys"].as_str().unwrap_or(""), earlier)));
+                    }
+                    self.bindings.insert(key, binding);
+                }
+                Err(e) => errors.push(ConfigError::new(source, Some(line), e)),
+            }
+        }
+        true
+    }
+
+    /// The binding for a key with these modifiers, if there is one.
+    pub fn lookup(&self, vk_code: i32, mods: u32) -> Option<&Binding> {
+        self.bindings.get(&(Key::Vk(vk_code), mods)).or_else(||
+            vk_to_char(vk_code).and_then(|c| self.bindings.get(&(Key::Char(c), mods))))
+    }
+}
+
+/// Which of the characters keys type go into the text. The window gets a
+/// key's keydown first, then any characters it types, and a key that ran
+/// a binding doesn't also type.
+///
+/// Ctrl chords are commands, even on layouts where a key with Ctrl gives a
+/// printable character, so they type nothing. AltGr comes as Ctrl+Alt, and
+/// types its characters, like @ or { on German keyboards, unless its key
+/// ran a binding. A dead key types nothing itself: TranslateMessage puts
+/// the character it makes in with the next key's.
+pub struct CharFilter {
+    // Whether the last key down ran a binding, until its character comes.
+    ran_binding: bool,
+}
+
+impl CharFilter {
+    pub fn new() -> CharFilter {
+        CharFilter { ran_binding: false }
+    }
+
+    /// A key went down, and ran a binding or not. Bare modifiers don't
+    /// count: AltGr and Shift go down before the key they change.
+    pub fn keydown(&mut self, vk_code: i32, ran_binding: bool) {
+        if !is_modifier(vk_code) {
+            self.ran_binding = ran_binding;
+        }
+    }
+
+    /// A character came, typed with these modifiers. Returns whether it
+    /// goes into the text.
+    pub fn char(&mut self, ch: u32, mods: u32) -> bool {
+        if mem::replace(&mut self.ran_binding, false) {
+            return false;
+        }
+        if (mods & M_CTRL) != 0 && (mods & M_ALT) == 0 {
+            return false;
+        }
+        ::std::char::from_u32(ch).map_or(false, |c| !c.is_control())
+    }
+}
+
+/// Whether a key is a modifier, which goes down on its own ahead of the
+/// key it modifies.
+pub fn is_modifier(vk_code: i32) -> bool {
+    match vk_code {
+        VK_SHIFT | VK_CONTROL | VK_MENU | VK_LWIN | VK_RWIN => true,
+        _ => false,
+    }
+}
+
+// Write the defaults out next to the user's keymap, to copy from, unless
+// they're there already. A copy from an older version is replaced.
+fn write_default_keymap() {
+    let path = match config_path("default_keymap.json") {
+        Some(path) => path,
+        None => return,
+    };
+    match read_file(&path) {
+        Ok(ref text) if text == DEFAULT_KEYMAP => return,
+        _ => (),
+    }
+    let res = path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, DEFAULT_KEYMAP));
+    if let Err(e) = res {
+        println!("failed to write default keymap: {}", e);
+    }
+}
+
+// The line, from 1, that each element of the top-level array of JSON
+// text starts on, for pointing at bindings in errors. Values don't keep
+// where they were parsed from, so the text is gone over again; it's
+// known to parse.
+fn element_lines(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    // Whether the next thing in the array starts an element.
+    let mut expecting = false;
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+        }
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        if depth == 1 && expecting && !c.is_whitespace() && c != ']' {
+            lines.push(line);
+            expecting = false;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => {
+                depth += 1;
+                expecting = depth == 1;
+            }
+            ']' | '}' => depth -= 1,
+            ',' if depth == 1 => expecting = true,
+            _ => (),
+        }
+    }
+    lines
+}
+
+fn parse_binding(value: &Value) -> Result<((Key, u32), Binding), String> {
+    let keys = value["keys"].as_str().ok_or("no \"keys\"")?;
+    let key = parse_keys(keys).ok_or_else(|| format!("unknown keys \"{}\"", keys))?;
+    let binding = match (value["command"].as_str(), value["action"].as_str()) {
+        (Some(command), None) => EditCommand::from_name(command).map(Binding::Edit)
+            .ok_or_else(|| format!("unknown command \"{}\"", command))?,
+        (None, Some(action)) => parse_action(action)
+            .ok_or_else(|| format!("unknown action \"{}\"", action))?,
+        _ => return Err(format!("\"{}\" needs one of \"command\" or \"action\"", keys)),
+    };
+    Ok((key, binding))
+}
+
+fn parse_action(name: &str) -> Option<Binding> {
+    VIEW_ACTIONS.iter().find(|&&(n, _)| n == name).map(|&(_, action)| Binding::View(action))
+        .or_else(|| MENU_ACTIONS.iter().find(|&&(n, _)| n == name)
+            .map(|&(_, id)| Binding::Menu(id)))
+}
+
+// Parse keys like "ctrl+shift+up": modifiers, then one key.
+fn parse_keys(keys: &str) -> Option<(Key, u32)> {
+    let keys = keys.to_lowercase();
+    let mut parts: Vec<&str> = keys.split('+').collect();
+    // The plus key itself leaves an empty part at the end.
+    if keys.ends_with("++") || keys == "+" {
+        parts.pop();
+        parts.pop();
+        parts.push("+");
+    }
+    let key = parse_key(parts.pop()?)?;
+    let mut mods = 0;
+    for part in parts {
+        mods |= match part {
+            "ctrl" => M_CTRL,
+            "shift" => M_SHIFT,
+            "alt" => M_ALT,
+            _ => return None,
+        };
+    }
+    Some((key, mods))
+}
+
+fn parse_key(name: &str) -> Option<Key> {
+    let vk = match name {
+        "up" => VK_UP,
+        "down" => VK_DOWN,
+        "left" => VK_LEFT,
+        "right" => VK_RIGHT,
+        "home" => VK_HOME,
+        "end" => VK_END,
+        "pageup" => VK_PRIOR,
+        "pagedown" => VK_NEXT,
+        "insert" => VK_INSERT,
+        "delete" => VK_DELETE,
+        "backspace" => VK_BACK,
+        "tab" => VK_TAB,
+        "enter" => VK_RETURN,
+        "escape" => VK_ESCAPE,
+        "space" => VK_SPACE,
+        _ => {
+            let mut chars = name.chars();
+            return match (chars.next(), chars.next()) {
+                // Letters and digits are their own virtual keys.
+                (Some(c), None) if c.is_ascii_alphanumeric() => {
+                    Some(Key::Vk(c.to_ascii_uppercase() as i32))
+                }
+                (Some(c), None) => Some(Key::Char(c)),
+                (Some('f'), Some(_)) => name[1..].parse::<i32>().ok()
+                    .filter(|&n| n >= 1 && n <= 24)
+                    .map(|n| Key::Vk(VK_F1 + n - 1)),
+                _ => None,
+            };
+        }
+    };
+    Some(Key::Vk(vk))
+}
+
+// The character a key produces without modifiers in the current keyboard
+// layout, for bindings that follow the character rather than the key.
+fn vk_to_char(vk_code: i32) -> Option<char> {
+    let ch = unsafe { MapVirtualKeyW(vk_code as u32, MAPVK_VK_TO_CHAR) };
+    // The high bit marks a dead key.
+    if ch == 0 || (ch & 0x8000_0000) != 0 {
+        return None;
+    }
+    ::std::char::from_u32(ch & 0xffff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menus::accelerators;
+
+    fn keymap(text: &str) -> (Keymap, Vec<String>) {
+        let mut keymap = Keymap { bindings: HashMap::new(), user_text: None };
+        let mut errors = Vec::new();
+        keymap.add_bindings("keymap.json", text, &mut errors);
+        (keymap, errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    // The user's bindings from text, over none, falling back on last_good.
+    fn user_keymap(text: &str, last_good: Option<&str>) -> (Keymap, Vec<ConfigError>) {
+        let mut keymap = Keymap { bindings: HashMap::new(), user_text: None };
+        let mut errors = Vec::new();
+        keymap.add_user_bindings(text.to_owned(), last_good.map(|s| s.to_owned()), &mut errors);
+        (keymap, errors)
+    }
+
+    fn edit_command(keymap: &Keymap, key: (Key, u32)) -> Option<&EditCommand> {
+        match keymap.bindings.get(&key) {
+            Some(&Binding::Edit(ref command)) => Some(command),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn chords() {
+        assert_eq!(parse_keys("up"), Some((Key::Vk(VK_UP), 0)));
+        assert_eq!(parse_keys("ctrl+shift+up"), Some((Key::Vk(VK_UP), M_CTRL | M_SHIFT)));
+        assert_eq!(parse_keys("Shift+Ctrl+Up"), Some((Key::Vk(VK_UP), M_CTRL | M_SHIFT)));
+        assert_eq!(parse_keys("alt+pagedown"), Some((Key::Vk(VK_NEXT), M_ALT)));
+        assert_eq!(parse_keys("ctrl+a"), Some((Key::Vk('A' as i32), M_CTRL)));
+        assert_eq!(parse_keys("ctrl+7"), Some((Key::Vk('7' as i32), M_CTRL)));
+        assert_eq!(parse_keys("f1"), Some((Key::Vk(VK_F1), 0)));
+        assert_eq!(parse_keys("shift+F12"), Some((Key::Vk(VK_F12), M_SHIFT)));
+        assert_eq!(parse_keys("ctrl+["), Some((Key::Char('['), M_CTRL)));
+        assert_eq!(parse_keys("ctrl++"), Some((Key::Char('+'), M_CTRL)));
+        assert_eq!(parse_keys("+"), Some((Key::Char('+'), 0)));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for keys in &["", "ctrl+", "hyper+a", "ctrl+foo", "f0", "f25", "fx", "ctrl+shift"] {
+            assert_eq!(parse_keys(keys), None, "{}", keys);
+        }
+    }
+
+    #[test]
+    fn bindings() {
+        let (keymap, errors) = keymap(r#"[
+            {"keys": "ctrl+d", "command": "duplicate_line"},
+            {"keys": "home", "action": "smart_home"},
+            {"keys": "ctrl+s", "action": "save"}
+        ]"#);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(edit_command(&keymap, (Key::Vk('D' as i32), M_CTRL)),
+            Some(&EditCommand::DuplicateLine));
+        match keymap.bindings.get(&(Key::Vk(VK_HOME), 0)) {
+            Some(&Binding::View(action)) => assert!(action == ViewAction::SmartHome),
+            _ => panic!("home isn't bound to smart_home"),
+        }
+        match keymap.bindings.get(&(Key::Vk('S' as i32), M_CTRL)) {
+            Some(&Binding::Menu(id)) => assert_eq!(id, MenuEntries::Save as u32),
+            _ => panic!("ctrl+s isn't bound to save"),
+        }
+    }
+
+    #[test]
+    fn bad_bindings_are_skipped_with_their_lines() {
+        let (keymap, errors) = keymap(r#"[
+            {"keys": "ctrl+q", "command": "no_such_command"},
+            {"keys": "ctrl+w", "action": "no_such_action"},
+            {"keys": "ctrl+nothing", "command": "undo"},
+            {"command": "undo"},
+            {"keys": "ctrl+e", "command": "undo", "action": "save"},
+            {"keys": "ctrl+r",
+             "command": "redo"}
+        ]"#);
+        assert_eq!(errors, vec![
+            "keymap.json, line 2: unknown command \"no_such_command\"",
+            "keymap.json, line 3: unknown action \"no_such_action\"",
+            "keymap.json, line 4: unknown keys \"ctrl+nothing\"",
+            "keymap.json, line 5: no \"keys\"",
+            "keymap.json, line 6: \"ctrl+e\" needs one of \"command\" or \"action\"",
+        ]);
+        assert_eq!(keymap.bindings.len(), 1);
+        assert_eq!(edit_command(&keymap, (Key::Vk('R' as i32), M_CTRL)),
+            Some(&EditCommand::Redo));
+    }
+
+    #[test]
+    fn duplicate_bindings() {
+        let (keymap, errors) = keymap(r#"[
+            {"keys": "ctrl+k", "command": "undo"},
+            {"keys": "Ctrl+K", "command": "redo"}
+        ]"#);
+        assert_eq!(errors, vec!["keymap.json, line 3: \"Ctrl+K\" is already bound on line 2"]);
+        assert_eq!(edit_command(&keymap, (Key::Vk('K' as i32), M_CTRL)),
+            Some(&EditCommand::Redo));
+    }
+
+    #[test]
+    fn user_bindings_replace_defaults() {
+        let (mut keymap, _) = keymap(r#"[{"keys": "ctrl+k", "command": "undo"}]"#);
+        let mut errors = Vec::new();
+        assert!(keymap.add_bindings("user", r#"[{"keys": "ctrl+k", "command": "redo"}]"#,
+            &mut errors));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(edit_command(&keymap, (Key::Vk('K' as i32), M_CTRL)),
+            Some(&EditCommand::Redo));
+    }
+
+    #[test]
+    fn bad_json() {
+        let (keymap, errors) = keymap("[\n    {\"keys\": \"ctrl+k\",\n]");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("keymap.json, line 3: "), "{}", errors[0]);
+        assert!(keymap.bindings.is_empty());
+
+        let (_, errors) = keymap(r#"{"keys": "ctrl+k", "command": "undo"}"#);
+        assert_eq!(errors, vec!["keymap.json: expected a list of bindings"]);
+    }
+
+    #[test]
+    fn broken_files_fall_back_on_the_last_good_one() {
+        let good = r#"[{"keys": "ctrl+k", "command": "redo"}]"#;
+        let broken = [
+            ("[\n  {\"keys\": \"ctrl+j\", \"command\": \"undo\"},\n]", Some(3)),
+            ("[\n  {\"keys\": \"ctrl+j\" \"command\": \"undo\"}\n]", Some(2)),
+            ("[\n  {\"keys\": \"ctrl+j\", \"command\": \"undo\"}\n", Some(3)),
+            ("{\"keys\": \"ctrl+j\", \"command\": \"undo\"}", None),
+            ("", Some(1)),
+        ];
+        for &(text, line) in broken.iter() {
+            let (keymap, errors) = user_keymap(text, Some(good));
+            assert_eq!(errors.len(), 2, "{:?}", text);
+            assert_eq!(errors[0].line, line, "{:?}", text);
+            assert_eq!(errors[1].line, None);
+            assert_eq!(edit_command(&keymap, (Key::Vk('K' as i32), M_CTRL)),
+                Some(&EditCommand::Redo));
+            assert_eq!(keymap.bindings.len(), 1);
+            assert_eq!(keymap.user_text.as_ref().map(|s| s.as_str()), Some(good));
+
+            // With nothing to fall back on, there are no user bindings.
+            let (keymap, errors) = user_keymap(text, None);
+            assert_eq!(errors.len(), 1);
+            assert!(keymap.bindings.is_empty());
+            assert!(keymap.user_text.is_none());
+        }
+    }
+
+    #[test]
+    fn bad_bindings_dont_fall_back() {
+        // Those are skipped, keeping the rest of the file.
+        let text = "[\n  {\"keys\": \"ctrl+j\", \"command\": \"undo\"},\n  \
+            {\"keys\": \"ctrl+\"}\n]";
+        let last_good = r#"[{"keys": "ctrl+k", "command": "redo"}]"#;
+        let (keymap, errors) = user_keymap(text, Some(last_good));
+        assert_eq!(errors,
+            vec![ConfigError::new("keymap.json", Some(3), "unknown keys \"ctrl+\"")]);
+        assert_eq!(edit_command(&keymap, (Key::Vk('J' as i32), M_CTRL)),
+            Some(&EditCommand::Undo));
+        assert_eq!(keymap.user_text.as_ref().map(|s| s.as_str()), Some(text));
+    }
+
+    #[test]
+    fn lines_of_elements() {
+        assert_eq!(element_lines("[]"), Vec::<usize>::new());
+        assert_eq!(element_lines("[{}, {}]"), vec![1, 1]);
+        assert_eq!(element_lines("[\n  {\"keys\": \"[,{\\\"\"},\n\n  {\"a\": [1,\n 2]}\n]"),
+            vec![2, 4]);
+    }
+
+    #[test]
+    fn default_keymap() {
+        let (keymap, errors) = keymap(DEFAULT_KEYMAP);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(!keymap.bindings.is_empty());
+    }
+
+    #[test]
+    fn modifiers() {
+        for &vk in &[VK_SHIFT, VK_CONTROL, VK_MENU, VK_LWIN, VK_RWIN] {
+            assert!(is_modifier(vk));
+        }
+        for &vk in &[VK_ESCAPE, VK_SPACE, VK_CAPITAL, 'A' as i32] {
+            assert!(!is_modifier(vk));
+        }
+    }
+
+    enum Input {
+        // A key went down, with these modifiers.
+        Down(i32, u32),
+        // A character came, typed with these modifiers.
+        Char(char, u32),
+    }
+    use self::Input::*;
+
+    // The characters a keymap's window lets through when it gets these
+    // messages. A key runs a binding when the keymap has one for it.
+    fn typed(keymap: &Keymap, inputs: &[Input]) -> String {
+        let mut filter = CharFilter::new();
+        let mut text = String::new();
+        for input in inputs {
+            match *input {
+                Down(vk, mods) => filter.keydown(vk, keymap.lookup(vk, mods).is_some()),
+                Char(c, mods) => if filter.char(c as u32, mods) {
+                    text.push(c);
+                },
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn altgr_types() {
+        let (keymap, _) = keymap(DEFAULT_KEYMAP);
+        // AltGr+Q on a German layout: Ctrl and Alt go down first.
+        let altgr_q = [Down(VK_CONTROL, M_CTRL), Down(VK_MENU, M_CTRL | M_ALT),
+            Down('Q' as i32, M_CTRL | M_ALT), Char('@', M_CTRL | M_ALT)];
+        assert_eq!(typed(&keymap, &altgr_q), "@");
+
+        // Unless the chord is bound.
+        let (bound, _) = keymap(r#"[{"keys": "ctrl+alt+q", "command": "undo"}]"#);
+        assert_eq!(typed(&bound, &altgr_q), "");
+    }
+
+    #[test]
+    fn ctrl_chords_dont_type() {
+        let (keymap, _) = keymap("[]");
+        // Ctrl+A gives a control character, and Ctrl+Shift+7 on some
+        // layouts a printable one; neither is bound here.
+        assert_eq!(typed(&keymap, &[Down(VK_CONTROL, M_CTRL), Down('A' as i32, M_CTRL),
+            Char('\u{1}', M_CTRL)]), "");
+        assert_eq!(typed(&keymap, &[Down('7' as i32, M_CTRL | M_SHIFT),
+            Char('/', M_CTRL | M_SHIFT)]), "");
+        // Keys like Enter and Backspace give control characters too.
+        assert_eq!(typed(&keymap, &[Down(VK_RETURN, 0), Char('\r', 0),
+            Down(VK_BACK, 0), Char('\u{8}', 0)]), "");
+    }
+
+    #[test]
+    fn bindings_only_eat_their_own_characters() {
+        let (keymap, _) = keymap(r#"[{"keys": "shift+space", "command": "undo"}]"#);
+        assert_eq!(typed(&keymap, &[Down(VK_SHIFT, M_SHIFT), Down(VK_SPACE, M_SHIFT),
+            Char(' ', M_SHIFT), Down('A' as i32, M_SHIFT), Char('A', M_SHIFT)]), "A");
+        // A bound key that types nothing doesn't eat the next key's.
+        assert_eq!(typed(&keymap, &[Down(VK_SHIFT, M_SHIFT), Down(VK_SPACE, M_SHIFT),
+            Down('A' as i32, M_SHIFT), Char('A', M_SHIFT)]), "A");
+        assert_eq!(typed(&keymap, &[Down('X' as i32, 0), Char('x', 0)]), "x");
+    }
+
+    // The name of the command a key runs with the default keymap, from the
+    // menus' accelerators, which get it first, or the keymap; none when
+    // it's passed through to type.
+    fn command_for(keymap: &Keymap, vk: i32, mods: u32) -> Option<String> {
+        let mut f_virt = FVIRTKEY;
+        if (mods & M_CTRL) != 0 { f_virt |= FCONTROL; }
+        if (mods & M_ALT) != 0 { f_virt |= FALT; }
+        if (mods & M_SHIFT) != 0 { f_virt |= FSHIFT; }
+        let menu_name = |id: u32| MENU_ACTIONS.iter().find(|&&(_, i)| i == id)
+            .map(|&(name, _)| name.to_owned());
+        if let Some(a) = accelerators().iter().find(|a| a.fVirt == f_virt && a.key as i32 == vk) {
+            return menu_name(a.cmd as u32);
+        }
+        match keymap.lookup(vk, mods) {
+            Some(&Binding::Edit(ref command)) => Some(command.method().to_owned()),
+            Some(&Binding::View(action)) => VIEW_ACTIONS.iter().find(|&&(_, a)| a == action)
+                .map(|&(name, _)| name.to_owned()),
+            Some(&Binding::Menu(id)) => menu_name(id),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn default_chords() {
+        let (keymap, _) = keymap(DEFAULT_KEYMAP);
+        let table: &[(i32, u32, Option<&str>)] = &[
+            ('A' as i32, M_CTRL, Some("select_all")),
+            ('Z' as i32, M_CTRL, Some("undo")),
+            ('Y' as i32, M_CTRL, Some("redo")),
+            ('Z' as i32, M_CTRL | M_SHIFT, Some("redo")),
+            ('X' as i32, M_CTRL, Some("cut")),
+            ('C' as i32, M_CTRL, Some("copy")),
+            ('V' as i32, M_CTRL, Some("paste")),
+            ('O' as i32, M_CTRL, Some("open")),
+            ('S' as i32, M_CTRL, Some("save")),
+            ('S' as i32, M_CTRL | M_SHIFT, Some("save_as")),
+            ('S' as i32, M_CTRL | M_ALT, Some("save_all")),
+            ('P' as i32, M_CTRL | M_SHIFT, Some("command_palette")),
+            (VK_F11, 0, Some("full_screen")),
+            ('K' as i32, M_CTRL, Some("start_chord")),
+            ('D' as i32, M_CTRL, Some("select_next_occurrence")),
+            (VK_RETURN, 0, Some("insert_newline")),
+            (VK_BACK, M_CTRL, Some("delete_word_backward")),
+            (VK_LEFT, M_CTRL, Some("move_word_left")),
+            (VK_RIGHT, M_CTRL, Some("move_word_right")),
+            (VK_LEFT, M_ALT, Some("go_back")),
+            (VK_RIGHT, M_ALT, Some("go_forward")),
+            (VK_LEFT, M_ALT | M_SHIFT, Some("move_word_left_and_modify_selection")),
+            (VK_HOME, 0, Some("smart_home")),
+            (VK_F2, M_CTRL, Some("toggle_bookmark")),
+            // Passed through, to type or not as CharFilter decides.
+            ('A' as i32, 0, None),
+            ('A' as i32, M_SHIFT, None),
+            (VK_SPACE, 0, None),
+            ('B' as i32, M_CTRL, None),
+            ('Q' as i32, M_CTRL | M_ALT, None),
+            ('E' as i32, M_CTRL | M_ALT, None),
+            ('7' as i32, M_CTRL | M_ALT, None),
+            (VK_OEM_6, 0, None),
+        ];
+        for &(vk, mods, name) in table {
+            assert_eq!(command_for(&keymap, vk, mods).as_ref().map(|s| &s[..]), name,
+                "{:#x} with {:#x}", vk, mods);
+        }
+    }
+
+    #[test]
+    fn deletes_are_one_command() {
+        // keydown sends an edit binding's command once, and nothing else,
+        // so each of these presses deletes once.
+        let (keymap, _) = keymap(DEFAULT_KEYMAP);
+        let table = [
+            (VK_BACK, 0, EditCommand::DeleteBackward),
+            (VK_DELETE, 0, EditCommand::DeleteForward),
+            (VK_BACK, M_CTRL, EditCommand::DeleteWordBackward),
+            (VK_DELETE, M_CTRL, EditCommand::DeleteWordForward),
+        ];
+        for &(vk, mods, ref command) in &table {
+            assert_eq!(command_for(&keymap, vk, mods), Some(command.method().to_owned()),
+                "{:#x} with {:#x}", vk, mods);
+            assert_eq!(edit_command(&keymap, (Key::Vk(vk), mods)), Some(command));
+        }
+    }
+
+    #[test]
+    fn dead_keys() {
+        let (keymap, _) = keymap(DEFAULT_KEYMAP);
+        // The window gets the dead key's keydown, but not its WM_DEADCHAR,
+        // and then the next key's, with what they make together.
+        assert_eq!(typed(&keymap, &[Down(VK_OEM_6, 0), Down('E' as i32, 0),
+            Char('\u{e9}', 0)]), "\u{e9}");
+        assert_eq!(typed(&keymap, &[Down(VK_SHIFT, M_SHIFT), Down(VK_OEM_6, M_SHIFT),
+            Down('E' as i32, M_SHIFT), Char('\u{c8}', M_SHIFT)]), "\u{c8}");
+        assert_eq!(typed(&keymap, &[Down(VK_OEM_6, 0), Down(VK_SPACE, 0),
+            Char('\u{b4}', 0)]), "\u{b4}");
+        // Keys that don't combine type the accent and themselves.
+        assert_eq!(typed(&keymap, &[Down(VK_OEM_6, 0), Down('S' as i32, 0),
+            Char('\u{b4}', 0), Char('s', 0)]), "\u{b4}s");
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The text of a one-line field, with a caret and a selection, and the
+//! edits and moves on it. Keys, the clipboard and drawing are left to
+//! the text input that owns it.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+use word_boundary::is_word_char;
+
+pub struct LineEdit {
+    text: String,
+    // Byte offsets of the caret and of the other end of the selection,
+    // the same when nothing is selected. Always on char boundaries.
+    caret: usize,
+    anchor: usize,
+}
+
+impl LineEdit {
+    pub fn new() -> LineEdit {
+        LineEdit { text: String::new(), caret: 0, anchor: 0 }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn caret(&self) -> usize {
+        self.caret
+    }
+
+    /// Replace the text, with the caret at its end.
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.to_owned();
+        self.caret = self.text.len();
+        self.anchor = self.caret;
+    }
+
+    /// The selected byte range, empty at the caret when nothing is.
+    pub fn selection(&self) -> Range<usize> {
+        min(self.caret, self.anchor)..max(self.caret, self.anchor)
+    }
+
+    pub fn selected_text(&self) -> &str {
+        &self.text[self.selection()]
+    }
+
+    pub fn select_all(&mut self) {
+        self.anchor = 0;
+        self.caret = self.text.len();
+    }
+
+    /// Put text in place of the selection, with the caret after it.
+    pub fn insert(&mut self, text: &str) {
+        let selection = self.selection();
+        self.text.drain(selection.clone());
+        self.text.insert_str(selection.start, text);
+        self.caret = selection.start + text.len();
+        self.anchor = self.caret;
+    }
+
+    /// Take a typed character, in place of the selection. Control
+    /// characters, which come with Ctrl chords and keys like Backspace,
+    /// are left out. Returns whether the text changed.
+    pub fn type_char(&mut self, ch: u32) -> bool {
+        match ::std::char::from_u32(ch) {
+            Some(c) if !c.is_control() => {
+                self.insert(&c.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Paste text in place of the selection, on the one line: line breaks
+    /// become spaces, and other control characters but tabs are dropped.
+    /// Returns whether the text changed.
+    pub fn paste(&mut self, text: &str) -> bool {
+        let text = text.trim_end_matches(|c| c == '\n' || c == '\r')
+            .replace("\r\n", " ")
+            .replace(|c: char| c == '\n' || c == '\r', " ");
+        let text: String = text.chars().filter(|&c| c == '\t' || !c.is_control()).collect();
+        if text.is_empty() && self.caret == self.anchor {
+            return false;
+        }
+        self.insert(&text);
+        true
+    }
+
+    /// Move the caret a character or a word left. Without `extend`, a
+    /// selection collapses to its start instead of moving by a character.
+    pub fn move_left(&mut self, by_word: bool, extend: bool) {
+        let target = if by_word {
+            self.word_left(self.caret)
+        } else if !extend && self.caret != self.anchor {
+            self.selection().start
+        } else {
+            self.prev_char(self.caret)
+        };
+        self.move_to(target, extend);
+    }
+
+    /// Move the caret a character or a word right. Without `extend`, a
+    /// selection collapses to its end instead of moving by a character.
+    pub fn move_right(&mut self, by_word: bool, extend: bool) {
+        let target = if by_word {
+            self.word_right(self.caret)
+        } else if !extend && self.caret != self.anchor {
+            self.selection().end
+        } else {
+            self.next_char(self.caret)
+        };
+        self.move_to(target, extend);
+    }
+
+    pub fn move_home(&mut self, extend: bool) {
+        self.move_to(0, extend);
+    }
+
+    pub fn move_end(&mut self, extend: bool) {
+        let end = self.text.len();
+        self.move_to(end, extend);
+    }
+
+    // Move the caret, taking the selection along with it or not.
+    fn move_to(&mut self, offset: usize, extend: bool) {
+        self.caret = offset;
+        if !extend {
+            self.anchor = offset;
+        }
+    }
+
+    /// Delete the selection, or else the character or word before the
+    /// caret. Returns whether anything was deleted.
+    pub fn delete_backward(&mut self, by_word: bool) -> bool {
+        if self.caret == self.anchor {
+            self.anchor = if by_word {
+                self.word_left(self.caret)
+            } else {
+                self.prev_char(self.caret)
+            };
+        }
+        self.delete_selection()
+    }
+
+    /// Delete the selection, or else the character or word after the
+    /// caret. Returns whether anything was deleted.
+    pub fn delete_forward(&mut self, by_word: bool) -> bool {
+        if self.caret == self.anchor {
+            self.anchor = if by_word {
+                self.word_right(self.caret)
+            } else {
+                self.next_char(self.caret)
+            };
+        }
+        self.delete_selection()
+    }
+
+    /// Delete the selection. Returns whether there was one.
+    pub fn delete_selection(&mut self) -> bool {
+        if self.caret == self.anchor {
+            return false;
+        }
+        self.insert("");
+        true
+    }
+
+    fn prev_char(&self, offset: usize) -> usize {
+        self.text[..offset].char_indices().next_back().map_or(0, |(ix, _)| ix)
+    }
+
+    fn next_char(&self, offset: usize) -> usize {
+        self.text[offset..].chars().next().map_or(offset, |c| offset + c.len_utf8())
+    }
+
+    // The start of the word before an offset, past any spaces and
+    // punctuation in between.
+    fn word_left(&self, offset: usize) -> usize {
+        let mut before = self.text[..offset].char_indices().rev()
+            .skip_while(|&(_, c)| !is_word_char(c))
+            .skip_while(|&(_, c)| is_word_char(c));
+        before.next().map_or(0, |(ix, c)| ix + c.len_utf8())
+    }
+
+    // The start of the next word after an offset, as Ctrl+Right goes in
+    // Windows' own fields.
+    fn word_right(&self, offset: usize) -> usize {
+        let after = &self.text[offset..];
+        let mut rest = after.char_indices()
+            .skip_while(|&(_, c)| is_word_char(c))
+            .skip_while(|&(_, c)| !is_word_char(c));
+        offset + rest.next().map_or(after.len(), |(ix, _)| ix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A field holding text, with the caret at an offset.
+    fn edit(text: &str, caret: usize) -> LineEdit {
+        LineEdit { text: text.to_owned(), caret, anchor: caret }
+    }
+
+    #[test]
+    fn typing() {
+        let mut e = LineEdit::new();
+        assert!(e.type_char('a' as u32));
+        assert!(e.type_char('\u{e9}' as u32));
+        assert!(e.type_char(0x1f600));
+        assert_eq!(e.text(), "a\u{e9}\u{1f600}");
+        assert_eq!(e.caret(), 7);
+        // Backspace, Ctrl+Backspace, Enter and Ctrl+A come as controls.
+        for &ch in &[0x08, 0x7f, 0x0d, 0x01] {
+            assert!(!e.type_char(ch));
+        }
+        // Not a char at all.
+        assert!(!e.type_char(0xd800));
+        assert_eq!(e.text(), "a\u{e9}\u{1f600}");
+    }
+
+    #[test]
+    fn typing_replaces_the_selection() {
+        let mut e = edit("hello world", 0);
+        e.move_right(true, true);
+        assert_eq!(e.selected_text(), "hello ");
+        e.type_char('X' as u32);
+        assert_eq!(e.text(), "Xworld");
+        assert_eq!(e.caret(), 1);
+        assert!(e.selection().start == e.selection().end);
+    }
+
+    #[test]
+    fn moving_by_characters_over_multibyte_text() {
+        let text = "a\u{e9}\u{1f600}b";
+        let mut e = edit(text, 0);
+        let mut offsets = vec![e.caret()];
+        for _ in 0..5 {
+            e.move_right(false, false);
+            offsets.push(e.caret());
+        }
+        // Every stop is a char boundary, and it stops at the end.
+        assert_eq!(offsets, vec![0, 1, 3, 7, 8, 8]);
+        let mut back = Vec::new();
+        for _ in 0..5 {
+            e.move_left(false, false);
+            back.push(e.caret());
+        }
+        assert_eq!(back, vec![7, 3, 1, 0, 0]);
+    }
+
+    #[test]
+    fn moving_by_words() {
+        let mut e = edit("foo.bar  baz", 0);
+        let mut stops = Vec::new();
+        for _ in 0..4 {
+            e.move_right(true, false);
+            stops.push(e.caret());
+        }
+        assert_eq!(stops, vec![4, 9, 12, 12]);
+        let mut back = Vec::new();
+        for _ in 0..4 {
+            e.move_left(true, false);
+            back.push(e.caret());
+        }
+        assert_eq!(back, vec![9, 4, 0, 0]);
+    }
+
+    #[test]
+    fn words_of_other_scripts() {
+        let mut e = edit("\u{e9}t\u{e9} \u{65e5}\u{672c}", 0);
+        e.move_right(true, false);
+        assert_eq!(e.caret(), 6);
+        e.move_right(true, false);
+        assert_eq!(e.caret(), 12);
+        e.move_left(true, false);
+        assert_eq!(e.caret(), 6);
+    }
+
+    #[test]
+    fn selecting_and_collapsing() {
+        let mut e = edit("abcdef", 2);
+        e.move_right(false, true);
+        e.move_right(false, true);
+        assert_eq!(e.selection(), 2..4);
+        // Left without shift goes to the start of the selection.
+        e.move_left(false, false);
+        assert_eq!((e.caret(), e.selection()), (2, 2..2));
+        e.move_left(false, true);
+        assert_eq!(e.selection(), 1..2);
+        // Right without shift goes to its end.
+        e.move_right(false, false);
+        assert_eq!((e.caret(), e.selection()), (2, 2..2));
+        e.move_end(true);
+        assert_eq!(e.selected_text(), "cdef");
+        e.move_home(true);
+        assert_eq!(e.selected_text(), "ab");
+        e.move_home(false);
+        assert_eq!(e.selection(), 0..0);
+        e.select_all();
+        assert_eq!((e.caret(), e.selected_text()), (6, "abcdef"));
+    }
+
+    #[test]
+    fn deleting_characters() {
+        let mut e = edit("a\u{1f600}b", 5);
+        assert!(e.delete_backward(false));
+        assert_eq!((e.text(), e.caret()), ("ab", 1));
+        assert!(e.delete_forward(false));
+        assert_eq!((e.text(), e.caret()), ("a", 1));
+        assert!(!e.delete_forward(false));
+        e.move_home(false);
+        assert!(!e.delete_backward(false));
+        assert_eq!(e.text(), "a");
+    }
+
+    #[test]
+    fn deleting_words() {
+        let mut e = edit("one two  three", 9);
+        assert!(e.delete_backward(true));
+        assert_eq!((e.text(), e.caret()), ("one three", 4));
+        assert!(e.delete_forward(true));
+        assert_eq!((e.text(), e.caret()), ("one ", 4));
+        assert!(e.delete_backward(true));
+        assert_eq!((e.text(), e.caret()), ("", 0));
+    }
+
+    #[test]
+    fn deleting_a_selection() {
+        let mut e = edit("abcdef", 1);
+        e.move_right(false, true);
+        e.move_right(false, true);
+        // Ctrl or not, it's the selection that goes.
+        assert!(e.delete_forward(true));
+        assert_eq!((e.text(), e.caret()), ("adef", 1));
+        e.move_end(true);
+        assert!(e.delete_backward(false));
+        assert_eq!(e.text(), "a");
+        assert!(!e.delete_selection());
+    }
+
+    #[test]
+    fn pasting_joins_lines() {
+        let mut e = edit("ab", 1);
+        assert!(e.paste("one\r\ntwo\nthree\r\n"));
+        assert_eq!((e.text(), e.caret()), ("aone two threeb", 14));
+        assert!(e.paste("x\ty\u{7}"));
+        assert_eq!(e.text(), "aone two threex\tyb");
+        assert!(!e.paste(""));
+        e.select_all();
+        assert!(e.paste("\n"));
+        assert_eq!(e.text(), "");
+    }
+
+    #[test]
+    fn set_text_puts_the_caret_at_the_end() {
+        let mut e = edit("abc", 1);
+        e.set_text("\u{e9}t\u{e9}");
+        assert_eq!((e.text(), e.caret(), e.selection()), ("\u{e9}t\u{e9}", 5, 5..5));
+        e.set_text("");
+        assert_eq!((e.caret(), e.selection()), (0, 0..0));
+    }
+}
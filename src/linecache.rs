@@ -14,26 +14,84 @@
 
 //! The line cache (text, styles and cursors for a view).
 
+use std::cmp::{max, min};
 use std::mem;
+use std::ops::Range;
 
-use serde_json::Value;
+use protocol::{self, Annotation, Update};
+
+/// The style id the core reserves for selections.
+pub const SELECTION_STYLE: usize = 0;
+
+/// The style id the core reserves for find results.
+pub const FIND_STYLE: usize = 1;
 
 pub struct Line {
     text: String,
     cursor: Vec<usize>,
+    styles: Vec<StyleSpan>,
+    highlights: Vec<Highlight>,
+    ln: Option<usize>,
+}
+
+/// A range of a line with a style applied. Offsets are in the same units
+/// as cursor positions.
+pub struct StyleSpan {
+    pub start: usize,
+    pub end: usize,
+    pub style_id: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HighlightKind {
+    Selection,
+    Find,
+}
+
+/// A selected or found range of a line. Older cores send these as style
+/// spans with reserved ids, newer ones as annotations on the update; both
+/// end up here, so drawing doesn't depend on the protocol version.
+pub struct Highlight {
+    pub start: usize,
+    pub end: usize,
+    pub kind: HighlightKind,
+}
+
+impl HighlightKind {
+    fn from_style_id(style_id: usize) -> Option<HighlightKind> {
+        match style_id {
+            SELECTION_STYLE => Some(HighlightKind::Selection),
+            FIND_STYLE => Some(HighlightKind::Find),
+            _ => None,
+        }
+    }
+
+    fn from_annotation_type(annotation_type: &str) -> Option<HighlightKind> {
+        match annotation_type {
+            "selection" => Some(HighlightKind::Selection),
+            "find" => Some(HighlightKind::Find),
+            _ => None,
+        }
+    }
 }
 
 impl Line {
-    pub fn from_json(v: &Value) -> Line {
-        let text = v["text"].as_str().unwrap().to_owned();
-        let mut cursor = Vec::new();
-        if let Some(arr) = v["cursor"].as_array() {
-            for c in arr {
-                // TODO: this is probably the best place to convert to utf-16
-                cursor.push(c.as_u64().unwrap() as usize);
-            }
+    pub fn from_update(line: protocol::Line) -> Line {
+        let styles = parse_styles(&line.styles);
+        let highlights = style_highlights(&styles);
+        Line { text: line.text, cursor: line.cursor, styles, highlights, ln: line.ln }
+    }
+
+    /// Replace the cursors and styles from an "update" op, which leaves
+    /// the text alone.
+    fn update_from(&mut self, line: protocol::Line) {
+        let styles = parse_styles(&line.styles);
+        self.highlights = style_highlights(&styles);
+        self.cursor = line.cursor;
+        self.styles = styles;
+        if line.ln.is_some() {
+            self.ln = line.ln;
         }
-        Line { text, cursor }
     }
 
     pub fn text(&self) -> &str {
@@ -43,16 +101,66 @@ impl Line {
     pub fn cursor(&self) -> &[usize] {
         &self.cursor
     }
+
+    pub fn styles(&self) -> &[StyleSpan] {
+        &self.styles
+    }
+
+    pub fn highlights(&self) -> &[Highlight] {
+        &self.highlights
+    }
+
+    /// The number of the line in the file, counting from 1, if the core
+    /// sent it; it doesn't for the continuations of a wrapped line, or at
+    /// all before line numbers were added to the protocol.
+    pub fn ln(&self) -> Option<usize> {
+        self.ln
+    }
+
+    /// The selected ranges on this line.
+    pub fn selections<'a>(&'a self) -> impl Iterator<Item = &'a Highlight> + 'a {
+        self.highlights.iter().filter(|h| h.kind == HighlightKind::Selection)
+    }
+}
+
+fn style_highlights(styles: &[StyleSpan]) -> Vec<Highlight> {
+    styles.iter().filter_map(|span| HighlightKind::from_style_id(span.style_id)
+        .map(|kind| Highlight { start: span.start, end: span.end, kind }))
+        .collect()
+}
+
+// Turn the style triples of a line in an update into spans. The offsets
+// stay in bytes, like the text's; see utf16 for where they're converted.
+fn parse_styles(triples: &[i64]) -> Vec<StyleSpan> {
+    let mut styles = Vec::new();
+    let mut ix = 0;
+    for triple in triples.chunks(3) {
+        if triple.len() < 3 {
+            break;
+        }
+        let start = (ix + triple[0]).max(0);
+        let end = (start + triple[1]).max(start);
+        styles.push(StyleSpan {
+            start: start as usize,
+            end: end as usize,
+            style_id: triple[2].max(0) as usize,
+        });
+        ix = end;
+    }
+    styles
 }
 
 pub struct LineCache {
-    lines: Vec<Option<Line>>
+    lines: Vec<Option<Line>>,
+    // Whether the last update changed any text.
+    text_changed: bool,
 }
 
 impl LineCache {
     pub fn new() -> LineCache {
         LineCache {
             lines: Vec::new(),
+            text_changed: false,
         }
     }
 
@@ -60,39 +168,158 @@ impl LineCache {
         self.lines.push(line);
     }
 
-    pub fn apply_update(&mut self, update: &Value) {
+    /// Apply an update from the core. Returns the range of lines (in the
+    /// new numbering) that are different from before, if any: new lines,
+    /// and lines that have moved.
+    pub fn apply_update(&mut self, update: Update) -> Option<Range<usize>> {
         let old_cache = mem::replace(self, LineCache::new());
+        let old_height = old_cache.height();
         let mut old_iter = old_cache.lines.into_iter();
-        for op in update["ops"].as_array().unwrap() {
-            let op_type = &op["op"];
-            if op_type == "ins" {
-                for line in op["lines"].as_array().unwrap() {
-                    let line = Line::from_json(line);
-                    self.push_opt_line(Some(line));
+        // Index of the next line from old_iter.
+        let mut old_ix = 0;
+        let mut changed: Option<Range<usize>> = None;
+        // The text of the lines dropped and added. If they're the same, the
+        // update only moved carets or restyled. A line we didn't have could
+        // have been anything.
+        let mut removed_text = Vec::new();
+        let mut added_text = Vec::new();
+        let mut removed_unknown = false;
+        let mut mark_changed = |range: Range<usize>| {
+            if range.start < range.end {
+                changed = Some(match changed.take() {
+                    Some(r) => min(r.start, range.start)..max(r.end, range.end),
+                    None => range,
+                });
+            }
+        };
+        for op in update.ops {
+            let start = self.height();
+            let n = op.n;
+            match op.op.as_str() {
+                "ins" => {
+                    for line in op.lines {
+                        let line = Line::from_update(line);
+                        added_text.push(line.text.clone());
+                        self.push_opt_line(Some(line));
+                    }
+                    mark_changed(start..self.height());
                 }
-            } else if op_type == "copy" {
-                let n = op["n"].as_u64().unwrap();
-                for _ in 0..n {
-                    self.push_opt_line(old_iter.next().unwrap_or_default());
+                "copy" => {
+                    // Newer cores say where the copied lines start now; the
+                    // continuations of wrapped lines have no number to count.
+                    let mut ln = op.ln;
+                    for _ in 0..n {
+                        let line = old_iter.next().unwrap_or_default().map(|mut line| {
+                            if let (Some(_), Some(new_ln)) = (line.ln, ln) {
+                                line.ln = Some(new_ln);
+                                ln = Some(new_ln + 1);
+                            }
+                            line
+                        });
+                        self.push_opt_line(line);
+                    }
+                    if old_ix != start {
+                        mark_changed(start..self.height());
+                    }
+                    old_ix += n;
                 }
-            } else if op_type == "skip" {
-                let n = op["n"].as_u64().unwrap();
-                for _ in 0..n {
-                    let _ = old_iter.next();
+                "skip" => {
+                    for _ in 0..n {
+                        match old_iter.next() {
+                            Some(Some(line)) => removed_text.push(line.text),
+                            _ => removed_unknown = true,
+                        }
+                    }
+                    old_ix += n;
                 }
-            } else if op_type == "invalidate" {
-                let n = op["n"].as_u64().unwrap();
-                for _ in 0..n {
-                    self.push_opt_line(None);
+                "update" => {
+                    // Same text as before, with new cursors and styles.
+                    let n_lines = op.lines.len();
+                    for update_line in op.lines {
+                        let line = old_iter.next().unwrap_or_default().map(|mut line| {
+                            line.update_from(update_line);
+                            line
+                        });
+                        self.push_opt_line(line);
+                    }
+                    old_ix += n_lines;
+                    mark_changed(start..self.height());
+                }
+                "invalidate" => {
+                    for _ in 0..n {
+                        self.push_opt_line(None);
+                    }
+                    mark_changed(start..self.height());
+                }
+                other => println!("unknown update op {}", other),
+            }
+        }
+        if let Some(ref annotations) = update.annotations {
+            if let Some(range) = self.apply_annotations(annotations) {
+                mark_changed(range);
+            }
+        }
+        // Old lines the ops didn't reach are gone.
+        let removed_rest = old_iter.next().is_some();
+        self.text_changed = removed_unknown || removed_rest || removed_text != added_text;
+        // Lines removed from the end leave blank space behind.
+        let new_height = self.height();
+        mark_changed(min(old_height, new_height)..max(old_height, new_height));
+        changed
+    }
+
+    // Replace the highlights of all lines with the ones in an update's
+    // annotations. Each annotation has a type and ranges of
+    // [start line, start col, end line, end col] in document lines. The
+    // payloads (find query ids, so far) aren't needed for drawing.
+    // Returns the range of lines whose highlights may have changed.
+    fn apply_annotations(&mut self, annotations: &[Annotation]) -> Option<Range<usize>> {
+        let mut first = usize::max_value();
+        let mut last = 0;
+        for (ix, line) in self.lines.iter_mut().enumerate() {
+            if let Some(ref mut line) = *line {
+                if !line.highlights.is_empty() {
+                    line.highlights.clear();
+                    first = min(first, ix);
+                    last = max(last, ix + 1);
+                }
+            }
+        }
+        for annotation in annotations {
+            let kind = match HighlightKind::from_annotation_type(&annotation.annotation_type) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            for &[start_line, start_col, end_line, end_col] in &annotation.ranges {
+                for ix in start_line..min(end_line + 1, self.height()) {
+                    if let Some(&mut Some(ref mut line)) = self.lines.get_mut(ix) {
+                        let start = if ix == start_line { start_col } else { 0 };
+                        let end = if ix == end_line { end_col } else { line.text.len() };
+                        line.highlights.push(Highlight { start, end, kind });
+                        first = min(first, ix);
+                        last = max(last, ix + 1);
+                    }
                 }
             }
         }
+        if first < last { Some(first..last) } else { None }
+    }
+
+    /// Whether the last update changed the text, as far as the cache can
+    /// tell; replacing lines it didn't have counts as a change.
+    pub fn text_changed(&self) -> bool {
+        self.text_changed
     }
 
     pub fn height(&self) -> usize {
         self.lines.len()
     }
 
+    /// The number of lines the core has sent and not invalidated since.
+    pub fn cached_count(&self) -> usize {
+        self.lines.iter().filter(|line| line.is_some()).count()
+    }
+
     pub fn get_line(&self, ix: usize) -> Option<&Line> {
         if ix < self.lines.len() {
             self.lines[ix].as_ref()
@@ -100,4 +327,421 @@ impl LineCache {
             None
         }
     }
+
+    /// Whether any cached line has a non-empty selection. Lines the core
+    /// hasn't sent us can't be taken into account.
+    pub fn has_selection(&self) -> bool {
+        self.lines.iter().any(|line| match *line {
+            Some(ref line) => line.selections().any(|span| span.end > span.start),
+            None => false,
+        })
+    }
+
+    /// Whether a selection continues across a line break. A selection on
+    /// one line has a caret at one of its ends; on the lines a multi-line
+    /// selection crosses, at least one part of it doesn't.
+    pub fn has_multi_line_selection(&self) -> bool {
+        self.lines.iter().filter_map(|line| line.as_ref()).any(|line|
+            line.selections().any(|span| span.end > span.start &&
+                !line.cursor().iter().any(|&c| c == span.start || c == span.end)))
+    }
+
+    /// Every selection in the cached lines, in order, as its anchor and its
+    /// caret, by line and offset; a caret with nothing selected is both. A
+    /// selection is only whole if all its lines are cached, and goes
+    /// forward if its caret isn't at either end.
+    pub fn selections(&self) -> Vec<((usize, usize), (usize, usize))> {
+        // The start and end of each selection, and the carets.
+        let mut spans: Vec<((usize, usize), (usize, usize))> = Vec::new();
+        let mut carets = Vec::new();
+        // Whether the last selection goes on past its line's end.
+        let mut open = false;
+        for (ix, line) in self.lines.iter().enumerate() {
+            let line = match *line {
+                Some(ref line) => line,
+                None => {
+                    open = false;
+                    continue;
+                }
+            };
+            let len = line.text().trim_end_matches(|c| c == '\n' || c == '\r').len();
+            let continued = open;
+            open = false;
+            for span in line.selections().filter(|span| span.end > span.start) {
+                // Past the end, the newline is selected too.
+                let end = if span.end > len { (ix + 1, 0) } else { (ix, span.end) };
+                if continued && span.start == 0 {
+                    spans.last_mut().unwrap().1 = end;
+                } else {
+                    spans.push(((ix, span.start), end));
+                }
+                open = span.end > len;
+            }
+            carets.extend(line.cursor().iter().map(|&col| (ix, col)));
+        }
+        let mut selections: Vec<_> = spans.iter().map(|&(start, end)| {
+            let backward = carets.contains(&start) && !carets.contains(&end);
+            if backward { (end, start) } else { (start, end) }
+        }).collect();
+        selections.extend(carets.into_iter()
+            .filter(|&caret| !spans.iter().any(|&(start, end)| caret == start || caret == end))
+            .map(|caret| (caret, caret)));
+        selections.sort_by_key(|&(anchor, caret)| min(anchor, caret));
+        selections
+    }
+
+    /// The number of carets in the cached lines.
+    pub fn cursor_count(&self) -> usize {
+        self.lines.iter().filter_map(|line| line.as_ref()).map(|line| line.cursor().len()).sum()
+    }
+
+    /// The line and offset of the first caret in the cached lines.
+    pub fn first_cursor(&self) -> Option<(usize, usize)> {
+        for (ix, line) in self.lines.iter().enumerate() {
+            if let Some(&offset) = line.as_ref().and_then(|line| line.cursor().first()) {
+                return Some((ix, offset));
+            }
+        }
+        None
+    }
+}
+
+/// A run of old lines, as an update's ops go over them.
+pub enum Run<'a> {
+    /// Kept, starting at `new_start` afterwards.
+    Copied { old: Range<usize>, new_start: usize },
+    /// Skipped between copies, with the lines put in their place starting
+    /// at `new_start`, by their text, None for lines sent as invalid.
+    Replaced { old: Range<usize>, new_start: usize, added: &'a [Option<&'a str>] },
+}
+
+/// Follow the ops of an update over the old lines, for what's kept by
+/// line number outside the cache, like bookmarks. Old lines the ops don't
+/// reach are gone.
+pub fn walk_ops<F: FnMut(Run)>(ops: &[protocol::Op], mut f: F) {
+    let mut old_ix = 0;
+    let mut new_ix = 0;
+    let mut skipped = 0..0;
+    let mut added: Vec<Option<&str>> = Vec::new();
+    for op in ops {
+        let n = match op.op.as_str() {
+            "copy" => op.n,
+            "update" => op.lines.len(),
+            "skip" => {
+                old_ix += op.n;
+                skipped.end = old_ix;
+                continue;
+            }
+            "ins" => {
+                added.extend(op.lines.iter().map(|line| Some(line.text.as_str())));
+                new_ix += op.lines.len();
+                continue;
+            }
+            "invalidate" => {
+                added.extend((0..op.n).map(|_| None));
+                new_ix += op.n;
+                continue;
+            }
+            _ => continue,
+        };
+        if skipped.start < skipped.end || !added.is_empty() {
+            let new_start = new_ix - added.len();
+            f(Run::Replaced { old: skipped.clone(), new_start, added: &added });
+        }
+        f(Run::Copied { old: old_ix..old_ix + n, new_start: new_ix });
+        old_ix += n;
+        new_ix += n;
+        skipped = old_ix..old_ix;
+        added.clear();
+    }
+    if skipped.start < skipped.end || !added.is_empty() {
+        f(Run::Replaced { old: skipped, new_start: new_ix - added.len(), added: &added });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    fn update(value: Value) -> Update {
+        Update::deserialize(&value).unwrap()
+    }
+
+    // The highlights of a cached line, as (start, end, kind).
+    fn highlights(cache: &LineCache, ix: usize) -> Vec<(usize, usize, &'static str)> {
+        cache.get_line(ix).unwrap().highlights().iter().map(|h| {
+            let kind = match h.kind {
+                HighlightKind::Selection => "selection",
+                HighlightKind::Find => "find",
+            };
+            (h.start, h.end, kind)
+        }).collect()
+    }
+
+    // The cache line by line: the text, carets and line number of each
+    // line, or None for the ones it doesn't have.
+    fn lines(cache: &LineCache) -> Vec<Option<(&str, Vec<usize>, Option<usize>)>> {
+        (0..cache.height()).map(|ix| cache.get_line(ix)
+            .map(|line| (line.text(), line.cursor().to_vec(), line.ln())))
+            .collect()
+    }
+
+    // Three numbered lines, with the caret on the second.
+    fn three_lines() -> LineCache {
+        let mut cache = LineCache::new();
+        cache.apply_update(update(json!({"ops": [{"op": "ins", "lines": [
+            {"text": "a\n", "ln": 1},
+            {"text": "b\n", "cursor": [1], "ln": 2},
+            {"text": "c", "ln": 3},
+        ]}]})));
+        cache
+    }
+
+    #[test]
+    fn ops_keep_the_lines_they_dont_touch() {
+        let mut cache = three_lines();
+        assert_eq!(lines(&cache), vec![
+            Some(("a\n", vec![], Some(1))),
+            Some(("b\n", vec![1], Some(2))),
+            Some(("c", vec![], Some(3))),
+        ]);
+
+        // Replace the middle line; the others are copied as they were.
+        let changed = cache.apply_update(update(json!({"ops": [
+            {"op": "copy", "n": 1},
+            {"op": "skip", "n": 1},
+            {"op": "ins", "lines": [{"text": "B\n", "cursor": [0], "ln": 2}]},
+            {"op": "copy", "n": 1},
+        ]})));
+        assert_eq!(changed, Some(1..2));
+        assert!(cache.text_changed());
+        assert_eq!(lines(&cache), vec![
+            Some(("a\n", vec![], Some(1))),
+            Some(("B\n", vec![0], Some(2))),
+            Some(("c", vec![], Some(3))),
+        ]);
+
+        // An update op moves the caret and keeps the text.
+        let changed = cache.apply_update(update(json!({"ops": [
+            {"op": "copy", "n": 1},
+            {"op": "update", "n": 1, "lines": [{"cursor": [1]}]},
+            {"op": "copy", "n": 1},
+        ]})));
+        assert_eq!(changed, Some(1..2));
+        assert!(!cache.text_changed());
+        assert_eq!(lines(&cache)[1], Some(("B\n", vec![1], Some(2))));
+
+        // Lines the ops don't reach are gone, and leave blank space.
+        let changed = cache.apply_update(update(json!({"ops": [{"op": "copy", "n": 1}]})));
+        assert_eq!(changed, Some(1..3));
+        assert!(cache.text_changed());
+        assert_eq!(lines(&cache), vec![Some(("a\n", vec![], Some(1)))]);
+    }
+
+    #[test]
+    fn invalidating_the_first_and_last_lines() {
+        let mut cache = three_lines();
+        let changed = cache.apply_update(update(json!({"ops": [
+            {"op": "skip", "n": 1},
+            {"op": "invalidate", "n": 1},
+            {"op": "copy", "n": 1},
+            {"op": "skip", "n": 1},
+            {"op": "invalidate", "n": 1},
+        ]})));
+        assert_eq!(changed, Some(0..3));
+        assert_eq!(lines(&cache), vec![None, Some(("b\n", vec![1], Some(2))), None]);
+        assert_eq!(cache.cached_count(), 1);
+
+        // The core sends them again.
+        let changed = cache.apply_update(update(json!({"ops": [
+            {"op": "skip", "n": 1},
+            {"op": "ins", "lines": [{"text": "a\n", "ln": 1}]},
+            {"op": "copy", "n": 1},
+            {"op": "skip", "n": 1},
+            {"op": "ins", "lines": [{"text": "c", "ln": 3}]},
+        ]})));
+        assert_eq!(changed, Some(0..3));
+        assert_eq!(lines(&cache), lines(&three_lines()));
+
+        // A view the core hasn't sent any lines of yet.
+        let mut cache = LineCache::new();
+        cache.apply_update(update(json!({"ops": [{"op": "invalidate", "n": 4}]})));
+        assert_eq!(lines(&cache), vec![None, None, None, None]);
+    }
+
+    #[test]
+    fn copies_move_line_numbers() {
+        let mut cache = three_lines();
+        // A line inserted above pushes the others down to start at 2.
+        let changed = cache.apply_update(update(json!({"ops": [
+            {"op": "ins", "lines": [{"text": "new\n", "ln": 1}]},
+            {"op": "copy", "n": 3, "ln": 2},
+        ]})));
+        assert_eq!(changed, Some(0..4));
+        assert_eq!(lines(&cache), vec![
+            Some(("new\n", vec![], Some(1))),
+            Some(("a\n", vec![], Some(2))),
+            Some(("b\n", vec![1], Some(3))),
+            Some(("c", vec![], Some(4))),
+        ]);
+
+        // And deleting it pulls them back up to start at 1.
+        cache.apply_update(update(json!({"ops": [
+            {"op": "skip", "n": 1},
+            {"op": "copy", "n": 3, "ln": 1},
+        ]})));
+        assert_eq!(lines(&cache), lines(&three_lines()));
+
+        // The numbers come from the op, whatever the old ones were.
+        cache.apply_update(update(json!({"ops": [{"op": "copy", "n": 3, "ln": 10}]})));
+        let renumbered = vec![
+            Some(("a\n", vec![], Some(10))),
+            Some(("b\n", vec![1], Some(11))),
+            Some(("c", vec![], Some(12))),
+        ];
+        assert_eq!(lines(&cache), renumbered);
+
+        // An older core doesn't send ln, and the old numbers stay.
+        cache.apply_update(update(json!({"ops": [{"op": "copy", "n": 3}]})));
+        assert_eq!(lines(&cache), renumbered);
+
+        // Continuations of a wrapped line have no number, and don't count.
+        let mut cache = LineCache::new();
+        cache.apply_update(update(json!({"ops": [{"op": "ins", "lines": [
+            {"text": "wrapped ", "ln": 1},
+            {"text": "line\n"},
+            {"text": "next", "ln": 2},
+        ]}]})));
+        cache.apply_update(update(json!({"ops": [{"op": "copy", "n": 3, "ln": 5}]})));
+        assert_eq!(lines(&cache), vec![
+            Some(("wrapped ", vec![], Some(5))),
+            Some(("line\n", vec![], None)),
+            Some(("next", vec![], Some(6))),
+        ]);
+    }
+
+    #[test]
+    fn copying_lines_the_cache_never_had() {
+        let mut cache = LineCache::new();
+        let changed = cache.apply_update(update(json!({"ops": [
+            {"op": "copy", "n": 2},
+            {"op": "ins", "lines": [{"text": "x"}]},
+        ]})));
+        // All three lines are new to the view, known or not.
+        assert_eq!(changed, Some(0..3));
+        assert_eq!(lines(&cache), vec![None, None, Some(("x", vec![], None))]);
+    }
+
+    // The same three lines, with "two" and the line break after it
+    // selected into "th", and "four" found, from an older core, as style
+    // spans, and from a newer one, as annotations. Both color "one".
+    #[test]
+    fn selections_with_their_carets() {
+        let mut cache = LineCache::new();
+        cache.apply_update(update(json!({
+            "ops": [{"op": "ins", "lines": [
+                {"text": "alpha beta\n", "cursor": [6]},
+                {"text": "gamma\n"},
+                {"text": "delta epsilon\n"},
+                {"text": "zeta", "cursor": [0, 2]},
+            ]}],
+            "annotations": [{"type": "selection", "n": 2, "payloads": null,
+                "ranges": [[0, 6, 1, 3], [2, 6, 3, 0]]}],
+        })));
+        assert_eq!(cache.selections(), vec![
+            // Across a line break, with the caret at the start.
+            ((1, 3), (0, 6)),
+            // Up to the start of the next line, newline and all.
+            ((2, 6), (3, 0)),
+            // A caret on its own.
+            ((3, 2), (3, 2)),
+        ]);
+
+        // Without the line it ends on, the first stops at the line break.
+        cache.apply_update(update(json!({"ops": [
+            {"op": "copy", "n": 1},
+            {"op": "invalidate", "n": 1},
+            {"op": "skip", "n": 1},
+            {"op": "copy", "n": 2},
+        ]})));
+        assert_eq!(cache.selections(), vec![
+            ((1, 0), (0, 6)),
+            ((2, 6), (3, 0)),
+            ((3, 2), (3, 2)),
+        ]);
+    }
+
+    fn old_style() -> Update {
+        update(json!({"ops": [{"op": "ins", "lines": [
+            {"text": "one two\n", "cursor": [8], "styles": [0, 3, 2, 1, 4, 0]},
+            {"text": "three\n", "cursor": [2], "styles": [0, 2, 0]},
+            {"text": "four", "styles": [0, 4, 1]},
+        ]}]}))
+    }
+
+    fn annotation_style() -> Update {
+        update(json!({
+            "ops": [{"op": "ins", "lines": [
+                {"text": "one two\n", "cursor": [8], "styles": [0, 3, 2]},
+                {"text": "three\n", "cursor": [2]},
+                {"text": "four"},
+            ]}],
+            "annotations": [
+                {"type": "selection", "ranges": [[0, 4, 1, 2]], "payloads": null, "n": 1},
+                {"type": "find", "ranges": [[2, 0, 2, 4]], "payloads": [{"id": 1}], "n": 1},
+                {"type": "unknown", "ranges": [[0, 0, 0, 1]]},
+            ],
+        }))
+    }
+
+    #[test]
+    fn both_kinds_of_update_highlight_the_same() {
+        let mut old = LineCache::new();
+        let mut new = LineCache::new();
+        assert_eq!(old.apply_update(old_style()), Some(0..3));
+        assert_eq!(new.apply_update(annotation_style()), Some(0..3));
+        for cache in &[&old, &new] {
+            assert_eq!(highlights(cache, 0), vec![(4, 8, "selection")]);
+            assert_eq!(highlights(cache, 1), vec![(0, 2, "selection")]);
+            assert_eq!(highlights(cache, 2), vec![(0, 4, "find")]);
+            assert!(cache.has_selection());
+        }
+        // Styles other than the reserved ones stay styles either way.
+        assert_eq!(old.get_line(0).unwrap().styles()[0].style_id, 2);
+        assert_eq!(new.get_line(0).unwrap().styles().len(), 1);
+    }
+
+    #[test]
+    fn annotations_replace_the_highlights_before() {
+        let mut cache = LineCache::new();
+        cache.apply_update(annotation_style());
+        // Only the selection is sent now, moved to the last line; the
+        // lines that lost theirs are changed too.
+        let changed = cache.apply_update(update(json!({
+            "ops": [{"op": "copy", "n": 3}],
+            "annotations": [{"type": "selection", "ranges": [[2, 1, 2, 3]]}],
+        })));
+        assert_eq!(changed, Some(0..3));
+        assert!(!cache.text_changed());
+        assert_eq!(highlights(&cache, 0), vec![]);
+        assert_eq!(highlights(&cache, 1), vec![]);
+        assert_eq!(highlights(&cache, 2), vec![(1, 3, "selection")]);
+    }
+
+    #[test]
+    fn annotations_skip_lines_not_cached() {
+        let mut cache = LineCache::new();
+        let changed = cache.apply_update(update(json!({
+            "ops": [
+                {"op": "invalidate", "n": 1},
+                {"op": "ins", "lines": [{"text": "b\n"}]},
+            ],
+            "annotations": [{"type": "selection", "ranges": [[0, 0, 5, 1]]}],
+        })));
+        assert_eq!(changed, Some(0..2));
+        assert!(cache.get_line(0).is_none());
+        assert_eq!(highlights(&cache, 1), vec![(0, 2, "selection")]);
+    }
 }
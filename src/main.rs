@@ -28,6 +28,8 @@ extern crate directwrite;
 
 extern crate serde;
 #[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 
 extern crate xi_core_lib;
@@ -35,269 +37,3103 @@ extern crate xi_rpc;
 #[macro_use]
 extern crate xi_win_shell;
 
+mod accessibility;
+mod bookmarks;
+mod chrome;
+mod config_errors;
+mod definition;
 mod dialog;
+mod edit_command;
 mod edit_view;
+mod encoding;
+mod file_watcher;
+mod hover;
+mod infobar;
+mod keymap;
+mod line_edit;
 mod linecache;
 mod menus;
+mod minimap;
+mod overlay;
+mod paint_stats;
+mod palette;
+mod print;
+mod protocol;
 mod rpc;
+mod scrollbar;
+mod selection_count;
+mod settings;
+mod split;
+mod splitter;
+mod status_bar;
+mod taskbar;
+mod text_input;
+mod utf16;
+mod viewport;
+mod word_boundary;
 mod xi_thread;
 
 use std::any::Any;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::rc::{Rc, Weak};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+use winapi::shared::minwindef::{LPARAM, LRESULT, WPARAM};
 use winapi::shared::windef::*;
+use winapi::um::winuser::{VK_DOWN, VK_ESCAPE, VK_UP};
+
+use direct2d::math::Matrix3x2F;
 
 use serde_json::Value;
 
-use edit_view::EditView;
-use menus::MenuEntries;
-use rpc::{Core, Handler};
+use accessibility::{Accessibility, Request, Snapshot};
+use config_errors::ConfigError;
+use definition::{Location, Navigation};
+use edit_command::EditCommand;
+use edit_view::{CoreStall, EditSink, EditView, GutterColors, LineEnding, LineNumbers,
+    ShowWhitespace, STALL_POLL_MS};
+use encoding::{Contents, Encoding};
+use file_watcher::FileWatcher;
+use infobar::{InfoAction, Infobar};
+use keymap::{is_modifier, Binding, CharFilter, Keymap};
+use menus::{MenuEntries, BOOKMARK_MENU_BASE, LANGUAGE_MENU_BASE, PLUGIN_MENU_BASE,
+    RECENT_MENU_BASE};
+use overlay::{OverlayColors, OverlayResources, OverlayStyle};
+use palette::{Palette, PaletteEvent};
+use print::Printer;
+use protocol::{Notification, Update};
+use rpc::{Core, Handler, Responder};
+use settings::{SaveFixups, Session, SessionFile, Settings};
+use split::Split;
+use status_bar::{StatusBar, StatusItem, STATUS_BAR_HEIGHT};
 use xi_win_shell::util::Error;
-use dialog::{get_open_file_dialog_path, get_save_file_dialog_path};
+use dialog::{ask_save_changes, get_open_file_dialog_path, get_save_file_dialog_path,
+    show_alert, SaveChoice};
 use xi_thread::start_xi_thread;
 
-use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::clipboard;
+use xi_win_shell::paint::{PaintCtx, TextAntialias};
 use xi_win_shell::win_main::{self, RunLoopHandle};
-use xi_win_shell::window::{IdleHandle, WindowBuilder, WindowHandle, WinHandler};
+use xi_win_shell::window::{Cursor, IdleHandle, MouseButton, MouseType, TouchType,
+    WindowBuilder, WindowHandle, WinHandler, M_ALT, M_CTRL};
+
+/// Tokens for the timers running on the main window.
+#[repr(usize)]
+pub enum Timers {
+    CaretBlink = 1,
+    CoreStall,
+    FileChanged,
+    Autoscroll,
+    StatusNote,
+    WordMatches,
+    RetryPaint,
+    CountSelection,
+    TouchGlide,
+    Viewport,
+    Hover,
+    Definition,
+    Busy,
+    PrintWait,
+    SaveWait,
+}
+
+// Shown when an edit has gone unanswered for long enough that the core
+// doesn't seem to be responding.
+const CORE_STALL_TOAST: &str =
+    "The core is not responding. Your edits will be applied when it catches up.";
+
+// How long a note stays in the status bar.
+const STATUS_NOTE_MS: u32 = 2000;
+
+// How long the core can take to open or save a file before we show that
+// it's busy, so quick ones don't flash the indicator.
+const BUSY_DELAY_MS: u32 = 300;
+
+// How long to wait before trying again to draw a frame that failed.
+const RETRY_PAINT_MS: u32 = 250;
 
-struct MainWinState {
+// How long to wait for the core to send every line to print.
+const PRINT_WAIT_MS: u32 = 30_000;
+
+// How long a save waits for every line, for the fix-ups, before it goes
+// ahead with the lines there are.
+const SAVE_WAIT_MS: u32 = 10_000;
+
+// How long the open file has to be quiet after a change before we look at
+// it, as programs often write a file in several steps.
+const FILE_CHANGE_DEBOUNCE_MS: u32 = 200;
+// Changes to the file this soon after we asked the core to save it are
+// taken to be that save.
+const OWN_SAVE_WINDOW: Duration = Duration::from_secs(2);
+
+// The number of files in File > Open Recent.
+const MAX_RECENT_FILES: usize = 10;
+
+pub struct MainWinState {
+    // The focused pane, while the view is split.
     edit_view: EditView,
+    split: Option<Split>,
+    // The size of the area above the status bar that the panes share, in
+    // px units.
+    edit_size: (f32, f32),
+    // Across the top of the edit area, pushing the panes down, while it
+    // has a message.
+    infobar: Infobar,
+    status_bar: StatusBar,
+    // From the core's available_languages, sorted.
+    languages: Vec<String>,
+    // Most recent first, mirrored in the settings.
+    recent_files: Vec<String>,
+    // Also mirrored in the settings.
+    save_fixups: SaveFixups,
+    // The same, for the whole window's text.
+    text_antialias: TextAntialias,
+    palette: Palette,
+    // The commands the palette was opened with, while it's open.
+    palette_commands: Vec<PaletteCommand>,
+    // What the overlays over the edit view draw with.
+    overlay_style: OverlayStyle,
+    // Set between choosing a printer and having every line to print.
+    printer: Option<Printer>,
+    // The path to save to, while a save waits for every line, so the
+    // save fix-ups reach all of them.
+    pending_save: Option<String>,
+    // The file to encode the core's save to its UTF-8 copy back to, once
+    // the core has saved it.
+    pending_encode: Option<String>,
+    // The window's new_view requests the core hasn't answered, oldest
+    // first.
+    new_views: Vec<NewView>,
+}
+
+// A new_view request waiting for its answer: whether it's for the pane
+// without focus, and the view's id, once a message about the view has
+// come in ahead of the answer.
+struct NewView {
+    request: u64,
+    split: bool,
+    view_id: Option<String>,
+}
+
+/// A command in the command palette.
+struct PaletteCommand {
+    name: String,
+    run: Box<Fn(&MainWinHandler)>,
+}
+
+impl PaletteCommand {
+    // A command that does the same as a menu item.
+    fn menu<S: Into<String>>(name: S, id: u32) -> PaletteCommand {
+        PaletteCommand {
+            name: name.into(),
+            run: Box::new(move |handler| handler.command(id)),
+        }
+    }
+}
+
+impl MainWinState {
+    fn new() -> MainWinState {
+        MainWinState {
+            edit_view: EditView::new(),
+            split: None,
+            edit_size: (0.0, 0.0),
+            infobar: Infobar::new(),
+            status_bar: StatusBar::new(),
+            languages: Vec::new(),
+            recent_files: Vec::new(),
+            save_fixups: SaveFixups::default(),
+            text_antialias: TextAntialias::ClearType,
+            palette: Palette::new(),
+            palette_commands: Vec::new(),
+            overlay_style: OverlayStyle::new(),
+            printer: None,
+            pending_save: None,
+            pending_encode: None,
+            new_views: Vec::new(),
+        }
+    }
+
+    fn render(&mut self, p: &mut PaintCtx) -> Result<(), Error> {
+        p.set_text_antialias(self.text_antialias);
+        let (top, height) = self.panes_area();
+        render_pane(p, &mut self.edit_view)?;
+        if let Some(ref mut split) = self.split {
+            render_pane(p, &mut split.other)?;
+            split.render(p, top, self.edit_size.0, height)?;
+        }
+        self.status_bar.render(p)?;
+        if self.infobar.is_shown() || self.palette.is_open() {
+            let resources = self.overlay_style.resources(|colors|
+                OverlayResources::create(p, colors))?;
+            self.infobar.render(p, resources);
+            self.palette.render(p, resources);
+        }
+        Ok(())
+    }
+
+    // The top of the area the panes share, below the infobar, and its
+    // height.
+    fn panes_area(&self) -> (f32, f32) {
+        let top = self.infobar.height().min(self.edit_size.1);
+        (top, self.edit_size.1 - top)
+    }
+
+    // Whether either pane shows the view.
+    fn has_view(&self, view_id: &str) -> bool {
+        self.edit_view.view_id == view_id ||
+            self.split.as_ref().map_or(false, |split| split.other.view_id == view_id)
+    }
+
+    // The request for a view the core hasn't answered new_view for yet,
+    // but has sent messages about.
+    fn new_view(&self, view_id: &str) -> Option<&NewView> {
+        self.new_views.iter().find(|v| v.view_id.as_ref().map_or(false, |id| id == view_id))
+    }
+
+    // Whether a message about a view is for the pane that doesn't have
+    // focus.
+    fn is_other_pane(&self, view_id: Option<&str>) -> bool {
+        match (view_id, self.split.as_ref()) {
+            (Some(view_id), Some(split)) => split.other.view_id == view_id ||
+                self.new_view(view_id).map_or(false, |v| v.split),
+            _ => false,
+        }
+    }
+
+    // The pane waiting on a definition request. Both panes can show the
+    // same view, so the request's id says which.
+    fn definition_pane(&mut self, request_id: usize) -> Option<&mut EditView> {
+        if self.edit_view.awaits_definition(request_id) {
+            return Some(&mut self.edit_view);
+        }
+        match self.split {
+            Some(ref mut split) if split.other.awaits_definition(request_id) => {
+                Some(&mut split.other)
+            }
+            _ => None,
+        }
+    }
+
+    // The pane a message about a view is for.
+    fn pane_mut(&mut self, view_id: Option<&str>) -> &mut EditView {
+        let other = self.is_other_pane(view_id);
+        match self.split {
+            Some(ref mut split) if other => &mut split.other,
+            _ => &mut self.edit_view,
+        }
+    }
+}
+
+// Draw a pane where it is in the window, and only there.
+fn render_pane(p: &mut PaintCtx, pane: &mut EditView) -> Result<(), Error> {
+    let (x0, y0, x1, y1) = pane.bounds();
+    p.push_clip(x0, y0, x1, y1);
+    p.render_target().set_transform(&Matrix3x2F::new([[1.0, 0.0], [0.0, 1.0], [x0, y0]]));
+    let result = pane.render(p);
+    p.render_target().set_transform(&Matrix3x2F::new([[1.0, 0.0], [0.0, 1.0], [0.0, 0.0]]));
+    p.pop_clip();
+    result
+}
+
+struct MainWinHandler {
+    win: Rc<MainWin>,
+}
+
+impl MainWinHandler {
+    // Keys go to the palette while it's open, even ones it doesn't use,
+    // so they don't reach the buffer.
+    fn palette_keydown(&self, vk_code: i32, mods: u32) {
+        let event = self.win.state.borrow_mut().palette.keydown(vk_code, mods);
+        match event {
+            Some(PaletteEvent::Run(ix)) => {
+                let command = {
+                    let mut state = self.win.state.borrow_mut();
+                    state.palette.close();
+                    mem::replace(&mut state.palette_commands, Vec::new()).into_iter().nth(ix)
+                };
+                self.win.invalidate();
+                if let Some(command) = command {
+                    (command.run)(self);
+                }
+            }
+            Some(PaletteEvent::Dismiss) => self.win.close_palette(),
+            None => self.win.invalidate(),
+        }
+    }
+
+    // Cut, copy, paste and select all go to the palette's query while it's
+    // open. Returns whether the command was the palette's.
+    fn palette_edit(&self, id: u32) -> bool {
+        let mut state = self.win.state.borrow_mut();
+        if !state.palette.is_open() {
+            return false;
+        }
+        let handle = self.win.handle.borrow();
+        match id {
+            x if x == MenuEntries::Cut as u32 => state.palette.edit_query(|q| q.cut(&handle)),
+            x if x == MenuEntries::Copy as u32 => state.palette.edit_query(|q| {
+                q.copy(&handle);
+                false
+            }),
+            x if x == MenuEntries::Paste as u32 => state.palette.edit_query(|q| q.paste()),
+            x if x == MenuEntries::SelectAll as u32 => state.palette.edit_query(|q| {
+                q.select_all();
+                false
+            }),
+            _ => return false,
+        }
+        self.win.invalidate();
+        true
+    }
+
+    // Dragging the splitter between the panes resizes them, and clicking
+    // it gives it the focus for Ctrl+Alt+Up and Down, until a click
+    // elsewhere. Returns whether the event was the splitter's.
+    fn splitter_mouse(&self, y: f32, which: MouseButton, ty: MouseType) -> bool {
+        let changed = {
+            let state = &mut *self.win.state.borrow_mut();
+            let (top, height) = state.panes_area();
+            let splitter = match state.split {
+                Some(ref mut split) => &mut split.splitter,
+                None => return false,
+            };
+            match ty {
+                MouseType::Down if which == MouseButton::Left
+                    && splitter.contains(y - top, height) => splitter.press(y - top, height),
+                MouseType::Up if which == MouseButton::Left && splitter.is_dragging() => {
+                    splitter.release();
+                    true
+                }
+                MouseType::Down => {
+                    if splitter.set_focused(false) {
+                        self.win.invalidate();
+                    }
+                    return false;
+                }
+                _ => return false,
+            }
+        };
+        // A drag ended, or a double-click put it back.
+        if changed {
+            self.win.layout_panes();
+            self.win.save_split_height();
+        }
+        self.win.invalidate();
+        true
+    }
+
+    // While the splitter has the focus, Ctrl+Alt+Up and Down move it, and
+    // any other key gives the focus back to the panes. Returns whether the
+    // key was the splitter's.
+    fn splitter_keydown(&self, vk_code: i32, mods: u32) -> bool {
+        let moved = {
+            let state = &mut *self.win.state.borrow_mut();
+            let (_, height) = state.panes_area();
+            let splitter = match state.split {
+                Some(ref mut split) if split.splitter.is_focused() => &mut split.splitter,
+                _ => return false,
+            };
+            match vk_code {
+                VK_UP if mods == M_CTRL | M_ALT => splitter.step(true, height),
+                VK_DOWN if mods == M_CTRL | M_ALT => splitter.step(false, height),
+                _ if is_modifier(vk_code) => return false,
+                _ => {
+                    splitter.set_focused(false);
+                    self.win.invalidate();
+                    return false;
+                }
+            }
+        };
+        if moved {
+            self.win.layout_panes();
+            self.win.save_split_height();
+            self.win.invalidate();
+        }
+        true
+    }
+
+    // Run the action of an infobar button, which also dismisses its
+    // message.
+    fn run_info_action(&self, action: InfoAction) {
+        self.win.dismiss_info();
+        match action {
+            InfoAction::Reload(path) => self.win.reload_file(path),
+            InfoAction::SaveAs => self.command(MenuEntries::SaveAs as u32),
+            InfoAction::ShowConfigErrors => self.win.show_config_errors(),
+            InfoAction::Dismiss => (),
+        }
+    }
+}
+
+// The open file, watched for changes made by other programs.
+struct WatchedFile {
+    _watcher: FileWatcher,
+    path: String,
+    // Modification time as of the last time we looked.
+    mtime: Option<SystemTime>,
+    last_save: Option<Instant>,
+}
+
+/// What the top-level windows share: the connection to the core, the
+/// settings, and the list of windows, for routing messages from the core
+/// to the window showing the view they're about.
+struct App {
+    core: Core,
+    settings: RefCell<Settings>,
+    // What was wrong with settings.json at startup, if anything.
+    settings_error: Option<ConfigError>,
+    keymap: RefCell<Keymap>,
+    // What was wrong with the keymap the last time it was loaded.
+    keymap_errors: RefCell<Vec<ConfigError>>,
+    windows: RefCell<Vec<Weak<MainWin>>>,
+    // The most recently focused window, which gets the messages that
+    // aren't about one view.
+    active: RefCell<Weak<MainWin>>,
+    // The idle queue that messages from the core are posted to; it's one
+    // of the windows', and changes when that window closes.
+    idle_handle: Arc<Mutex<Option<IdleHandle>>>,
+    // Whether the first window has told the core we've started.
+    started: Cell<bool>,
+    // Whether the chrome is laid out right to left.
+    mirrored: bool,
+    // Where jumps to definitions came from, in any window.
+    navigation: RefCell<Navigation>,
+    save_all: RefCell<Option<SaveAll>>,
+    // From the core's theme_changed, for windows opened after it.
+    gutter_colors: Cell<GutterColors>,
+    overlay_colors: Cell<OverlayColors>,
+    theme_name: RefCell<Option<String>>,
+}
+
+// A Save All waiting to hear how its saves went: how many it hasn't heard
+// about yet, how many were saved, and why the others weren't.
+struct SaveAll {
+    pending: usize,
+    saved: usize,
+    failures: Vec<String>,
+}
+
+impl App {
+    fn windows(&self) -> Vec<Rc<MainWin>> {
+        self.windows.borrow().iter().filter_map(|win| win.upgrade()).collect()
+    }
+
+    fn active_window(&self) -> Option<Rc<MainWin>> {
+        self.active.borrow().upgrade().or_else(|| self.windows().into_iter().next())
+    }
+
+    /// Everything known to be wrong with the configuration files.
+    fn config_errors(&self) -> Vec<ConfigError> {
+        let mut errors: Vec<ConfigError> = self.settings_error.iter().cloned().collect();
+        errors.extend(self.keymap_errors.borrow().iter().cloned());
+        errors
+    }
+
+    fn add_window(&self, win: &Rc<MainWin>) {
+        self.windows.borrow_mut().push(Rc::downgrade(win));
+    }
+
+    // Count an answer to one of a Save All's saves: a view becoming
+    // pristine, or an alert, which is taken to be a failed save. Returns
+    // whether a Save All was waiting for it.
+    fn save_all_answered(&self, failure: Option<String>) -> bool {
+        let done = match *self.save_all.borrow_mut() {
+            Some(ref mut save_all) => {
+                save_all.pending = save_all.pending.saturating_sub(1);
+                match failure {
+                    Some(msg) => save_all.failures.push(msg),
+                    None => save_all.saved += 1,
+                }
+                save_all.pending == 0
+            }
+            None => return false,
+        };
+        if done {
+            self.finish_save_all();
+        }
+        true
+    }
+
+    // Report how a Save All went, in the status bar, with one message box
+    // for all the files that couldn't be saved.
+    fn finish_save_all(&self) {
+        let save_all = match self.save_all.borrow_mut().take() {
+            Some(save_all) => save_all,
+            None => return,
+        };
+        for win in self.windows() {
+            win.saving_all.set(false);
+        }
+        let win = match self.active_window() {
+            Some(win) => win,
+            None => return,
+        };
+        let note = match save_all.saved {
+            0 if save_all.failures.is_empty() => "No unsaved changes".to_owned(),
+            1 => "Saved 1 file".to_owned(),
+            n => format!("Saved {} files", n),
+        };
+        win.show_note(&mut win.state.borrow_mut(), &note);
+        if !save_all.failures.is_empty() {
+            win.alert(&format!("Some files couldn't be saved:\n\n{}",
+                save_all.failures.join("\n")));
+        }
+    }
+
+    // Forget a closed window. Returns whether it was the last one.
+    fn remove_window(&self, win: &MainWin) -> bool {
+        self.windows.borrow_mut()
+            .retain(|w| w.upgrade().map_or(false, |w| &*w as *const MainWin != win as *const _));
+        let windows = self.windows();
+        *self.idle_handle.lock().unwrap() =
+            windows.first().and_then(|w| w.handle.borrow().get_idle_handle());
+        windows.is_empty()
+    }
+
+    // The window showing a view. Messages about a new view can come in
+    // before the core has answered new_view with its id. The core answers
+    // requests in turn, so a view we haven't heard of is the oldest
+    // request's that hasn't had messages yet, in whichever window asked.
+    fn window_for_view(&self, view_id: &str) -> Option<Rc<MainWin>> {
+        let windows = self.windows();
+        if let Some(win) = windows.iter().find(|w| w.state.borrow().has_view(view_id)) {
+            return Some(win.clone());
+        }
+        if let Some(win) = windows.iter().find(|w| w.state.borrow().new_view(view_id).is_some()) {
+            // Unless it was for a split that's been closed since.
+            let state = win.state.borrow();
+            let closed = state.new_view(view_id).map_or(false, |v| v.split) &&
+                state.split.is_none();
+            return if closed { None } else { Some(win.clone()) };
+        }
+        let oldest = windows.iter()
+            .filter_map(|w| {
+                let state = w.state.borrow();
+                let request = state.new_views.iter().find(|v| v.view_id.is_none())
+                    .map(|v| v.request);
+                request.map(|request| (request, w))
+            })
+            .min_by_key(|&(request, _)| request);
+        let (request, win) = oldest?;
+        for v in win.state.borrow_mut().new_views.iter_mut().filter(|v| v.request == request) {
+            v.view_id = Some(view_id.to_owned());
+        }
+        Some(win.clone())
+    }
+
+    /// Route a notification from the core: to the window with its view,
+    /// or to all of them if it isn't about a view. Alerts go to the
+    /// active window.
+    fn handle_cmd(&self, notification: Notification) {
+        let (method, params) = match notification {
+            Notification::Update { view_id, update } => {
+                // Updates for views that have since been closed are dropped.
+                if let Some(win) = self.window_for_view(&view_id) {
+                    win.handle_update(&view_id, update);
+                }
+                return;
+            }
+            Notification::Other { method, params } => (method, params),
+        };
+        let (method, params) = (method.as_str(), &params);
+        if method == "alert" {
+            // Likely a save that failed, so windows waiting for theirs to
+            // close stay open.
+            for win in self.windows() {
+                win.closing.set(false);
+                win.end_busy();
+            }
+            if let Some(msg) = params["msg"].as_str() {
+                if self.save_all_answered(Some(msg.to_owned())) {
+                    return;
+                }
+            }
+            if let Some(win) = self.active_window() {
+                win.handle_cmd(method, params);
+            }
+            return;
+        }
+        match params["view_id"].as_str() {
+            Some(view_id) => {
+                // Messages for views that have since been closed are dropped.
+                if let Some(win) = self.window_for_view(view_id) {
+                    win.handle_cmd(method, params);
+                }
+            }
+            None => {
+                for win in self.windows() {
+                    win.handle_cmd(method, params);
+                }
+            }
+        }
+    }
+}
+
+/// Work on a file that can keep the core busy for a while.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Busy {
+    Opening,
+    Saving,
+}
+
+// Maybe combine all this, put as a single item inside a RefCell.
+pub struct MainWin {
+    app: Rc<App>,
+    core: RefCell<Core>,
+    handle: RefCell<WindowHandle>,
+    state: RefCell<MainWinState>,
+    watched_file: RefCell<Option<WatchedFile>>,
+    // Set while reloading the file with the split open, until the split's
+    // pane gets its new view.
+    reopen_split: Cell<bool>,
+    // Set while the window waits for a save to finish before closing.
+    closing: Cell<bool>,
+    // Set while a Save All waits for this window's save.
+    saving_all: Cell<bool>,
+    // What the window is waiting on the core for, and whether that's
+    // being shown, which it is once it's taken BUSY_DELAY_MS.
+    busy: Cell<Option<Busy>>,
+    busy_shown: Cell<bool>,
+    // Messages waiting to be shown, and whether one is showing.
+    alerts: RefCell<VecDeque<String>>,
+    showing_alert: Cell<bool>,
+    // Made when a screen reader or other client first asks for it.
+    accessibility: RefCell<Option<Accessibility>>,
+    // Which typed characters go into the text or the palette.
+    char_filter: RefCell<CharFilter>,
+    // The id of the last Ctrl+click definition request from either pane.
+    // Both panes can show the same view, so answers find the pane that
+    // asked by it.
+    definition_requests: Cell<usize>,
+}
+
+impl MainWin {
+    fn new(app: Rc<App>, state: MainWinState) -> MainWin {
+        MainWin {
+            core: RefCell::new(app.core.clone()),
+            app,
+            handle: Default::default(),
+            state: RefCell::new(state),
+            watched_file: Default::default(),
+            reopen_split: Cell::new(false),
+            closing: Cell::new(false),
+            saving_all: Cell::new(false),
+            busy: Cell::new(None),
+            busy_shown: Cell::new(false),
+            alerts: Default::default(),
+            showing_alert: Cell::new(false),
+            accessibility: Default::default(),
+            char_filter: RefCell::new(CharFilter::new()),
+            definition_requests: Cell::new(0),
+        }
+    }
+
+    /// Show a message to the user. Messages are shown one at a time, so a
+    /// burst of them doesn't stack up dialogs; ones that arrive while a
+    /// message box is up are queued behind it.
+    ///
+    /// The message box runs a modal loop, so the caller can't be borrowing
+    /// the state.
+    fn alert(&self, msg: &str) {
+        self.alerts.borrow_mut().push_back(msg.to_owned());
+        if self.showing_alert.get() {
+            return;
+        }
+        self.showing_alert.set(true);
+        let hwnd = self.handle.borrow().get_hwnd().unwrap_or(null_mut());
+        loop {
+            let msg = match self.alerts.borrow_mut().pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+            unsafe { show_alert(hwnd, &msg) };
+        }
+        self.showing_alert.set(false);
+    }
+
+    /// Show a message in the infobar, which unlike an alert doesn't stop
+    /// the user. Messages are shown one at a time.
+    fn show_info(&self, message: String, actions: Vec<InfoAction>) {
+        self.state.borrow_mut().infobar.show(message, actions);
+        self.layout_panes();
+        self.invalidate();
+    }
+
+    fn dismiss_info(&self) {
+        self.state.borrow_mut().infobar.dismiss();
+        self.layout_panes();
+        self.invalidate();
+    }
+
+    fn send_notification(&self, method: &str, params: &Value) {
+        self.core.borrow().send_notification(method, params);
+    }
+
+    /// Send an edit request for a view. The callback is run with the result
+    /// from an idle handler on the UI thread.
+    fn send_edit_request<F>(&self, cmd: &EditCommand, view_id: &str, callback: F)
+        where F: FnOnce(&MainWinHandler, &Value) + Send + 'static
+    {
+        let handle = self.handle.borrow().get_idle_handle().unwrap();
+        self.core.borrow_mut().send_request("edit", &cmd.edit_params(view_id),
+            move |value| {
+                let value = value.clone();
+                handle.add_idle(move |a| {
+                    let handler = a.downcast_ref::<MainWinHandler>().unwrap();
+                    callback(handler, &value);
+                });
+            }
+        );
+    }
+
+    // Wait for the core to open or save the file, showing that it's busy
+    // if it takes long enough to notice.
+    fn start_busy(&self, busy: Busy) {
+        self.busy.set(Some(busy));
+        self.handle.borrow().set_timer(Timers::Busy as usize, BUSY_DELAY_MS);
+    }
+
+    fn show_busy(&self) {
+        self.handle.borrow().kill_timer(Timers::Busy as usize);
+        let busy = match self.busy.get() {
+            Some(busy) if !self.busy_shown.get() => busy,
+            _ => return,
+        };
+        self.busy_shown.set(true);
+        if let Some(hwnd) = self.handle.borrow().get_hwnd() {
+            taskbar::set_busy(hwnd, true);
+        }
+        if busy == Busy::Opening {
+            self.state.borrow_mut().edit_view.set_loading(true);
+            self.invalidate();
+        }
+    }
+
+    // The core has opened or saved the file, or failed to.
+    fn end_busy(&self) {
+        if self.busy.get().is_none() {
+            return;
+        }
+        self.busy.set(None);
+        self.handle.borrow().kill_timer(Timers::Busy as usize);
+        if self.busy_shown.get() {
+            self.busy_shown.set(false);
+            if let Some(hwnd) = self.handle.borrow().get_hwnd() {
+                taskbar::set_busy(hwnd, false);
+            }
+            self.state.borrow_mut().edit_view.set_loading(false);
+            self.invalidate();
+        }
+    }
+
+    /// Polled while an edit is waiting for the core; updates the busy
+    /// indicator in the status bar, and says in a toast when the core isn't
+    /// responding.
+    fn check_core_stall(&self) {
+        let stall = {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+            let stall = state.edit_view.check_core_stall(Instant::now(), self);
+            state.status_bar.set_busy(match stall {
+                CoreStall::Answered | CoreStall::Pending => None,
+                CoreStall::Waiting => Some("Waiting for core\u{2026}"),
+                CoreStall::NotResponding { .. } => Some("The core is not responding"),
+            });
+            stall
+        };
+        match stall {
+            CoreStall::NotResponding { toast: true } => {
+                self.show_info(CORE_STALL_TOAST.to_owned(), vec![InfoAction::Dismiss]);
+            }
+            CoreStall::NotResponding { .. } => (),
+            _ => {
+                // It's caught up, so the toast no longer holds.
+                if self.state.borrow_mut().infobar.withdraw(CORE_STALL_TOAST) {
+                    self.layout_panes();
+                }
+            }
+        }
+        if stall == CoreStall::Answered {
+            self.handle.borrow().kill_timer(Timers::CoreStall as usize);
+        }
+        self.invalidate();
+    }
+
+    // TODO: arguably these should be moved to MainWinHandler to avoid the need
+    // for the parent reference.
+    fn file_open(&self, hwnd_owner: HWND) {
+        let filename = unsafe { get_open_file_dialog_path(hwnd_owner) };
+        if let Some(filename) = filename {
+            self.open_file(filename);
+        }
+    }
+
+    fn open_file(&self, filename: String) {
+        self.open_read(filename, encoding::read);
+    }
+
+    // Open a file the core refused, though it started as UTF-8, as it has
+    // invalid bytes further on.
+    fn open_lossy(&self, filename: String) {
+        self.open_read(filename, encoding::read_lossy);
+    }
+
+    fn open_read(&self, filename: String, read: fn(&str) -> io::Result<Contents>) {
+        // The core only reads UTF-8; anything else is decoded into a copy
+        // for it to open, which is encoded back to the file when it's
+        // saved.
+        let read = read(&filename).and_then(|contents| {
+            let copy = match contents.decoded {
+                Some(ref text) => Some(encoding::write_copy(&filename, text)?),
+                None => None,
+            };
+            Ok((contents, copy))
+        });
+        let (contents, copy) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                self.show_info(format!("Couldn't open {}: {}", filename, e),
+                    vec![InfoAction::Dismiss]);
+                return;
+            }
+        };
+        let encoding = contents.encoding;
+        let core_path = copy.as_ref().map_or_else(|| filename.clone(), |copy| copy.path());
+        self.forget_places();
+        self.req_new_view(Some(&core_path));
+        self.start_busy(Busy::Opening);
+        self.add_recent_file(&filename);
+        self.watch_file(&filename);
+        // A file we can't write is opened read-only, and so is one with
+        // bytes that had to be replaced, as saving would lose them.
+        let read_only = encoding == Encoding::Invalid ||
+            fs::metadata(&filename).map(|m| m.permissions().readonly()).unwrap_or(false);
+        self.set_read_only(read_only);
+        if encoding == Encoding::Invalid {
+            self.show_info(format!("{} isn't valid UTF-8. It's shown read-only, with the invalid \
+                bytes replaced.", filename), vec![InfoAction::Dismiss]);
+        }
+        if read_only && encoding != Encoding::Invalid {
+            self.show_info(format!("{} is read-only.", filename),
+                vec![InfoAction::SaveAs, InfoAction::Dismiss]);
+        }
+        // Files without line breaks get the default for new files. A big
+        // UTF-8 file is told by its start.
+        let line_ending = LineEnding::detect(contents.sample())
+            .unwrap_or_else(|| self.app.settings.borrow().line_ending());
+        let mut state = self.state.borrow_mut();
+        state.edit_view.set_encoding(encoding);
+        state.edit_view.set_temp_copy(copy);
+        state.status_bar.set_encoding(encoding);
+        state.edit_view.set_line_ending(line_ending);
+        state.status_bar.set_line_ending(line_ending);
+        state.edit_view.filename = Some(filename);
+        state.edit_view.clear_line_cache();
+    }
+
+    // Returns whether a save was sent, which it isn't if Save As was
+    // cancelled.
+    fn file_save(&self, hwnd_owner: HWND) -> bool {
+        let filename: Option<String> = self.state.borrow_mut().edit_view.filename.clone();
+        // A read-only view can only be saved somewhere else.
+        let read_only = self.state.borrow().edit_view.is_read_only();
+        if let (Some(filename), false) = (filename, read_only) {
+            let pristine = self.state.borrow().edit_view.is_pristine();
+            self.save_when_ready(&filename);
+            // There's no telling when saving a pristine file is done.
+            if !pristine {
+                self.start_busy(Busy::Saving);
+            }
+            self.add_recent_file(&filename);
+            true
+        } else {
+            self.file_save_as(hwnd_owner)
+        }
+    }
+
+    fn file_save_as(&self, hwnd_owner: HWND) -> bool {
+        if let Some(filename) = unsafe { get_save_file_dialog_path(hwnd_owner) } {
+            // The copy can be edited, unlike the file it's of.
+            self.set_read_only(false);
+            let pristine = self.state.borrow().edit_view.is_pristine();
+            self.save_when_ready(&filename);
+            if !pristine {
+                self.start_busy(Busy::Saving);
+            }
+            self.add_recent_file(&filename);
+            self.watch_file(&filename);
+            self.state.borrow_mut().edit_view.filename = Some(filename);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Save the files of every window that has unsaved changes, asking
+    /// where to save untitled ones, one at a time. Read-only files are
+    /// left unsaved. How it went is reported once the core has answered
+    /// every save.
+    fn save_all(&self) {
+        if self.app.save_all.borrow().is_some() {
+            return;
+        }
+        let mut pending = 0;
+        let mut failures = Vec::new();
+        for win in self.app.windows() {
+            let (pristine, read_only, name) = {
+                let edit_view = &win.state.borrow().edit_view;
+                (edit_view.is_pristine(), edit_view.is_read_only(),
+                    edit_view.filename.clone().unwrap_or_else(|| "Untitled".to_owned()))
+            };
+            if pristine {
+                continue;
+            }
+            if read_only {
+                failures.push(format!("{}: it's open read-only", name));
+                continue;
+            }
+            let hwnd = win.handle.borrow().get_hwnd().unwrap_or(null_mut());
+            // An untitled file whose Save As is cancelled is skipped.
+            if win.file_save(hwnd) {
+                win.saving_all.set(true);
+                pending += 1;
+            }
+        }
+        *self.app.save_all.borrow_mut() = Some(SaveAll { pending, saved: 0, failures });
+        if pending == 0 {
+            self.app.finish_save_all();
+        }
+    }
+
+    /// Close windows, once the user has chosen to save or discard their
+    /// unsaved changes, in one message box for all of them. Untitled files
+    /// go through Save As, and cancelling that cancels the whole close,
+    /// as does Cancel. The windows being saved close when the core reports
+    /// them saved, and stay open if a save fails.
+    fn close_windows(&self, windows: Vec<Rc<MainWin>>) {
+        let dirty: Vec<Rc<MainWin>> = windows.iter()
+            .filter(|win| !win.state.borrow().edit_view.is_pristine())
+            .cloned()
+            .collect();
+        if !dirty.is_empty() {
+            let names: Vec<String> = dirty.iter().map(|win| {
+                win.state.borrow().edit_view.filename.clone()
+                    .unwrap_or_else(|| "Untitled".to_owned())
+            }).collect();
+            let msg = if names.len() == 1 {
+                format!("Save the changes to {}?", names[0])
+            } else {
+                format!("Save the changes to these files?\n\n{}", names.join("\n"))
+            };
+            let hwnd = self.handle.borrow().get_hwnd().unwrap_or(null_mut());
+            match unsafe { ask_save_changes(hwnd, &msg) } {
+                SaveChoice::Save => {
+                    for win in &dirty {
+                        let hwnd = win.handle.borrow().get_hwnd().unwrap_or(null_mut());
+                        if !win.file_save(hwnd) {
+                            for win in &dirty {
+                                win.closing.set(false);
+                            }
+                            return;
+                        }
+                        win.closing.set(true);
+                    }
+                }
+                SaveChoice::Discard => (),
+                SaveChoice::Cancel => return,
+            }
+        }
+        for win in windows {
+            if !win.closing.get() {
+                let handle = win.handle.borrow().clone();
+                handle.close();
+            }
+        }
+    }
+
+    // Save the focused pane's document to a path. The save fix-ups edit
+    // every line, so if they're on, the core is first asked for any lines
+    // the cache doesn't have, as for printing, and the save waits for
+    // them.
+    fn save_when_ready(&self, filename: &str) {
+        let mut state = self.state.borrow_mut();
+        let fixups = state.save_fixups;
+        if !fixups.trim_whitespace && !fixups.final_newline {
+            return self.send_save(&mut state, filename);
+        }
+        state.pending_save = Some(filename.to_owned());
+        state.edit_view.hold_all_lines(self);
+        self.save_if_ready(&mut state, false);
+        if state.pending_save.is_some() {
+            self.handle.borrow().set_timer(Timers::SaveWait as usize, SAVE_WAIT_MS);
+            self.show_note(&mut state, "Getting the lines to save");
+        }
+    }
+
+    // Send the save waiting for lines, if they're all here, or if `anyway`,
+    // when it's waited long enough; the fix-ups then only reach the lines
+    // there are.
+    fn save_if_ready(&self, state: &mut MainWinState, anyway: bool) {
+        if state.pending_save.is_none() {
+            return;
+        }
+        if !anyway && !state.edit_view.has_all_lines() {
+            // Lines added since asking are needed too.
+            state.edit_view.hold_all_lines(self);
+            return;
+        }
+        let filename = state.pending_save.take().unwrap();
+        self.handle.borrow().kill_timer(Timers::SaveWait as usize);
+        self.send_save(state, &filename);
+        if anyway {
+            self.show_note(state, "Saved before the whole document loaded; the fix-ups may have \
+                missed some of it");
+        }
+        // Printing may still want them.
+        if state.printer.is_none() {
+            self.release_all_lines(state);
+        }
+    }
+
+    // Make the edits that go with saving, then send the save; the core
+    // takes them in order, so the save has them.
+    fn send_save(&self, state: &mut MainWinState, filename: &str) {
+        self.prepare_save(state);
+        let encoding = state.edit_view.encoding();
+        let copy = if encoding.is_utf8() { None } else { state.edit_view.temp_copy_path() };
+        let copy = match copy {
+            Some(copy) => copy,
+            None => {
+                self.send_notification("save", &json!({
+                    "view_id": state.edit_view.view_id,
+                    "file_path": filename,
+                }));
+                self.note_own_save();
+                return;
+            }
+        };
+        // The core saves its UTF-8 copy, which is then encoded back to the
+        // file. It answers in order, so once it's answered the copy is
+        // saved.
+        self.send_notification("save", &json!({
+            "view_id": state.edit_view.view_id,
+            "file_path": copy,
+        }));
+        state.pending_encode = Some(filename.to_owned());
+        self.send_edit_request(&EditCommand::Copy, &state.edit_view.view_id, move |handler, _| {
+            handler.win.encode_saved_copy(&copy, encoding);
+        });
+    }
+
+    // Write the copy the core saved to the file it's of, in the encoding
+    // the file was opened in. Text that encoding can't hold is saved as
+    // UTF-8 instead, by the core, which then has the file itself open.
+    fn encode_saved_copy(&self, copy: &str, encoding: Encoding) {
+        let filename = match self.state.borrow_mut().pending_encode.take() {
+            Some(filename) => filename,
+            None => return,
+        };
+        let encoded = fs::read_to_string(copy).map(|text| encoding::encode(&text, encoding));
+        let written = match encoded {
+            Ok(Some(bytes)) => {
+                self.note_own_save();
+                fs::write(&filename, bytes)
+            }
+            Ok(None) => {
+                {
+                    let mut state = self.state.borrow_mut();
+                    state.edit_view.set_encoding(Encoding::Utf8);
+                    state.status_bar.set_encoding(Encoding::Utf8);
+                    self.send_notification("save", &json!({
+                        "view_id": state.edit_view.view_id,
+                        "file_path": filename,
+                    }));
+                }
+                self.note_own_save();
+                self.show_info(format!("{} has characters {} doesn't, so it was saved as UTF-8.",
+                    filename, encoding.label()), vec![InfoAction::Dismiss]);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            let msg = format!("Couldn't save {}: {}", filename, e);
+            // The view is pristine, but the file isn't saved, so it mustn't
+            // close.
+            self.closing.set(false);
+            if self.saving_all.replace(false) {
+                self.app.save_all_answered(Some(msg.clone()));
+            }
+            self.show_info(msg, vec![InfoAction::Dismiss]);
+        }
+        self.finish_cmd(false, false, false, None);
+    }
+
+    fn prepare_save(&self, state: &mut MainWinState) {
+        // A file whose invalid bytes were replaced can't be encoded back,
+        // so it's written as UTF-8.
+        if !state.edit_view.encoding().can_encode() {
+            state.edit_view.set_encoding(Encoding::Utf8);
+            state.status_bar.set_encoding(Encoding::Utf8);
+        }
+        let fixups = state.save_fixups;
+        if fixups.trim_whitespace {
+            state.edit_view.trim_trailing_whitespace(self);
+        }
+        if fixups.final_newline {
+            state.edit_view.ensure_final_newline(fixups.trim_whitespace, self);
+        }
+    }
+
+    fn set_save_fixups(&self, fixups: SaveFixups) {
+        {
+            let mut settings = self.app.settings.borrow_mut();
+            settings.set_save_fixups(fixups);
+            if let Err(e) = settings.save() {
+                println!("failed to save settings: {}", e);
+            }
+        }
+        // They're the same for every window.
+        for win in self.app.windows() {
+            win.state.borrow_mut().save_fixups = fixups;
+            let handle = win.handle.borrow();
+            handle.check_menu_item(MenuEntries::TrimOnSave as u32, fixups.trim_whitespace);
+            handle.check_menu_item(MenuEntries::FinalNewlineOnSave as u32, fixups.final_newline);
+        }
+    }
+
+    fn set_line_numbers(&self, mode: LineNumbers) {
+        {
+            let mut settings = self.app.settings.borrow_mut();
+            settings.set_line_numbers(mode);
+            if let Err(e) = settings.save() {
+                println!("failed to save settings: {}", e);
+            }
+        }
+        for win in self.app.windows() {
+            {
+                let state = &mut *win.state.borrow_mut();
+                state.edit_view.set_line_numbers(mode);
+                state.edit_view.update_wrap_width(&win);
+                if let Some(ref mut split) = state.split {
+                    split.other.set_line_numbers(mode);
+                    split.other.update_wrap_width(&win);
+                }
+            }
+            let handle = win.handle.borrow();
+            for &(entry_mode, id) in &menus::LINE_NUMBERS_ENTRIES {
+                handle.check_menu_item(id, entry_mode == mode);
+            }
+            handle.invalidate();
+        }
+    }
+
+    fn set_text_antialias(&self, mode: TextAntialias) {
+        {
+            let mut settings = self.app.settings.borrow_mut();
+            settings.set_text_antialias(mode);
+            if let Err(e) = settings.save() {
+                println!("failed to save settings: {}", e);
+            }
+        }
+        for win in self.app.windows() {
+            win.state.borrow_mut().text_antialias = mode;
+            let handle = win.handle.borrow();
+            for &(entry_mode, id) in &menus::ANTIALIAS_ENTRIES {
+                handle.check_menu_item(id, entry_mode == mode);
+            }
+            handle.invalidate();
+        }
+    }
+
+    // Convert the document to one line ending; both panes show it.
+    fn convert_line_endings(&self, line_ending: LineEnding) {
+        {
+            let state = &mut *self.state.borrow_mut();
+            state.edit_view.convert_line_endings(line_ending, self);
+            let line_ending = state.edit_view.line_ending();
+            if let Some(ref mut split) = state.split {
+                split.other.set_line_ending(line_ending);
+            }
+            state.status_bar.set_line_ending(line_ending);
+        }
+        self.rebuild_menus();
+        self.invalidate();
+    }
+
+    // Choose a printer, then have the core send every line of the
+    // document; the job runs once they're all in the line cache. The view
+    // holds on to them until then, however it scrolls. Waiting stops
+    // after a while, or on Escape.
+    fn print(&self, hwnd_owner: HWND) {
+        let printer = match print::choose_printer(hwnd_owner) {
+            Some(printer) => printer,
+            None => return,
+        };
+        let mut state = self.state.borrow_mut();
+        state.edit_view.hold_all_lines(self);
+        state.printer = Some(printer);
+        self.print_if_ready(&mut state);
+        if state.printer.is_some() {
+            self.handle.borrow().set_timer(Timers::PrintWait as usize, PRINT_WAIT_MS);
+            self.show_note(&mut state, "Getting the lines to print (Escape cancels)");
+        }
+    }
+
+    // Run the print job waiting for lines, if they're all here.
+    fn print_if_ready(&self, state: &mut MainWinState) {
+        if state.printer.is_none() {
+            return;
+        }
+        let lines = match state.edit_view.all_lines() {
+            Some(lines) => lines,
+            None => {
+                // Lines added since asking are needed too.
+                state.edit_view.hold_all_lines(self);
+                return;
+            }
+        };
+        let printer = state.printer.take().unwrap();
+        let title = state.edit_view.filename.clone().unwrap_or_else(|| "Untitled".to_owned());
+        let result = print::print(&printer, &title, &lines, state.edit_view.tab_size());
+        self.release_print_lines(state);
+        if let Err(e) = result {
+            self.show_note(state, &e);
+        }
+    }
+
+    // Stop waiting for the lines to print, if it is, with a note saying why.
+    fn cancel_print(&self, state: &mut MainWinState, note: &str) -> bool {
+        if state.printer.take().is_none() {
+            return false;
+        }
+        self.release_print_lines(state);
+        self.show_note(state, note);
+        true
+    }
+
+    fn release_print_lines(&self, state: &mut MainWinState) {
+        self.handle.borrow().kill_timer(Timers::PrintWait as usize);
+        // A save may still want them.
+        if state.pending_save.is_none() {
+            self.release_all_lines(state);
+        }
+    }
+
+    // Back to asking for the visible lines only, in whichever pane held
+    // them; the focus may have moved since.
+    fn release_all_lines(&self, state: &mut MainWinState) {
+        state.edit_view.release_all_lines(self);
+        if let Some(ref mut split) = state.split {
+            split.other.release_all_lines(self);
+        }
+    }
+
+    /// Make the focused pane read-only, or editable again.
+    fn set_read_only(&self, read_only: bool) {
+        let mut state = self.state.borrow_mut();
+        state.edit_view.set_read_only(read_only);
+        state.status_bar.set_read_only(read_only);
+        self.handle.borrow().check_menu_item(MenuEntries::ReadOnly as u32, read_only);
+        self.invalidate();
+    }
+
+    // Start watching the view's file for changes by other programs.
+    fn watch_file(&self, filename: &str) {
+        let idle_handle = self.handle.borrow().get_idle_handle();
+        let watcher = idle_handle.and_then(|idle_handle| {
+            FileWatcher::new(Path::new(filename), move || {
+                idle_handle.add_idle(|a| {
+                    let handler = a.downcast_ref::<MainWinHandler>().unwrap();
+                    // Restarting the timer on each event debounces them.
+                    handler.win.handle.borrow()
+                        .set_timer(Timers::FileChanged as usize, FILE_CHANGE_DEBOUNCE_MS);
+                });
+            })
+        });
+        *self.watched_file.borrow_mut() = watcher.map(|watcher| WatchedFile {
+            _watcher: watcher,
+            path: filename.to_owned(),
+            mtime: modified_time(filename),
+            last_save: None,
+        });
+    }
+
+    // Our own saves change the file too, and shouldn't prompt a reload.
+    fn note_own_save(&self) {
+        if let Some(ref mut watched) = *self.watched_file.borrow_mut() {
+            watched.last_save = Some(Instant::now());
+        }
+    }
+
+    // After the watched file has changed on disk, reload it if there are no
+    // unsaved changes, or offer to if there are.
+    fn check_file_changed(&self) {
+        self.handle.borrow().kill_timer(Timers::FileChanged as usize);
+        let path = {
+            let mut watched_file = self.watched_file.borrow_mut();
+            let watched = match *watched_file {
+                Some(ref mut watched) => watched,
+                None => return,
+            };
+            let mtime = modified_time(&watched.path);
+            // Nothing to reload if the file is gone.
+            if mtime.is_none() || mtime == watched.mtime {
+                return;
+            }
+            watched.mtime = mtime;
+            if watched.last_save.map(|t| t.elapsed() < OWN_SAVE_WINDOW).unwrap_or(false) {
+                return;
+            }
+            watched.path.clone()
+        };
+        let pristine = self.state.borrow().edit_view.is_pristine();
+        if pristine {
+            self.reload_file(path);
+        } else {
+            let msg = format!("{} has changed on disk. Reloading it discards your changes.", path);
+            self.show_info(msg, vec![InfoAction::Reload(path), InfoAction::Dismiss]);
+        }
+    }
+
+    // Replace the views of the file with new ones, at the same places. The
+    // core keeps a file's buffer, unread, for as long as any view of it is
+    // open, so the split's is closed too, and made again once the core has
+    // answered for the focused pane's.
+    fn reload_file(&self, path: String) {
+        let (view_id, scroll_offset, caret_line, other_view_id) = {
+            let state = self.state.borrow();
+            let edit_view = &state.edit_view;
+            (edit_view.view_id.clone(), edit_view.scroll_offset(),
+                edit_view.caret_line().unwrap_or(0),
+                state.split.as_ref().map(|split| split.other.view_id.clone())
+                    .and_then(|id| if id.is_empty() { None } else { Some(id) }))
+        };
+        self.send_notification("close_view", &json!({"view_id": view_id}));
+        if let Some(other_view_id) = other_view_id {
+            // Its id stays until then, so messages about the new view go
+            // to the focused pane.
+            self.send_notification("close_view", &json!({"view_id": other_view_id}));
+            self.reopen_split.set(true);
+        }
+        self.open_file(path);
+        self.state.borrow_mut().edit_view.set_pending_scroll(scroll_offset, caret_line);
+    }
+
+    // Give the split's pane a new view of the reloaded file, at the same
+    // place, or close the split if the file can't be split any more.
+    fn reopen_split_pane(&self) {
+        let filename = {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+            let filename = match state.edit_view.filename {
+                Some(ref filename) if state.edit_view.encoding().is_utf8() => filename.clone(),
+                _ => {
+                    state.split = None;
+                    return;
+                }
+            };
+            let pane = match state.split {
+                Some(ref mut split) => &mut split.other,
+                None => return,
+            };
+            let (scroll_offset, caret_line) = (pane.scroll_offset(),
+                pane.caret_line().unwrap_or(0));
+            pane.set_view_id("");
+            pane.clear_line_cache();
+            pane.set_pending_scroll(scroll_offset, caret_line);
+            filename
+        };
+        self.req_split_view(&filename);
+    }
+
+    // Move a file to the top of the recent files, adding it if it's new.
+    fn add_recent_file(&self, filename: &str) {
+        {
+            let recent_files = &mut self.state.borrow_mut().recent_files;
+            // Paths on Windows are case-insensitive.
+            let key = filename.to_lowercase();
+            recent_files.retain(|f| f.to_lowercase() != key);
+            recent_files.insert(0, filename.to_owned());
+            recent_files.truncate(MAX_RECENT_FILES);
+        }
+        self.recent_files_changed();
+    }
+
+    /// Load the key bindings again, for all windows, after the keymap file
+    /// has been edited.
+    fn reload_keymap(&self) {
+        let (keymap, errors) = Keymap::load(Some(&*self.app.keymap.borrow()));
+        *self.app.keymap.borrow_mut() = keymap;
+        if errors.is_empty() {
+            self.show_note(&mut self.state.borrow_mut(), "Keymap reloaded");
+        }
+        self.report_config_errors(&errors);
+        *self.app.keymap_errors.borrow_mut() = errors;
+    }
+
+    fn recent_files_changed(&self) {
+        let recent_files = self.state.borrow().recent_files.clone();
+        {
+            let mut settings = self.app.settings.borrow_mut();
+            settings.set_recent_files(&recent_files);
+            if let Err(e) = settings.save() {
+                println!("failed to save settings: {}", e);
+            }
+        }
+        // Every window's File menu lists them.
+        for win in self.app.windows() {
+            win.state.borrow_mut().recent_files = recent_files.clone();
+            win.rebuild_menus();
+        }
+    }
+
+    fn open_recent_file(&self, index: usize) {
+        let filename = match self.state.borrow().recent_files.get(index) {
+            Some(filename) => filename.clone(),
+            None => return,
+        };
+        if Path::new(&filename).exists() {
+            self.open_file(filename);
+        } else {
+            self.state.borrow_mut().recent_files.remove(index);
+            self.recent_files_changed();
+            self.alert(&format!("{} no longer exists.", filename));
+        }
+    }
+}
+
+impl EditSink for MainWin {
+    // Note: caller can't be borrowing the state.
+    fn send_edit_cmd(&self, cmd: &EditCommand, view_id: &str) {
+        self.send_notification("edit", &cmd.edit_params(view_id));
+    }
+
+    fn watch_core_stall(&self) {
+        self.handle.borrow().set_timer(Timers::CoreStall as usize, STALL_POLL_MS);
+    }
+
+    fn send_stall_probe(&self, view_id: &str) {
+        // `copy` has no side effects, we only care that it's answered.
+        self.send_edit_request(&EditCommand::Copy, view_id, |handler, _| {
+            let mut state = handler.win.state.borrow_mut();
+            state.edit_view.stall_probe_answered();
+            handler.win.update_history(&mut state);
+        });
+    }
+}
+
+impl WinHandler for MainWinHandler {
+    fn connect(&self, handle: &WindowHandle) {
+        *self.win.handle.borrow_mut() = handle.clone();
+        // Windows after the first start out empty.
+        if self.win.app.started.replace(true) {
+            self.win.req_new_view(None);
+        } else {
+            self.win.send_notification("client_started", &json!({}));
+            self.win.open_startup_file();
+        }
+    }
+
+    fn size(&self, x: u32, y: u32) {
+        // Minimized, the window has no area. The panes keep their size and
+        // place, rather than rewrapping to nothing, until it's shown again.
+        if x == 0 || y == 0 {
+            self.win.set_panes_hidden(true);
+            return;
+        }
+        let (x_px, y_px) = self.win.handle.borrow().pixels_to_px_xy(x, y);
+        {
+            let mut state = self.win.state.borrow_mut();
+            state.edit_size = (x_px, (y_px - STATUS_BAR_HEIGHT).max(0.0));
+            state.infobar.set_width(x_px);
+            state.palette.size(x_px, (y_px - STATUS_BAR_HEIGHT).max(0.0));
+            state.status_bar.size(x_px, y_px);
+        }
+        self.win.layout_panes();
+        self.win.set_panes_hidden(false);
+    }
+
+    fn paint(&self, paint_ctx: &mut PaintCtx) -> bool {
+        let mut state = self.win.state.borrow_mut();
+        if let Err(e) = state.render(paint_ctx) {
+            // Skip the rest of the frame; the resources that couldn't be
+            // made are tried again next time.
+            println!("can't draw: {:?}", e);
+            self.win.handle.borrow().set_timer(Timers::RetryPaint as usize, RETRY_PAINT_MS);
+        }
+        false
+    }
+
+    fn rebuild_resources(&self) {
+        let mut state = self.win.state.borrow_mut();
+        state.edit_view.rebuild_resources();
+        if let Some(ref mut split) = state.split {
+            split.rebuild_resources();
+        }
+        state.status_bar.rebuild_resources();
+        state.overlay_style.rebuild_resources();
+    }
+
+    fn command(&self, id: u32) {
+        // The clipboard shortcuts edit the palette's query while it's open.
+        if self.palette_edit(id) {
+            return;
+        }
+        // Menu commands and shortcuts dismiss the palette.
+        if id != MenuEntries::CommandPalette as u32 && self.win.state.borrow().palette.is_open() {
+            self.win.close_palette();
+        }
+        match id {
+            x if x == MenuEntries::Exit as u32 => {
+                self.win.close_windows(self.win.app.windows());
+            }
+            x if x == MenuEntries::NewWindow as u32 => {
+                match create_main(&self.win.app) {
+                    Ok(window) => window.show(),
+                    Err(e) => println!("failed to create window: {:?}", e),
+                }
+            }
+            x if x == MenuEntries::Open as u32 => {
+                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
+                self.win.file_open(hwnd);
+            }
+            x if x == MenuEntries::Save as u32 => {
+                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
+                self.win.file_save(hwnd);
+            }
+            x if x == MenuEntries::SaveAs as u32 => {
+                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
+                self.win.file_save_as(hwnd);
+            }
+            x if x == MenuEntries::SaveAll as u32 => self.win.save_all(),
+            x if x == MenuEntries::Print as u32 => {
+                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
+                self.win.print(hwnd);
+            }
+
+            x if x == MenuEntries::Undo as u32 => {
+                self.win.state.borrow_mut().edit_view.undo(&self.win);
+            }
+            x if x == MenuEntries::Redo as u32 => {
+                self.win.state.borrow_mut().edit_view.redo(&self.win);
+            }
+            x if x == MenuEntries::Cut as u32 => {
+                self.win.state.borrow_mut().edit_view.cut(&self.win);
+            }
+            x if x == MenuEntries::Copy as u32 => {
+                self.win.state.borrow_mut().edit_view.copy(&self.win);
+            }
+            x if x == MenuEntries::Paste as u32 => {
+                self.win.state.borrow_mut().edit_view.paste(&self.win);
+            }
+            x if x == MenuEntries::UpperCase as u32 => {
+                self.win.state.borrow_mut().edit_view.upper_case(&self.win);
+            }
+            x if x == MenuEntries::LowerCase as u32 => {
+                self.win.state.borrow_mut().edit_view.lower_case(&self.win);
+            }
+            x if x == MenuEntries::TitleCase as u32 => {
+                self.win.state.borrow_mut().edit_view.title_case(&self.win);
+            }
+            x if x == MenuEntries::Transpose as u32 => {
+                self.win.state.borrow_mut().edit_view.transpose(&self.win);
+            }
+            x if x == MenuEntries::DuplicateLine as u32 => {
+                self.win.state.borrow_mut().edit_view.duplicate_line(&self.win);
+            }
+            x if x == MenuEntries::JoinLines as u32 => {
+                self.win.state.borrow_mut().edit_view.join_lines(&self.win);
+            }
+            x if x == MenuEntries::SortLines as u32 => {
+                self.win.state.borrow_mut().edit_view.sort_lines(true, &self.win);
+            }
+            x if x == MenuEntries::SortLinesCaseInsensitive as u32 => {
+                self.win.state.borrow_mut().edit_view.sort_lines(false, &self.win);
+            }
+            x if x == MenuEntries::ToggleComment as u32 => {
+                self.win.state.borrow_mut().edit_view.toggle_comment(&self.win);
+            }
+            x if x == MenuEntries::TrimWhitespace as u32 => {
+                self.win.state.borrow_mut().edit_view.trim_trailing_whitespace(&self.win);
+            }
+
+            x if x == MenuEntries::TabSize2 as u32 => {
+                self.win.state.borrow_mut().edit_view.set_tab_size(2, &self.win);
+            }
+            x if x == MenuEntries::TabSize4 as u32 => {
+                self.win.state.borrow_mut().edit_view.set_tab_size(4, &self.win);
+            }
+            x if x == MenuEntries::TabSize8 as u32 => {
+                self.win.state.borrow_mut().edit_view.set_tab_size(8, &self.win);
+            }
+            x if x == MenuEntries::IndentUsingSpaces as u32 => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let spaces = !edit_view.translate_tabs_to_spaces();
+                edit_view.set_translate_tabs_to_spaces(spaces, &self.win);
+            }
+            x if x == MenuEntries::LineEndingLf as u32 ||
+                x == MenuEntries::LineEndingCrlf as u32 =>
+            {
+                let line_ending = if x == MenuEntries::LineEndingLf as u32 {
+                    LineEnding::Lf
+                } else {
+                    LineEnding::Crlf
+                };
+                self.win.convert_line_endings(line_ending);
+            }
+            x if x == MenuEntries::ReadOnly as u32 => {
+                let read_only = !self.win.state.borrow().edit_view.is_read_only();
+                self.win.set_read_only(read_only);
+            }
+            x if x == MenuEntries::TrimOnSave as u32 => {
+                let mut fixups = self.win.state.borrow().save_fixups;
+                fixups.trim_whitespace = !fixups.trim_whitespace;
+                self.win.set_save_fixups(fixups);
+            }
+            x if x == MenuEntries::FinalNewlineOnSave as u32 => {
+                let mut fixups = self.win.state.borrow().save_fixups;
+                fixups.final_newline = !fixups.final_newline;
+                self.win.set_save_fixups(fixups);
+            }
+            x if menus::ANTIALIAS_ENTRIES.iter().any(|&(_, id)| id == x) => {
+                let mode = menus::ANTIALIAS_ENTRIES.iter().find(|&&(_, id)| id == x).unwrap().0;
+                self.win.set_text_antialias(mode);
+            }
+
+            x if x == MenuEntries::ShowWhitespace as u32 ||
+                x == MenuEntries::ShowTrailingWhitespace as u32 =>
+            {
+                let mode = if x == MenuEntries::ShowWhitespace as u32 {
+                    ShowWhitespace::All
+                } else {
+                    ShowWhitespace::Trailing
+                };
+                {
+                    let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                    // Choosing the checked mode again turns it off.
+                    let mode = if edit_view.show_whitespace() == mode {
+                        ShowWhitespace::Off
+                    } else {
+                        mode
+                    };
+                    edit_view.set_show_whitespace(mode, &self.win);
+                }
+                self.win.rebuild_menus();
+            }
+
+            x if menus::LINE_NUMBERS_ENTRIES.iter().any(|&(_, id)| id == x) => {
+                let mode = menus::LINE_NUMBERS_ENTRIES.iter().find(|&&(_, id)| id == x).unwrap().0;
+                self.win.set_line_numbers(mode);
+            }
+            x if x == MenuEntries::IndentGuides as u32 => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let indent_guides = !edit_view.indent_guides();
+                edit_view.set_indent_guides(indent_guides, &self.win);
+                self.win.handle.borrow().check_menu_item(id, indent_guides);
+            }
+            x if x == MenuEntries::Rulers as u32 => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let show_rulers = !edit_view.show_rulers();
+                edit_view.set_show_rulers(show_rulers, &self.win);
+                self.win.handle.borrow().check_menu_item(id, show_rulers);
+            }
+            x if x == MenuEntries::ScrollPastEnd as u32 => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let scroll_past_end = !edit_view.scroll_past_end();
+                edit_view.set_scroll_past_end(scroll_past_end, &self.win);
+                self.win.handle.borrow().check_menu_item(id, scroll_past_end);
+            }
+            x if x == MenuEntries::Minimap as u32 => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let show_minimap = !edit_view.show_minimap();
+                edit_view.set_show_minimap(show_minimap, &self.win);
+                self.win.handle.borrow().check_menu_item(id, show_minimap);
+            }
+            x if x == MenuEntries::WordWrap as u32 => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let word_wrap = !edit_view.is_word_wrap();
+                edit_view.set_word_wrap(word_wrap, &self.win);
+                self.win.handle.borrow().check_menu_item(id, word_wrap);
+            }
+            x if x == MenuEntries::SplitView as u32 => {
+                self.win.toggle_split();
+            }
+            x if x == MenuEntries::FullScreen as u32 => {
+                self.win.toggle_fullscreen();
+            }
+            x if x == MenuEntries::GoBack as u32 => self.win.navigate(true),
+            x if x == MenuEntries::GoForward as u32 => self.win.navigate(false),
+            x if x == MenuEntries::CommandPalette as u32 => {
+                if self.win.state.borrow().palette.is_open() {
+                    self.win.close_palette();
+                } else {
+                    self.win.open_palette();
+                }
+            }
+
+            x if x >= PLUGIN_MENU_BASE && x < LANGUAGE_MENU_BASE => {
+                let index = (x - PLUGIN_MENU_BASE) as usize;
+                self.win.state.borrow_mut().edit_view.toggle_plugin(index, &self.win);
+            }
+            x if x >= LANGUAGE_MENU_BASE && x < RECENT_MENU_BASE => {
+                let state = &mut *self.win.state.borrow_mut();
+                if let Some(language) = state.languages.get((x - LANGUAGE_MENU_BASE) as usize) {
+                    state.edit_view.request_language(language, &self.win);
+                }
+            }
+
+            x if x == MenuEntries::ClearRecent as u32 => {
+                self.win.state.borrow_mut().recent_files.clear();
+                self.win.recent_files_changed();
+            }
+            x if x == MenuEntries::ReloadKeymap as u32 => self.win.reload_keymap(),
+            x if x >= RECENT_MENU_BASE && x < BOOKMARK_MENU_BASE => {
+                self.win.open_recent_file((x - RECENT_MENU_BASE) as usize);
+            }
+            x if x >= BOOKMARK_MENU_BASE => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                let line = edit_view.bookmarks().get((x - BOOKMARK_MENU_BASE) as usize)
+                    .map(|&(line, _)| line);
+                if let Some(line) = line {
+                    edit_view.jump_to(line, 0, &self.win);
+                }
+            }
+            x if x == MenuEntries::ToggleBookmark as u32 => {
+                self.win.state.borrow_mut().edit_view.toggle_bookmark(&self.win);
+            }
+            x if x == MenuEntries::NextBookmark as u32 => {
+                self.win.state.borrow_mut().edit_view.goto_bookmark(true, &self.win);
+            }
+            x if x == MenuEntries::PrevBookmark as u32 => {
+                self.win.state.borrow_mut().edit_view.goto_bookmark(false, &self.win);
+            }
+            x if x == MenuEntries::ClearBookmarks as u32 => {
+                self.win.state.borrow_mut().edit_view.clear_bookmarks(&self.win);
+            }
+
+            x if x == MenuEntries::AddCursorAbove as u32 => {
+                self.win.state.borrow_mut().edit_view.add_cursor_above(&self.win);
+            }
+            x if x == MenuEntries::AddCursorBelow as u32 => {
+                self.win.state.borrow_mut().edit_view.add_cursor_below(&self.win);
+            }
+            x if x == MenuEntries::SingleSelection as u32 => {
+                self.win.state.borrow_mut().edit_view.single_selection(&self.win);
+            }
+            x if x == MenuEntries::SelectAll as u32 => {
+                self.win.state.borrow_mut().edit_view.select_all(&self.win);
+            }
+
+            x if x == MenuEntries::ShowConfigErrors as u32 => self.win.show_config_errors(),
+            x if x == MenuEntries::ChangeLanguage as u32 => self.win.pick_language(None),
+            x if x == MenuEntries::SetLanguageForExtension as u32 => {
+                let extension = self.win.state.borrow().edit_view.filename.as_ref()
+                    .and_then(|path| settings::extension(path));
+                if extension.is_some() {
+                    self.win.pick_language(extension);
+                }
+            }
+            _ => println!("unexpected id {}", id),
+        }
+        // An edit the view turned down leaves a note for the status bar.
+        self.win.update_history(&mut self.win.state.borrow_mut());
+        if self.win.state.borrow_mut().edit_view.take_bookmarks_changed() {
+            self.win.rebuild_menus();
+        }
+    }
+
+    fn char(&self, ch: u32, mods: u32) {
+        if !self.win.char_filter.borrow_mut().char(ch, mods) {
+            return;
+        }
+        let mut state = self.win.state.borrow_mut();
+        if state.palette.is_open() {
+            state.palette.char(ch);
+            self.win.invalidate();
+            return;
+        }
+        state.edit_view.char(ch, &self.win);
+        self.win.update_history(&mut state);
+    }
+
+    fn keydown(&self, vk_code: i32, mods: u32) -> bool {
+        if self.win.state.borrow().palette.is_open() {
+            // The palette's keys don't type, but its query takes the rest.
+            self.win.char_filter.borrow_mut().keydown(vk_code, false);
+            self.palette_keydown(vk_code, mods);
+            return true;
+        }
+        if self.splitter_keydown(vk_code, mods) {
+            self.win.char_filter.borrow_mut().keydown(vk_code, true);
+            return true;
+        }
+        if vk_code == VK_ESCAPE && self.win.handle.borrow().is_fullscreen() {
+            self.win.char_filter.borrow_mut().keydown(vk_code, true);
+            self.win.toggle_fullscreen();
+            return true;
+        }
+        // Escape stops waiting to print.
+        if vk_code == VK_ESCAPE &&
+            self.win.cancel_print(&mut self.win.state.borrow_mut(), "Printing was cancelled")
+        {
+            self.win.char_filter.borrow_mut().keydown(vk_code, true);
+            return true;
+        }
+        // Escape dismisses the infobar's message before anything else.
+        if vk_code == VK_ESCAPE && self.win.state.borrow().infobar.is_shown() {
+            self.win.char_filter.borrow_mut().keydown(vk_code, true);
+            self.win.dismiss_info();
+            return true;
+        }
+        // A bare modifier doesn't stop scrolling; the key it goes with does.
+        if !is_modifier(vk_code) {
+            self.win.state.borrow_mut().edit_view.end_pan(&self.win);
+        }
+        let binding = self.win.app.keymap.borrow().lookup(vk_code, mods).cloned();
+        if let Some(Binding::Menu(id)) = binding {
+            self.win.char_filter.borrow_mut().keydown(vk_code, true);
+            self.command(id);
+            return true;
+        }
+        let mut state = self.win.state.borrow_mut();
+        let handled = state.edit_view.keydown(vk_code, binding, &self.win);
+        self.win.char_filter.borrow_mut().keydown(vk_code, handled);
+        let overwrite = state.edit_view.is_overwrite();
+        state.status_bar.set_overwrite(overwrite);
+        self.win.update_history(&mut state);
+        handled
+    }
+
+    fn keyup(&self, vk_code: i32, _mods: u32) -> bool {
+        self.win.state.borrow_mut().edit_view.keyup(vk_code, &self.win);
+        false
+    }
+
+    fn mouse_wheel(&self, delta: i32, mods: u32) {
+        let edit_view = &mut self.win.state.borrow_mut().edit_view;
+        edit_view.mouse_wheel(delta, mods, &self.win)
+    }
+
+    fn mouse(&self, x: i32, y: i32, mods: u32, which: MouseButton, ty: MouseType) {
+        let (x_px, y_px) = self.win.handle.borrow().pixels_to_px_xy(x, y);
+        if ty == MouseType::Down && self.win.state.borrow().palette.is_open() {
+            self.win.close_palette();
+        }
+        if self.splitter_mouse(y_px, which, ty) {
+            return;
+        }
+        // A click anywhere stops scrolling with the middle button.
+        if ty == MouseType::Down && self.win.state.borrow_mut().edit_view.end_pan(&self.win) {
+            return;
+        }
+        if ty == MouseType::Down && self.win.state.borrow().infobar.contains(y_px) {
+            let action = self.win.state.borrow().infobar.button_at(x_px, y_px);
+            if let (MouseButton::Left, Some(action)) = (which, action) {
+                self.run_info_action(action);
+            }
+            return;
+        }
+        // Clicking in the other pane gives it focus.
+        if ty == MouseType::Down {
+            let in_other_pane = match self.win.state.borrow().split {
+                Some(ref split) => split.other.contains(x_px, y_px),
+                None => false,
+            };
+            if in_other_pane {
+                self.win.switch_pane();
+            }
+        }
+        // The mouse is captured while a button is held, so a drag that
+        // started in the edit view can end anywhere.
+        let in_edit_view = self.win.state.borrow().edit_view.contains(x_px, y_px);
+        match ty {
+            MouseType::Down if in_edit_view => {
+                let edit_view = &mut self.win.state.borrow_mut().edit_view;
+                edit_view.mouse_down(x_px, y_px, mods, which, &self.win);
+            }
+            MouseType::Down if which == MouseButton::Left => {
+                let item = self.win.state.borrow().status_bar.item_at(x_px, y_px);
+                let menu = match item {
+                    Some(StatusItem::Indentation) => {
+                        Some(menus::create_indentation_menu(&self.win.state.borrow().edit_view))
+                    }
+                    Some(StatusItem::LineEnding) => {
+                        Some(menus::create_line_ending_menu(&self.win.state.borrow().edit_view))
+                    }
+                    _ => None,
+                };
+                if let Some(menu) = menu {
+                    self.win.handle.borrow().show_context_menu(menu, x, y, self.win.app.mirrored);
+                }
+            }
+            MouseType::Up => {
+                self.win.state.borrow_mut().edit_view.mouse_up(which, &self.win);
+                if which == MouseButton::Right && in_edit_view {
+                    let has_selection =
+                        self.win.state.borrow_mut().edit_view.is_in_selection(x_px, y_px);
+                    let menu = menus::create_context_menu(has_selection, clipboard::has_text());
+                    self.win.handle.borrow().show_context_menu(menu, x, y, self.win.app.mirrored);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn touch(&self, id: u32, x: i32, y: i32, ty: TouchType) -> bool {
+        let (x_px, y_px) = self.win.handle.borrow().pixels_to_px_xy(x, y);
+        // Touches elsewhere, like on the infobar's buttons, go on as
+        // mouse clicks.
+        if ty == TouchType::Down {
+            let in_other_pane = match self.win.state.borrow().split {
+                Some(ref split) => split.other.contains(x_px, y_px),
+                None => false,
+            };
+            if in_other_pane {
+                self.win.switch_pane();
+            }
+            if !self.win.state.borrow().edit_view.contains(x_px, y_px) {
+                return false;
+            }
+        }
+        let edit_view = &mut self.win.state.borrow_mut().edit_view;
+        edit_view.touch(id, x_px, y_px, ty, &self.win)
+    }
+
+    fn mouse_move(&self, x: i32, y: i32, mods: u32) {
+        let (x_px, y_px) = self.win.handle.borrow().pixels_to_px_xy(x, y);
+        let (dragging_splitter, on_splitter) = {
+            let state = &mut *self.win.state.borrow_mut();
+            let (top, height) = state.panes_area();
+            match state.split {
+                Some(ref mut split) if split.splitter.is_dragging() => {
+                    split.splitter.drag_to(y_px - top, height);
+                    (true, true)
+                }
+                Some(ref split) => (false, split.splitter.contains(y_px - top, height)),
+                None => (false, false),
+            }
+        };
+        let cursor = if on_splitter { Cursor::ResizeUpDown } else { Cursor::IBeam };
+        self.win.handle.borrow().set_cursor(cursor);
+        if dragging_splitter {
+            self.win.layout_panes();
+            self.win.invalidate();
+            return;
+        }
+        let edit_view = &mut self.win.state.borrow_mut().edit_view;
+        edit_view.mouse_move(x_px, y_px, mods, &self.win);
+    }
+
+    fn timer(&self, token: usize) {
+        match token {
+            x if x == Timers::CaretBlink as usize => {
+                self.win.state.borrow_mut().edit_view.blink(&self.win);
+            }
+            x if x == Timers::CoreStall as usize => {
+                self.win.check_core_stall();
+            }
+            x if x == Timers::FileChanged as usize => {
+                self.win.check_file_changed();
+            }
+            x if x == Timers::Autoscroll as usize => {
+                self.win.state.borrow_mut().edit_view.autoscroll(&self.win);
+            }
+            x if x == Timers::PrintWait as usize => {
+                let state = &mut *self.win.state.borrow_mut();
+                self.win.cancel_print(state, "The document didn't load in time to print");
+            }
+            x if x == Timers::SaveWait as usize => {
+                let state = &mut *self.win.state.borrow_mut();
+                self.win.save_if_ready(state, true);
+            }
+            x if x == Timers::Viewport as usize => {
+                self.win.handle.borrow().kill_timer(Timers::Viewport as usize);
+                let state = &mut *self.win.state.borrow_mut();
+                state.edit_view.flush_viewport(&self.win);
+                if let Some(ref mut split) = state.split {
+                    split.other.flush_viewport(&self.win);
+                }
+            }
+            x if x == Timers::Definition as usize => {
+                self.win.handle.borrow().kill_timer(Timers::Definition as usize);
+                let state = &mut *self.win.state.borrow_mut();
+                state.edit_view.definition_timed_out(&self.win);
+                if let Some(ref mut split) = state.split {
+                    split.other.definition_timed_out(&self.win);
+                }
+            }
+            x if x == Timers::Hover as usize => {
+                self.win.handle.borrow().kill_timer(Timers::Hover as usize);
+                self.win.state.borrow_mut().edit_view.request_hover(&self.win);
+            }
+            x if x == Timers::TouchGlide as usize => {
+                self.win.state.borrow_mut().edit_view.glide(&self.win);
+            }
+            x if x == Timers::WordMatches as usize => {
+                let state = &mut *self.win.state.borrow_mut();
+                state.edit_view.find_word_matches(&self.win);
+                if let Some(ref mut split) = state.split {
+                    split.other.find_word_matches(&self.win);
+                }
+            }
+            x if x == Timers::StatusNote as usize => {
+                let handle = self.win.handle.borrow();
+                handle.kill_timer(Timers::StatusNote as usize);
+                let mut state = self.win.state.borrow_mut();
+                state.status_bar.clear_note();
+                let (x0, y0, x1, y1) = state.status_bar.bounds();
+                handle.invalidate_rect(x0, y0, x1, y1);
+            }
+            x if x == Timers::Busy as usize => {
+                self.win.show_busy();
+            }
+            x if x == Timers::RetryPaint as usize => {
+                let handle = self.win.handle.borrow();
+                handle.kill_timer(Timers::RetryPaint as usize);
+                handle.invalidate();
+            }
+            x if x == Timers::CountSelection as usize => {
+                self.win.handle.borrow().kill_timer(Timers::CountSelection as usize);
+                let state = &mut *self.win.state.borrow_mut();
+                state.edit_view.count_selection(&self.win);
+                if let Some(ref mut split) = state.split {
+                    split.other.count_selection(&self.win);
+                }
+                self.win.update_counts(state);
+            }
+            _ => println!("unexpected timer {}", token),
+        }
+    }
+
+    fn capture_lost(&self) {
+        // The capture goes as the button comes up, ahead of the mouse
+        // event, so this is where a drag of the splitter ends.
+        let dragged_splitter = {
+            let state = &mut *self.win.state.borrow_mut();
+            state.edit_view.end_drag(&self.win);
+            state.split.as_mut().map_or(false, |split| split.splitter.release())
+        };
+        if dragged_splitter {
+            self.win.save_split_height();
+        }
+    }
+
+    fn got_focus(&self) {
+        *self.win.app.active.borrow_mut() = Rc::downgrade(&self.win);
+        let mut state = self.win.state.borrow_mut();
+        state.edit_view.set_focus(true, &self.win);
+        self.win.update_accessibility(&state);
+    }
+
+    fn lost_focus(&self) {
+        let mut state = self.win.state.borrow_mut();
+        state.edit_view.set_focus(false, &self.win);
+        self.win.update_accessibility(&state);
+    }
+
+    fn get_object(&self, wparam: WPARAM, lparam: LPARAM) -> Option<LRESULT> {
+        if self.win.accessibility.borrow().is_none() {
+            let handle = self.win.handle.borrow();
+            let (hwnd, idle_handle) = (handle.get_hwnd()?, handle.get_idle_handle()?);
+            let requests = move |request: Request| {
+                idle_handle.add_idle(move |a| {
+                    let handler = a.downcast_ref::<MainWinHandler>().unwrap();
+                    handler.win.run_accessibility_request(request);
+                });
+            };
+            *self.win.accessibility.borrow_mut() =
+                Some(Accessibility::new(hwnd, Box::new(requests)));
+            // Clients can ask while the state is borrowed, from inside a
+            // modal loop; the next update fills the snapshot in then.
+            if let Ok(state) = self.win.state.try_borrow() {
+                self.win.update_accessibility(&state);
+            }
+        }
+        self.win.accessibility.borrow().as_ref()
+            .and_then(|accessibility| accessibility.get_object(wparam, lparam))
+    }
+
+    fn request_close(&self) -> bool {
+        self.win.close_windows(vec![self.win.clone()]);
+        false
+    }
+
+    fn destroy(&self) {
+        if let Some(accessibility) = self.win.accessibility.borrow_mut().take() {
+            accessibility.disconnect();
+        }
+        self.win.forget_places();
+        self.win.state.borrow_mut().edit_view.set_temp_copy(None);
+        // The last window to close saves its file and place for next time.
+        if self.win.app.remove_window(&self.win) {
+            self.win.save_settings();
+            win_main::request_quit();
+        } else {
+            let view_id = self.win.state.borrow().edit_view.view_id.clone();
+            self.win.send_notification("close_view", &json!({"view_id": view_id}));
+            self.win.close_split();
+        }
+    }
+
+    fn as_any(&self) -> &Any { self }
 }
 
-impl MainWinState {
-    fn new() -> MainWinState {
-        MainWinState {
-            edit_view: EditView::new(),
+impl MainWin {
+    fn req_new_view(&self, filename: Option<&str>) {
+        let mut params = json!({});
+        if let Some(filename) = filename {
+            params["file_path"] = json!(filename);
         }
+        let filename = filename.map(str::to_owned);
+        let handle = self.handle.borrow().get_idle_handle().unwrap();
+        let request = self.core.borrow_mut().send_request_with_id("new_view", &params,
+            move |request, value| {
+                let value = value.clone();
+                handle.add_idle(move |a| {
+                    let handler = a.downcast_ref::<MainWinHandler>().unwrap();
+                    handler.win.state.borrow_mut().new_views.retain(|v| v.request != request);
+                    let view_id = match value.as_str() {
+                        Some(view_id) => view_id,
+                        None => return handler.win.new_view_failed(filename),
+                    };
+                    {
+                        let edit_view = &mut handler.win.state.borrow_mut().edit_view;
+                        edit_view.set_view_id(view_id);
+                        edit_view.update_viewport(&handler.win);
+                        edit_view.send_line_ending(&handler.win);
+                    }
+                    handler.win.apply_language_override();
+                    if handler.win.reopen_split.replace(false) {
+                        handler.win.reopen_split_pane();
+                    }
+                });
+            }
+        );
+        self.state.borrow_mut().new_views.push(NewView { request, split: false, view_id: None });
     }
 
-    fn render(&mut self, p: &mut PaintCtx) {
-        self.edit_view.render(p);
+    // The core couldn't open a file, and has sent an error saying why. A
+    // file it was given as is, as it started as UTF-8, is opened again
+    // from a copy with its invalid bytes replaced.
+    fn new_view_failed(&self, filename: Option<String>) {
+        self.end_busy();
+        let lossy = {
+            let edit_view = &self.state.borrow().edit_view;
+            filename.is_some() && edit_view.filename == filename && edit_view.encoding().is_utf8()
+        };
+        if lossy {
+            self.open_lossy(filename.unwrap());
+        }
     }
-}
 
-struct MainWinHandler {
-    win: Rc<MainWin>,
-}
+    fn handle_request(&self, method: &str, params: &Value, responder: Responder) {
+        match method {
+            "measure_width" => {
+                let widths = self.state.borrow_mut().edit_view.measure_widths(params);
+                responder.respond(Ok(widths));
+            }
+            _ => {
+                println!("unhandled request from core: {}", method);
+                responder.respond(Err(json!({
+                    "code": -32601,
+                    "message": format!("unknown method {}", method),
+                })));
+            }
+        }
+    }
 
-// Maybe combine all this, put as a single item inside a RefCell.
-pub struct MainWin {
-    core: RefCell<Core>,
-    handle: RefCell<WindowHandle>,
-    state: RefCell<MainWinState>,
-}
+    // Apply an update from the core to the pane with its view. Updates are
+    // by far the most frequent message, so only the lines that changed are
+    // repainted.
+    fn handle_update(&self, view_id: &str, update: Update) {
+        let mut state = self.state.borrow_mut();
+        let view_id = Some(view_id);
+        let focused = !state.is_other_pane(view_id);
+        let mut rebuild_menus = false;
+        {
+            let handle = self.handle.borrow();
+            // The places to go back to in the file move with their lines;
+            // the other pane gets the same edits.
+            if let (true, Some(path)) = (focused, state.edit_view.filename.as_ref()) {
+                self.app.navigation.borrow_mut().apply_ops(path, &update.ops);
+            }
+            if let Some((x0, y0, x1, y1)) = state.pane_mut(view_id).apply_update(update, self) {
+                handle.invalidate_rect(x0, y0, x1, y1);
+            }
+            if let Some((line, col)) = state.edit_view.take_caret_jump() {
+                if let Some(path) = state.edit_view.filename.clone() {
+                    self.app.navigation.borrow_mut().push(Location { path, line, col });
+                }
+            }
+            if state.status_bar.is_busy() {
+                state.status_bar.set_busy(None);
+                let (x0, y0, x1, y1) = state.status_bar.bounds();
+                handle.invalidate_rect(x0, y0, x1, y1);
+            }
+        }
+        if focused {
+            self.update_counts(&mut state);
+            self.print_if_ready(&mut state);
+            self.save_if_ready(&mut state, false);
+            self.update_accessibility(&state);
+            // The Bookmarks menu lists the lines they're on.
+            rebuild_menus |= state.edit_view.take_bookmarks_changed();
+        }
+        self.update_history(&mut state);
+        drop(state);
+        self.finish_cmd(focused, rebuild_menus, false, None);
+    }
 
-impl MainWin {
-    fn new(core: Core, state: MainWinState) -> MainWin {
-        MainWin {
-            core: RefCell::new(core),
-            handle: Default::default(),
-            state: RefCell::new(state),
+    fn handle_cmd(&self, method: &str, params: &Value) {
+        if method == "alert" {
+            // Like a failed save; shown in the infobar, so typing can go on.
+            let msg = params["msg"].as_str().unwrap_or("Unknown error from the core");
+            self.show_info(msg.to_owned(), vec![InfoAction::Dismiss]);
+            return;
+        }
+        let mut state = self.state.borrow_mut();
+        let mut rebuild_menus = false;
+        let mut definition = None;
+        // The status bar and menus show the focused pane.
+        let view_id = params["view_id"].as_str();
+        let focused = !state.is_other_pane(view_id);
+        match method {
+            "scroll_to" => {
+                let line = params["line"].as_u64().unwrap() as usize;
+                let col = params["col"].as_u64().unwrap_or(0) as usize;
+                state.pane_mut(view_id).scroll_to(line, col, self);
+            }
+            "show_definition" => {
+                let request_id = params["request_id"].as_u64().unwrap_or(0) as usize;
+                let location = Location::from_result(&params["result"]);
+                let found = location.is_some();
+                if let Some(pane) = state.definition_pane(request_id) {
+                    if pane.definition_answered(request_id, found, self) {
+                        definition = location;
+                    }
+                }
+            }
+            "show_hover" => {
+                let request_id = params["request_id"].as_u64().unwrap_or(0) as usize;
+                let content = params["result"]["content"].as_str().unwrap_or("");
+                state.pane_mut(view_id).show_hover(request_id, content, self);
+            }
+            "find_status" => {
+                // Only a single query is used so far. The core sends the
+                // lines of its matches too, and again as the text changes,
+                // so the count follows both edits and the caret.
+                let query = &params["queries"][0];
+                let searching = query["chars"].as_str().map_or(false, |chars| !chars.is_empty());
+                let caret_line = state.pane_mut(view_id).caret_line();
+                let message = match (query["matches"].as_u64(), query["lines"].as_array()) {
+                    (Some(0), _) if searching => "No results".to_owned(),
+                    (Some(0), _) | (None, _) => String::new(),
+                    (Some(n), Some(lines)) if caret_line.is_some() && !lines.is_empty() => {
+                        // The first match not above the caret's line is
+                        // the current one, or the last past the end.
+                        let caret_line = caret_line.unwrap_or(0) as u64;
+                        let above = lines.iter()
+                            .filter(|line| line.as_u64().map_or(false, |line| line < caret_line))
+                            .count() as u64;
+                        format!("{} of {}", (above + 1).min(n), n)
+                    }
+                    (Some(1), _) => "1 match".to_owned(),
+                    (Some(n), _) => format!("{} matches", n),
+                };
+                state.status_bar.set_message(message);
+            }
+            "available_themes" => (), // TODO
+            "theme_changed" => {
+                let gutter_colors = GutterColors::from_theme(&params["theme"]);
+                self.app.gutter_colors.set(gutter_colors);
+                let overlay_colors = OverlayColors::from_theme(&params["theme"]);
+                self.app.overlay_colors.set(overlay_colors);
+                state.overlay_style.set_colors(overlay_colors);
+                *self.app.theme_name.borrow_mut() = params["name"].as_str().map(str::to_owned);
+                state.edit_view.set_gutter_colors(gutter_colors);
+                if let Some(ref mut split) = state.split {
+                    split.other.set_gutter_colors(gutter_colors);
+                }
+            }
+            "available_languages" => {
+                let mut languages: Vec<String> = params["languages"].as_array()
+                    .map(|langs| langs.iter().filter_map(|l| l.as_str())
+                        .map(|l| l.to_owned()).collect())
+                    .unwrap_or_default();
+                languages.sort_by_key(|l| l.to_lowercase());
+                state.languages = languages;
+                rebuild_menus = true;
+            }
+            "language_changed" => {
+                let language = params["language_id"].as_str().unwrap_or("");
+                state.pane_mut(view_id).set_language(language);
+                if focused {
+                    state.status_bar.set_language(Some(language));
+                }
+                rebuild_menus = true;
+            }
+            "available_plugins" => {
+                state.pane_mut(view_id).set_available_plugins(&params["plugins"]);
+                rebuild_menus = true;
+            }
+            "plugin_started" => {
+                let name = params["plugin"].as_str().unwrap_or("");
+                state.pane_mut(view_id).set_plugin_running(name, true);
+                rebuild_menus = true;
+            }
+            "plugin_stopped" => {
+                let name = params["plugin"].as_str().unwrap_or("");
+                state.pane_mut(view_id).set_plugin_running(name, false);
+                // A nonzero code means the plugin exited with an error.
+                let code = params["code"].as_i64().unwrap_or(0);
+                if code != 0 {
+                    state.status_bar.set_message(
+                        format!("Plugin {} stopped with error {}", name, code));
+                }
+                rebuild_menus = true;
+            }
+            "config_changed" => {
+                let (tab_size, spaces) = {
+                    let pane = state.pane_mut(view_id);
+                    pane.apply_config(&params["changes"]);
+                    if let Some(rulers) = params["changes"]["rulers"].as_array() {
+                        let rulers = rulers.iter().filter_map(|col| col.as_u64())
+                            .map(|col| col as usize).collect();
+                        pane.set_rulers(rulers);
+                    }
+                    (pane.tab_size(), pane.translate_tabs_to_spaces())
+                };
+                if focused {
+                    state.status_bar.set_indentation(tab_size, spaces);
+                }
+                rebuild_menus = true;
+            }
+            _ => println!("unhandled core->fe method {}", method),
         }
+        drop(state);
+        self.finish_cmd(false, rebuild_menus, true, definition);
     }
 
-    fn send_notification(&self, method: &str, params: &Value) {
-        self.core.borrow().send_notification(method, params);
+    // What's left to do after a message from the core, from any handler:
+    // ending the busy state and closing once the core is done, and
+    // redrawing. `opened` is for an update of the focused pane, which is
+    // the file being opened.
+    fn finish_cmd(&self, opened: bool, rebuild_menus: bool, invalidate_all: bool,
+        definition: Option<Location>)
+    {
+        // A save to a copy isn't done until it's encoded back to the file.
+        let saved = {
+            let state = self.state.borrow();
+            state.edit_view.is_pristine() && state.pending_encode.is_none()
+        };
+        let done = match self.busy.get() {
+            Some(Busy::Opening) => opened,
+            Some(Busy::Saving) => saved,
+            None => false,
+        };
+        if done {
+            self.end_busy();
+        }
+        if self.saving_all.get() && saved {
+            self.saving_all.set(false);
+            self.app.save_all_answered(None);
+        }
+        if self.closing.get() && saved {
+            let handle = self.handle.borrow().clone();
+            handle.close();
+            return;
+        }
+        if let Some(location) = definition {
+            self.goto_definition(location);
+        }
+        if rebuild_menus {
+            self.rebuild_menus();
+        }
+        // TODO: edit view should probably handle this logic
+        if invalidate_all {
+            self.invalidate();
+        }
     }
 
-    // Note: caller can't be borrowing the state.
-    fn send_edit_cmd(&self, method: &str, params: &Value, view_id: &str) {
-        let edit_params = json!({
-            "method": method,
-            "params": params,
-            "view_id": view_id,
-        });
-        self.send_notification("edit", &edit_params);
+    // Tell the user what's wrong with their configuration, starting with
+    // the first problem; what was understood is in use anyway.
+    fn report_config_errors(&self, errors: &[ConfigError]) {
+        let msg = match errors.len() {
+            0 => return,
+            1 => errors[0].to_string(),
+            n => format!("{} (and {} more)", errors[0], n - 1),
+        };
+        self.show_info(msg, vec![InfoAction::ShowConfigErrors, InfoAction::Dismiss]);
     }
 
-    // TODO: arguably these should be moved to MainWinHandler to avoid the need
-    // for the parent reference.
-    fn file_open(&self, hwnd_owner: HWND) {
-        let filename = unsafe { get_open_file_dialog_path(hwnd_owner) };
-        if let Some(filename) = filename {
-            self.req_new_view(Some(&filename));
-            let mut state = self.state.borrow_mut();
-            state.edit_view.filename = Some(filename);
-            state.edit_view.clear_line_cache();
+    // List the problems with the configuration files in the palette;
+    // picking one opens its file at its line.
+    fn show_config_errors(&self) {
+        let errors = self.app.config_errors();
+        if errors.is_empty() {
+            self.show_note(&mut self.state.borrow_mut(), "No configuration errors");
+            return;
         }
+        let commands = errors.into_iter().map(|error| {
+            PaletteCommand {
+                name: error.to_string(),
+                run: Box::new(move |handler| {
+                    if let Some(path) = error.path() {
+                        handler.win.goto_location(&Location {
+                            path: path.to_string_lossy().into_owned(),
+                            line: error.line.map_or(0, |line| line.saturating_sub(1)),
+                            col: 0,
+                        });
+                    }
+                }),
+            }
+        }).collect();
+        self.open_picker(commands, "Type to filter configuration errors", None);
     }
 
-    fn file_save(&self, hwnd_owner: HWND) {
-        let filename: Option<String> = self.state.borrow_mut().edit_view.filename.clone();
-        if filename.is_none() {
-            self.file_save_as(hwnd_owner);
+    // Pick a language for the view from the palette, starting at its
+    // current one. With an extension, the language is also set for files
+    // with it from now on, as they're opened.
+    fn pick_language(&self, extension: Option<String>) {
+        let (commands, current) = {
+            let state = self.state.borrow();
+            let commands = state.languages.iter().map(|language| {
+                let (language, extension) = (language.clone(), extension.clone());
+                PaletteCommand {
+                    name: language.clone(),
+                    run: Box::new(move |handler| {
+                        handler.win.set_language(&language, extension.as_ref().map(|e| e.as_str()));
+                    }),
+                }
+            }).collect();
+            let current = state.edit_view.language()
+                .and_then(|current| state.languages.iter().position(|l| l == current));
+            (commands, current)
+        };
+        let placeholder = if extension.is_some() {
+            "Type to filter languages for files like this one"
         } else {
-            let state = self.state.borrow_mut();
-            self.send_notification("save", &json!({
-                "view_id": state.edit_view.view_id,
-                "file_path": filename,
-            }));
+            "Type to filter languages"
+        };
+        self.open_picker(commands, placeholder, current);
+    }
+
+    fn set_language(&self, language: &str, extension: Option<&str>) {
+        self.state.borrow_mut().edit_view.request_language(language, self);
+        if let Some(extension) = extension {
+            let mut settings = self.app.settings.borrow_mut();
+            settings.set_language_override(extension, language);
+            if let Err(e) = settings.save() {
+                println!("failed to save settings: {}", e);
+            }
         }
     }
 
-    fn file_save_as(&self, hwnd_owner: HWND) {
-        if let Some(filename) = unsafe { get_save_file_dialog_path(hwnd_owner) } {
-            let mut state = self.state.borrow_mut();
-            self.send_notification("save", &json!({
-                "view_id": state.edit_view.view_id,
-                "file_path": filename,
-            }));
+    // Give a newly opened file the language set for its extension, if
+    // there is one, in place of the one the core chose.
+    fn apply_language_override(&self) {
+        let edit_view = &mut self.state.borrow_mut().edit_view;
+        let language = edit_view.filename.as_ref()
+            .and_then(|path| self.app.settings.borrow().language_override(path));
+        if let Some(language) = language {
+            edit_view.request_language(&language, self);
+        }
+    }
 
-            state.edit_view.filename = Some(filename.clone());
+    // Write what the focused pane knows to a temp file, and open it in a
+    // new window, for working out where the view and the core disagree.
+    fn dump_view_state(&self) {
+        let dump = {
+            let state = self.state.borrow();
+            let mut dump = state.edit_view.debug_state();
+            dump["split"] = json!(state.split.is_some());
+            dump["theme"] = json!(*self.app.theme_name.borrow());
+            dump
+        };
+        let view_id = dump["view_id"].as_str().unwrap_or("").to_owned();
+        let path = env::temp_dir().join(format!("xi-win-state-{}.json", view_id));
+        let text = serde_json::to_string_pretty(&dump).unwrap_or_default();
+        if let Err(e) = fs::write(&path, text) {
+            self.show_info(format!("Couldn't write {}: {}", path.display(), e),
+                vec![InfoAction::Dismiss]);
+            return;
+        }
+        match create_main(&self.app) {
+            Ok(window) => window.show(),
+            Err(e) => {
+                println!("failed to create window: {:?}", e);
+                return;
+            }
+        }
+        if let Some(win) = self.app.windows().pop() {
+            win.open_file(path.to_string_lossy().into_owned());
         }
     }
-}
 
-impl WinHandler for MainWinHandler {
-    fn connect(&self, handle: &WindowHandle) {
-        *self.win.handle.borrow_mut() = handle.clone();
-        self.win.send_notification("client_started", &json!({}));
-        self.win.req_new_view(None);
+    /// Go to where a plugin found a definition, remembering where the caret
+    /// was for going back.
+    fn goto_definition(&self, location: Location) {
+        if let Some(from) = self.current_location() {
+            self.app.navigation.borrow_mut().push(from);
+        }
+        self.goto_location(&location);
     }
 
-    fn size(&self, x: u32, y: u32) {
-        let (x_px, y_px) = self.win.handle.borrow().pixels_to_px_xy(x, y);
-        self.win.state.borrow_mut().edit_view.size(x_px, y_px);
+    // Forget the places to go back to in this window's file, as it's being
+    // closed, unless another window has it open too.
+    fn forget_places(&self) {
+        let path = match self.state.borrow().edit_view.filename.clone() {
+            Some(path) => path,
+            None => return,
+        };
+        let open_elsewhere = self.app.windows().iter().any(|win| {
+            &**win as *const MainWin != self as *const _ &&
+                win.state.borrow().edit_view.filename.as_ref() == Some(&path)
+        });
+        if !open_elsewhere {
+            self.app.navigation.borrow_mut().forget(&path);
+        }
     }
 
-    fn paint(&self, paint_ctx: &mut PaintCtx) -> bool {
-        let mut state = self.win.state.borrow_mut();
-        state.render(paint_ctx);
-        false
+    /// Go back to where the last jump was from, or forward again.
+    fn navigate(&self, back: bool) {
+        let current = self.current_location();
+        let to = {
+            let mut navigation = self.app.navigation.borrow_mut();
+            if back { navigation.back(current) } else { navigation.forward(current) }
+        };
+        if let Some(location) = to {
+            self.goto_location(&location);
+        }
     }
 
-    fn rebuild_resources(&self) {
-        let mut state = self.win.state.borrow_mut();
-        state.edit_view.rebuild_resources();
+    // The first caret, in a view of a file; untitled buffers can't be
+    // found again.
+    fn current_location(&self) -> Option<Location> {
+        let state = self.state.borrow();
+        let (line, col) = state.edit_view.caret()?;
+        let path = state.edit_view.filename.clone()?;
+        Some(Location { path, line, col })
     }
 
-    fn command(&self, id: u32) {
-        match id {
-            x if x == MenuEntries::Exit as u32 => {
-                self.win.handle.borrow().close();
-            }
-            x if x == MenuEntries::Open as u32 => {
-                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
-                self.win.file_open(hwnd);
-            }
-            x if x == MenuEntries::Save as u32 => {
-                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
-                self.win.file_save(hwnd);
+    // Show a location in this window if it has the file, or in the window
+    // that has it, or else open it in a new one.
+    fn goto_location(&self, location: &Location) {
+        let windows = self.app.windows();
+        let shown_in = windows.iter().find(|win| {
+            let state = win.state.borrow();
+            state.edit_view.filename.as_ref().map_or(false, |path| location.is_in(path))
+        });
+        if let Some(win) = shown_in {
+            if &**win as *const MainWin != self as *const _ {
+                win.handle.borrow().activate();
             }
-            x if x == MenuEntries::SaveAs as u32 => {
-                let hwnd = self.win.handle.borrow().get_hwnd().unwrap();
-                self.win.file_save_as(hwnd);
+            let edit_view = &mut win.state.borrow_mut().edit_view;
+            edit_view.skip_caret_jump();
+            edit_view.jump_to(location.line, location.col, win);
+            return;
+        }
+        match create_main(&self.app) {
+            Ok(window) => window.show(),
+            Err(e) => {
+                println!("failed to create window: {:?}", e);
+                return;
             }
+        }
+        if let Some(win) = self.app.windows().pop() {
+            win.open_file(location.path.clone());
+            win.state.borrow_mut().edit_view.set_pending_jump(location.line, location.col);
+        }
+    }
 
-            x if x == MenuEntries::Undo as u32 => {
-                self.win.state.borrow_mut().edit_view.undo(&self.win);
-            }
-            x if x == MenuEntries::Redo as u32 => {
-                self.win.state.borrow_mut().edit_view.redo(&self.win);
-            }
-            // TODO: cut, copy, paste (requires pasteboard)
-            x if x == MenuEntries::UpperCase as u32 => {
-                self.win.state.borrow_mut().edit_view.upper_case(&self.win);
-            }
-            x if x == MenuEntries::LowerCase as u32 => {
-                self.win.state.borrow_mut().edit_view.lower_case(&self.win);
+    // Open the file given on the command line or, failing that, the one
+    // from the last session, back where it was scrolled to.
+    fn open_startup_file(&self) {
+        // Flags, like --paint-stats, aren't files.
+        if let Some(filename) = env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+            self.open_file(filename);
+            return;
+        }
+        let session = self.app.settings.borrow().session();
+        let file = session.map(|mut session| {
+            let active = session.active;
+            session.files.swap_remove(active)
+        });
+        match file {
+            Some(ref file) if Path::new(&file.path).exists() => {
+                self.open_file(file.path.clone());
+                self.state.borrow_mut().edit_view
+                    .set_pending_scroll(file.scroll_offset, file.caret_line);
             }
-            x if x == MenuEntries::Transpose as u32 => {
-                self.win.state.borrow_mut().edit_view.transpose(&self.win);
+            Some(file) => {
+                self.req_new_view(None);
+                self.state.borrow_mut().status_bar
+                    .set_message(format!("{} no longer exists", file.path));
             }
+            None => self.req_new_view(None),
+        }
+    }
 
-            x if x == MenuEntries::AddCursorAbove as u32 => {
-                self.win.state.borrow_mut().edit_view.add_cursor_above(&self.win);
-            }
-            x if x == MenuEntries::AddCursorBelow as u32 => {
-                self.win.state.borrow_mut().edit_view.add_cursor_below(&self.win);
-            }
-            x if x == MenuEntries::SingleSelection as u32 => {
-                self.win.state.borrow_mut().edit_view.single_selection(&self.win);
-            }
-            x if x == MenuEntries::SelectAll as u32 => {
-                self.win.state.borrow_mut().edit_view.select_all(&self.win);
-            }
-            _ => println!("unexpected id {}", id),
+    // Store the window placement and open file in the settings, and write
+    // them out.
+    fn save_settings(&self) {
+        let mut settings = self.app.settings.borrow_mut();
+        if let Some(placement) = self.handle.borrow().get_placement() {
+            settings.set_window_placement(&placement);
+        }
+        {
+            // Untitled buffers aren't saved in the session.
+            let edit_view = &self.state.borrow().edit_view;
+            let session = edit_view.filename.as_ref().map(|filename| Session {
+                files: vec![SessionFile {
+                    path: filename.clone(),
+                    scroll_offset: edit_view.scroll_offset(),
+                    caret_line: edit_view.caret_line().unwrap_or(0),
+                }],
+                active: 0,
+            });
+            settings.set_session(session.as_ref());
+        }
+        if let Err(e) = settings.save() {
+            println!("failed to save settings: {}", e);
         }
     }
 
-    fn char(&self, ch: u32, mods: u32) {
-        let edit_view = &mut self.win.state.borrow_mut().edit_view;
-        edit_view.char(ch, mods, &self.win);
+    /// Bring Undo and Redo in the menubar up to date with the edit view,
+    /// and show the note it left, if an undo or redo had nothing to do or
+    /// an edit was turned down.
+    fn update_history(&self, state: &mut MainWinState) {
+        let handle = self.handle.borrow();
+        handle.enable_menu_item(MenuEntries::Undo as u32, state.edit_view.can_undo());
+        handle.enable_menu_item(MenuEntries::Redo as u32, state.edit_view.can_redo());
+        if let Some(note) = state.edit_view.take_note() {
+            self.show_note(state, note);
+        }
     }
 
-    fn keydown(&self, vk_code: i32, mods: u32) -> bool {
-        let edit_view = &mut self.win.state.borrow_mut().edit_view;
-        edit_view.keydown(vk_code, mods, &self.win)
+    /// Bring the line and selection counts in the status bar, and the
+    /// commands that need a selection, up to date with the edit view.
+    fn update_counts(&self, state: &mut MainWinState) {
+        state.status_bar.set_counts(state.edit_view.line_count(),
+            state.edit_view.selection_counts());
+        let handle = self.handle.borrow();
+        let (x0, y0, x1, y1) = state.status_bar.bounds();
+        handle.invalidate_rect(x0, y0, x1, y1);
+        let has_selection = state.edit_view.has_selection();
+        for &id in menus::CASE_ENTRIES.iter() {
+            handle.enable_menu_item(id, has_selection);
+        }
     }
 
-    fn mouse_wheel(&self, delta: i32, mods: u32) {
-        let edit_view = &mut self.win.state.borrow_mut().edit_view;
-        edit_view.mouse_wheel(delta, mods, &self.win)
+    // Give accessibility clients what the focused pane shows now, if any
+    // have asked for it.
+    fn update_accessibility(&self, state: &MainWinState) {
+        if let Some(ref accessibility) = *self.accessibility.borrow() {
+            let edit_view = &state.edit_view;
+            let name = edit_view.filename.as_ref()
+                .and_then(|filename| Path::new(filename).file_name())
+                .and_then(|name| name.to_str()).unwrap_or("Untitled");
+            accessibility.update(Snapshot::new(name, &edit_view.cached_lines(),
+                edit_view.first_selection(), edit_view.visible_lines(), edit_view.has_focus()));
+        }
     }
 
-    fn destroy(&self) {
-        win_main::request_quit();
+    fn run_accessibility_request(&self, request: Request) {
+        let edit_view = &mut self.state.borrow_mut().edit_view;
+        match request {
+            Request::Select(start, end) => edit_view.select_range(start, end, self),
+            Request::ScrollTo(line) => edit_view.scroll_to(line, 0, self),
+        }
     }
 
-    fn as_any(&self) -> &Any { self }
-}
+    /// Show a note in the status bar for a moment.
+    fn show_note(&self, state: &mut MainWinState, note: &str) {
+        let handle = self.handle.borrow();
+        state.status_bar.set_note(note);
+        handle.set_timer(Timers::StatusNote as usize, STATUS_NOTE_MS);
+        let (x0, y0, x1, y1) = state.status_bar.bounds();
+        handle.invalidate_rect(x0, y0, x1, y1);
+    }
 
-impl MainWin {
-    fn req_new_view(&self, filename: Option<&str>) {
-        let mut params = json!({});
-        if let Some(filename) = filename {
-            params["file_path"] = json!(filename);
+    /// The commands offered in the command palette: for now, those of the
+    /// menus, with the current plugins, languages and recent files.
+    /// Features without a menu item can add entries here.
+    fn palette_commands(&self) -> Vec<PaletteCommand> {
+        let mut commands: Vec<PaletteCommand> = [
+            ("New Window", MenuEntries::NewWindow as u32),
+            ("Open File…", MenuEntries::Open as u32),
+            ("Save", MenuEntries::Save as u32),
+            ("Save As…", MenuEntries::SaveAs as u32),
+            ("Save All", MenuEntries::SaveAll as u32),
+            ("Print…", MenuEntries::Print as u32),
+            ("Clear Recent Files", MenuEntries::ClearRecent as u32),
+            ("Reload Keymap", MenuEntries::ReloadKeymap as u32),
+            ("Show Config Errors", MenuEntries::ShowConfigErrors as u32),
+            ("Change Language…", MenuEntries::ChangeLanguage as u32),
+            ("Undo", MenuEntries::Undo as u32),
+            ("Redo", MenuEntries::Redo as u32),
+            ("Cut", MenuEntries::Cut as u32),
+            ("Copy", MenuEntries::Copy as u32),
+            ("Paste", MenuEntries::Paste as u32),
+            ("Upper Case", MenuEntries::UpperCase as u32),
+            ("Lower Case", MenuEntries::LowerCase as u32),
+            ("Title Case", MenuEntries::TitleCase as u32),
+            ("Transpose", MenuEntries::Transpose as u32),
+            ("Duplicate Line", MenuEntries::DuplicateLine as u32),
+            ("Join Lines", MenuEntries::JoinLines as u32),
+            ("Sort Lines", MenuEntries::SortLines as u32),
+            ("Sort Lines (Case Insensitive)", MenuEntries::SortLinesCaseInsensitive as u32),
+            ("Toggle Comment", MenuEntries::ToggleComment as u32),
+            ("Trim Trailing Whitespace", MenuEntries::TrimWhitespace as u32),
+            ("Tab Size: 2", MenuEntries::TabSize2 as u32),
+            ("Tab Size: 4", MenuEntries::TabSize4 as u32),
+            ("Tab Size: 8", MenuEntries::TabSize8 as u32),
+            ("Toggle Indent Using Spaces", MenuEntries::IndentUsingSpaces as u32),
+            ("Convert Line Endings to LF", MenuEntries::LineEndingLf as u32),
+            ("Convert Line Endings to CRLF", MenuEntries::LineEndingCrlf as u32),
+            ("Toggle Read-Only", MenuEntries::ReadOnly as u32),
+            ("Toggle Trim Trailing Whitespace on Save", MenuEntries::TrimOnSave as u32),
+            ("Toggle End with Newline on Save", MenuEntries::FinalNewlineOnSave as u32),
+            ("Toggle Word Wrap", MenuEntries::WordWrap as u32),
+            ("Toggle Show Whitespace", MenuEntries::ShowWhitespace as u32),
+            ("Toggle Show Trailing Whitespace", MenuEntries::ShowTrailingWhitespace as u32),
+            ("Hide Line Numbers", MenuEntries::LineNumbersOff as u32),
+            ("Show Line Numbers", MenuEntries::LineNumbersAbsolute as u32),
+            ("Show Relative Line Numbers", MenuEntries::LineNumbersRelative as u32),
+            ("Show Hybrid Line Numbers", MenuEntries::LineNumbersHybrid as u32),
+            ("Toggle Indent Guides", MenuEntries::IndentGuides as u32),
+            ("Toggle Ruler", MenuEntries::Rulers as u32),
+            ("Toggle Scroll Past End", MenuEntries::ScrollPastEnd as u32),
+            ("Toggle Minimap", MenuEntries::Minimap as u32),
+            ("Antialias Text with ClearType", MenuEntries::AntialiasClearType as u32),
+            ("Antialias Text in Grayscale", MenuEntries::AntialiasGrayscale as u32),
+            ("Don't Antialias Text", MenuEntries::AntialiasAliased as u32),
+            ("Toggle Split View", MenuEntries::SplitView as u32),
+            ("Toggle Full Screen", MenuEntries::FullScreen as u32),
+            ("Go Back", MenuEntries::GoBack as u32),
+            ("Go Forward", MenuEntries::GoForward as u32),
+            ("Add Cursor Above", MenuEntries::AddCursorAbove as u32),
+            ("Add Cursor Below", MenuEntries::AddCursorBelow as u32),
+            ("Single Selection", MenuEntries::SingleSelection as u32),
+            ("Select All", MenuEntries::SelectAll as u32),
+            ("Toggle Bookmark", MenuEntries::ToggleBookmark as u32),
+            ("Next Bookmark", MenuEntries::NextBookmark as u32),
+            ("Previous Bookmark", MenuEntries::PrevBookmark as u32),
+            ("Clear Bookmarks", MenuEntries::ClearBookmarks as u32),
+            ("Exit", MenuEntries::Exit as u32),
+        ].iter().map(|&(name, id)| PaletteCommand::menu(name, id)).collect();
+        commands.push(PaletteCommand {
+            name: "Center Current Line".to_owned(),
+            run: Box::new(|handler| {
+                handler.win.state.borrow_mut().edit_view.recenter(&handler.win);
+            }),
+        });
+        commands.push(PaletteCommand {
+            name: "Debug: Dump View State".to_owned(),
+            run: Box::new(|handler| handler.win.dump_view_state()),
+        });
+        let state = self.state.borrow();
+        if let Some(extension) = state.edit_view.filename.as_ref()
+            .and_then(|path| settings::extension(path))
+        {
+            commands.push(PaletteCommand::menu(
+                format!("Set Language for All .{} Files…", extension),
+                MenuEntries::SetLanguageForExtension as u32));
+        }
+        for (i, plugin) in state.edit_view.plugins().iter().enumerate() {
+            let verb = if plugin.running { "Stop" } else { "Start" };
+            commands.push(PaletteCommand::menu(format!("{} Plugin: {}", verb, plugin.name),
+                PLUGIN_MENU_BASE + i as u32));
+        }
+        for (i, language) in state.languages.iter().enumerate() {
+            commands.push(PaletteCommand::menu(format!("Set Language: {}", language),
+                LANGUAGE_MENU_BASE + i as u32));
+        }
+        for (i, path) in state.recent_files.iter().enumerate() {
+            commands.push(PaletteCommand::menu(format!("Open Recent: {}", path),
+                RECENT_MENU_BASE + i as u32));
+        }
+        commands
+    }
+
+    /// Size and place the panes in the edit area: the edit view, and the
+    /// other pane while split. Each pane tells the core its own wrap width
+    /// and visible lines.
+    fn layout_panes(&self) {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let width = state.edit_size.0;
+        let (y0, height) = state.panes_area();
+        match state.split {
+            None => {
+                state.edit_view.set_top(y0);
+                state.edit_view.size(width, height);
+                state.edit_view.update_wrap_width(self);
+                state.edit_view.update_viewport(self);
+            }
+            Some(ref mut split) => {
+                let (top_height, bottom_top) = split.pane_heights(height);
+                let (top, bottom) = if split.focused_on_top {
+                    (&mut state.edit_view, &mut split.other)
+                } else {
+                    (&mut split.other, &mut state.edit_view)
+                };
+                top.set_top(y0);
+                top.size(width, top_height);
+                bottom.set_top(y0 + bottom_top);
+                bottom.size(width, (height - bottom_top).max(0.0));
+                for pane in vec![top, bottom] {
+                    pane.update_wrap_width(self);
+                    pane.update_viewport(self);
+                }
+            }
+        }
+    }
+
+    fn toggle_split(&self) {
+        if self.state.borrow().split.is_some() {
+            // The focused pane takes over the edit area, where it's
+            // scrolled to.
+            self.close_split();
+        } else {
+            self.split_view();
+        }
+        self.layout_panes();
+        self.rebuild_menus();
+        self.invalidate();
+    }
+
+    // Remember where the splitter was moved to, for the splits made after
+    // it in every window.
+    fn save_split_height(&self) {
+        let height = match self.state.borrow().split {
+            Some(ref split) => split.splitter.height(),
+            None => return,
+        };
+        let mut settings = self.app.settings.borrow_mut();
+        settings.set_split_height(height);
+        if let Err(e) = settings.save() {
+            println!("failed to save settings: {}", e);
         }
+    }
+
+    // Split the edit area, with a second view of the file below the
+    // first, scrolled to the same place. The core only shares a buffer
+    // between views of the same file, so an untitled buffer can't be
+    // split.
+    fn split_view(&self) {
+        let filename = {
+            let mut state = self.state.borrow_mut();
+            let filename = state.edit_view.filename.clone();
+            if filename.is_none() {
+                self.show_note(&mut state, "Save the file to split the view");
+                return;
+            }
+            // The core has a copy of the file open, not the file.
+            if !state.edit_view.encoding().is_utf8() {
+                self.show_note(&mut state, "Only a UTF-8 file can be split");
+                return;
+            }
+            let mut pane = state.edit_view.new_pane();
+            pane.set_pending_scroll(state.edit_view.scroll_offset(),
+                state.edit_view.caret_line().unwrap_or(0));
+            let height = self.app.settings.borrow().split_height();
+            state.split = Some(Split::new(pane, height));
+            filename.unwrap()
+        };
+        self.req_split_view(&filename);
+    }
+
+    // Ask the core for a view of the file for the split's pane without a
+    // view id.
+    fn req_split_view(&self, filename: &str) {
         let handle = self.handle.borrow().get_idle_handle().unwrap();
-        self.core.borrow_mut().send_request("new_view", &params,
-            move |value| {
+        let params = json!({"file_path": filename});
+        let request = self.core.borrow_mut().send_request_with_id("new_view", &params,
+            move |request, value| {
                 let value = value.clone();
                 handle.add_idle(move |a| {
                     let handler = a.downcast_ref::<MainWinHandler>().unwrap();
-                    let edit_view = &mut handler.win.state.borrow_mut().edit_view;
-                    edit_view.set_view_id(value.as_str().unwrap());
+                    let win = &handler.win;
+                    let view_id = value.as_str().unwrap();
+                    let mut state = win.state.borrow_mut();
+                    let state = &mut *state;
+                    state.new_views.retain(|v| v.request != request);
+                    // The pane without an id, unless the split has been
+                    // closed in the meantime.
+                    let pane = match state.split {
+                        Some(ref mut split) if split.other.view_id.is_empty() => {
+                            Some(&mut split.other)
+                        }
+                        Some(_) if state.edit_view.view_id.is_empty() => {
+                            Some(&mut state.edit_view)
+                        }
+                        _ => None,
+                    };
+                    match pane {
+                        Some(pane) => {
+                            pane.set_view_id(view_id);
+                            pane.update_wrap_width(win);
+                            pane.update_viewport(win);
+                        }
+                        None => win.send_notification("close_view", &json!({"view_id": view_id})),
+                    }
                 });
             }
         );
+        self.state.borrow_mut().new_views.push(NewView { request, split: true, view_id: None });
     }
 
-    fn handle_cmd(&self, method: &str, params: &Value) {
+    // Hide both panes while the window is minimized, or show them again.
+    fn set_panes_hidden(&self, hidden: bool) {
         let mut state = self.state.borrow_mut();
-        match method {
-            "update" => state.edit_view.apply_update(&params["update"]),
-            "scroll_to" => state.edit_view.scroll_to(params["line"].as_u64().unwrap() as usize),
-            "available_themes" => (), // TODO
-            "available_plugins" => (), // TODO
-            "config_changed" => (), // TODO
-            _ => println!("unhandled core->fe method {}", method),
+        let state = &mut *state;
+        state.edit_view.set_hidden(hidden, self);
+        if let Some(ref mut split) = state.split {
+            split.other.set_hidden(hidden, self);
+        }
+    }
+
+    // Drop the pane that doesn't have focus, and its view.
+    fn close_split(&self) {
+        let split = self.state.borrow_mut().split.take();
+        if let Some(split) = split {
+            if !split.other.view_id.is_empty() {
+                self.send_notification("close_view", &json!({"view_id": split.other.view_id}));
+            }
+        }
+    }
+
+    // Give focus to the pane that doesn't have it.
+    fn switch_pane(&self) {
+        {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+            match state.split {
+                Some(ref mut split) => {
+                    mem::swap(&mut state.edit_view, &mut split.other);
+                    // Views still on their way go to the panes that asked.
+                    for v in &mut state.new_views {
+                        v.split = !v.split;
+                    }
+                    split.focused_on_top = !split.focused_on_top;
+                    split.other.end_drag(self);
+                    split.other.set_focus(false, self);
+                }
+                None => return,
+            }
+            state.edit_view.set_focus(true, self);
+            state.status_bar.set_overwrite(state.edit_view.is_overwrite());
+            state.status_bar.set_read_only(state.edit_view.is_read_only());
+            self.update_counts(state);
+            state.status_bar.set_line_ending(state.edit_view.line_ending());
+            state.status_bar.set_encoding(state.edit_view.encoding());
+            state.status_bar.set_language(state.edit_view.language());
+            state.status_bar.set_indentation(state.edit_view.tab_size(),
+                state.edit_view.translate_tabs_to_spaces());
+            self.update_history(state);
+            self.update_accessibility(state);
+        }
+        self.rebuild_menus();
+    }
+
+    fn toggle_fullscreen(&self) {
+        let handle = self.handle.borrow().clone();
+        // The window is resized through the handler, which borrows the
+        // state.
+        handle.set_fullscreen(!handle.is_fullscreen());
+    }
+
+    fn open_palette(&self) {
+        let commands = self.palette_commands();
+        self.open_picker(commands, "Type to filter commands", None);
+    }
+
+    // Open the palette on a list of its own, with the entry at `selected`
+    // highlighted.
+    fn open_picker(&self, commands: Vec<PaletteCommand>, placeholder: &'static str,
+        selected: Option<usize>)
+    {
+        let names = commands.iter().map(|command| command.name.clone()).collect();
+        {
+            let mut state = self.state.borrow_mut();
+            state.palette.open(names, placeholder, selected);
+            state.palette_commands = commands;
+        }
+        self.invalidate();
+    }
+
+    fn close_palette(&self) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.palette.close();
+            state.palette_commands.clear();
         }
-        // TODO: edit view should probably handle this logic
         self.invalidate();
     }
 
+    /// Rebuild the menubar after the state it shows has changed.
+    fn rebuild_menus(&self) {
+        let menubar = menus::create_menus(&self.state.borrow());
+        // Setting the menu can resize the window, so the state can't be
+        // borrowed.
+        self.handle.borrow().set_menu(menubar);
+    }
+
     pub fn invalidate(&self) {
         self.handle.borrow().invalidate();
     }
 }
 
-fn create_main(core: Core) -> Result<WindowHandle, Error> {
-    let main_state = MainWinState::new();
-    let main_win = Rc::new(MainWin::new(core, main_state));
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn create_main(app: &Rc<App>) -> Result<WindowHandle, Error> {
+    let mut main_state = MainWinState::new();
+    main_state.recent_files = app.settings.borrow().recent_files();
+    main_state.save_fixups = app.settings.borrow().save_fixups();
+    main_state.text_antialias = app.settings.borrow().text_antialias();
+    main_state.edit_view.set_gutter_colors(app.gutter_colors.get());
+    main_state.overlay_style.set_colors(app.overlay_colors.get());
+    main_state.edit_view.set_line_numbers(app.settings.borrow().line_numbers());
+    main_state.edit_view.set_overscroll_lines(app.settings.borrow().overscroll_lines());
+    main_state.edit_view.set_middle_click(app.settings.borrow().middle_click());
+    main_state.edit_view.set_mirrored(app.mirrored);
+    main_state.status_bar.set_mirrored(app.mirrored);
+    let line_ending = app.settings.borrow().line_ending();
+    main_state.edit_view.set_line_ending(line_ending);
+    main_state.status_bar.set_line_ending(line_ending);
+    main_state.status_bar.set_encoding(Encoding::Utf8);
+    // The core only sends the languages once, at startup.
+    if let Some(win) = app.active_window() {
+        main_state.languages = win.state.borrow().languages.clone();
+    }
+    let main_win = Rc::new(MainWin::new(app.clone(), main_state));
+    app.add_window(&main_win);
+    let menubar = menus::create_menus(&main_win.state.borrow());
     let main_win_handler = MainWinHandler {
         win: main_win,
     };
 
-    let menubar = menus::create_menus();
-
     let mut builder = WindowBuilder::new();
     builder.set_handler(Box::new(main_win_handler));
     builder.set_title("xi-editor");
     builder.set_menu(menubar);
     let window = builder.build().unwrap();
+    let mut idle_handle = app.idle_handle.lock().unwrap();
+    if idle_handle.is_none() {
+        *idle_handle = window.get_idle_handle();
+    }
     Ok(window)
 }
 
@@ -319,12 +3155,43 @@ impl MyHandler {
 impl Handler for MyHandler {
     fn notification(&self, method: &str, params: &Value) {
         if let Some(idle_handle) = self.win_handle.lock().unwrap().as_ref() {
-            // Note: could pass these by ownership, but we'll change where they get parsed.
+            let notification = match Notification::parse(method, params) {
+                Ok(notification) => notification,
+                Err(e) => {
+                    println!("malformed {} from core: {}", method, e);
+                    return;
+                }
+            };
+            idle_handle.add_idle(move |a| {
+                let handler = a.downcast_ref::<MainWinHandler>().unwrap();
+                handler.win.app.handle_cmd(notification);
+            });
+        }
+    }
+
+    fn request(&self, method: &str, params: &Value, responder: Responder) {
+        // Measurement needs the DirectWrite objects on the UI thread.
+        if let Some(idle_handle) = self.win_handle.lock().unwrap().as_ref() {
             let method = method.to_owned();
             let params = params.clone();
             idle_handle.add_idle(move |a| {
                 let handler = a.downcast_ref::<MainWinHandler>().unwrap();
-                handler.win.handle_cmd(&method, &params);
+                let win = handler.win.app.active_window().unwrap_or_else(|| handler.win.clone());
+                win.handle_request(&method, &params, responder);
+            });
+            return;
+        }
+        responder.respond(Err(json!({"code": -32603, "message": "no window yet"})));
+    }
+
+    fn error(&self, error: &Value) {
+        if let Some(idle_handle) = self.win_handle.lock().unwrap().as_ref() {
+            let msg = error["message"].as_str().unwrap_or("Unknown error from the core")
+                .to_owned();
+            idle_handle.add_idle(move |a| {
+                let handler = a.downcast_ref::<MainWinHandler>().unwrap();
+                let win = handler.win.app.active_window().unwrap_or_else(|| handler.win.clone());
+                win.alert(&msg);
             });
         }
     }
@@ -339,8 +3206,34 @@ fn main() {
     menus::set_accel(&mut runloop);
     let handler = MyHandler::new(runloop.get_handle());
     let core = Core::new(xi_peer, rx, handler.clone());
-    let window = create_main(core).unwrap();
-    *handler.win_handle.lock().unwrap() = window.get_idle_handle();
-    window.show();
+    let (settings, settings_error) = Settings::load();
+    let placement = settings.window_placement();
+    let mirrored = settings.mirrored();
+    let (keymap, keymap_errors) = Keymap::load(None);
+    let app = Rc::new(App {
+        core,
+        settings: RefCell::new(settings),
+        settings_error,
+        keymap: RefCell::new(keymap),
+        keymap_errors: RefCell::new(keymap_errors),
+        windows: Default::default(),
+        active: Default::default(),
+        idle_handle: handler.win_handle.clone(),
+        started: Cell::new(false),
+        mirrored,
+        navigation: RefCell::new(Navigation::new()),
+        save_all: RefCell::new(None),
+        gutter_colors: Cell::new(GutterColors::default()),
+        overlay_colors: Cell::new(OverlayColors::default()),
+        theme_name: RefCell::new(None),
+    });
+    let window = create_main(&app).unwrap();
+    match placement {
+        Some(placement) => window.show_with_placement(&placement),
+        None => window.show(),
+    }
+    if let Some(win) = app.active_window() {
+        win.report_config_errors(&app.config_errors());
+    }
     runloop.run();
 }
@@ -14,19 +14,48 @@
 
 //! Configuration and runtime for the main window's menus.
 
+use std::path::Path;
+
 use winapi::shared::minwindef::WORD;
 use winapi::um::winuser::*;
 
 use xi_win_shell::menu::Menu;
+use xi_win_shell::paint::TextAntialias;
 use xi_win_shell::win_main::RunLoop;
 
+use MainWinState;
+use edit_view::{EditView, LineEnding, LineNumbers, ShowWhitespace};
+use settings;
+
+/// Ids for the items of the Plugins menu, which are numbered from this in
+/// the order of the view's plugin list.
+pub const PLUGIN_MENU_BASE: u32 = 0x1000;
+/// Ids for the items of the Language menu, numbered in the order of the
+/// (sorted) list of available languages.
+pub const LANGUAGE_MENU_BASE: u32 = 0x2000;
+/// Ids for the items of the File > Recent menu, numbered in the order of
+/// the recent files list.
+pub const RECENT_MENU_BASE: u32 = 0x3000;
+/// Ids for the bookmarks listed in the Bookmarks menu, numbered in line
+/// order.
+pub const BOOKMARK_MENU_BASE: u32 = 0x4000;
+
+// Paths longer than this (in chars) are shortened in the Recent menu.
+const MAX_RECENT_LABEL: usize = 60;
+// And the text of bookmarked lines, in the Bookmarks menu.
+const MAX_BOOKMARK_PREVIEW: usize = 40;
+
 #[repr(u32)]
 pub enum MenuEntries {
     // File menu entries
     Exit = 0x100,
+    NewWindow,
     Open,
     Save,
     SaveAs,
+    SaveAll,
+    Print,
+    ClearRecent,
 
     // Edit menu entries
     Undo,
@@ -36,48 +65,388 @@ pub enum MenuEntries {
     Paste,
     UpperCase,
     LowerCase,
+    TitleCase,
     Transpose,
+    DuplicateLine,
+    JoinLines,
+    SortLines,
+    SortLinesCaseInsensitive,
+    ToggleComment,
+    TrimWhitespace,
+    TabSize2,
+    TabSize4,
+    TabSize8,
+    IndentUsingSpaces,
+    LineEndingLf,
+    LineEndingCrlf,
+    ReadOnly,
+    TrimOnSave,
+    FinalNewlineOnSave,
+
+    // View menu entries
+    WordWrap,
+    ShowWhitespace,
+    ShowTrailingWhitespace,
+    LineNumbersOff,
+    LineNumbersAbsolute,
+    LineNumbersRelative,
+    LineNumbersHybrid,
+    IndentGuides,
+    Rulers,
+    ScrollPastEnd,
+    Minimap,
+    AntialiasClearType,
+    AntialiasGrayscale,
+    AntialiasAliased,
+    SplitView,
+    FullScreen,
+    CommandPalette,
+    GoBack,
+    GoForward,
 
     // Selection menu entries
     SingleSelection,
     AddCursorAbove,
     AddCursorBelow,
     SelectAll,
+
+    // Language menu entries
+    ChangeLanguage,
+    SetLanguageForExtension,
+
+    ShowConfigErrors,
+
+    // Bookmarks menu entries
+    ToggleBookmark,
+    NextBookmark,
+    PrevBookmark,
+    ClearBookmarks,
+
+    // Added after the others, so their ids stay the same.
+    ReloadKeymap,
 }
 
-pub fn create_menus() -> Menu {
+/// The commands that change the case of the selection, which are greyed
+/// out while there isn't one.
+pub const CASE_ENTRIES: [u32; 3] = [
+    MenuEntries::UpperCase as u32,
+    MenuEntries::LowerCase as u32,
+    MenuEntries::TitleCase as u32,
+];
+
+/// The line number modes, by their menu entries.
+pub const LINE_NUMBERS_ENTRIES: [(LineNumbers, u32); 4] = [
+    (LineNumbers::Off, MenuEntries::LineNumbersOff as u32),
+    (LineNumbers::Absolute, MenuEntries::LineNumbersAbsolute as u32),
+    (LineNumbers::Relative, MenuEntries::LineNumbersRelative as u32),
+    (LineNumbers::Hybrid, MenuEntries::LineNumbersHybrid as u32),
+];
+
+/// The text antialiasing modes, by their menu entries.
+pub const ANTIALIAS_ENTRIES: [(TextAntialias, u32); 3] = [
+    (TextAntialias::ClearType, MenuEntries::AntialiasClearType as u32),
+    (TextAntialias::Grayscale, MenuEntries::AntialiasGrayscale as u32),
+    (TextAntialias::Aliased, MenuEntries::AntialiasAliased as u32),
+];
+
+/// Create the menubar, with checkmarks and the plugin and language lists
+/// reflecting the current state.
+pub fn create_menus(state: &MainWinState) -> Menu {
+    let edit_view = &state.edit_view;
     let mut file_menu = Menu::new();
+    file_menu.add_item(MenuEntries::NewWindow as u32, "New &Window\tCtrl+Shift+N");
     file_menu.add_item(MenuEntries::Open as u32, "&Open…\tCtrl+O");
+    file_menu.add_dropdown(create_recent_menu(&state.recent_files), "Open &Recent");
     file_menu.add_item(MenuEntries::Save as u32, "&Save\tCtrl+S");
     file_menu.add_item(MenuEntries::SaveAs as u32, "Save &as…\tCtrl+Shift+S");
+    file_menu.add_item(MenuEntries::SaveAll as u32, "Save A&ll\tCtrl+Alt+S");
+    file_menu.add_separator();
+    file_menu.add_item(MenuEntries::Print as u32, "&Print…\tCtrl+P");
+    file_menu.add_item(MenuEntries::ShowConfigErrors as u32, "Show Config &Errors…");
+    file_menu.add_separator();
     file_menu.add_item(MenuEntries::Exit as u32, "E&xit");
     let mut menubar = Menu::new();
     menubar.add_dropdown(file_menu, "&File");
     let mut edit_menu = Menu::new();
     edit_menu.add_item(MenuEntries::Undo as u32, "&Undo\tCtrl+Z");
     edit_menu.add_item(MenuEntries::Redo as u32, "&Redo\tCtrl+Y");
+    edit_menu.enable_item(MenuEntries::Undo as u32, edit_view.can_undo());
+    edit_menu.enable_item(MenuEntries::Redo as u32, edit_view.can_redo());
     edit_menu.add_separator();
     edit_menu.add_item(MenuEntries::Cut as u32, "Cu&t\tCtrl+X");
     edit_menu.add_item(MenuEntries::Copy as u32, "&Copy\tCtrl+C");
     edit_menu.add_item(MenuEntries::Paste as u32, "&Paste\tCtrl+V");
-    edit_menu.add_item(MenuEntries::UpperCase as u32, "Upper Case");
-    edit_menu.add_item(MenuEntries::LowerCase as u32, "Lower Case");
-    edit_menu.add_item(MenuEntries::Transpose as u32, "Transpose");
+    edit_menu.add_item(MenuEntries::UpperCase as u32, "Upper Case\tCtrl+Shift+U");
+    edit_menu.add_item(MenuEntries::LowerCase as u32, "Lower Case\tCtrl+U");
+    edit_menu.add_item(MenuEntries::TitleCase as u32, "Title Case");
+    let has_selection = edit_view.has_selection();
+    for &id in CASE_ENTRIES.iter() {
+        edit_menu.enable_item(id, has_selection);
+    }
+    edit_menu.add_item(MenuEntries::Transpose as u32, "Transpose\tCtrl+T");
+    edit_menu.add_item(MenuEntries::DuplicateLine as u32, "&Duplicate Line\tCtrl+Shift+D");
+    edit_menu.add_item(MenuEntries::JoinLines as u32, "&Join Lines\tCtrl+J");
+    edit_menu.add_item(MenuEntries::SortLines as u32, "S&ort Lines");
+    edit_menu.add_item(MenuEntries::ToggleComment as u32, "Toggle Co&mment\tCtrl+/");
+    edit_menu.add_item(MenuEntries::TrimWhitespace as u32, "Trim Trailing &Whitespace");
+    edit_menu.add_separator();
+    edit_menu.add_dropdown(create_indentation_menu(edit_view), "&Indentation");
+    edit_menu.add_dropdown(create_line_ending_menu(edit_view), "&Line Endings");
+    edit_menu.add_item(MenuEntries::ReadOnly as u32, "Read &Only");
+    edit_menu.check_item(MenuEntries::ReadOnly as u32, edit_view.is_read_only());
+    edit_menu.add_item(MenuEntries::TrimOnSave as u32, "Trim Trailing Whitespace on &Save");
+    edit_menu.check_item(MenuEntries::TrimOnSave as u32, state.save_fixups.trim_whitespace);
+    edit_menu.add_item(MenuEntries::FinalNewlineOnSave as u32, "End with &Newline on Save");
+    edit_menu.check_item(MenuEntries::FinalNewlineOnSave as u32,
+        state.save_fixups.final_newline);
     menubar.add_dropdown(edit_menu, "&Edit");
+    let mut view_menu = Menu::new();
+    view_menu.add_item(MenuEntries::WordWrap as u32, "&Word Wrap");
+    view_menu.check_item(MenuEntries::WordWrap as u32, edit_view.is_word_wrap());
+    view_menu.add_item(MenuEntries::ShowWhitespace as u32, "Show W&hitespace");
+    view_menu.add_item(MenuEntries::ShowTrailingWhitespace as u32, "Show &Trailing Whitespace");
+    let show_whitespace = edit_view.show_whitespace();
+    view_menu.check_item(MenuEntries::ShowWhitespace as u32,
+        show_whitespace == ShowWhitespace::All);
+    view_menu.check_item(MenuEntries::ShowTrailingWhitespace as u32,
+        show_whitespace == ShowWhitespace::Trailing);
+    view_menu.add_dropdown(create_line_numbers_menu(edit_view.line_numbers()), "Line &Numbers");
+    view_menu.add_item(MenuEntries::IndentGuides as u32, "&Indent Guides");
+    view_menu.check_item(MenuEntries::IndentGuides as u32, edit_view.indent_guides());
+    view_menu.add_item(MenuEntries::Rulers as u32, "&Ruler");
+    view_menu.check_item(MenuEntries::Rulers as u32, edit_view.show_rulers());
+    view_menu.add_item(MenuEntries::ScrollPastEnd as u32, "Scroll &Past End");
+    view_menu.check_item(MenuEntries::ScrollPastEnd as u32, edit_view.scroll_past_end());
+    view_menu.add_item(MenuEntries::Minimap as u32, "&Minimap");
+    view_menu.check_item(MenuEntries::Minimap as u32, edit_view.show_minimap());
+    view_menu.add_dropdown(create_antialias_menu(state.text_antialias), "Text &Antialiasing");
+    view_menu.add_separator();
+    view_menu.add_item(MenuEntries::SplitView as u32, "&Split Horizontally");
+    view_menu.check_item(MenuEntries::SplitView as u32, state.split.is_some());
+    view_menu.add_item(MenuEntries::FullScreen as u32, "&Full Screen\tF11");
+    view_menu.add_item(MenuEntries::CommandPalette as u32, "Command &Palette…\tCtrl+Shift+P");
+    view_menu.add_separator();
+    view_menu.add_item(MenuEntries::GoBack as u32, "Go &Back\tAlt+Left");
+    view_menu.add_item(MenuEntries::GoForward as u32, "Go F&orward\tAlt+Right");
+    menubar.add_dropdown(view_menu, "&View");
     let mut selection_menu = Menu::new();
     selection_menu.add_item(MenuEntries::AddCursorAbove as u32, "Add Cursor Above\tCtrl+Alt+Up");
     selection_menu.add_item(MenuEntries::AddCursorBelow as u32, "Add Cursor Below\tCtrl+Alt+Down");
     selection_menu.add_item(MenuEntries::SingleSelection as u32, "Single Selection\tEscape");
     selection_menu.add_item(MenuEntries::SelectAll as u32, "Select All\tCtrl+A");
     menubar.add_dropdown(selection_menu, "&Selection");
+    menubar.add_dropdown(create_bookmarks_menu(edit_view), "&Bookmarks");
+    let mut plugins_menu = Menu::new();
+    for (i, plugin) in edit_view.plugins().iter().enumerate() {
+        let id = PLUGIN_MENU_BASE + i as u32;
+        plugins_menu.add_item(id, &plugin.name);
+        plugins_menu.check_item(id, plugin.running);
+    }
+    if edit_view.plugins().is_empty() {
+        plugins_menu.add_item(PLUGIN_MENU_BASE, "No plugins");
+        plugins_menu.enable_item(PLUGIN_MENU_BASE, false);
+    }
+    menubar.add_dropdown(plugins_menu, "&Plugins");
+    let extension = edit_view.filename.as_ref().and_then(|path| settings::extension(path));
+    menubar.add_dropdown(create_language_menu(&state.languages, edit_view.language(),
+        extension.as_ref().map(|e| e.as_str())), "&Language");
     menubar
 }
 
-pub fn set_accel(runloop: &mut RunLoop) {
-    let accel = accel!{
+/// Create the indentation menu, which is in the Edit menu and also pops up
+/// from the status bar.
+pub fn create_indentation_menu(edit_view: &EditView) -> Menu {
+    let mut menu = Menu::new_for_popup();
+    menu.add_item(MenuEntries::IndentUsingSpaces as u32, "Indent Using &Spaces");
+    menu.check_item(MenuEntries::IndentUsingSpaces as u32, edit_view.translate_tabs_to_spaces());
+    menu.add_separator();
+    for &(tab_size, id) in &[(2, MenuEntries::TabSize2 as u32), (4, MenuEntries::TabSize4 as u32),
+        (8, MenuEntries::TabSize8 as u32)]
+    {
+        menu.add_item(id, &format!("Tab Size: &{}", tab_size));
+        menu.check_item(id, edit_view.tab_size() == tab_size);
+    }
+    menu
+}
+
+/// Create the line endings menu, which is in the Edit menu and also pops
+/// up from the status bar. Choosing one converts the document to it.
+pub fn create_line_ending_menu(edit_view: &EditView) -> Menu {
+    let mut menu = Menu::new_for_popup();
+    for &(line_ending, id, label) in &[
+        (LineEnding::Crlf, MenuEntries::LineEndingCrlf as u32, "&CRLF (Windows)"),
+        (LineEnding::Lf, MenuEntries::LineEndingLf as u32, "&LF (Unix)"),
+    ] {
+        menu.add_item(id, label);
+        menu.check_item(id, edit_view.line_ending() == line_ending);
+    }
+    menu
+}
+
+fn create_line_numbers_menu(mode: LineNumbers) -> Menu {
+    let mut menu = Menu::new();
+    for &(entry_mode, id) in &LINE_NUMBERS_ENTRIES {
+        let label = match entry_mode {
+            LineNumbers::Off => "&Off",
+            LineNumbers::Absolute => "&Absolute",
+            LineNumbers::Relative => "&Relative",
+            LineNumbers::Hybrid => "&Hybrid",
+        };
+        menu.add_item(id, label);
+        menu.check_item(id, entry_mode == mode);
+    }
+    menu
+}
+
+fn create_antialias_menu(mode: TextAntialias) -> Menu {
+    let mut menu = Menu::new();
+    for &(entry_mode, id) in &ANTIALIAS_ENTRIES {
+        let label = match entry_mode {
+            TextAntialias::ClearType => "&ClearType",
+            TextAntialias::Grayscale => "&Grayscale",
+            TextAntialias::Aliased => "&Aliased",
+        };
+        menu.add_item(id, label);
+        menu.check_item(id, entry_mode == mode);
+    }
+    menu
+}
+
+// The bookmark commands, then the bookmarks, by line number and text.
+fn create_bookmarks_menu(edit_view: &EditView) -> Menu {
+    let mut menu = Menu::new();
+    menu.add_item(MenuEntries::ToggleBookmark as u32, "&Toggle Bookmark\tCtrl+F2");
+    menu.add_item(MenuEntries::NextBookmark as u32, "&Next Bookmark\tF2");
+    menu.add_item(MenuEntries::PrevBookmark as u32, "&Previous Bookmark\tShift+F2");
+    menu.add_item(MenuEntries::ClearBookmarks as u32, "&Clear Bookmarks\tCtrl+Shift+F2");
+    let bookmarks = edit_view.bookmarks();
+    for &id in &[MenuEntries::NextBookmark, MenuEntries::PrevBookmark,
+        MenuEntries::ClearBookmarks]
+    {
+        menu.enable_item(id as u32, !bookmarks.is_empty());
+    }
+    if !bookmarks.is_empty() {
+        menu.add_separator();
+    }
+    for (i, &(line, text)) in bookmarks.iter().enumerate() {
+        let mut label = format!("Line {}", line + 1);
+        // Lines the core hasn't sent have no text to show yet.
+        if let Some(text) = text.map(str::trim).filter(|text| !text.is_empty()) {
+            let mut preview: String = text.chars().take(MAX_BOOKMARK_PREVIEW).collect();
+            if preview.len() < text.len() {
+                preview.push('\u{2026}');
+            }
+            label.push_str(&format!(": {}", preview.replace('&', "&&")));
+        }
+        menu.add_item(BOOKMARK_MENU_BASE + i as u32, &label);
+    }
+    menu
+}
+
+fn create_recent_menu(recent_files: &[String]) -> Menu {
+    let mut menu = Menu::new();
+    for (i, path) in recent_files.iter().enumerate() {
+        let id = RECENT_MENU_BASE + i as u32;
+        let label = ellipsize_middle(path, MAX_RECENT_LABEL).replace('&', "&&");
+        menu.add_item(id, &format!("&{} {}", (i + 1) % 10, label));
+        // Files that have gone away since are greyed out.
+        menu.enable_item(id, Path::new(path).exists());
+    }
+    if recent_files.is_empty() {
+        menu.add_item(MenuEntries::ClearRecent as u32, "No recent files");
+        menu.enable_item(MenuEntries::ClearRecent as u32, false);
+    } else {
+        menu.add_separator();
+        menu.add_item(MenuEntries::ClearRecent as u32, "&Clear Recent");
+    }
+    menu
+}
+
+// Shorten text by replacing its middle with an ellipsis, which keeps both
+// the start of a path and the file name.
+fn ellipsize_middle(text: &str, max_chars: usize) -> String {
+    let n_chars = text.chars().count();
+    if n_chars <= max_chars {
+        return text.to_owned();
+    }
+    let head = (max_chars - 1) / 2;
+    let tail = max_chars - 1 - head;
+    let start: String = text.chars().take(head).collect();
+    let end: String = text.chars().skip(n_chars - tail).collect();
+    format!("{}\u{2026}{}", start, end)
+}
+
+// The languages go in submenus by initial, as there can be dozens, below
+// the commands that pick one from a filtered list, the second for every
+// file with the view's file's extension.
+fn create_language_menu(languages: &[String], current: Option<&str>, extension: Option<&str>)
+    -> Menu
+{
+    let mut menu = Menu::new();
+    menu.add_item(MenuEntries::ChangeLanguage as u32, "&Change Language…");
+    let for_extension = MenuEntries::SetLanguageForExtension as u32;
+    match extension {
+        Some(extension) => {
+            menu.add_item(for_extension, &format!("Set Language for All .{} &Files…",
+                extension));
+        }
+        None => {
+            menu.add_item(for_extension, "Set Language for All &Files with This Extension…");
+            menu.enable_item(for_extension, false);
+        }
+    }
+    menu.add_separator();
+    let mut group: Option<(char, Menu)> = None;
+    for (i, language) in languages.iter().enumerate() {
+        let initial = language.chars().next().unwrap_or(' ').to_ascii_uppercase();
+        if group.as_ref().map(|&(c, _)| c) != Some(initial) {
+            if let Some((c, submenu)) = group.take() {
+                menu.add_dropdown(submenu, &c.to_string());
+            }
+            group = Some((initial, Menu::new()));
+        }
+        let id = LANGUAGE_MENU_BASE + i as u32;
+        let submenu = &mut group.as_mut().unwrap().1;
+        submenu.add_item(id, language);
+        submenu.check_item(id, Some(language.as_str()) == current);
+    }
+    if let Some((c, submenu)) = group {
+        menu.add_dropdown(submenu, &c.to_string());
+    }
+    menu
+}
+
+/// Create the context menu for the edit area. Cut, copy and the case
+/// commands need a selection, paste needs text on the clipboard.
+pub fn create_context_menu(has_selection: bool, can_paste: bool) -> Menu {
+    let mut menu = Menu::new_for_popup();
+    menu.add_item(MenuEntries::Cut as u32, "Cu&t\tCtrl+X");
+    menu.add_item(MenuEntries::Copy as u32, "&Copy\tCtrl+C");
+    menu.add_item(MenuEntries::Paste as u32, "&Paste\tCtrl+V");
+    menu.add_separator();
+    menu.add_item(MenuEntries::SelectAll as u32, "Select &All\tCtrl+A");
+    if has_selection {
+        menu.add_separator();
+        menu.add_item(MenuEntries::UpperCase as u32, "&Upper Case");
+        menu.add_item(MenuEntries::LowerCase as u32, "&Lower Case");
+        menu.add_item(MenuEntries::TitleCase as u32, "T&itle Case");
+    }
+    menu.enable_item(MenuEntries::Cut as u32, has_selection);
+    menu.enable_item(MenuEntries::Copy as u32, has_selection);
+    menu.enable_item(MenuEntries::Paste as u32, can_paste);
+    menu
+}
+
+/// The menu commands' shortcuts. TranslateAccelerator runs these before
+/// the window sees their keys, so no keydown or character follows them.
+pub fn accelerators() -> Vec<ACCEL> {
+    accel!{
+        FCONTROL | FSHIFT, 'N', MenuEntries::NewWindow,
         FCONTROL, 'O', MenuEntries::Open,
         FCONTROL, 'S', MenuEntries::Save,
         FCONTROL | FSHIFT, 'S', MenuEntries::SaveAs,
+        FCONTROL | FALT, 'S', MenuEntries::SaveAll,
+        FCONTROL, 'P', MenuEntries::Print,
 
         FCONTROL, 'Z', MenuEntries::Undo,
         FCONTROL, 'Y', MenuEntries::Redo,
@@ -85,10 +454,17 @@ pub fn set_accel(runloop: &mut RunLoop) {
         FCONTROL, 'X', MenuEntries::Cut,
         FCONTROL, 'C', MenuEntries::Copy,
         FCONTROL, 'V', MenuEntries::Paste,
-        FCONTROL, 'T', MenuEntries::Transpose,
+        FCONTROL | FSHIFT, 'D', MenuEntries::DuplicateLine,
+        FCONTROL, 'J', MenuEntries::JoinLines,
+        FCONTROL | FSHIFT, 'P', MenuEntries::CommandPalette,
+        0, VK_F11, MenuEntries::FullScreen,
 
-        // Note: arrow keys and escape are actually handled in edit_view
+        // Note: arrow keys and escape are actually handled in edit_view, as is
+        // Ctrl+/, which isn't on the same key on every keyboard layout
         FCONTROL, 'A', MenuEntries::SelectAll,
-    };
-    runloop.set_accel(&accel);
+    }.to_vec()
+}
+
+pub fn set_accel(runloop: &mut RunLoop) {
+    runloop.set_accel(&accelerators());
 }
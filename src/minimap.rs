@@ -0,0 +1,211 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The minimap: a strip left of the scrollbar showing the lines around
+//! the view in miniature, as a bar for each run of text on a line, with a
+//! band behind them where the view is. Text at a couple of points is
+//! unreadable anyway, and bars are cheap to draw in the thousands.
+//!
+//! The bars of a line are worked out from its text once, and kept until
+//! an update changes or moves the line.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use direct2d::brush;
+use direct2d::math::*;
+use direct2d::render_target::RenderTarget;
+
+use linecache::LineCache;
+
+/// The width of the minimap, in px units.
+pub const MINIMAP_WIDTH: f32 = 100.0;
+
+/// About how many lines the minimap shows, when the file has that many.
+pub const MINIMAP_LINES: usize = 2000;
+
+// Lines are squeezed to fit MINIMAP_LINES in the height, but not drawn
+// taller than this in short files.
+const MAX_LINE_HEIGHT: f32 = 2.0;
+// The width of a column, and the room either side of the bars.
+const COL_WIDTH: f32 = 1.0;
+const PAD: f32 = 4.0;
+// Past this many lines' bars, the cache starts over.
+const MAX_CACHED_LINES: usize = 4 * MINIMAP_LINES;
+
+pub struct Minimap {
+    // The columns of each run of text on a line, by line number.
+    bars: HashMap<usize, Vec<(usize, usize)>>,
+    dragging: bool,
+}
+
+impl Minimap {
+    pub fn new() -> Minimap {
+        Minimap { bars: HashMap::new(), dragging: false }
+    }
+
+    /// Forget the bars of all lines, as for a new file or tab size.
+    pub fn clear(&mut self) {
+        self.bars.clear();
+    }
+
+    /// Forget the bars of lines that have changed or moved.
+    pub fn invalidate(&mut self, lines: Range<usize>) {
+        for line_num in lines {
+            self.bars.remove(&line_num);
+        }
+    }
+
+    /// The first line shown and the height of each line, for a view of
+    /// this height and scroll. When the file has more lines than fit, the
+    /// minimap scrolls along with the view, in proportion.
+    pub fn layout(view_height: f32, n_lines: usize, scroll_offset: f32, max_scroll: f32)
+        -> (usize, f32)
+    {
+        let line_height = (view_height / n_lines.min(MINIMAP_LINES).max(1) as f32)
+            .min(MAX_LINE_HEIGHT);
+        let rows = (view_height / line_height) as usize;
+        let first = if n_lines > rows && max_scroll > 0.0 {
+            ((scroll_offset / max_scroll) * (n_lines - rows) as f32).round() as usize
+        } else {
+            0
+        };
+        (first, line_height)
+    }
+
+    /// The lines shown, for asking the core to send them.
+    pub fn lines(view_height: f32, n_lines: usize, scroll_offset: f32, max_scroll: f32)
+        -> Range<usize>
+    {
+        let (first, line_height) = Minimap::layout(view_height, n_lines, scroll_offset,
+            max_scroll);
+        let rows = (view_height / line_height).ceil() as usize;
+        first..(first + rows).min(n_lines)
+    }
+
+    /// Where a range of lines is, from the top of the view, if any of it
+    /// is shown.
+    pub fn lines_y(lines: Range<usize>, first: usize, line_height: f32) -> Option<(f32, f32)> {
+        if lines.end <= first {
+            return None;
+        }
+        let top = lines.start.saturating_sub(first) as f32 * line_height;
+        let bottom = (lines.end - first) as f32 * line_height;
+        Some((top.floor(), bottom.ceil()))
+    }
+
+    /// The scroll offset for a click or drag at a point, in proportion to
+    /// how far down the lines shown it is.
+    pub fn scroll_for(y: f32, view_height: f32, n_lines: usize, line_height: f32,
+        max_scroll: f32) -> f32
+    {
+        let shown_height = (n_lines as f32 * line_height).min(view_height);
+        if shown_height <= 0.0 {
+            return 0.0;
+        }
+        (y / shown_height).max(0.0).min(1.0) * max_scroll
+    }
+
+    pub fn start_drag(&mut self) {
+        self.dragging = true;
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.dragging
+    }
+
+    /// Stop dragging. Returns whether it was being dragged.
+    pub fn end_drag(&mut self) -> bool {
+        let dragging = self.dragging;
+        self.dragging = false;
+        dragging
+    }
+
+    /// Draw the minimap with its left edge at x0. The band where the view
+    /// is goes under the bars, so it reads as laid over them. Lines the
+    /// core hasn't sent yet are left blank.
+    pub fn render(&mut self, rt: &mut RenderTarget, x0: f32, view_height: f32,
+        (first, line_height): (usize, f32), visible: Range<usize>, line_cache: &LineCache,
+        tab_size: usize, bg: &brush::SolidColor, band: &brush::SolidColor,
+        bar: &brush::SolidColor)
+    {
+        rt.fill_rectangle(&RectF::from((x0, 0.0, x0 + MINIMAP_WIDTH, view_height)), bg);
+        if let Some((top, bottom)) = Minimap::lines_y(visible, first, line_height) {
+            rt.fill_rectangle(&RectF::from((x0, top, x0 + MINIMAP_WIDTH, bottom)), band);
+        }
+        if self.bars.len() >= MAX_CACHED_LINES {
+            self.bars.clear();
+        }
+        let max_cols = ((MINIMAP_WIDTH - 2.0 * PAD) / COL_WIDTH) as usize;
+        let rows = (view_height / line_height).ceil() as usize;
+        let end = (first + rows).min(line_cache.height());
+        for line_num in first..end {
+            if !self.bars.contains_key(&line_num) {
+                match line_cache.get_line(line_num) {
+                    Some(line) => {
+                        self.bars.insert(line_num, text_runs(line.text(), tab_size, max_cols));
+                    }
+                    None => continue,
+                }
+            }
+            let bars = &self.bars[&line_num];
+            let y = (line_num - first) as f32 * line_height;
+            // A gap between lines while there's room for one.
+            let bar_height = if line_height >= MAX_LINE_HEIGHT {
+                line_height - 1.0
+            } else {
+                line_height
+            };
+            for &(start, end) in bars {
+                let rect = RectF::from((x0 + PAD + start as f32 * COL_WIDTH, y,
+                    x0 + PAD + end as f32 * COL_WIDTH, y + bar_height));
+                rt.fill_rectangle(&rect, bar);
+            }
+        }
+    }
+}
+
+// The column ranges of the runs of non-blank characters on a line, with
+// tabs expanded, cut off at max_cols.
+fn text_runs(text: &str, tab_size: usize, max_cols: usize) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    let mut col = 0;
+    for c in text.chars() {
+        if col >= max_cols {
+            break;
+        }
+        if c.is_whitespace() {
+            if let Some(start) = run_start.take() {
+                runs.push((start, col));
+            }
+            col = if c == '\t' {
+                (col / tab_size.max(1) + 1) * tab_size.max(1)
+            } else if c == '\n' || c == '\r' {
+                col
+            } else {
+                col + 1
+            };
+        } else {
+            if run_start.is_none() {
+                run_start = Some(col);
+            }
+            col += 1;
+        }
+    }
+    if let Some(start) = run_start {
+        runs.push((start, col.min(max_cols)));
+    }
+    runs
+}
@@ -0,0 +1,221 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The brushes and font shared by the overlays drawn over the edit view,
+//! like the command palette, so they all look alike and none of them has
+//! to manage its own.
+
+use serde_json::Value;
+
+use direct2d::brush;
+use directwrite::{self, TextFormat};
+use directwrite::text_format;
+
+use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::util::Error;
+
+use edit_view::theme_color;
+
+/// The colors of the overlays, as 0xRRGGBB.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OverlayColors {
+    pub bg: u32,
+    pub border: u32,
+    pub text: u32,
+    /// Hint text shown where nothing has been typed yet.
+    pub placeholder: u32,
+    /// The highlighted row of a list.
+    pub selection: u32,
+}
+
+impl Default for OverlayColors {
+    /// The colors to use when the theme doesn't say.
+    fn default() -> OverlayColors {
+        OverlayColors {
+            bg: 0x3e3d32,
+            border: 0x75715e,
+            text: 0xf0f0ea,
+            placeholder: 0x75715e,
+            selection: 0x5b5a4f,
+        }
+    }
+}
+
+impl OverlayColors {
+    /// The colors from the theme of a theme_changed, with the defaults for
+    /// those it doesn't have.
+    pub fn from_theme(theme: &Value) -> OverlayColors {
+        let default = OverlayColors::default();
+        OverlayColors {
+            bg: theme_color(&theme["line_highlight"]).unwrap_or(default.bg),
+            border: theme_color(&theme["guide"]).unwrap_or(default.border),
+            text: theme_color(&theme["foreground"]).unwrap_or(default.text),
+            placeholder: theme_color(&theme["gutter_foreground"]).unwrap_or(default.placeholder),
+            selection: theme_color(&theme["selection"]).unwrap_or(default.selection),
+        }
+    }
+}
+
+/// The overlays' colors, and the resources made from them when an overlay
+/// is first drawn. New colors, as from switching themes, and rebuilding
+/// the window's resources drop the resources, so no overlay draws with the
+/// brushes of old colors or of a lost device.
+pub struct OverlayStyle<R = OverlayResources> {
+    colors: OverlayColors,
+    resources: Option<R>,
+}
+
+impl<R> OverlayStyle<R> {
+    pub fn new() -> OverlayStyle<R> {
+        OverlayStyle { colors: OverlayColors::default(), resources: None }
+    }
+
+    pub fn set_colors(&mut self, colors: OverlayColors) {
+        if colors != self.colors {
+            self.colors = colors;
+            self.resources = None;
+        }
+    }
+
+    pub fn rebuild_resources(&mut self) {
+        self.resources = None;
+    }
+
+    /// The resources, made with `create` from the colors if there are none.
+    pub fn resources<F>(&mut self, create: F) -> Result<&R, Error>
+        where F: FnOnce(&OverlayColors) -> Result<R, Error>
+    {
+        if self.resources.is_none() {
+            self.resources = Some(create(&self.colors)?);
+        }
+        Ok(self.resources.as_ref().unwrap())
+    }
+}
+
+pub struct OverlayResources {
+    pub bg: brush::SolidColor,
+    pub border: brush::SolidColor,
+    pub text: brush::SolidColor,
+    pub placeholder: brush::SolidColor,
+    pub selection: brush::SolidColor,
+    pub text_format: TextFormat,
+    /// For laying out text to measure, as text fields do to place the
+    /// caret.
+    pub dwrite_factory: directwrite::Factory,
+}
+
+impl OverlayResources {
+    /// Create the resources for drawing overlays in these colors, as an
+    /// OverlayStyle does when they're needed.
+    pub fn create(p: &mut PaintCtx, colors: &OverlayColors) -> Result<OverlayResources, Error> {
+        let dwrite_factory = directwrite::Factory::new().map_err(|_| Error::D2Error)?;
+        let text_format_params = text_format::ParamBuilder::new()
+            .size(13.0)
+            .family("Segoe UI")
+            .build().ok_or(Error::D2Error)?;
+        let text_format = dwrite_factory.create(text_format_params)
+            .map_err(|_| Error::D2Error)?;
+        Ok(OverlayResources {
+            bg: p.solid_brush(colors.bg)?,
+            border: p.solid_brush(colors.border)?,
+            text: p.solid_brush(colors.text)?,
+            placeholder: p.solid_brush(colors.placeholder)?,
+            selection: p.solid_brush(colors.selection)?,
+            text_format: text_format,
+            dwrite_factory: dwrite_factory,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Stands in for the brushes: counts the ones still around, as COM
+    // references would be, and remembers its colors.
+    struct Brushes {
+        colors: OverlayColors,
+        live: Rc<Cell<usize>>,
+    }
+
+    impl Drop for Brushes {
+        fn drop(&mut self) {
+            self.live.set(self.live.get() - 1);
+        }
+    }
+
+    fn draw(style: &mut OverlayStyle<Brushes>, live: &Rc<Cell<usize>>) -> OverlayColors {
+        style.resources(|colors| {
+            live.set(live.get() + 1);
+            Ok(Brushes { colors: *colors, live: live.clone() })
+        }).ok().unwrap().colors
+    }
+
+    #[test]
+    fn switching_themes() {
+        let live = Rc::new(Cell::new(0));
+        let mut style = OverlayStyle::new();
+        assert_eq!(draw(&mut style, &live), OverlayColors::default());
+        assert_eq!(draw(&mut style, &live), OverlayColors::default());
+        assert_eq!(live.get(), 1);
+
+        // The old brushes go as soon as the theme does, not at the next
+        // paint, and the next paint makes new ones.
+        let theme = OverlayColors { bg: 0xffffff, ..OverlayColors::default() };
+        style.set_colors(theme);
+        assert_eq!(live.get(), 0);
+        assert_eq!(draw(&mut style, &live), theme);
+        assert_eq!(live.get(), 1);
+
+        // The same theme again keeps them.
+        style.set_colors(theme);
+        assert_eq!(live.get(), 1);
+
+        // As does rebuilding the window's resources, after a device loss.
+        style.rebuild_resources();
+        assert_eq!(live.get(), 0);
+        assert_eq!(draw(&mut style, &live), theme);
+        drop(style);
+        assert_eq!(live.get(), 0);
+    }
+
+    #[test]
+    fn failing_to_make_brushes() {
+        let live = Rc::new(Cell::new(0));
+        let mut style = OverlayStyle::new();
+        assert!(style.resources(|_| -> Result<Brushes, Error> { Err(Error::D2Error) }).is_err());
+        // It's tried again next time.
+        assert_eq!(draw(&mut style, &live), OverlayColors::default());
+    }
+
+    #[test]
+    fn colors_from_a_theme() {
+        let theme = json!({
+            "foreground": {"r": 1, "g": 2, "b": 3, "a": 255},
+            "selection": {"r": 255, "g": 0, "b": 0, "a": 255},
+            // Transparent, so left to the default.
+            "line_highlight": {"r": 9, "g": 9, "b": 9, "a": 0},
+        });
+        let colors = OverlayColors::from_theme(&theme);
+        let default = OverlayColors::default();
+        assert_eq!(colors, OverlayColors {
+            text: 0x010203,
+            selection: 0xff0000,
+            ..default
+        });
+        assert_eq!(OverlayColors::from_theme(&json!({})), default);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics of the edit view's painting, for performance work: how long
+//! frames take and how much they do. They're off unless the app is started
+//! with --paint-stats or the toggle_paint_stats action turns them on, and
+//! cost next to nothing while off.
+
+use std::collections::VecDeque;
+use std::env;
+use std::time::Instant;
+
+// How many frames the average is over.
+const AVERAGE_FRAMES: usize = 30;
+
+pub struct PaintStats {
+    enabled: bool,
+    frame_start: Option<Instant>,
+    // Paint times of the last frames, in ms, the latest at the back.
+    recent_ms: VecDeque<f32>,
+    layouts_built: usize,
+    lines_painted: usize,
+}
+
+impl PaintStats {
+    pub fn new() -> PaintStats {
+        PaintStats {
+            enabled: env::args().any(|arg| arg == "--paint-stats"),
+            frame_start: None,
+            recent_ms: VecDeque::with_capacity(AVERAGE_FRAMES),
+            layouts_built: 0,
+            lines_painted: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.recent_ms.clear();
+    }
+
+    /// Start counting a frame.
+    pub fn start_frame(&mut self) {
+        if self.enabled {
+            self.frame_start = Some(Instant::now());
+            self.layouts_built = 0;
+            self.lines_painted = 0;
+        }
+    }
+
+    pub fn layouts_built(&mut self, n: usize) {
+        self.layouts_built += n;
+    }
+
+    pub fn line_painted(&mut self) {
+        self.lines_painted += 1;
+    }
+
+    /// Finish the frame, before the statistics are drawn, so drawing them
+    /// isn't counted.
+    pub fn end_frame(&mut self) {
+        if let Some(start) = self.frame_start.take() {
+            let elapsed = start.elapsed();
+            let ms = elapsed.as_secs() as f32 * 1000.0 + elapsed.subsec_nanos() as f32 / 1e6;
+            if self.recent_ms.len() == AVERAGE_FRAMES {
+                self.recent_ms.pop_front();
+            }
+            self.recent_ms.push_back(ms);
+        }
+    }
+
+    /// The statistics of the last frame, to draw.
+    pub fn summary(&self) -> String {
+        let last = self.recent_ms.back().cloned().unwrap_or(0.0);
+        let average = self.recent_ms.iter().sum::<f32>() / self.recent_ms.len().max(1) as f32;
+        format!("paint {:.2} ms, avg {:.2} ms\n{} layouts built, {} lines painted",
+            last, average, self.layouts_built, self.lines_painted)
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The command palette, a list of commands drawn over the top of the edit
+//! view and filtered by what's typed. It's also the picker for other
+//! lists, like the languages.
+
+use std::cmp::min;
+
+use winapi::um::winuser::*;
+
+use direct2d::math::*;
+
+use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::util::default_text_options;
+
+use overlay::OverlayResources;
+use text_input::{InputEvent, TextInput};
+
+// Size and placement of the palette, in px units.
+const MAX_WIDTH: f32 = 500.0;
+const MARGIN: f32 = 20.0;
+const ROW_HEIGHT: f32 = 22.0;
+const PAD: f32 = 6.0;
+// The width of the "> " before the query.
+const PROMPT_WIDTH: f32 = 14.0;
+
+// The most commands shown at once; the list scrolls to keep the
+// highlighted one in view.
+const MAX_ROWS: usize = 12;
+
+pub struct Palette {
+    size: (f32, f32),  // of the edit view, in px units
+    open: bool,
+    names: Vec<String>,
+    query: TextInput,
+    // Shown in the query while it's empty.
+    placeholder: &'static str,
+    // Indices into names of the commands that match the query, best first.
+    filtered: Vec<usize>,
+    // Index into filtered of the highlighted command, and of the first
+    // one shown.
+    selected: usize,
+    first_row: usize,
+}
+
+/// What the palette wants done after a key.
+pub enum PaletteEvent {
+    /// Run the command at this index of the names it was opened with.
+    Run(usize),
+    Dismiss,
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette {
+            size: (0.0, 0.0),
+            open: false,
+            names: Vec::new(),
+            query: TextInput::new(),
+            placeholder: "",
+            filtered: Vec::new(),
+            selected: 0,
+            first_row: 0,
+        }
+    }
+
+    /// Set the size of the edit view, which the palette is drawn over.
+    pub fn size(&mut self, x: f32, y: f32) {
+        self.size = (x, y);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Open the palette with an empty query, to choose from the commands
+    /// with these names, with the one at `selected` highlighted, if any.
+    pub fn open(&mut self, names: Vec<String>, placeholder: &'static str,
+        selected: Option<usize>)
+    {
+        self.open = true;
+        self.names = names;
+        self.placeholder = placeholder;
+        self.query.clear();
+        self.filter();
+        // Nothing's filtered out yet, so the rows are in the names' order.
+        if let Some(selected) = selected.filter(|&ix| ix < self.filtered.len()) {
+            self.select(selected);
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.open = false;
+        self.names.clear();
+    }
+
+    /// Take a typed character into the query.
+    pub fn char(&mut self, ch: u32) {
+        if self.query.char(ch) {
+            self.filter();
+        }
+    }
+
+    /// Edit the query some other way than by typing, as with the
+    /// clipboard commands, which come as menu commands rather than keys.
+    /// The edit returns whether it changed the query.
+    pub fn edit_query<F: FnOnce(&mut TextInput) -> bool>(&mut self, edit: F) {
+        if edit(&mut self.query) {
+            self.filter();
+        }
+    }
+
+    /// Handle a key while the palette is open. All keys are the palette's
+    /// while it's open, whether or not they do anything; the ones that
+    /// aren't for the list edit the query.
+    pub fn keydown(&mut self, vk_code: i32, mods: u32) -> Option<PaletteEvent> {
+        match vk_code {
+            VK_ESCAPE => return Some(PaletteEvent::Dismiss),
+            VK_RETURN => return self.filtered.get(self.selected).map(|&ix| PaletteEvent::Run(ix)),
+            VK_UP if self.selected > 0 => self.select(self.selected - 1),
+            VK_DOWN if self.selected + 1 < self.filtered.len() => self.select(self.selected + 1),
+            VK_PRIOR => self.select(self.selected.saturating_sub(MAX_ROWS)),
+            VK_NEXT if !self.filtered.is_empty() => {
+                self.select(min(self.selected + MAX_ROWS, self.filtered.len() - 1));
+            }
+            _ => {
+                if self.query.keydown(vk_code, mods) == InputEvent::Changed {
+                    self.filter();
+                }
+            }
+        }
+        None
+    }
+
+    fn select(&mut self, selected: usize) {
+        self.selected = selected;
+        if selected < self.first_row {
+            self.first_row = selected;
+        } else if selected >= self.first_row + MAX_ROWS {
+            self.first_row = selected + 1 - MAX_ROWS;
+        }
+    }
+
+    // Match the commands against the query, and highlight the best one.
+    fn filter(&mut self) {
+        let mut scored: Vec<(i32, usize)> = self.names.iter().enumerate()
+            .filter_map(|(ix, name)| fuzzy_score(self.query.text(), name).map(|score| (score, ix)))
+            .collect();
+        // Stable, so equal scores keep the order they were given in.
+        scored.sort_by_key(|&(score, _)| -score);
+        self.filtered = scored.into_iter().map(|(_, ix)| ix).collect();
+        self.selected = 0;
+        self.first_row = 0;
+    }
+
+    pub fn render(&mut self, p: &mut PaintCtx, resources: &OverlayResources) {
+        if !self.open {
+            return;
+        }
+        let width = (self.size.0 - 2.0 * MARGIN).min(MAX_WIDTH).max(0.0);
+        let x0 = ((self.size.0 - width) / 2.0).round();
+        let x1 = x0 + width;
+        let n_rows = min(self.filtered.len() - self.first_row.min(self.filtered.len()), MAX_ROWS);
+        // The query on top, then the commands.
+        let y0 = 0.0;
+        let y1 = y0 + ROW_HEIGHT * (1 + n_rows) as f32 + PAD;
+        let mut y = y0 + PAD / 2.0;
+        {
+            let rt = p.render_target();
+            rt.fill_rectangle(&RectF::from((x0, y0, x1, y1)), &resources.bg);
+            rt.draw_rectangle(&RectF::from((x0 + 0.5, y0 + 0.5, x1 - 0.5, y1 - 0.5)),
+                &resources.border, 1.0, None);
+            let prompt_brush = if self.query.is_empty() {
+                &resources.placeholder
+            } else {
+                &resources.text
+            };
+            rt.draw_text(
+                ">",
+                &resources.text_format,
+                &RectF::from((x0 + PAD, y + 2.0, x0 + PAD + PROMPT_WIDTH, y + ROW_HEIGHT)),
+                prompt_brush,
+                default_text_options()
+            );
+        }
+        self.query.render(p, (x0 + PAD + PROMPT_WIDTH, y + 2.0, x1 - PAD, y + ROW_HEIGHT - 2.0),
+            self.placeholder, resources);
+        let rt = p.render_target();
+        for row in self.first_row..self.first_row + n_rows {
+            y += ROW_HEIGHT;
+            if row == self.selected {
+                rt.fill_rectangle(&RectF::from((x0 + 1.0, y, x1 - 1.0, y + ROW_HEIGHT)),
+                    &resources.selection);
+            }
+            rt.draw_text(
+                &self.names[self.filtered[row]],
+                &resources.text_format,
+                &RectF::from((x0 + PAD, y + 2.0, x1 - PAD, y + ROW_HEIGHT)),
+                &resources.text,
+                default_text_options()
+            );
+        }
+    }
+}
+
+// How well a name matches the query: the query's characters have to
+// appear in the name in order, ignoring case. Higher scores are better;
+// characters that follow the previous match, or start a word, count
+// extra. None if the name doesn't match.
+fn fuzzy_score(query: &str, name: &str) -> Option<i32> {
+    let query: Vec<char> = query.to_lowercase().chars().collect();
+    let mut matched = 0;
+    let mut score = 0;
+    let mut prev: Option<char> = None;
+    let mut prev_matched = false;
+    for c in name.to_lowercase().chars() {
+        if matched < query.len() && c == query[matched] {
+            score += if prev_matched {
+                3
+            } else if prev.map_or(true, |p| !p.is_alphanumeric()) {
+                2
+            } else {
+                1
+            };
+            matched += 1;
+            prev_matched = true;
+        } else {
+            prev_matched = false;
+        }
+        prev = Some(c);
+    }
+    if matched == query.len() { Some(score) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages() -> Vec<String> {
+        (0..30).map(|i| format!("Language {}", i)).collect()
+    }
+
+    #[test]
+    fn opening_with_a_selection() {
+        let mut palette = Palette::new();
+        palette.open(languages(), "", Some(20));
+        assert_eq!(palette.filtered[palette.selected], 20);
+        // Scrolled just far enough to show it.
+        assert_eq!(palette.first_row, 20 + 1 - MAX_ROWS);
+        match palette.keydown(VK_RETURN, 0) {
+            Some(PaletteEvent::Run(20)) => (),
+            _ => panic!("expected the selected row to run"),
+        }
+        // Typing filters, and the best match is highlighted.
+        palette.char('2' as u32);
+        palette.char('9' as u32);
+        assert_eq!(palette.filtered, vec![29]);
+        assert_eq!((palette.selected, palette.first_row), (0, 0));
+    }
+
+    #[test]
+    fn opening_without_one() {
+        let mut palette = Palette::new();
+        palette.open(languages(), "", None);
+        assert_eq!((palette.selected, palette.first_row), (0, 0));
+        // One past the end highlights the first.
+        palette.open(languages(), "", Some(30));
+        assert_eq!(palette.selected, 0);
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Printing. The print dialog gives a device context for the printer, and
+//! the pages are drawn on it with GDI, in the edit view's font at a size
+//! for paper. Long lines wrap at the page width.
+
+use std::cmp::{max, min};
+use std::mem;
+use std::ptr::null_mut;
+
+use winapi::ctypes::c_int;
+use winapi::shared::minwindef::{BOOL, FALSE, TRUE};
+use winapi::shared::windef::{HDC, HFONT, HWND, SIZE};
+use winapi::um::commdlg::*;
+use winapi::um::winbase::{GlobalFree, MulDiv};
+use winapi::um::wingdi::*;
+use winapi::um::winuser::{GetAsyncKeyState, VK_ESCAPE};
+
+use xi_win_shell::util::ToWide;
+
+use edit_view::FONT_FAMILIES;
+
+// The size of the text, in points.
+const FONT_POINTS: c_int = 10;
+
+/// A printer chosen in the print dialog.
+pub struct Printer {
+    hdc: HDC,
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        unsafe {
+            DeleteDC(self.hdc);
+        }
+    }
+}
+
+/// Show the print dialog. Returns None if it's cancelled.
+pub fn choose_printer(hwnd_owner: HWND) -> Option<Printer> {
+    unsafe {
+        let mut pd: PRINTDLGW = mem::zeroed();
+        pd.lStructSize = mem::size_of::<PRINTDLGW>() as u32;
+        pd.hwndOwner = hwnd_owner;
+        pd.Flags = PD_RETURNDC | PD_NOSELECTION | PD_NOPAGENUMS | PD_USEDEVMODECOPIESANDCOLLATE;
+        let ok = PrintDlgW(&mut pd) != 0;
+        // The device context has the settings; these copies aren't needed.
+        if !pd.hDevMode.is_null() {
+            GlobalFree(pd.hDevMode);
+        }
+        if !pd.hDevNames.is_null() {
+            GlobalFree(pd.hDevNames);
+        }
+        if ok && !pd.hDC.is_null() { Some(Printer { hdc: pd.hDC }) } else { None }
+    }
+}
+
+/// Print lines, without their newlines, with the title and page number
+/// at the top of each page. Holding Escape while it spools cancels the
+/// job, which is then dropped from the queue.
+pub fn print(printer: &Printer, title: &str, lines: &[String], tab_size: usize)
+    -> Result<(), String>
+{
+    let hdc = printer.hdc;
+    unsafe {
+        let dpi_y = GetDeviceCaps(hdc, LOGPIXELSY);
+        // Half an inch all around.
+        let margin_x = GetDeviceCaps(hdc, LOGPIXELSX) / 2;
+        let margin_y = dpi_y / 2;
+        let width = GetDeviceCaps(hdc, HORZRES) - 2 * margin_x;
+        let height = GetDeviceCaps(hdc, VERTRES) - 2 * margin_y;
+        let font = create_font(dpi_y);
+        if font.is_null() {
+            return Err("Couldn't make the font to print with".to_owned());
+        }
+        let old_font = SelectObject(hdc, font as _);
+        let mut metrics: TEXTMETRICW = mem::zeroed();
+        GetTextMetricsW(hdc, &mut metrics);
+        let line_height = max(metrics.tmHeight + metrics.tmExternalLeading, 1);
+        // The header takes a line, with a blank one under it.
+        let rows_per_page = max(height / line_height - 2, 1) as usize;
+        let rows = wrap_lines(hdc, lines, tab_size, width);
+        let pages = max((rows.len() + rows_per_page - 1) / rows_per_page, 1);
+        SetAbortProc(hdc, Some(abort_proc));
+        let doc_name = title.to_wide();
+        let mut doc_info: DOCINFOW = mem::zeroed();
+        doc_info.cbSize = mem::size_of::<DOCINFOW>() as c_int;
+        doc_info.lpszDocName = doc_name.as_ptr();
+        let result = if StartDocW(hdc, &doc_info) <= 0 {
+            Err("Couldn't start printing".to_owned())
+        } else {
+            let title: Vec<u16> = title.encode_utf16().collect();
+            let mut result = Ok(());
+            for page in 0..pages {
+                if StartPage(hdc) <= 0 {
+                    result = Err("Printing failed".to_owned());
+                    break;
+                }
+                text_out(hdc, margin_x, margin_y, &title);
+                let number: Vec<u16> = format!("Page {} of {}", page + 1, pages)
+                    .encode_utf16().collect();
+                text_out(hdc, margin_x + width - text_width(hdc, &number), margin_y, &number);
+                let page_rows = rows.iter().skip(page * rows_per_page).take(rows_per_page);
+                for (ix, row) in page_rows.enumerate() {
+                    text_out(hdc, margin_x, margin_y + (ix as c_int + 2) * line_height, row);
+                }
+                // Fails when the abort procedure cancels the job.
+                if EndPage(hdc) <= 0 {
+                    result = Err("Printing was cancelled".to_owned());
+                    break;
+                }
+            }
+            if result.is_ok() {
+                EndDoc(hdc);
+            } else {
+                AbortDoc(hdc);
+            }
+            result
+        };
+        SelectObject(hdc, old_font);
+        DeleteObject(font as _);
+        result
+    }
+}
+
+// Called by GDI from time to time while it spools the job; returning
+// FALSE cancels it. The job runs on the UI thread, so messages aren't
+// pumped here, and the keyboard is checked directly.
+unsafe extern "system" fn abort_proc(_hdc: HDC, _error: c_int) -> BOOL {
+    if GetAsyncKeyState(VK_ESCAPE) < 0 { FALSE } else { TRUE }
+}
+
+unsafe fn create_font(dpi_y: c_int) -> HFONT {
+    // GDI falls back on another fixed-pitch font if this one is missing.
+    let family = FONT_FAMILIES[0].to_wide();
+    CreateFontW(-MulDiv(FONT_POINTS, dpi_y, 72), 0, 0, 0, FW_NORMAL, 0, 0, 0,
+        DEFAULT_CHARSET, OUT_DEFAULT_PRECIS, CLIP_DEFAULT_PRECIS, DEFAULT_QUALITY,
+        FIXED_PITCH | FF_MODERN, family.as_ptr())
+}
+
+// Split lines into rows that fit across the page, with tabs expanded.
+unsafe fn wrap_lines(hdc: HDC, lines: &[String], tab_size: usize, width: c_int)
+    -> Vec<Vec<u16>>
+{
+    let mut rows = Vec::new();
+    for line in lines {
+        let text: Vec<u16> = expand_tabs(line, tab_size).encode_utf16().collect();
+        if text.is_empty() {
+            rows.push(text);
+            continue;
+        }
+        let mut start = 0;
+        while start < text.len() {
+            let rest = &text[start..];
+            let mut fit = 0;
+            let mut size: SIZE = mem::zeroed();
+            GetTextExtentExPointW(hdc, rest.as_ptr(), rest.len() as c_int, width, &mut fit,
+                null_mut(), &mut size);
+            // At least one character a row, however narrow the page, and
+            // surrogate pairs kept together.
+            let mut fit = min(max(fit as usize, 1), rest.len());
+            let high_surrogate = rest[fit - 1] >= 0xd800 && rest[fit - 1] < 0xdc00;
+            if fit < rest.len() && fit > 1 && high_surrogate {
+                fit -= 1;
+            }
+            rows.push(rest[..fit].to_vec());
+            start += fit;
+        }
+    }
+    rows
+}
+
+fn expand_tabs(line: &str, tab_size: usize) -> String {
+    let tab_size = max(tab_size, 1);
+    let mut text = String::new();
+    let mut col = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let n = tab_size - col % tab_size;
+            text.extend((0..n).map(|_| ' '));
+            col += n;
+        } else {
+            text.push(c);
+            col += 1;
+        }
+    }
+    text
+}
+
+unsafe fn text_out(hdc: HDC, x: c_int, y: c_int, text: &[u16]) {
+    TextOutW(hdc, x, y, text.as_ptr(), text.len() as c_int);
+}
+
+unsafe fn text_width(hdc: HDC, text: &[u16]) -> c_int {
+    let mut size: SIZE = mem::zeroed();
+    GetTextExtentPoint32W(hdc, text.as_ptr(), text.len() as c_int, &mut size);
+    size.cx
+}
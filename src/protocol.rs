@@ -0,0 +1,168 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The update messages from the core, as typed structs. Fields we don't
+//! know about are ignored, so newer cores can add them.
+
+use serde::Deserialize;
+use serde_json::{self, Value};
+
+/// A notification from the core. Updates, by far the most frequent, are
+/// parsed once as they're read from it, so the UI thread gets them typed;
+/// the rest keep their JSON.
+pub enum Notification {
+    Update { view_id: String, update: Update },
+    Other { method: String, params: Value },
+}
+
+#[derive(Deserialize)]
+struct UpdateParams {
+    view_id: String,
+    update: Update,
+}
+
+impl Notification {
+    pub fn parse(method: &str, params: &Value) -> Result<Notification, serde_json::Error> {
+        Ok(match method {
+            "update" => {
+                let UpdateParams { view_id, update } = UpdateParams::deserialize(params)?;
+                Notification::Update { view_id, update }
+            }
+            _ => Notification::Other { method: method.to_owned(), params: params.clone() },
+        })
+    }
+}
+
+/// The changes to a view's lines, sent with the "update" notification.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Update {
+    pub ops: Vec<Op>,
+    #[serde(default)]
+    pub pristine: Option<bool>,
+    /// Only sent by newer cores, which don't send selections and find
+    /// results as styles.
+    #[serde(default)]
+    pub annotations: Option<Vec<Annotation>>,
+}
+
+/// One op of an update, applied to the old lines in order. Kept as a
+/// string rather than an enum, so an op type we don't know can be skipped.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Op {
+    pub op: String,
+    /// The number of lines, for copy, skip and invalidate.
+    #[serde(default)]
+    pub n: usize,
+    /// For copy, the number in the file of the first copied line, on
+    /// newer cores.
+    #[serde(default)]
+    pub ln: Option<usize>,
+    /// The lines, for ins and update.
+    #[serde(default)]
+    pub lines: Vec<Line>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Line {
+    /// Missing in update ops, which keep the old text.
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub cursor: Vec<usize>,
+    /// Triples of (start, length, style id), where start is relative to
+    /// the end of the previous span, and can be negative.
+    #[serde(default)]
+    pub styles: Vec<i64>,
+    /// The number of the line in the file, counting from 1, on newer
+    /// cores. Missing on the lines after the first of a wrapped line.
+    #[serde(default)]
+    pub ln: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Annotation {
+    #[serde(rename = "type")]
+    pub annotation_type: String,
+    /// Ranges of [start line, start col, end line, end col].
+    #[serde(default)]
+    pub ranges: Vec<[usize; 4]>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An update as a newer core sends it, with every field we know.
+    fn full_update() -> Value {
+        json!({
+            "ops": [
+                {"op": "copy", "n": 2, "ln": 1, "lines": []},
+                {"op": "ins", "n": 1, "ln": null, "lines": [{
+                    "text": "caf\u{e9}\n", "cursor": [3], "styles": [0, 4, 2, -2, 1, 5],
+                    "ln": 3,
+                }]},
+                {"op": "update", "n": 1, "ln": null, "lines": [{
+                    "text": "", "cursor": [], "styles": [], "ln": null,
+                }]},
+                {"op": "skip", "n": 4, "ln": null, "lines": []},
+            ],
+            "pristine": false,
+            "annotations": [{"type": "selection", "ranges": [[0, 1, 2, 3]]}],
+        })
+    }
+
+    #[test]
+    fn an_update_round_trips() {
+        let update = Update::deserialize(&full_update()).unwrap();
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(json, full_update());
+        assert_eq!(Update::deserialize(&json).unwrap(), update);
+    }
+
+    #[test]
+    fn missing_and_unknown_fields() {
+        let update = Update::deserialize(&json!({
+            "ops": [{"op": "ins", "lines": [{"text": "a"}], "new_field": 1}],
+            "rev": 12,
+        })).unwrap();
+        assert_eq!(update.pristine, None);
+        assert_eq!(update.annotations, None);
+        let op = &update.ops[0];
+        assert_eq!((op.n, op.ln), (0, None));
+        assert_eq!(op.lines[0], Line { text: "a".to_owned(), cursor: vec![], styles: vec![],
+            ln: None });
+        // And it reads back the same, with the unknown fields gone.
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(Update::deserialize(&json).unwrap(), update);
+    }
+
+    #[test]
+    fn parsing_notifications() {
+        let params = json!({"view_id": "view-id-1", "update": full_update()});
+        match Notification::parse("update", &params).unwrap() {
+            Notification::Update { view_id, update } => {
+                assert_eq!(view_id, "view-id-1");
+                assert_eq!(update, Update::deserialize(&full_update()).unwrap());
+            }
+            Notification::Other { .. } => panic!("update not parsed"),
+        }
+        assert!(Notification::parse("update", &json!({"view_id": "view-id-1"})).is_err());
+        match Notification::parse("scroll_to", &json!({"line": 3})).unwrap() {
+            Notification::Other { method, params } => {
+                assert_eq!((method.as_str(), params), ("scroll_to", json!({"line": 3})));
+            }
+            Notification::Update { .. } => panic!("not an update"),
+        }
+    }
+}
@@ -40,6 +40,31 @@ trait Callback: Send {
 
 pub trait Handler {
     fn notification(&self, method: &str, params: &Value);
+
+    /// Called for requests from the core. The core waits for the answer,
+    /// which is sent through the responder (from any thread).
+    fn request(&self, method: &str, params: &Value, responder: Responder);
+
+    /// Called when the core answers a request with an error. The request's
+    /// callback still gets called, with a null result.
+    fn error(&self, error: &Value);
+}
+
+/// Answers one request from the core.
+pub struct Responder {
+    core: Core,
+    id: Value,
+}
+
+impl Responder {
+    pub fn respond(self, result: Result<Value, Value>) {
+        let msg = match result {
+            Ok(result) => json!({"id": self.id, "result": result}),
+            Err(error) => json!({"id": self.id, "error": error}),
+        };
+        let state = self.core.state.lock().unwrap();
+        state.xi_peer.send_json(&msg);
+    }
 }
 
 impl<F: FnOnce(&Value) + Send> Callback for F {
@@ -52,8 +77,9 @@ impl Core {
     /// Sets up a new RPC connection, also starting a thread to receive
     /// responses.
     ///
-    /// The handler is invoked for incoming RPC notifications. Note that
-    /// it must be `Send` because it is called from a dedicated thread.
+    /// The handler is invoked for incoming RPC notifications and requests.
+    /// Note that it must be `Send` because it is called from a dedicated
+    /// thread.
     pub fn new<H>(xi_peer: XiPeer, rx: Receiver<Value>, handler: H) -> Core
         where H: Handler + Send + 'static
     {
@@ -67,8 +93,19 @@ impl Core {
         thread::spawn(move || {
             while let Ok(msg) = rx.recv() {
                 if let Value::String(ref method) = msg["method"] {
-                    handler.notification(&method, &msg["params"]);
+                    if msg["id"].is_null() {
+                        handler.notification(&method, &msg["params"]);
+                    } else {
+                        let responder = Responder {
+                            core: rx_core_handle.clone(),
+                            id: msg["id"].clone(),
+                        };
+                        handler.request(&method, &msg["params"], responder);
+                    }
                 } else if let Some(id) = msg["id"].as_u64() {
+                    if !msg["error"].is_null() {
+                        handler.error(&msg["error"]);
+                    }
                     let mut state = rx_core_handle.state.lock().unwrap();
                     if let Some(callback) = state.pending.remove(&id) {
                         callback.call(&msg["result"]);
@@ -95,6 +132,14 @@ impl Core {
     /// Calls the callback with the result (from a different thread).
     pub fn send_request<F>(&mut self, method: &str, params: &Value, callback: F)
         where F: FnOnce(&Value) + Send + 'static
+    {
+        self.send_request_with_id(method, params, move |_, result| callback(result));
+    }
+
+    /// Like send_request, but returns the id the request is sent with, and
+    /// gives it to the callback too, for telling requests apart.
+    pub fn send_request_with_id<F>(&mut self, method: &str, params: &Value, callback: F) -> u64
+        where F: FnOnce(u64, &Value) + Send + 'static
     {
         let mut state = self.state.lock().unwrap();
         let id = state.id;
@@ -104,7 +149,8 @@ impl Core {
             "id": id,
         });
         state.xi_peer.send_json(&cmd);
-        state.pending.insert(id, Box::new(callback));
+        state.pending.insert(id, Box::new(move |result: &Value| callback(id, result)));
         state.id += 1;
+        id
     }
 }
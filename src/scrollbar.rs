@@ -0,0 +1,213 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The edit view's vertical scrollbar. It's drawn by the view rather than
+//! being a Windows one, so its track can carry marks of where the find
+//! results, carets, lines changed since the file was opened and bookmarks
+//! are, placed by line number over the whole document.
+//!
+//! The marks are kept by line, updated from the lines each update changes
+//! and moved through its ops, so painting doesn't go over the cache.
+
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use linecache::{walk_ops, HighlightKind, LineCache, Run};
+use protocol::Op;
+
+/// The width of the scrollbar at the right of the view, in px units.
+pub const SCROLLBAR_WIDTH: f32 = 12.0;
+
+// The thumb doesn't get shorter than this, to stay easy to grab.
+const MIN_THUMB: f32 = 20.0;
+// How close to a mark a click has to be to go to it (in px units).
+const MARK_SLOP: f32 = 3.0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MarkKind {
+    Changed,
+    Find,
+    Bookmark,
+    Caret,
+}
+
+pub struct Scrollbar {
+    find: BTreeSet<usize>,
+    carets: BTreeSet<usize>,
+    changed: BTreeSet<usize>,
+    // While the thumb is dragged, how far below its top it was grabbed.
+    grab: Option<f32>,
+}
+
+impl Scrollbar {
+    pub fn new() -> Scrollbar {
+        Scrollbar {
+            find: BTreeSet::new(),
+            carets: BTreeSet::new(),
+            changed: BTreeSet::new(),
+            grab: None,
+        }
+    }
+
+    /// Forget the marks, as for a new file.
+    pub fn clear(&mut self) {
+        self.find.clear();
+        self.carets.clear();
+        self.changed.clear();
+    }
+
+    /// Move the changed-line marks through the ops of an update, before
+    /// it's applied to the cache, which still has the old lines. A line
+    /// sent with new text is marked; one sent again with the same text, or
+    /// in place of a line we didn't have, as when scrolling, keeps the mark
+    /// it had.
+    pub fn apply_ops(&mut self, ops: &[Op], cache: &LineCache) {
+        // The first update of a file only loads it.
+        if cache.height() == 0 {
+            return;
+        }
+        let mut changed = BTreeSet::new();
+        walk_ops(ops, |run| match run {
+            Run::Copied { old, new_start } => {
+                for line in self.changed.range(old.clone()) {
+                    changed.insert(line - old.start + new_start);
+                }
+            }
+            Run::Replaced { old, new_start, added } => {
+                let in_place = old.end - old.start == added.len();
+                for (k, text) in added.iter().enumerate() {
+                    let same_text = text.and_then(|text| old.clone().find(|&line| {
+                        cache.get_line(line).map(|line| line.text()) == Some(text)
+                    }));
+                    let was_changed = match same_text {
+                        Some(line) => self.changed.contains(&line),
+                        None if in_place => {
+                            let line = old.start + k;
+                            self.changed.contains(&line) ||
+                                (text.is_some() && cache.get_line(line).is_some())
+                        }
+                        None => text.is_some(),
+                    };
+                    if was_changed {
+                        changed.insert(new_start + k);
+                    }
+                }
+            }
+        });
+        self.changed = changed;
+    }
+
+    /// Bring the find and caret marks up to date with the lines an update
+    /// changed, after it's applied.
+    pub fn update_lines(&mut self, cache: &LineCache, lines: Range<usize>) {
+        let height = cache.height();
+        self.find.split_off(&height);
+        self.carets.split_off(&height);
+        self.changed.split_off(&height);
+        for ix in lines.start..min(lines.end, height) {
+            let (find, caret) = match cache.get_line(ix) {
+                Some(line) => (line.highlights().iter().any(|h| h.kind == HighlightKind::Find),
+                    !line.cursor().is_empty()),
+                None => (false, false),
+            };
+            set(&mut self.find, ix, find);
+            set(&mut self.carets, ix, caret);
+        }
+    }
+
+    /// The lines of each kind of mark, in the order to draw them, with the
+    /// lines of the bookmarks, which the view keeps.
+    pub fn marks<'a>(&'a self, bookmarks: &'a BTreeSet<usize>)
+        -> [(MarkKind, &'a BTreeSet<usize>); 4]
+    {
+        [
+            (MarkKind::Changed, &self.changed),
+            (MarkKind::Find, &self.find),
+            (MarkKind::Bookmark, bookmarks),
+            (MarkKind::Caret, &self.carets),
+        ]
+    }
+
+    /// The top and bottom of the thumb, for a view of this height.
+    pub fn thumb(view_height: f32, scroll_offset: f32, max_scroll: f32) -> (f32, f32) {
+        let content_height = view_height + max_scroll;
+        let length = (view_height * view_height / content_height).max(MIN_THUMB)
+            .min(view_height);
+        let top = if max_scroll > 0.0 {
+            scroll_offset / max_scroll * (view_height - length)
+        } else {
+            0.0
+        };
+        (top, top + length)
+    }
+
+    /// Where a line's mark goes on the track.
+    pub fn line_y(line: usize, n_lines: usize, view_height: f32) -> f32 {
+        (line as f32 / n_lines.max(1) as f32 * view_height).floor()
+    }
+
+    /// The line of the mark nearest a point on the track, if one is close
+    /// enough to have been clicked.
+    pub fn mark_at(&self, y: f32, n_lines: usize, view_height: f32,
+        bookmarks: &BTreeSet<usize>) -> Option<usize>
+    {
+        let mut nearest: Option<(f32, usize)> = None;
+        // Only the marks on either side of the point can be nearest.
+        let at = ((y / view_height) * n_lines as f32).max(0.0) as usize;
+        for &(_, lines) in self.marks(bookmarks).iter() {
+            let below = lines.range(at..).next();
+            let above = lines.range(..at).next_back();
+            for &line in below.into_iter().chain(above) {
+                let distance = (Scrollbar::line_y(line, n_lines, view_height) - y).abs();
+                if distance <= MARK_SLOP && nearest.map_or(true, |(d, _)| distance < d) {
+                    nearest = Some((distance, line));
+                }
+            }
+        }
+        nearest.map(|(_, line)| line)
+    }
+
+    /// Start dragging the thumb, grabbed this far below its top.
+    pub fn start_drag(&mut self, grab: f32) {
+        self.grab = Some(grab);
+    }
+
+    /// The scroll offset for the thumb dragged to a point, if it's being
+    /// dragged.
+    pub fn drag_to(&self, y: f32, view_height: f32, scroll_offset: f32, max_scroll: f32)
+        -> Option<f32>
+    {
+        let grab = self.grab?;
+        let (top, bottom) = Scrollbar::thumb(view_height, scroll_offset, max_scroll);
+        let travel = view_height - (bottom - top);
+        if travel <= 0.0 {
+            return Some(0.0);
+        }
+        Some(((y - grab) / travel * max_scroll).max(0.0).min(max_scroll))
+    }
+
+    /// Stop dragging the thumb. Returns whether it was being dragged.
+    pub fn end_drag(&mut self) -> bool {
+        self.grab.take().is_some()
+    }
+}
+
+fn set(lines: &mut BTreeSet<usize>, line: usize, on: bool) {
+    if on {
+        lines.insert(line);
+    } else {
+        lines.remove(&line);
+    }
+}
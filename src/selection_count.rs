@@ -0,0 +1,283 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The selected characters and lines of a view, for the status bar. Each
+//! line's count is kept, so after an update only the lines it changed are
+//! counted again, and those a step at a time, so selecting all of a huge
+//! file doesn't hold up the window. Lines the core hasn't sent can't be
+//! counted; when the selection runs into one, the counts say so.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+use linecache::LineCache;
+
+/// The counts of the whole selection.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Counts {
+    /// Selected characters, as Unicode scalar values.
+    pub chars: usize,
+    /// Lines with a selection on them.
+    pub lines: usize,
+    /// Whether the selection goes on into lines the core hasn't sent, so
+    /// it's bigger than counted.
+    pub partial: bool,
+}
+
+// What's selected on one line.
+#[derive(Clone, Copy)]
+struct LineCount {
+    chars: usize,
+    // Whether the selection runs into a neighboring line that isn't cached.
+    partial: bool,
+}
+
+pub struct SelectionCounts {
+    // The count for each line with a selection on it. Lines waiting to be
+    // counted are None, and left out of the totals until they are.
+    lines: Vec<Option<LineCount>>,
+    chars: usize,
+    selected_lines: usize,
+    partial_lines: usize,
+    // The lines waiting to be counted.
+    pending: Option<Range<usize>>,
+}
+
+impl SelectionCounts {
+    pub fn new() -> SelectionCounts {
+        SelectionCounts {
+            lines: Vec::new(),
+            chars: 0,
+            selected_lines: 0,
+            partial_lines: 0,
+            pending: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = SelectionCounts::new();
+    }
+
+    /// Count again the lines an update changed or moved, given the range
+    /// apply_update returned and the cache's new height. The lines either
+    /// side are counted again too, as whether a selection runs into a
+    /// line the core hasn't sent depends on its neighbors.
+    pub fn invalidate(&mut self, changed: Range<usize>, height: usize) {
+        for ix in height..self.lines.len() {
+            self.forget(ix);
+        }
+        self.lines.resize(height, None);
+        let start = changed.start.saturating_sub(1);
+        let end = min(changed.end + 1, height);
+        let pending = match self.pending.take() {
+            Some(pending) => min(pending.start, start)..min(max(pending.end, end), height),
+            None => start..end,
+        };
+        for ix in pending.clone() {
+            self.forget(ix);
+        }
+        if pending.start < pending.end {
+            self.pending = Some(pending);
+        }
+    }
+
+    // Take a line out of the totals.
+    fn forget(&mut self, ix: usize) {
+        if let Some(count) = self.lines.get_mut(ix).and_then(|count| count.take()) {
+            self.chars -= count.chars;
+            self.selected_lines -= 1;
+            if count.partial {
+                self.partial_lines -= 1;
+            }
+        }
+    }
+
+    /// Count up to `max_lines` of the lines waiting. Returns whether some
+    /// are still waiting after.
+    pub fn step(&mut self, cache: &LineCache, max_lines: usize) -> bool {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return false,
+        };
+        let end = min(pending.start + max_lines, pending.end);
+        for ix in pending.start..end {
+            if let Some(count) = count_line(cache, ix) {
+                self.chars += count.chars;
+                self.selected_lines += 1;
+                if count.partial {
+                    self.partial_lines += 1;
+                }
+                self.lines[ix] = Some(count);
+            }
+        }
+        if end < pending.end {
+            self.pending = Some(end..pending.end);
+        }
+        self.pending.is_some()
+    }
+
+    /// The counts, or None while some lines are still waiting.
+    pub fn counts(&self) -> Option<Counts> {
+        match self.pending {
+            Some(_) => None,
+            None => Some(Counts {
+                chars: self.chars,
+                lines: self.selected_lines,
+                partial: self.partial_lines > 0,
+            }),
+        }
+    }
+}
+
+// Count the selection on a cached line, if there is one.
+fn count_line(cache: &LineCache, ix: usize) -> Option<LineCount> {
+    let line = cache.get_line(ix)?;
+    let text = line.text();
+    let len = text.trim_end_matches(|c| c == '\n' || c == '\r').len();
+    let mut count = None;
+    for span in line.selections().filter(|span| span.end > span.start) {
+        let span_end = min(span.end, text.len());
+        let chars = text.get(span.start..span_end)
+            .map_or(span_end.saturating_sub(span.start), |s| s.chars().count());
+        // A selection past the line break goes on to the next line, and
+        // one from the start may have come from the line before.
+        let missing = |ix: usize| ix < cache.height() && cache.get_line(ix).is_none();
+        let partial = (span.end > len && missing(ix + 1)) ||
+            (span.start == 0 && ix > 0 && missing(ix - 1));
+        let count = count.get_or_insert(LineCount { chars: 0, partial: false });
+        count.chars += chars;
+        count.partial |= partial;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    use protocol::Update;
+
+    fn update(ops: Value) -> Update {
+        Update::deserialize(&json!({ "ops": ops })).unwrap()
+    }
+
+    // A line, selected from one offset to another.
+    fn selected(text: &str, start: i64, end: i64) -> Value {
+        json!({"text": text, "cursor": [end], "styles": [start, end - start, 0]})
+    }
+
+    fn plain(text: &str) -> Value {
+        json!({"text": text})
+    }
+
+    // Count everything waiting, at most `step` lines at a time, and return
+    // the number of steps it took.
+    fn count_all(counts: &mut SelectionCounts, cache: &LineCache, step: usize) -> usize {
+        let mut steps = 1;
+        while counts.step(cache, step) {
+            assert_eq!(counts.counts(), None);
+            steps += 1;
+        }
+        steps
+    }
+
+    #[test]
+    fn counting_a_selection() {
+        let mut cache = LineCache::new();
+        let mut counts = SelectionCounts::new();
+        let changed = cache.apply_update(update(json!([{"op": "ins", "lines": [
+            plain("one\n"), selected("t\u{e9}o\n", 1, 5), selected("three\n", 0, 2),
+            plain("four"),
+        ]}]))).unwrap();
+        counts.invalidate(changed, cache.height());
+        assert_eq!(counts.counts(), None);
+        assert_eq!(count_all(&mut counts, &cache, 100), 1);
+        assert_eq!(counts.counts(), Some(Counts { chars: 5, lines: 2, partial: false }));
+    }
+
+    #[test]
+    fn only_changed_lines_are_counted_again() {
+        let mut cache = LineCache::new();
+        let mut counts = SelectionCounts::new();
+        let lines: Vec<Value> = (0..1000).map(|_| selected("ab\n", 0, 3)).collect();
+        let changed = cache.apply_update(update(json!([{"op": "ins", "lines": lines}])));
+        counts.invalidate(changed.unwrap(), cache.height());
+        assert_eq!(count_all(&mut counts, &cache, 100), 10);
+        assert_eq!(counts.counts(), Some(Counts { chars: 3000, lines: 1000, partial: false }));
+
+        // Deselect one line in the middle: only it and its neighbors wait.
+        let changed = cache.apply_update(update(json!([
+            {"op": "copy", "n": 500},
+            {"op": "update", "n": 1, "lines": [{"cursor": [0]}]},
+            {"op": "copy", "n": 499},
+        ]))).unwrap();
+        assert_eq!(changed, 500..501);
+        counts.invalidate(changed, cache.height());
+        assert_eq!(counts.pending, Some(499..502));
+        assert_eq!(count_all(&mut counts, &cache, 100), 1);
+        assert_eq!(counts.counts(), Some(Counts { chars: 2997, lines: 999, partial: false }));
+
+        // Delete the last 500 lines.
+        let changed = cache.apply_update(update(json!([{"op": "copy", "n": 500}]))).unwrap();
+        counts.invalidate(changed, cache.height());
+        count_all(&mut counts, &cache, 100);
+        assert_eq!(counts.counts(), Some(Counts { chars: 1500, lines: 500, partial: false }));
+    }
+
+    #[test]
+    fn an_update_while_counting() {
+        let mut cache = LineCache::new();
+        let mut counts = SelectionCounts::new();
+        let lines: Vec<Value> = (0..300).map(|_| selected("ab\n", 0, 3)).collect();
+        let changed = cache.apply_update(update(json!([{"op": "ins", "lines": lines}])));
+        counts.invalidate(changed.unwrap(), cache.height());
+        assert!(counts.step(&cache, 100));
+        // The next update changes a line already counted; what's waiting
+        // still gets counted, and nothing twice.
+        let changed = cache.apply_update(update(json!([
+            {"op": "copy", "n": 10},
+            {"op": "update", "n": 1, "lines": [{"cursor": [0]}]},
+            {"op": "copy", "n": 289},
+        ]))).unwrap();
+        counts.invalidate(changed, cache.height());
+        count_all(&mut counts, &cache, 100);
+        assert_eq!(counts.counts(), Some(Counts { chars: 897, lines: 299, partial: false }));
+    }
+
+    #[test]
+    fn selections_into_lines_not_sent() {
+        let mut cache = LineCache::new();
+        let mut counts = SelectionCounts::new();
+        // The selection runs past the end of line 1, into line 2, which
+        // the core hasn't sent.
+        let changed = cache.apply_update(update(json!([
+            {"op": "ins", "lines": [plain("one\n"), selected("two\n", 1, 4)]},
+            {"op": "invalidate", "n": 2},
+        ]))).unwrap();
+        counts.invalidate(changed, cache.height());
+        count_all(&mut counts, &cache, 100);
+        assert_eq!(counts.counts(), Some(Counts { chars: 3, lines: 1, partial: true }));
+
+        // Once it's sent, line 1 is counted again, and the count is whole.
+        let changed = cache.apply_update(update(json!([
+            {"op": "copy", "n": 2},
+            {"op": "ins", "lines": [selected("three\n", 0, 2), plain("four")]},
+        ]))).unwrap();
+        counts.invalidate(changed, cache.height());
+        count_all(&mut counts, &cache, 100);
+        assert_eq!(counts.counts(), Some(Counts { chars: 5, lines: 2, partial: false }));
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Front-end settings that persist across runs, kept as a JSON object in
+//! %APPDATA%\xi-win\settings.json. These are things the core doesn't
+//! know about, like the languages chosen for file extensions and where
+//! the window was.
+//!
+//! If the file is broken, the settings it had the last time it could be
+//! read are used, and it's left for the user to fix.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+use xi_win_shell::paint::TextAntialias;
+use xi_win_shell::window::WindowPlacement;
+
+use chrome;
+use config_errors::{self, ConfigError};
+use edit_view::{LineEnding, LineNumbers, MiddleClick};
+
+pub struct Settings {
+    values: Value,
+    // Whether settings.json couldn't be read, so that saving doesn't
+    // overwrite what the user is in the middle of fixing.
+    broken: bool,
+}
+
+/// The files that were open when the app last exited, to reopen them.
+pub struct Session {
+    pub files: Vec<SessionFile>,
+    // Index into files of the one that had focus.
+    pub active: usize,
+}
+
+pub struct SessionFile {
+    pub path: String,
+    pub scroll_offset: f32,
+    pub caret_line: usize,
+}
+
+/// The fix-ups made to a file as it's saved.
+#[derive(Clone, Copy, Default)]
+pub struct SaveFixups {
+    pub trim_whitespace: bool,
+    /// End the file in exactly one newline.
+    pub final_newline: bool,
+}
+
+impl Settings {
+    /// Load the settings. A missing or unreadable file gives the defaults.
+    /// Also returns what's wrong with the file, if it's broken.
+    pub fn load() -> (Settings, Option<ConfigError>) {
+        let text = match settings_path().and_then(|path| read_file(&path).ok()) {
+            Some(text) => text,
+            None => return (Settings { values: json!({}), broken: false }, None),
+        };
+        let (settings, error) = Settings::parse(&text,
+            || config_errors::read_last_good("settings.json"));
+        if !settings.broken {
+            config_errors::write_last_good("settings.json", &text);
+        }
+        (settings, error)
+    }
+
+    // The settings in the text of settings.json, or if it isn't an object
+    // of them, those in its last good text, or the defaults.
+    fn parse<F>(text: &str, last_good: F) -> (Settings, Option<ConfigError>)
+        where F: FnOnce() -> Option<String>
+    {
+        let error = match serde_json::from_str::<Value>(text) {
+            Ok(values @ Value::Object(_)) => return (Settings { values, broken: false }, None),
+            Ok(_) => ConfigError::new("settings.json", None, "expected an object of settings"),
+            Err(e) => ConfigError::from_json("settings.json", &e),
+        };
+        let values = last_good()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .filter(|values| values.is_object())
+            .unwrap_or_else(|| json!({}));
+        (Settings { values, broken: true }, Some(error))
+    }
+
+    /// Save the settings, unless settings.json is broken; they're kept
+    /// with its last good copy either way.
+    pub fn save(&self) -> io::Result<()> {
+        let path = settings_path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no APPDATA directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = serde_json::to_string_pretty(&self.values)?;
+        config_errors::write_last_good("settings.json", &text);
+        if self.broken {
+            return Ok(());
+        }
+        File::create(&path)?.write_all(text.as_bytes())
+    }
+
+    /// The value of a setting, or null if it isn't set.
+    pub fn get(&self, key: &str) -> &Value {
+        &self.values[key]
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.values[key] = value;
+    }
+
+    /// Recently opened or saved files, most recent first.
+    pub fn recent_files(&self) -> Vec<String> {
+        self.get("recent_files").as_array()
+            .map(|files| files.iter().filter_map(|f| f.as_str()).map(|f| f.to_owned()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_recent_files(&mut self, files: &[String]) {
+        self.set("recent_files", json!(files));
+    }
+
+    /// How many lines the view can scroll past the end, if set; there's
+    /// no UI for it, it can only be set in the file.
+    pub fn overscroll_lines(&self) -> Option<usize> {
+        self.get("overscroll_lines").as_u64().map(|lines| lines as usize)
+    }
+
+    /// What the middle button does in the view: "autoscroll", the
+    /// default, or "paste". Also only set in the file.
+    pub fn middle_click(&self) -> MiddleClick {
+        match self.get("middle_click").as_str() {
+            Some("paste") => MiddleClick::Paste,
+            _ => MiddleClick::Autoscroll,
+        }
+    }
+
+    /// Whether to mirror the chrome for a right-to-left UI. Unless it's
+    /// set in the file, it's told from the process layout and the UI
+    /// language.
+    pub fn mirrored(&self) -> bool {
+        self.get("mirrored").as_bool().unwrap_or_else(chrome::detect_mirrored)
+    }
+
+    pub fn save_fixups(&self) -> SaveFixups {
+        let on = |key: &str| self.get(key).as_bool().unwrap_or(false);
+        SaveFixups {
+            trim_whitespace: on("trim_trailing_whitespace_on_save"),
+            final_newline: on("final_newline_on_save"),
+        }
+    }
+
+    pub fn set_save_fixups(&mut self, fixups: SaveFixups) {
+        self.set("trim_trailing_whitespace_on_save", json!(fixups.trim_whitespace));
+        self.set("final_newline_on_save", json!(fixups.final_newline));
+    }
+
+    /// How the lines of new files end: "crlf", the default on Windows, or
+    /// "lf". Files that have line breaks keep theirs.
+    pub fn line_ending(&self) -> LineEnding {
+        match self.get("line_ending").as_str() {
+            Some("lf") => LineEnding::Lf,
+            _ => LineEnding::Crlf,
+        }
+    }
+
+    /// How text is antialiased: "cleartype", the default, "grayscale" or
+    /// "aliased".
+    pub fn line_numbers(&self) -> LineNumbers {
+        match self.get("line_numbers").as_str() {
+            Some("off") => LineNumbers::Off,
+            Some("relative") => LineNumbers::Relative,
+            Some("hybrid") => LineNumbers::Hybrid,
+            _ => LineNumbers::Absolute,
+        }
+    }
+
+    pub fn set_line_numbers(&mut self, mode: LineNumbers) {
+        let name = match mode {
+            LineNumbers::Off => "off",
+            LineNumbers::Absolute => "absolute",
+            LineNumbers::Relative => "relative",
+            LineNumbers::Hybrid => "hybrid",
+        };
+        self.set("line_numbers", json!(name));
+    }
+
+    pub fn text_antialias(&self) -> TextAntialias {
+        match self.get("text_antialias").as_str() {
+            Some("grayscale") => TextAntialias::Grayscale,
+            Some("aliased") => TextAntialias::Aliased,
+            _ => TextAntialias::ClearType,
+        }
+    }
+
+    pub fn set_text_antialias(&mut self, mode: TextAntialias) {
+        let name = match mode {
+            TextAntialias::ClearType => "cleartype",
+            TextAntialias::Grayscale => "grayscale",
+            TextAntialias::Aliased => "aliased",
+        };
+        self.set("text_antialias", json!(name));
+    }
+
+    /// The height of the bottom pane of a split view, as it was last
+    /// dragged to, in px units. Unset, the panes share the edit area
+    /// evenly.
+    pub fn split_height(&self) -> Option<f32> {
+        self.get("split_height").as_f64().map(|height| height as f32)
+    }
+
+    pub fn set_split_height(&mut self, height: Option<f32>) {
+        self.set("split_height", json!(height));
+    }
+
+    /// The language set for files with the extension of a path, which
+    /// they're given as they're opened.
+    pub fn language_override(&self, path: &str) -> Option<String> {
+        let extension = extension(path)?;
+        self.get("language_overrides")[&extension].as_str().map(|l| l.to_owned())
+    }
+
+    pub fn set_language_override(&mut self, extension: &str, language: &str) {
+        if !self.get("language_overrides").is_object() {
+            self.set("language_overrides", json!({}));
+        }
+        self.values["language_overrides"][extension.to_lowercase()] = json!(language);
+    }
+
+    pub fn session(&self) -> Option<Session> {
+        let v = self.get("session");
+        let files: Vec<SessionFile> = v["files"].as_array()?.iter().filter_map(|file| {
+            Some(SessionFile {
+                path: file["path"].as_str()?.to_owned(),
+                scroll_offset: file["scroll_offset"].as_f64().unwrap_or(0.0) as f32,
+                caret_line: file["caret_line"].as_u64().unwrap_or(0) as usize,
+            })
+        }).collect();
+        if files.is_empty() {
+            return None;
+        }
+        let active = v["active"].as_u64().unwrap_or(0) as usize;
+        Some(Session { active: if active < files.len() { active } else { 0 }, files })
+    }
+
+    /// Set or (with `None`) clear the session.
+    pub fn set_session(&mut self, session: Option<&Session>) {
+        let value = match session {
+            Some(session) => json!({
+                "files": session.files.iter().map(|file| json!({
+                    "path": file.path,
+                    "scroll_offset": file.scroll_offset,
+                    "caret_line": file.caret_line,
+                })).collect::<Vec<_>>(),
+                "active": session.active,
+            }),
+            None => Value::Null,
+        };
+        self.set("session", value);
+    }
+
+    pub fn window_placement(&self) -> Option<WindowPlacement> {
+        let v = self.get("window_placement");
+        let coord = |key: &str| v[key].as_i64().map(|x| x as i32);
+        let placement = WindowPlacement {
+            left: coord("left")?,
+            top: coord("top")?,
+            right: coord("right")?,
+            bottom: coord("bottom")?,
+            maximized: v["maximized"].as_bool().unwrap_or(false),
+        };
+        if placement.right > placement.left && placement.bottom > placement.top {
+            Some(placement)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_window_placement(&mut self, placement: &WindowPlacement) {
+        self.set("window_placement", json!({
+            "left": placement.left,
+            "top": placement.top,
+            "right": placement.right,
+            "bottom": placement.bottom,
+            "maximized": placement.maximized,
+        }));
+    }
+}
+
+/// The extension of a path, lowercased, as paths on Windows are
+/// case-insensitive.
+pub fn extension(path: &str) -> Option<String> {
+    Path::new(path).extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+}
+
+fn settings_path() -> Option<PathBuf> {
+    config_path("settings.json")
+}
+
+/// The path of a file in %APPDATA%\xi-win, where the front end keeps its
+/// configuration.
+pub fn config_path(name: &str) -> Option<PathBuf> {
+    env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("xi-win").join(name))
+}
+
+pub fn read_file(path: &PathBuf) -> io::Result<String> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_overrides() {
+        let mut settings = Settings { values: json!({}), broken: false };
+        assert_eq!(settings.language_override("C:\\src\\build.gradle"), None);
+        settings.set_language_override("gradle", "Groovy");
+        settings.set_language_override("H", "C++");
+        assert_eq!(settings.language_override("C:\\src\\build.gradle"), Some("Groovy".into()));
+        assert_eq!(settings.language_override("C:\\src\\Main.GRADLE"), Some("Groovy".into()));
+        assert_eq!(settings.language_override("util.h"), Some("C++".into()));
+        assert_eq!(settings.language_override("C:\\src\\Makefile"), None);
+        // A later choice replaces an earlier one.
+        settings.set_language_override("h", "C");
+        assert_eq!(settings.language_override("util.h"), Some("C".into()));
+    }
+
+    #[test]
+    fn language_overrides_replace_a_bad_value() {
+        let mut settings = Settings {
+            values: json!({"language_overrides": "Rust"}),
+            broken: false,
+        };
+        assert_eq!(settings.language_override("main.rs"), None);
+        settings.set_language_override("rs", "Rust");
+        assert_eq!(settings.language_override("main.rs"), Some("Rust".into()));
+    }
+
+    #[test]
+    fn broken_files_fall_back_on_the_last_good_one() {
+        let last_good = || Some(r#"{"tab_size": 2}"#.to_owned());
+        let broken = [
+            ("{\n  \"line_numbers\": \"off\"\n  \"word_wrap\": true\n}", Some(3)),
+            ("{\n  \"tab_size\": 4,\n}", Some(3)),
+            ("{\n  'tab_size': 4\n}", Some(2)),
+            ("{\n  \"tab_size\": 4\n", Some(3)),
+            ("[\"tab_size\", 4]", None),
+            ("null", None),
+        ];
+        for &(text, line) in broken.iter() {
+            let (settings, error) = Settings::parse(text, last_good);
+            assert_eq!(error.map(|e| e.line), Some(line), "{:?}", text);
+            assert!(settings.broken);
+            assert_eq!(settings.get("tab_size"), &json!(2));
+
+            let (settings, _) = Settings::parse(text, || None);
+            assert_eq!(settings.values, json!({}));
+        }
+        // A broken last good copy is no better.
+        let (settings, _) = Settings::parse("{", || Some("{".to_owned()));
+        assert_eq!(settings.values, json!({}));
+    }
+
+    #[test]
+    fn good_files_are_used() {
+        let (settings, error) = Settings::parse(r#"{"tab_size": 4}"#, || panic!("not needed"));
+        assert!(error.is_none());
+        assert!(!settings.broken);
+        assert_eq!(settings.get("tab_size"), &json!(4));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting the edit area into two panes, one above the other, showing
+//! the same file.
+
+use direct2d::brush;
+use direct2d::math::*;
+
+use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::util::Error;
+
+use edit_view::EditView;
+use splitter::{Splitter, SPLITTER_HEIGHT};
+
+// Dragging the splitter can't make a pane shorter than this.
+const MIN_PANE_HEIGHT: f32 = 40.0;
+
+/// The state of a split edit area. The focused pane is the window's edit
+/// view; this holds the other one.
+pub struct Split {
+    pub other: EditView,
+    /// Whether the focused pane is the top one.
+    pub focused_on_top: bool,
+    /// Between the panes. It keeps the height of the bottom pane, which is
+    /// remembered for the next split.
+    pub splitter: Splitter,
+    resources: Option<Resources>,
+}
+
+struct Resources {
+    bg: brush::SolidColor,
+    // While the splitter has the focus for the keys.
+    focused_bg: brush::SolidColor,
+}
+
+impl Split {
+    /// Split with the bottom pane the height it was last given, or half
+    /// the edit area.
+    pub fn new(other: EditView, height: Option<f32>) -> Split {
+        let mut splitter = Splitter::new(MIN_PANE_HEIGHT, MIN_PANE_HEIGHT, 0.5);
+        splitter.set_height(height);
+        Split {
+            other,
+            focused_on_top: true,
+            splitter,
+            resources: None,
+        }
+    }
+
+    fn create_resources(&mut self, p: &mut PaintCtx) -> Result<Resources, Error> {
+        Ok(Resources {
+            bg: p.solid_brush(0x1e1f1c)?,
+            focused_bg: p.solid_brush(0x49483e)?,
+        })
+    }
+
+    pub fn rebuild_resources(&mut self) {
+        self.resources = None;
+        self.other.rebuild_resources();
+    }
+
+    /// The height of the top pane and the top of the bottom one, for an
+    /// edit area of this height.
+    pub fn pane_heights(&self, height: f32) -> (f32, f32) {
+        let top_height = self.splitter.position(height);
+        (top_height, top_height + SPLITTER_HEIGHT)
+    }
+
+    /// Draw the splitter, between panes that share an area this far down
+    /// the window and this high; the panes are drawn separately.
+    pub fn render(&mut self, p: &mut PaintCtx, top: f32, width: f32, height: f32)
+        -> Result<(), Error>
+    {
+        if self.resources.is_none() {
+            self.resources = Some(self.create_resources(p)?);
+        }
+        let (y0, y1) = self.pane_heights(height);
+        let (y0, y1) = (top + y0, top + y1);
+        let resources = &self.resources.as_ref().unwrap();
+        let bg = if self.splitter.is_focused() { &resources.focused_bg } else { &resources.bg };
+        let rt = p.render_target();
+        rt.fill_rectangle(&RectF::from((0.0, y0, width, y1)), bg);
+        Ok(())
+    }
+}
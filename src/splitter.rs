@@ -0,0 +1,237 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bar between two areas, one above the other, that's dragged to share
+//! the height between them, like the one between the panes of a split
+//! view. It can also be moved with Ctrl+Alt+Up and Down once clicked, and
+//! double-clicking it puts it back where it started.
+//!
+//! It keeps the height of the area below, which is what's remembered, and
+//! fits it to the height there is each time, so a height set in a bigger
+//! window still leaves both areas their minimum in a smaller one.
+
+use std::time::{Duration, Instant};
+
+use winapi::um::winuser::GetDoubleClickTime;
+
+/// Height of the bar, in px units.
+pub const SPLITTER_HEIGHT: f32 = 5.0;
+
+// How far Ctrl+Alt+Up or Down moves the bar, in px units.
+const KEY_STEP: f32 = 20.0;
+
+pub struct Splitter {
+    min_above: f32,
+    min_below: f32,
+    // The share of the height the area below has until the bar is moved.
+    default_share: f32,
+    // The height of the area below, once the bar has been moved.
+    below: Option<f32>,
+    // While the bar is dragged, how far below its top it was grabbed.
+    grab: Option<f32>,
+    // When the bar was last pressed, to tell a double-click.
+    last_press: Option<Instant>,
+    // Whether the keys move the bar.
+    focused: bool,
+}
+
+impl Splitter {
+    pub fn new(min_above: f32, min_below: f32, default_share: f32) -> Splitter {
+        Splitter {
+            min_above,
+            min_below,
+            default_share,
+            below: None,
+            grab: None,
+            last_press: None,
+            focused: false,
+        }
+    }
+
+    /// The height of the area below, if the bar has been moved, to be
+    /// remembered.
+    pub fn height(&self) -> Option<f32> {
+        self.below
+    }
+
+    /// Give the area below a remembered height.
+    pub fn set_height(&mut self, below: Option<f32>) {
+        self.below = below.filter(|below| below.is_finite() && *below >= 0.0);
+    }
+
+    /// The height of the area above the bar, which is where the bar's
+    /// top is, in areas this high together. When there isn't room for
+    /// both minimums, the area above keeps its own.
+    pub fn position(&self, total: f32) -> f32 {
+        let room = (total - SPLITTER_HEIGHT).max(0.0);
+        let below = self.below.unwrap_or(room * self.default_share).round();
+        let below = below.max(self.min_below).min(room - self.min_above).max(0.0);
+        room - below
+    }
+
+    /// Whether a point this far down the areas is on the bar.
+    pub fn contains(&self, y: f32, total: f32) -> bool {
+        let y0 = self.position(total);
+        y >= y0 && y < y0 + SPLITTER_HEIGHT
+    }
+
+    /// Press the left button on the bar, which takes the focus for the
+    /// keys and starts a drag, or for a double-click puts the bar back.
+    /// Returns whether the bar moved.
+    pub fn press(&mut self, y: f32, total: f32) -> bool {
+        self.focused = true;
+        let now = Instant::now();
+        let max_ms = unsafe { GetDoubleClickTime() };
+        let double = self.last_press
+            .map_or(false, |last| now - last <= Duration::from_millis(max_ms as u64));
+        if double {
+            self.last_press = None;
+            return self.below.take().is_some();
+        }
+        self.last_press = Some(now);
+        self.grab = Some(y - self.position(total));
+        false
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.grab.is_some()
+    }
+
+    /// Follow the mouse while the bar is dragged. Returns whether it moved.
+    pub fn drag_to(&mut self, y: f32, total: f32) -> bool {
+        let grab = match self.grab {
+            Some(grab) => grab,
+            None => return false,
+        };
+        // Pressing again after a drag is a new click, not a double one.
+        self.last_press = None;
+        self.move_to(y - grab, total)
+    }
+
+    /// End a drag, returning whether there was one.
+    pub fn release(&mut self) -> bool {
+        self.grab.take().is_some()
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    /// Returns whether the focus changed.
+    pub fn set_focused(&mut self, focused: bool) -> bool {
+        let changed = self.focused != focused;
+        self.focused = focused;
+        changed
+    }
+
+    /// Move the bar up or down a step, for the keys. Returns whether it
+    /// moved.
+    pub fn step(&mut self, up: bool, total: f32) -> bool {
+        let step = if up { -KEY_STEP } else { KEY_STEP };
+        let top = self.position(total) + step;
+        self.move_to(top, total)
+    }
+
+    // Put the bar's top this far down, as far as both areas can keep
+    // their minimum heights; if they can't, it stays where it is.
+    fn move_to(&mut self, top: f32, total: f32) -> bool {
+        let room = total - SPLITTER_HEIGHT;
+        if room < self.min_above + self.min_below {
+            return false;
+        }
+        let old = self.position(total);
+        let top = top.round().max(self.min_above).min(room - self.min_below);
+        self.below = Some(room - top);
+        self.position(total) != old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_and_remembered_heights() {
+        let mut splitter = Splitter::new(40.0, 40.0, 0.5);
+        assert_eq!(splitter.position(405.0), 200.0);
+        splitter.set_height(Some(100.0));
+        assert_eq!(splitter.position(405.0), 300.0);
+        assert!(splitter.contains(300.0, 405.0));
+        assert!(splitter.contains(304.0, 405.0));
+        assert!(!splitter.contains(305.0, 405.0));
+    }
+
+    #[test]
+    fn remembered_height_fits_a_smaller_window() {
+        let mut splitter = Splitter::new(40.0, 30.0, 0.5);
+        splitter.set_height(Some(500.0));
+        // The area above keeps its minimum.
+        assert_eq!(splitter.position(205.0), 40.0);
+        // The remembered height is kept for when there's room again.
+        assert_eq!(splitter.height(), Some(500.0));
+        assert_eq!(splitter.position(1005.0), 500.0);
+        // Without room for both, the area below gives way first.
+        assert_eq!(splitter.position(65.0), 40.0);
+        assert_eq!(splitter.position(20.0), 15.0);
+        splitter.set_height(Some(10.0));
+        assert_eq!(splitter.position(205.0), 170.0);
+    }
+
+    #[test]
+    fn drags_keep_the_minimums() {
+        let mut splitter = Splitter::new(40.0, 30.0, 0.5);
+        assert!(!splitter.drag_to(100.0, 405.0));
+        splitter.press(202.0, 405.0);
+        assert!(splitter.is_focused());
+        assert!(splitter.drag_to(102.0, 405.0));
+        assert_eq!(splitter.position(405.0), 100.0);
+        assert!(splitter.drag_to(0.0, 405.0));
+        assert_eq!(splitter.position(405.0), 40.0);
+        assert!(splitter.drag_to(1000.0, 405.0));
+        assert_eq!(splitter.position(405.0), 370.0);
+        assert!(!splitter.drag_to(1000.0, 405.0));
+        assert!(splitter.release());
+        assert!(!splitter.release());
+        assert_eq!(splitter.height(), Some(30.0));
+        // Too short for both areas.
+        splitter.set_height(None);
+        assert!(!splitter.step(true, 60.0));
+        assert_eq!(splitter.height(), None);
+    }
+
+    #[test]
+    fn steps() {
+        let mut splitter = Splitter::new(40.0, 40.0, 0.5);
+        assert!(splitter.step(true, 405.0));
+        assert_eq!(splitter.position(405.0), 180.0);
+        assert!(splitter.step(false, 405.0));
+        assert!(splitter.step(false, 405.0));
+        assert_eq!(splitter.position(405.0), 220.0);
+        splitter.set_height(Some(45.0));
+        assert!(splitter.step(false, 405.0));
+        assert!(!splitter.step(false, 405.0));
+        assert_eq!(splitter.height(), Some(40.0));
+    }
+
+    #[test]
+    fn double_click_puts_it_back() {
+        let mut splitter = Splitter::new(40.0, 40.0, 0.5);
+        splitter.set_height(Some(100.0));
+        assert!(!splitter.press(300.0, 405.0));
+        splitter.release();
+        assert!(splitter.press(300.0, 405.0));
+        assert!(!splitter.is_dragging());
+        assert_eq!(splitter.position(405.0), 200.0);
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The status bar along the bottom of the main window. Mirrored for
+//! right-to-left UIs, the message is on the right and the items on the
+//! left.
+
+use direct2d::brush;
+use direct2d::math::*;
+use directwrite::{self, TextFormat, TextLayout};
+use directwrite::{text_format, text_layout};
+
+use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::util::{default_text_options, Error};
+
+use edit_view::LineEnding;
+use encoding::Encoding;
+use selection_count::Counts;
+
+/// Height of the status bar, in px units.
+pub const STATUS_BAR_HEIGHT: f32 = 22.0;
+
+// Horizontal padding at the ends of the bar, and between the items.
+const PAD: f32 = 6.0;
+const ITEM_GAP: f32 = 16.0;
+
+const SPINNER: &[&str] = &["|", "/", "-", "\\"];
+
+pub struct StatusBar {
+    dwrite_factory: directwrite::Factory,
+    resources: Option<Resources>,
+    size: (f32, f32),  // of the whole window, in px units
+    mirrored: bool,
+    message: String,
+    // Shown with a spinner in place of the message while set.
+    busy: Option<String>,
+    // A brief note shown in place of the message until cleared.
+    note: Option<String>,
+    spinner_phase: usize,
+    overwrite: bool,
+    read_only: bool,
+    // The number of lines, and what's selected, if anything.
+    line_count: String,
+    selection_count: String,
+    language: Option<String>,
+    indentation: String,
+    line_ending: &'static str,
+    encoding: &'static str,
+    // Where the items were drawn, for hit testing clicks.
+    item_spans: Vec<(StatusItem, f32, f32)>,
+}
+
+/// The items on the right side of the bar, or the left when it's mirrored,
+/// that respond to clicks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StatusItem {
+    SelectionCount,
+    LineCount,
+    Overwrite,
+    ReadOnly,
+    Language,
+    Indentation,
+    LineEnding,
+    Encoding,
+}
+
+struct Resources {
+    fg: brush::SolidColor,
+    bg: brush::SolidColor,
+    text_format: TextFormat,
+}
+
+impl StatusBar {
+    pub fn new() -> StatusBar {
+        StatusBar {
+            dwrite_factory: directwrite::Factory::new().unwrap(),
+            resources: None,
+            size: (0.0, 0.0),
+            mirrored: false,
+            message: String::new(),
+            busy: None,
+            note: None,
+            spinner_phase: 0,
+            overwrite: false,
+            read_only: false,
+            line_count: String::new(),
+            selection_count: String::new(),
+            language: None,
+            indentation: String::new(),
+            line_ending: "",
+            encoding: "",
+            item_spans: Vec::new(),
+        }
+    }
+
+    fn create_resources(&mut self, p: &mut PaintCtx) -> Result<Resources, Error> {
+        let text_format_params = text_format::ParamBuilder::new()
+            .size(12.0)
+            .family("Segoe UI")
+            .build().ok_or(Error::D2Error)?;
+        let text_format = self.dwrite_factory.create(text_format_params)
+            .map_err(|_| Error::D2Error)?;
+        Ok(Resources {
+            fg: p.solid_brush(0xa6a69c)?,
+            bg: p.solid_brush(0x1e1f1c)?,
+            text_format: text_format,
+        })
+    }
+
+    pub fn rebuild_resources(&mut self) {
+        self.resources = None;
+    }
+
+    /// Set the size of the window; the bar occupies the bottom strip.
+    pub fn size(&mut self, x: f32, y: f32) {
+        self.size = (x, y);
+    }
+
+    /// Put the message on the right and the items on the left.
+    pub fn set_mirrored(&mut self, mirrored: bool) {
+        self.mirrored = mirrored;
+    }
+
+    /// Set the message on the left side of the bar.
+    pub fn set_message<S: Into<String>>(&mut self, message: S) {
+        self.message = message.into();
+    }
+
+    /// Show a note in place of the message, until `clear_note`.
+    pub fn set_note<S: Into<String>>(&mut self, note: S) {
+        self.note = Some(note.into());
+    }
+
+    pub fn clear_note(&mut self) {
+        self.note = None;
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy.is_some()
+    }
+
+    /// The area of the bar, in px units.
+    pub fn bounds(&self) -> (f32, f32, f32, f32) {
+        (0.0, self.size.1 - STATUS_BAR_HEIGHT, self.size.0, self.size.1)
+    }
+
+    /// Show or clear the busy indicator. Each call with `Some` advances
+    /// the spinner, so calling it from a timer animates it.
+    pub fn set_busy(&mut self, busy: Option<&str>) {
+        match busy {
+            Some(text) => {
+                self.busy = Some(text.to_owned());
+                self.spinner_phase = (self.spinner_phase + 1) % SPINNER.len();
+            }
+            None => self.busy = None,
+        }
+    }
+
+    /// Show whether the edit view is in overwrite mode.
+    pub fn set_overwrite(&mut self, overwrite: bool) {
+        self.overwrite = overwrite;
+    }
+
+    /// Show whether the edit view is read-only.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Show the number of lines, and of selected characters and lines,
+    /// or None while those are being counted. When the selection goes on
+    /// into lines the core hasn't sent, the counts are shown as at least.
+    pub fn set_counts(&mut self, lines: usize, selection: Option<Counts>) {
+        self.line_count = plural(lines, "line");
+        self.selection_count = match selection {
+            Some(Counts { chars: 0, .. }) => String::new(),
+            Some(counts) => {
+                let at_least = if counts.partial { "at least " } else { "" };
+                format!("Sel: {}{}, {}", at_least, plural(counts.chars, "char"),
+                    plural(counts.lines, "line"))
+            }
+            None => "Sel: \u{2026}".to_owned(),
+        };
+    }
+
+    /// Show how the lines of the edit view's document end.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending.label();
+    }
+
+    /// Show the encoding of the edit view's file.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding.label();
+    }
+
+    /// Show the language of the edit view.
+    pub fn set_language(&mut self, language: Option<&str>) {
+        self.language = language.map(|s| s.to_owned());
+    }
+
+    /// Show the indentation settings of the edit view.
+    pub fn set_indentation(&mut self, tab_size: usize, spaces: bool) {
+        self.indentation = if spaces {
+            format!("Spaces: {}", tab_size)
+        } else {
+            format!("Tab Size: {}", tab_size)
+        };
+    }
+
+    // The items on the right side of the bar, left to right, or on the left
+    // side, right to left.
+    fn right_items(&self) -> Vec<(StatusItem, &str)> {
+        let mut items = Vec::new();
+        if !self.selection_count.is_empty() {
+            items.push((StatusItem::SelectionCount, self.selection_count.as_str()));
+        }
+        if !self.line_count.is_empty() {
+            items.push((StatusItem::LineCount, self.line_count.as_str()));
+        }
+        if self.overwrite {
+            items.push((StatusItem::Overwrite, "OVR"));
+        }
+        if self.read_only {
+            items.push((StatusItem::ReadOnly, "RO"));
+        }
+        if !self.indentation.is_empty() {
+            items.push((StatusItem::Indentation, self.indentation.as_str()));
+        }
+        if !self.line_ending.is_empty() {
+            items.push((StatusItem::LineEnding, self.line_ending));
+        }
+        if !self.encoding.is_empty() {
+            items.push((StatusItem::Encoding, self.encoding));
+        }
+        if let Some(ref language) = self.language {
+            items.push((StatusItem::Language, language.as_str()));
+        }
+        items
+    }
+
+    /// The item at a point (in px units), as last drawn.
+    pub fn item_at(&self, x: f32, y: f32) -> Option<StatusItem> {
+        if y < self.size.1 - STATUS_BAR_HEIGHT || y >= self.size.1 {
+            return None;
+        }
+        self.item_spans.iter()
+            .find(|&&(_, x0, x1)| x >= x0 && x < x1)
+            .map(|&(item, _, _)| item)
+    }
+
+    pub fn render(&mut self, p: &mut PaintCtx) -> Result<(), Error> {
+        if self.resources.is_none() {
+            self.resources = Some(self.create_resources(p)?);
+        }
+        let resources = &self.resources.as_ref().unwrap();
+        let rt = p.render_target();
+        let y0 = self.size.1 - STATUS_BAR_HEIGHT;
+        let mut item_spans = Vec::new();
+        let rect = RectF::from((0.0, y0, self.size.0, self.size.1));
+        rt.fill_rectangle(&rect, &resources.bg);
+
+        let text = match (&self.busy, &self.note) {
+            (&Some(ref busy), _) => format!("{} {}", SPINNER[self.spinner_phase], busy),
+            (&None, &Some(ref note)) => note.clone(),
+            (&None, &None) => self.message.clone(),
+        };
+        let factory = &self.dwrite_factory;
+        let layout_text = |text: &str| {
+            let params = text_layout::ParamBuilder::new()
+                .text(text)
+                .font(resources.text_format.clone())
+                .width(1e6)
+                .height(1e6)
+                .build().unwrap();
+            let layout: TextLayout = factory.create(params).unwrap();
+            let width = layout.hit_test_text_position(text.encode_utf16().count() as u32, false)
+                .map(|pos| pos.point_x)
+                .unwrap_or(0.0);
+            (layout, width)
+        };
+        if self.mirrored {
+            let (layout, width) = layout_text(&text);
+            let x = (self.size.0 - PAD - width).max(PAD);
+            rt.draw_text_layout(&Point2F::from((x, y0 + 3.0)), &layout, &resources.fg,
+                default_text_options());
+        } else {
+            rt.draw_text(
+                &text,
+                &resources.text_format,
+                &RectF::from((PAD, y0 + 3.0, self.size.0 - PAD, self.size.1)),
+                &resources.fg,
+                default_text_options()
+            );
+        }
+
+        // Lay out the items from the edge in.
+        let mut x = if self.mirrored { PAD } else { self.size.0 - PAD };
+        for &(item, text) in self.right_items().iter().rev() {
+            let (layout, width) = layout_text(text);
+            let x0 = if self.mirrored { x } else { x - width };
+            rt.draw_text_layout(
+                &Point2F::from((x0, y0 + 3.0)),
+                &layout,
+                &resources.fg,
+                default_text_options()
+            );
+            item_spans.push((item, x0, x0 + width));
+            if self.mirrored {
+                x += width + ITEM_GAP;
+            } else {
+                x -= width + ITEM_GAP;
+            }
+        }
+        self.item_spans = item_spans;
+        Ok(())
+    }
+}
+
+// A count and a noun, made plural unless the count is one.
+fn plural(n: usize, noun: &str) -> String {
+    if n == 1 { format!("1 {}", noun) } else { format!("{} {}s", n, noun) }
+}
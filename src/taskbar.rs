@@ -0,0 +1,48 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress on a window's taskbar button, for work the user waits on
+//! with nothing else moving, like the core opening a big file.
+
+use std::ptr::null_mut;
+
+use winapi::Interface;
+use winapi::shared::minwindef::LPVOID;
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::SUCCEEDED;
+use winapi::shared::wtypesbase::CLSCTX_INPROC_SERVER;
+use winapi::um::combaseapi::CoCreateInstance;
+use winapi::um::shobjidl_core::{ITaskbarList3, TBPF_INDETERMINATE, TBPF_NOPROGRESS};
+
+// TODO: remove this when it gets added to winapi
+DEFINE_GUID!{CLSID_TaskbarList,
+    0x56FDF344, 0xFD6D, 0x11D0, 0x95, 0x8A, 0x00, 0x60, 0x97, 0xC9, 0xA0, 0x90}
+
+/// Show the taskbar button as busy, with progress that goes back and forth
+/// rather than filling up, or stop showing it.
+pub fn set_busy(hwnd: HWND, busy: bool) {
+    unsafe {
+        let mut taskbar: *mut ITaskbarList3 = null_mut();
+        let hr = CoCreateInstance(&CLSID_TaskbarList, null_mut(), CLSCTX_INPROC_SERVER,
+            &ITaskbarList3::uuidof(), &mut taskbar as *mut *mut ITaskbarList3 as *mut LPVOID);
+        if !SUCCEEDED(hr) {
+            return;
+        }
+        if SUCCEEDED((*taskbar).HrInit()) {
+            let state = if busy { TBPF_INDETERMINATE } else { TBPF_NOPROGRESS };
+            (*taskbar).SetProgressState(hwnd, state);
+        }
+        (*taskbar).Release();
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A one-line text field for the overlays, like the command palette's
+//! query. Its text is kept and edited here rather than by the core, in a
+//! LineEdit; this adds keys, the clipboard and drawing, and scrolls
+//! sideways to keep the caret in view when the text is wider than the
+//! field.
+
+use winapi::um::winuser::*;
+
+use direct2d::math::*;
+use directwrite::TextLayout;
+use directwrite::text_layout;
+
+use xi_win_shell::clipboard;
+use xi_win_shell::paint::PaintCtx;
+use xi_win_shell::util::default_text_options;
+use xi_win_shell::window::{WindowHandle, M_CTRL, M_SHIFT};
+
+use line_edit::LineEdit;
+use overlay::OverlayResources;
+use utf16::utf8_to_utf16;
+
+// Room kept right of the caret when the field scrolls, in px units.
+const CARET_SLOP: f32 = 2.0;
+
+/// What a key did to the field.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// It isn't one of the field's keys, and is left to its owner.
+    Ignored,
+    /// It's the field's; the caret or the selection may have moved.
+    Handled,
+    /// The text changed, so an owner filtering or searching by it should
+    /// do it again.
+    Changed,
+}
+
+pub struct TextInput {
+    edit: LineEdit,
+    // How far the text is scrolled left, in px units.
+    scroll: f32,
+}
+
+impl TextInput {
+    pub fn new() -> TextInput {
+        TextInput { edit: LineEdit::new(), scroll: 0.0 }
+    }
+
+    pub fn text(&self) -> &str {
+        self.edit.text()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edit.text().is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.edit.set_text("");
+        self.scroll = 0.0;
+    }
+
+    pub fn select_all(&mut self) {
+        self.edit.select_all();
+    }
+
+    /// Take a typed character, in place of the selection. Returns whether
+    /// the text changed.
+    pub fn char(&mut self, ch: u32) -> bool {
+        self.edit.type_char(ch)
+    }
+
+    /// Handle a key: the arrows, Home and End move the caret, with Shift
+    /// to select and Ctrl to go by words, and Backspace and Delete delete
+    /// the selection, or a character or with Ctrl a word.
+    pub fn keydown(&mut self, vk_code: i32, mods: u32) -> InputEvent {
+        let ctrl = (mods & M_CTRL) != 0;
+        let shift = (mods & M_SHIFT) != 0;
+        let changed = match vk_code {
+            VK_LEFT => {
+                self.edit.move_left(ctrl, shift);
+                false
+            }
+            VK_RIGHT => {
+                self.edit.move_right(ctrl, shift);
+                false
+            }
+            VK_HOME => {
+                self.edit.move_home(shift);
+                false
+            }
+            VK_END => {
+                self.edit.move_end(shift);
+                false
+            }
+            VK_BACK => self.edit.delete_backward(ctrl),
+            VK_DELETE => self.edit.delete_forward(ctrl),
+            _ => return InputEvent::Ignored,
+        };
+        if changed { InputEvent::Changed } else { InputEvent::Handled }
+    }
+
+    /// Put the selection on the clipboard, if there is one.
+    pub fn copy(&self, owner: &WindowHandle) {
+        let selected = self.edit.selected_text();
+        if !selected.is_empty() {
+            if let Err(e) = clipboard::set_text(owner, selected) {
+                println!("error setting clipboard: {:?}", e);
+            }
+        }
+    }
+
+    /// Copy the selection and delete it. Returns whether the text changed.
+    pub fn cut(&mut self, owner: &WindowHandle) -> bool {
+        self.copy(owner);
+        self.edit.delete_selection()
+    }
+
+    /// Paste the clipboard's text in place of the selection, on the one
+    /// line. Returns whether the text changed.
+    pub fn paste(&mut self) -> bool {
+        match clipboard::get_text() {
+            Some(text) => self.edit.paste(&text),
+            None => false,
+        }
+    }
+
+    /// Draw the field in a rect: the text, over the selection, and the
+    /// caret, or the placeholder when there's no text yet. The text is
+    /// scrolled to keep the caret inside the rect.
+    pub fn render(&mut self, p: &mut PaintCtx, (x0, y0, x1, y1): (f32, f32, f32, f32),
+        placeholder: &str, resources: &OverlayResources)
+    {
+        let text = self.edit.text();
+        let (shown, brush) = if text.is_empty() {
+            (placeholder, &resources.placeholder)
+        } else {
+            (text, &resources.text)
+        };
+        let params = text_layout::ParamBuilder::new()
+            .text(shown)
+            .font(resources.text_format.clone())
+            .width(1e6)
+            .height(y1 - y0)
+            .build().unwrap();
+        let layout: TextLayout = match resources.dwrite_factory.create(params) {
+            Ok(layout) => layout,
+            Err(_) => return,
+        };
+        let x_at = |offset: usize| {
+            layout.hit_test_text_position(utf8_to_utf16(text, offset) as u32, false)
+                .map_or(0.0, |pos| pos.point_x)
+        };
+        let caret_x = x_at(self.edit.caret());
+        let width = x1 - x0;
+        if caret_x - self.scroll > width - CARET_SLOP {
+            self.scroll = caret_x - width + CARET_SLOP;
+        } else if caret_x < self.scroll {
+            self.scroll = caret_x;
+        }
+        self.scroll = self.scroll.max(0.0);
+        let origin = x0 - self.scroll;
+        let selection = self.edit.selection();
+
+        p.push_clip(x0, y0, x1, y1);
+        {
+            let rt = p.render_target();
+            if selection.start < selection.end {
+                let rect = RectF::from((origin + x_at(selection.start), y0,
+                    origin + x_at(selection.end), y1));
+                rt.fill_rectangle(&rect, &resources.selection);
+            }
+            rt.draw_text_layout(&Point2F::from((origin, y0)), &layout, brush,
+                default_text_options());
+            let x = (origin + caret_x).round() + 0.5;
+            rt.draw_line(&Point2F::from((x, y0)), &Point2F::from((x, y1)), &resources.text,
+                1.0, None);
+        }
+        p.pop_clip();
+    }
+}
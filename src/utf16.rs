@@ -0,0 +1,122 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Converting offsets in a line between the core's units, UTF-8 bytes,
+//! and DirectWrite's, UTF-16 code units. The line cache keeps the core's
+//! offsets as they come; they're converted where they meet a layout.
+
+/// The UTF-16 offset of a byte offset in text. An offset inside a
+/// character counts the whole character; one past the end is the end.
+pub fn utf8_to_utf16(text: &str, offset: usize) -> usize {
+    if offset <= text.len() && text.as_bytes()[..offset].iter().all(|&b| b < 0x80) {
+        return offset;
+    }
+    text.char_indices()
+        .take_while(|&(ix, _)| ix < offset)
+        .map(|(_, c)| c.len_utf16())
+        .sum()
+}
+
+/// The byte offset of a UTF-16 offset in text. An offset between the
+/// halves of a surrogate pair goes to the end of the character.
+pub fn utf16_to_utf8(text: &str, offset: usize) -> usize {
+    let mut units = 0;
+    for (ix, c) in text.char_indices() {
+        if units >= offset {
+            return ix;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A multibyte character, a combining mark and an astral emoji, which
+    // takes a surrogate pair, in UTF-8 bytes and UTF-16 units.
+    const E_ACUTE: (&str, usize, usize) = ("\u{e9}", 2, 1);
+    const COMBINING: (&str, usize, usize) = ("e\u{301}", 3, 2);
+    const EMOJI: (&str, usize, usize) = ("\u{1f600}", 4, 2);
+
+    // Every char boundary of the text converts to UTF-16 and back.
+    fn assert_round_trips(text: &str) {
+        for (ix, _) in text.char_indices().chain(Some((text.len(), ' '))) {
+            let units = utf8_to_utf16(text, ix);
+            assert_eq!(units, text[..ix].encode_utf16().count(), "{:?} at {}", text, ix);
+            assert_eq!(utf16_to_utf8(text, units), ix, "{:?} at {}", text, ix);
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(utf8_to_utf16("", 0), 0);
+        assert_eq!(utf16_to_utf8("", 0), 0);
+        assert_eq!(utf8_to_utf16("", 1), 0);
+        assert_eq!(utf16_to_utf8("", 1), 0);
+    }
+
+    #[test]
+    fn ascii() {
+        assert_round_trips("abc");
+        assert_eq!(utf8_to_utf16("abc", 2), 2);
+        assert_eq!(utf16_to_utf8("abc", 2), 2);
+    }
+
+    #[test]
+    fn round_trips_at_start_middle_and_end() {
+        for &(c, _, _) in &[E_ACUTE, COMBINING, EMOJI] {
+            assert_round_trips(c);
+            assert_round_trips(&format!("{}ab", c));
+            assert_round_trips(&format!("a{}b", c));
+            assert_round_trips(&format!("ab{}", c));
+            assert_round_trips(&format!("{}{}", c, c));
+        }
+    }
+
+    #[test]
+    fn units_of_each_kind() {
+        for &(c, bytes, units) in &[E_ACUTE, COMBINING, EMOJI] {
+            let text = format!("a{}b", c);
+            assert_eq!(utf8_to_utf16(&text, 1 + bytes), 1 + units);
+            assert_eq!(utf16_to_utf8(&text, 1 + units), 1 + bytes);
+        }
+    }
+
+    #[test]
+    fn offset_inside_a_character() {
+        // Each byte after the first counts the whole character.
+        let text = "a\u{1f600}b";
+        for ix in 2..5 {
+            assert_eq!(utf8_to_utf16(text, ix), 3);
+        }
+        assert_eq!(utf8_to_utf16("\u{e9}", 1), 1);
+    }
+
+    #[test]
+    fn offset_between_surrogate_halves() {
+        assert_eq!(utf16_to_utf8("\u{1f600}", 1), 4);
+        assert_eq!(utf16_to_utf8("a\u{1f600}b", 2), 5);
+        assert_eq!(utf16_to_utf8("\u{1f600}\u{1f600}", 3), 8);
+    }
+
+    #[test]
+    fn one_past_the_end() {
+        assert_eq!(utf8_to_utf16("ab", 3), 2);
+        assert_eq!(utf8_to_utf16("a\u{1f600}", 6), 3);
+        assert_eq!(utf16_to_utf8("ab", 3), 2);
+        assert_eq!(utf16_to_utf8("a\u{1f600}", 4), 5);
+    }
+}
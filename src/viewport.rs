@@ -0,0 +1,348 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Which lines of a view the core is told are visible. A burst of
+//! scrolling changes them many times a frame; the changes are held back
+//! and sent as one scroll from a timer, asking for a screenful more in
+//! the direction of the scroll. Printing holds the whole document in
+//! place of them. While the view is hidden nothing is sent; the lines
+//! are kept, and sent again once it's shown.
+//!
+//! Also here is where to scroll to bring lines into view, as for the
+//! core's scroll_to.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+pub struct Viewport {
+    // The lines last told to the core as visible, and the ones to tell it
+    // next.
+    sent: Range<usize>,
+    pending: Option<Range<usize>>,
+    // The height of the document when the core was asked for all of it,
+    // while the lines are held for printing.
+    all_held: Option<usize>,
+    suspended: bool,
+}
+
+impl Viewport {
+    pub fn new() -> Viewport {
+        Viewport { sent: 0..0, pending: None, all_held: None, suspended: false }
+    }
+
+    /// Forget what the core was told, for a new view, which doesn't know
+    /// what it shows yet. A hidden view stays hidden.
+    pub fn reset(&mut self) {
+        let suspended = self.suspended;
+        *self = Viewport::new();
+        self.suspended = suspended;
+    }
+
+    /// Stop sending, while the view is hidden. Changes to the visible
+    /// lines are still taken, to send once it's shown.
+    pub fn suspend(&mut self) {
+        self.suspended = true;
+    }
+
+    /// Start sending again, once the view is shown. Returns whether it
+    /// was hidden, and so the visible lines are to be flushed, as the core
+    /// may have dropped some of them meanwhile.
+    pub fn resume(&mut self) -> bool {
+        if !self.suspended {
+            return false;
+        }
+        self.suspended = false;
+        self.refresh();
+        true
+    }
+
+    /// The lines in view, even if the core hasn't been told yet.
+    pub fn visible(&self) -> Range<usize> {
+        self.pending.clone().unwrap_or_else(|| self.sent.clone())
+    }
+
+    pub fn sent(&self) -> Range<usize> {
+        self.sent.clone()
+    }
+
+    pub fn pending(&self) -> Option<Range<usize>> {
+        self.pending.clone()
+    }
+
+    /// Take the lines now visible. Returns whether the timer needs to be
+    /// started to send them: for the first change since the last send.
+    pub fn update(&mut self, visible: Range<usize>) -> bool {
+        if self.all_held.is_some() {
+            return false;
+        }
+        if visible == self.sent {
+            // Back where the core already knows, before the timer fired.
+            self.pending = None;
+            return false;
+        }
+        let start_timer = self.pending.is_none() && !self.suspended;
+        self.pending = Some(visible);
+        start_timer
+    }
+
+    /// Send the visible lines again on the next flush, even if the core
+    /// has been told them, as when `also` in flush has changed.
+    pub fn refresh(&mut self) {
+        if self.all_held.is_none() {
+            self.pending = Some(self.visible());
+        }
+    }
+
+    /// The first and last lines to ask the core for, if the visible lines
+    /// have changed since they were last sent, from the timer. There's a
+    /// screenful of slack ahead of the scroll, so the lines are usually
+    /// there before they come into view, and `also` is taken in, for
+    /// lines shown elsewhere, like the minimap.
+    pub fn flush(&mut self, also: Option<Range<usize>>) -> Option<(usize, usize)> {
+        if self.all_held.is_some() {
+            self.pending = None;
+            return None;
+        }
+        if self.suspended {
+            return None;
+        }
+        let visible = self.pending.take()?;
+        let slack = visible.end - visible.start;
+        let (mut first, mut last) = if visible.start >= self.sent.start {
+            (visible.start, visible.end + slack)
+        } else {
+            (visible.start.saturating_sub(slack), visible.end)
+        };
+        if let Some(also) = also {
+            first = min(first, also.start);
+            last = max(last, also.end);
+        }
+        self.sent = visible;
+        Some((first, last))
+    }
+
+    /// Ask for every line of a document this high, and keep asking for all
+    /// of them, however the view scrolls, until release_all. Returns the
+    /// scroll to send, if the document has grown since it was last asked.
+    pub fn hold_all(&mut self, height: usize) -> Option<(usize, usize)> {
+        if self.all_held == Some(height) {
+            return None;
+        }
+        self.all_held = Some(height);
+        self.sent = 0..height;
+        self.pending = None;
+        Some((0, height))
+    }
+
+    /// Stop holding every line. Returns whether they were held, so the
+    /// visible lines should be sent again.
+    pub fn release_all(&mut self) -> bool {
+        self.all_held.take().is_some()
+    }
+}
+
+/// The scroll offset that brings the span from `top` to `bottom` (in
+/// content px) into a view `height` high, scrolled to `offset`, moving as
+/// little as it can, and no further than `max_scroll`. A span already in
+/// view leaves the offset alone; one taller than the view shows its top.
+pub fn scroll_into_view(offset: f32, top: f32, bottom: f32, height: f32, max_scroll: f32)
+    -> f32
+{
+    let offset = if top < offset {
+        top
+    } else if bottom > offset + height {
+        (bottom - height).min(top)
+    } else {
+        offset
+    };
+    offset.max(0.0).min(max_scroll.max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The visible lines after a number of wheel ticks of three lines, in
+    // a view forty lines high.
+    fn after_ticks(ticks: usize) -> Range<usize> {
+        ticks * 3..ticks * 3 + 40
+    }
+
+    // A document of lines 17px high with 6px of padding above and below,
+    // in a view 340px (twenty lines) high, as the edit view lays it out.
+    const LINE: f32 = 17.0;
+    const PAD: f32 = 6.0;
+    const HEIGHT: f32 = 340.0;
+
+    fn content_height(lines: usize) -> f32 {
+        PAD + lines as f32 * LINE + PAD
+    }
+
+    // Where scrolling to a line, padded above and below, puts the view.
+    fn scroll_to_line(offset: f32, line: usize, lines: usize, overscroll: f32) -> f32 {
+        let top = PAD + line as f32 * LINE;
+        let max_scroll = content_height(lines) - HEIGHT + overscroll;
+        scroll_into_view(offset, top - PAD, top + LINE + PAD, HEIGHT, max_scroll)
+    }
+
+    #[test]
+    fn wheel_ticks_make_few_scrolls() {
+        // Fifty ticks, four to a frame, with the timer firing once a frame
+        // while it's running.
+        let mut viewport = Viewport::new();
+        let mut timer = false;
+        let mut scrolls = Vec::new();
+        for tick in 1..51 {
+            timer |= viewport.update(after_ticks(tick));
+            if tick % 4 == 0 && timer {
+                timer = false;
+                scrolls.extend(viewport.flush(None));
+            }
+        }
+        if timer {
+            scrolls.extend(viewport.flush(None));
+        }
+        assert!(scrolls.len() <= 13, "{} scrolls", scrolls.len());
+        // The last one asks for where the view came to rest, and the lines
+        // past it.
+        assert_eq!(scrolls.last(), Some(&(150, 230)));
+        assert_eq!(viewport.visible(), after_ticks(50));
+    }
+
+    #[test]
+    fn a_burst_before_the_timer_is_one_scroll() {
+        let mut viewport = Viewport::new();
+        let starts: Vec<bool> = (1..51).map(|tick| viewport.update(after_ticks(tick))).collect();
+        // Only the first change starts the timer.
+        assert_eq!(starts.iter().filter(|&&start| start).count(), 1);
+        assert_eq!(viewport.flush(None), Some((150, 230)));
+        assert_eq!(viewport.flush(None), None);
+    }
+
+    #[test]
+    fn scrolling_back_before_the_timer_sends_nothing() {
+        let mut viewport = Viewport::new();
+        viewport.update(after_ticks(0));
+        viewport.flush(None);
+        assert!(viewport.update(after_ticks(1)));
+        assert!(!viewport.update(after_ticks(0)));
+        assert_eq!(viewport.flush(None), None);
+    }
+
+    #[test]
+    fn slack_goes_ahead_of_the_scroll() {
+        let mut viewport = Viewport::new();
+        viewport.update(100..140);
+        assert_eq!(viewport.flush(None), Some((100, 180)));
+        // Up, the slack goes above.
+        viewport.update(90..130);
+        assert_eq!(viewport.flush(None), Some((50, 130)));
+        // The minimap's lines are taken in.
+        viewport.update(95..135);
+        assert_eq!(viewport.flush(Some(0..300)), Some((0, 300)));
+        viewport.refresh();
+        assert_eq!(viewport.flush(Some(20..120)), Some((20, 175)));
+    }
+
+    #[test]
+    fn switching_between_views() {
+        // Two views, the first shown and the second hidden, and what each
+        // sends when the timer fires.
+        let mut first = Viewport::new();
+        let mut second = Viewport::new();
+        second.suspend();
+        first.update(100..140);
+        assert!(!second.update(0..40));
+        assert_eq!((first.flush(None), second.flush(None)), (Some((100, 180)), None));
+
+        // Switch to the second: it sends the lines it was left on, and the
+        // first, now hidden, sends nothing as it changes.
+        first.suspend();
+        assert!(second.resume());
+        assert!(!first.update(200..240));
+        assert_eq!((first.flush(None), second.flush(None)), (None, Some((0, 80))));
+
+        // And back: the first sends where it was left, though the core
+        // was last told something else, and the second is quiet.
+        second.suspend();
+        assert!(first.resume());
+        assert!(!first.resume());
+        assert_eq!((first.flush(None), second.flush(None)), (Some((200, 280)), None));
+    }
+
+    #[test]
+    fn a_hidden_view_stays_hidden_when_reset() {
+        let mut viewport = Viewport::new();
+        viewport.suspend();
+        viewport.reset();
+        assert!(!viewport.update(0..40));
+        assert_eq!(viewport.flush(None), None);
+        assert!(viewport.resume());
+        assert_eq!(viewport.flush(None), Some((0, 80)));
+    }
+
+    #[test]
+    fn holding_every_line() {
+        let mut viewport = Viewport::new();
+        viewport.update(after_ticks(0));
+        viewport.flush(None);
+        viewport.update(after_ticks(1));
+        assert_eq!(viewport.hold_all(1000), Some((0, 1000)));
+        // Scrolling while held doesn't ask for fewer.
+        assert!(!viewport.update(after_ticks(2)));
+        viewport.refresh();
+        assert_eq!(viewport.flush(None), None);
+        // Asking again only sends when the document has grown.
+        assert_eq!(viewport.hold_all(1000), None);
+        assert_eq!(viewport.hold_all(1001), Some((0, 1001)));
+        assert!(viewport.release_all());
+        assert!(!viewport.release_all());
+        assert!(viewport.update(after_ticks(2)));
+        assert_eq!(viewport.flush(None), Some((6, 86)));
+    }
+
+    #[test]
+    fn scrolling_to_the_first_line() {
+        assert_eq!(scroll_to_line(0.0, 0, 100, 0.0), 0.0);
+        assert_eq!(scroll_to_line(500.0, 0, 100, 0.0), 0.0);
+        // A line in view doesn't move it.
+        assert_eq!(scroll_to_line(0.0, 10, 100, 0.0), 0.0);
+    }
+
+    #[test]
+    fn scrolling_to_the_last_line() {
+        // The last line sits at the bottom with its padding below it, and
+        // no further, so nothing past the end shows.
+        let bottom = content_height(100) - HEIGHT;
+        assert_eq!(scroll_to_line(0.0, 99, 100, 0.0), bottom);
+        // Other than the overscroll, if that's on, which it doesn't reach
+        // into.
+        assert_eq!(scroll_to_line(0.0, 99, 100, HEIGHT - LINE), bottom);
+        // Scrolled all the way past the end, it's brought back to the line.
+        let past_end = bottom + HEIGHT - LINE;
+        assert_eq!(scroll_to_line(past_end, 99, 100, HEIGHT - LINE), 99.0 * LINE);
+        // A document shorter than the view doesn't scroll.
+        assert_eq!(scroll_to_line(0.0, 9, 10, 0.0), 0.0);
+    }
+
+    #[test]
+    fn scrolling_past_the_end() {
+        // A line the document doesn't have yet, as when the caret's line
+        // arrives before the update that adds it, stops at the end.
+        let bottom = content_height(100) - HEIGHT;
+        assert_eq!(scroll_to_line(0.0, 120, 100, 0.0), bottom);
+        assert_eq!(scroll_to_line(0.0, 120, 100, 3.0 * LINE), bottom + 3.0 * LINE);
+        assert_eq!(scroll_to_line(0.0, 120, 10, 0.0), 0.0);
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Word boundaries within a line of text, for the front-end operations
+//! that need them (the core has its own notion for its commands).
+//!
+//! A "word" is a maximal run of characters of the same class: word
+//! characters, whitespace, or punctuation. Offsets are byte offsets.
+
+use std::cmp::min;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+/// Whether a character can be part of a word.
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn classify(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Whitespace
+    } else if is_word_char(c) {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+// Clamp an offset to the text and round it down to a char boundary.
+fn floor_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = min(offset, text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+// The class of the character at `offset`, or of the one before it at the
+// end of the text.
+fn class_at(text: &str, offset: usize) -> Option<CharClass> {
+    text[offset..].chars().next()
+        .or_else(|| text[..offset].chars().next_back())
+        .map(classify)
+}
+
+/// The start of the word containing `offset`.
+pub fn word_start(text: &str, offset: usize) -> usize {
+    let offset = floor_boundary(text, offset);
+    let class = match class_at(text, offset) {
+        Some(class) => class,
+        None => return offset,
+    };
+    let mut start = offset;
+    for (ix, c) in text[..offset].char_indices().rev() {
+        if classify(c) != class {
+            break;
+        }
+        start = ix;
+    }
+    start
+}
+
+/// The end of the word containing `offset`.
+pub fn word_end(text: &str, offset: usize) -> usize {
+    let offset = floor_boundary(text, offset);
+    let class = match class_at(text, offset) {
+        Some(class) => class,
+        None => return offset,
+    };
+    for (ix, c) in text[offset..].char_indices() {
+        if classify(c) != class {
+            return offset + ix;
+        }
+    }
+    text.len()
+}
+
+/// The start offsets of the occurrences of `word` in the text that are
+/// whole words, not parts of longer ones.
+pub fn find_whole_word(text: &str, word: &str) -> Vec<usize> {
+    text.match_indices(word).map(|(ix, _)| ix).filter(|&ix| {
+        let before = text[..ix].chars().next_back();
+        let after = text[ix + word.len()..].chars().next();
+        !before.map_or(false, is_word_char) && !after.map_or(false, is_word_char)
+    }).collect()
+}
@@ -0,0 +1,120 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Safe wrapper for the system clipboard. Only Unicode text is supported,
+//! plus private formats that mark what kind of text it is.
+
+use std::mem;
+use std::ptr::{self, null_mut};
+
+use winapi::shared::minwindef::UINT;
+use winapi::shared::ntdef::LPWSTR;
+use winapi::um::winbase::*;
+use winapi::um::winuser::*;
+
+use Error;
+use util::{FromWide, ToWide};
+use window::WindowHandle;
+
+/// Determine whether the clipboard has text on it.
+pub fn has_text() -> bool {
+    unsafe {
+        IsClipboardFormatAvailable(CF_UNICODETEXT) != 0
+    }
+}
+
+/// Register a private clipboard format by name. Registering a name again
+/// gives the same format.
+pub fn register_format(name: &str) -> UINT {
+    unsafe {
+        RegisterClipboardFormatW(name.to_wide().as_ptr())
+    }
+}
+
+/// Determine whether the clipboard has data in a format.
+pub fn has_format(format: UINT) -> bool {
+    unsafe {
+        IsClipboardFormatAvailable(format) != 0
+    }
+}
+
+/// Get the text on the clipboard, if any.
+pub fn get_text() -> Option<String> {
+    unsafe {
+        if OpenClipboard(null_mut()) == 0 {
+            return None;
+        }
+        let mut result = None;
+        let handle = GetClipboardData(CF_UNICODETEXT);
+        if !handle.is_null() {
+            let ptr = GlobalLock(handle) as LPWSTR;
+            if !ptr.is_null() {
+                result = ptr.from_wide();
+                GlobalUnlock(handle);
+            }
+        }
+        CloseClipboard();
+        result
+    }
+}
+
+/// Put text on the clipboard, replacing its contents. The window becomes
+/// the clipboard owner.
+pub fn set_text(owner: &WindowHandle, text: &str) -> Result<(), Error> {
+    set_text_with_markers(owner, text, &[])
+}
+
+/// Put text on the clipboard like `set_text`, along with (empty) data in
+/// each of the marker formats. Anything else that changes the clipboard
+/// removes the markers too.
+pub fn set_text_with_markers(owner: &WindowHandle, text: &str, markers: &[UINT])
+    -> Result<(), Error>
+{
+    let hwnd = owner.get_hwnd().ok_or(Error::Null)?;
+    let wide = text.to_wide();
+    unsafe {
+        if OpenClipboard(hwnd) == 0 {
+            return Err(Error::Null);
+        }
+        EmptyClipboard();
+        let size = wide.len() * mem::size_of::<u16>();
+        let hmem = GlobalAlloc(GMEM_MOVEABLE, size);
+        let mut result = Err(Error::Null);
+        if !hmem.is_null() {
+            let dst = GlobalLock(hmem) as *mut u16;
+            if !dst.is_null() {
+                ptr::copy_nonoverlapping(wide.as_ptr(), dst, wide.len());
+                GlobalUnlock(hmem);
+                if !SetClipboardData(CF_UNICODETEXT, hmem).is_null() {
+                    // The clipboard owns the memory now.
+                    result = Ok(());
+                }
+            }
+            if result.is_err() {
+                GlobalFree(hmem);
+            }
+        }
+        if result.is_ok() {
+            for &format in markers {
+                // Null data would mean delayed rendering, so give it a byte.
+                let marker = GlobalAlloc(GMEM_MOVEABLE, 1);
+                if !marker.is_null() && SetClipboardData(format, marker).is_null() {
+                    GlobalFree(marker);
+                }
+            }
+        }
+        CloseClipboard();
+        result
+    }
+}
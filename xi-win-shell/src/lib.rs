@@ -20,6 +20,7 @@ extern crate wio;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod clipboard;
 mod dcomp;
 pub mod menu;
 pub mod paint;
@@ -45,6 +45,15 @@ impl Menu {
         }
     }
 
+    /// Create a menu to be shown as a context menu (see
+    /// `WindowHandle::show_context_menu`).
+    pub fn new_for_popup() -> Menu {
+        unsafe {
+            let hmenu = CreatePopupMenu();
+            Menu { hmenu }
+        }
+    }
+
     pub fn into_hmenu(self) -> HMENU {
         let hmenu = self.hmenu;
         mem::forget(self);
@@ -75,4 +84,22 @@ impl Menu {
             AppendMenuW(self.hmenu, MF_SEPARATOR, 0, null());
         }
     }
+
+    /// Enable or gray out the item with the given id. This also finds items
+    /// in submenus.
+    pub fn enable_item(&mut self, id: u32, enable: bool) {
+        let flag = if enable { MF_ENABLED } else { MF_GRAYED };
+        unsafe {
+            EnableMenuItem(self.hmenu, id, MF_BYCOMMAND | flag);
+        }
+    }
+
+    /// Check or uncheck the item with the given id. This also finds items
+    /// in submenus.
+    pub fn check_item(&mut self, id: u32, checked: bool) {
+        let flag = if checked { MF_CHECKED } else { MF_UNCHECKED };
+        unsafe {
+            CheckMenuItem(self.hmenu, id, MF_BYCOMMAND | flag);
+        }
+    }
 }
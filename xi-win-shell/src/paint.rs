@@ -35,6 +35,8 @@ use winapi::shared::windef::*;
 use winapi::shared::winerror::*;
 
 use direct2d;
+use direct2d::brush;
+use direct2d::math::BrushProperties;
 use direct2d::render_target::{RenderTarget, RenderTargetBacking};
 
 use Error;
@@ -46,10 +48,19 @@ pub struct PaintCtx<'a> {
     pub(crate) render_target: &'a mut RenderTarget,
 }
 
+/// How text is antialiased.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TextAntialias {
+    ClearType,
+    Grayscale,
+    Aliased,
+}
+
 struct HwndRtParams {
     hwnd: HWND,
     width: u32,
     height: u32,
+    dpi: f32,
 }
 
 unsafe impl RenderTargetBacking for HwndRtParams {
@@ -64,8 +75,8 @@ unsafe impl RenderTargetBacking for HwndRtParams {
                     format: DXGI_FORMAT_UNKNOWN,
                     alphaMode: D2D1_ALPHA_MODE_UNKNOWN,
                 },
-                dpiX: 0.0,
-                dpiY: 0.0,
+                dpiX: self.dpi,
+                dpiY: self.dpi,
                 usage: D2D1_RENDER_TARGET_USAGE_NONE,
                 minLevel: D2D1_FEATURE_LEVEL_DEFAULT,
             };
@@ -92,14 +103,14 @@ unsafe impl RenderTargetBacking for HwndRtParams {
     }
 }
 
-pub(crate) unsafe fn create_render_target(d2d_factory: &direct2d::Factory, hwnd: HWND)
+pub(crate) unsafe fn create_render_target(d2d_factory: &direct2d::Factory, hwnd: HWND, dpi: f32)
         -> Result<RenderTarget, Error>
 {
     let mut rect: RECT = mem::uninitialized();
     GetClientRect(hwnd, &mut rect);
     let width = (rect.right - rect.left) as u32;
     let height = (rect.bottom - rect.top) as u32;
-    let params = HwndRtParams { hwnd: hwnd, width: width, height: height };
+    let params = HwndRtParams { hwnd: hwnd, width: width, height: height, dpi: dpi };
     d2d_factory.create_render_target(params).map_err(|_| Error::D2Error)
 }
 
@@ -123,7 +134,6 @@ unsafe impl RenderTargetBacking for DxgiBacking {
             let mut render_target: *mut ID2D1RenderTarget = null_mut();
             let res = factory.CreateDxgiSurfaceRenderTarget(self.0, &props, &mut render_target);
             if SUCCEEDED(res) {
-                //(*render_target).SetTextAntialiasMode(D2D1_TEXT_ANTIALIAS_MODE_GRAYSCALE);
                 Ok(render_target)
             } else {
                 Err(res)
@@ -157,4 +167,48 @@ impl<'a> PaintCtx<'a> {
     pub fn render_target(&mut self) -> &mut RenderTarget {
         self.render_target
     }
+
+    /// Create a brush of a solid color, given as 0xRRGGBB. This can fail
+    /// when the device has been lost.
+    pub fn solid_brush(&mut self, rgb: u32) -> Result<brush::SolidColor, Error> {
+        self.render_target.create_solid_color_brush(rgb, &BrushProperties::default())
+            .map_err(|_| Error::D2Error)
+    }
+
+    /// Restrict drawing to a rectangle (in px units, under the current
+    /// transform) until the matching `pop_clip`. Clips nest.
+    pub fn push_clip(&mut self, x0: f32, y0: f32, x1: f32, y1: f32) {
+        let rect = D2D1_RECT_F { left: x0, top: y0, right: x1, bottom: y1 };
+        unsafe {
+            (*self.render_target.get_ptr())
+                .PushAxisAlignedClip(&rect, D2D1_ANTIALIAS_MODE_ALIASED);
+        }
+    }
+
+    pub fn pop_clip(&mut self) {
+        unsafe {
+            (*self.render_target.get_ptr()).PopAxisAlignedClip();
+        }
+    }
+
+    /// Set how text drawn from here on is antialiased.
+    pub fn set_text_antialias(&mut self, mode: TextAntialias) {
+        let mode = match mode {
+            TextAntialias::ClearType => D2D1_TEXT_ANTIALIAS_MODE_CLEARTYPE,
+            TextAntialias::Grayscale => D2D1_TEXT_ANTIALIAS_MODE_GRAYSCALE,
+            TextAntialias::Aliased => D2D1_TEXT_ANTIALIAS_MODE_ALIASED,
+        };
+        unsafe {
+            (*self.render_target.get_ptr()).SetTextAntialiasMode(mode);
+        }
+    }
+
+    /// The dpi being drawn at; px units are pixels at 96.
+    pub fn dpi(&self) -> f32 {
+        let (mut dpi_x, mut dpi_y) = (0.0, 0.0);
+        unsafe {
+            (*self.render_target.get_ptr()).GetDpi(&mut dpi_x, &mut dpi_y);
+        }
+        dpi_x
+    }
 }
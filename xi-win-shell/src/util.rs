@@ -100,8 +100,11 @@ impl FromWide for [u16] {
 // from shcore.dll
 type GetDpiForSystem = unsafe extern "system" fn() -> UINT;
 type GetDpiForMonitor = unsafe extern "system" fn(HMONITOR, MONITOR_DPI_TYPE, *mut UINT, *mut UINT);
-// from user32.dll
 type SetProcessDpiAwareness = unsafe extern "system" fn(PROCESS_DPI_AWARENESS) -> HRESULT;
+// from user32.dll
+type SetProcessDpiAwarenessContext = unsafe extern "system" fn(DPI_AWARENESS_CONTEXT) -> BOOL;
+type GetDpiForWindow = unsafe extern "system" fn(HWND) -> UINT;
+type GetPointerType = unsafe extern "system" fn(UINT, *mut DWORD) -> BOOL;
 type DCompositionCreateDevice2 = unsafe extern "system" fn(
     renderingDevice: *const IUnknown,
     iid: REFIID,
@@ -118,6 +121,9 @@ pub struct OptionalFunctions {
     pub GetDpiForSystem: Option<GetDpiForSystem>,
     pub GetDpiForMonitor: Option<GetDpiForMonitor>,
     pub SetProcessDpiAwareness: Option<SetProcessDpiAwareness>,
+    pub SetProcessDpiAwarenessContext: Option<SetProcessDpiAwarenessContext>,
+    pub GetDpiForWindow: Option<GetDpiForWindow>,
+    pub GetPointerType: Option<GetPointerType>,
     pub DCompositionCreateDevice2: Option<DCompositionCreateDevice2>,
     pub CreateDXGIFactory2: Option<CreateDXGIFactory2>,
 }
@@ -171,6 +177,9 @@ fn load_optional_functions() -> OptionalFunctions {
     let mut GetDpiForSystem = None;
     let mut GetDpiForMonitor = None;
     let mut SetProcessDpiAwareness = None;
+    let mut SetProcessDpiAwarenessContext = None;
+    let mut GetDpiForWindow = None;
+    let mut GetPointerType = None;
     let mut DCompositionCreateDevice2 = None;
     let mut CreateDXGIFactory2 = None;
 
@@ -185,6 +194,9 @@ fn load_optional_functions() -> OptionalFunctions {
         println!("No user32.dll");
     } else {
         load_function!(user32, GetDpiForSystem, "10");
+        load_function!(user32, GetDpiForWindow, "10");
+        load_function!(user32, SetProcessDpiAwarenessContext, "10 (1703)");
+        load_function!(user32, GetPointerType, "8");
     }
 
     if !dcomp.is_null() {
@@ -199,6 +211,9 @@ fn load_optional_functions() -> OptionalFunctions {
         GetDpiForSystem,
         GetDpiForMonitor,
         SetProcessDpiAwareness,
+        SetProcessDpiAwarenessContext,
+        GetDpiForWindow,
+        GetPointerType,
         DCompositionCreateDevice2,
         CreateDXGIFactory2,
     }
@@ -209,11 +224,19 @@ lazy_static! {
 }
 
 /// Initialize the app. At the moment, this is mostly needed for hi-dpi.
+///
+/// The process is per-monitor dpi aware, so windows get `WM_DPICHANGED`
+/// when moved to a monitor with a different scale.
 pub fn init() {
-    if let Some(func) = OPTIONAL_FUNCTIONS.SetProcessDpiAwareness {
-        // This function is only supported on windows 10
-        unsafe {
-            func(PROCESS_SYSTEM_DPI_AWARE); // TODO: per monitor (much harder)
+    unsafe {
+        if let Some(func) = OPTIONAL_FUNCTIONS.SetProcessDpiAwarenessContext {
+            // V2 also scales the non-client area (title bar, menus).
+            if func(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) != 0 {
+                return;
+            }
+        }
+        if let Some(func) = OPTIONAL_FUNCTIONS.SetProcessDpiAwareness {
+            func(PROCESS_PER_MONITOR_DPI_AWARE);
         }
     }
 }
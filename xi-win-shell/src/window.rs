@@ -18,6 +18,7 @@
 
 use std::any::Any;
 use std::cell::{Cell, RefCell};
+use std::cmp::{max, min};
 use std::mem;
 use std::ptr::{null, null_mut};
 use std::rc::{Rc, Weak};
@@ -86,6 +87,18 @@ pub enum PresentStrategy {
 #[derive(Clone, Default)]
 pub struct WindowHandle(Weak<WindowState>);
 
+/// Where a window is on the desktop, for saving and restoring it. The
+/// rect is the restored (not maximized) one, in the coordinates used by
+/// GetWindowPlacement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowPlacement {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    pub maximized: bool,
+}
+
 /// A handle that can get used to schedule an idle handler. Note that
 /// this handle is thread safe. If the handle is used after the hwnd
 /// has been destroyed, probably not much will go wrong (the XI_RUN_IDLE
@@ -111,6 +124,18 @@ struct WindowState {
     dpi: Cell<f32>,
     wndproc: Box<WndProc>,
     idle_queue: Arc<Mutex<Vec<Box<IdleCallback>>>>,
+    // Set while full screen, to go back to.
+    fullscreen: RefCell<Option<SavedWindow>>,
+    // Shown over the client area, in place of the class cursor.
+    cursor: Cell<HCURSOR>,
+}
+
+// What a window looked like before it went full screen.
+struct SavedWindow {
+    placement: WINDOWPLACEMENT,
+    style: LONG_PTR,
+    // The menubar, which is taken off while full screen.
+    menu: HMENU,
 }
 
 /// App behavior, supplied by the app.
@@ -145,6 +170,9 @@ pub trait WinHandler {
     /// to the WM_CHAR message. Handling of text input will continue to
     /// evolve, we need to handle input methods and more.
     ///
+    /// Dead keys don't call this; the key after one does, once, with the
+    /// character they make together.
+    ///
     /// The modifiers are a combination of `M_ALT`, `M_CTRL`, `M_SHIFT`.
     #[allow(unused_variables)]
     fn char(&self, ch: u32, mods: u32) {}
@@ -154,11 +182,20 @@ pub trait WinHandler {
     /// like the modifier state.
     ///
     /// The modifiers are a combination of `M_ALT`, `M_CTRL`, `M_SHIFT`.
+    /// AltGr comes as `M_CTRL | M_ALT`. Any character the key types comes
+    /// afterwards, as `char`, whether or not this handles it.
     ///
     /// Return `true` if the event is handled.
     #[allow(unused_variables)]
     fn keydown(&self, vkey_code: i32, mods: u32) -> bool { false }
 
+    /// Called on a key up event. This corresponds to the WM_KEYUP
+    /// message, with the key code and modifiers as for `keydown`.
+    ///
+    /// Return `true` if the event is handled.
+    #[allow(unused_variables)]
+    fn keyup(&self, vkey_code: i32, mods: u32) -> bool { false }
+
     /// Called on a mouse wheel event. This corresponds to a
     /// [WM_MOUSEWHEEL](https://msdn.microsoft.com/en-us/library/windows/desktop/ms645617(v=vs.85).aspx)
     /// message.
@@ -177,14 +214,60 @@ pub trait WinHandler {
 
     /// Called when the mouse moves. Note that the x, y coordinates are
     /// in absolute pixels.
+    ///
+    /// The modifiers are a combination of `M_ALT`, `M_CTRL`, `M_SHIFT`.
     #[allow(unused_variables)]
     fn mouse_move(&self, x: i32, y: i32, mods: u32) {}
 
     /// Called on mouse button up or down. Note that the x, y
     /// coordinates are in absolute pixels.
+    ///
+    /// The mouse is captured while any button is held, so moves and the
+    /// matching up event arrive even when the pointer leaves the window.
+    ///
+    /// The modifiers are a combination of `M_ALT`, `M_CTRL`, `M_SHIFT`.
     #[allow(unused_variables)]
     fn mouse(&self, x: i32, y: i32, mods: u32, which: MouseButton, ty: MouseType) {}
 
+    /// Called on touchscreen input, from WM_POINTER messages, which only
+    /// Windows 8 and later send. Pen and mouse input stay mouse events,
+    /// and on Windows 7 touch does too. The id tells fingers apart. Note
+    /// that the x, y coordinates are in absolute pixels.
+    ///
+    /// Return `true` if the event is handled; otherwise Windows turns it
+    /// into mouse events.
+    #[allow(unused_variables)]
+    fn touch(&self, id: u32, x: i32, y: i32, ty: TouchType) -> bool { false }
+
+    /// Called when a timer set with `WindowHandle::set_timer` fires. The
+    /// token is the one passed to `set_timer`.
+    #[allow(unused_variables)]
+    fn timer(&self, token: usize) {}
+
+    /// Called when the window loses the mouse capture it takes while a
+    /// button is held: when the buttons are released, or when another
+    /// window takes the capture.
+    fn capture_lost(&self) {}
+
+    /// Called when the window gains keyboard focus.
+    fn got_focus(&self) {}
+
+    /// Called when the window loses keyboard focus.
+    fn lost_focus(&self) {}
+
+    /// Called on WM_GETOBJECT, when an accessibility client asks for an
+    /// object of the window. Return the result to answer with, or `None`
+    /// to leave it to the default.
+    #[allow(unused_variables)]
+    fn get_object(&self, wparam: WPARAM, lparam: LPARAM) -> Option<LRESULT> { None }
+
+    /// Called when the user asks to close the window, with its close
+    /// button, Alt+F4 or the system menu. This corresponds to the WM_CLOSE
+    /// message.
+    ///
+    /// Return `false` to keep the window open; otherwise it's destroyed.
+    fn request_close(&self) -> bool { true }
+
     /// Called when the window is being destroyed. Note that this happens
     /// earlier in the sequence than drop (at WM_DESTROY, while the latter is
     /// WM_NCDESTROY).
@@ -221,6 +304,31 @@ pub enum MouseType {
     Up,
 }
 
+/// The phase of a touch, for `WinHandler::touch`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TouchType {
+    /// A finger is put down.
+    Down,
+    Move,
+    /// The finger is lifted, or the touch is cancelled.
+    Up,
+}
+
+/// The mouse cursor shown over the window's client area.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Cursor {
+    /// The text cursor, the default.
+    IBeam,
+    /// The up-down arrow, for something that resizes vertically.
+    ResizeUpDown,
+}
+
+// TODO: remove these when they get added to winapi
+const WM_POINTERUPDATE: UINT = 0x0245;
+const WM_POINTERDOWN: UINT = 0x0246;
+const WM_POINTERUP: UINT = 0x0247;
+const PT_TOUCH: DWORD = 2;
+
 /// Generic handler trait for the winapi window procedure entry point.
 trait WndProc {
     fn connect(&self, handle: &WindowHandle, state: WndState);
@@ -248,15 +356,29 @@ struct MyWndProc {
 }
 
 struct WndState {
-    swap_chain: *mut IDXGISwapChain1,
     render_target: Option<RenderTarget>,
-    dcomp_state: Option<DCompState>,
+    /// DirectComposition, which is optional because it is only supported on
+    /// 8.1 and up.
+    composition: Option<Composition<DCompFactory>>,
     dpi: f32,
 }
 
-/// State for DirectComposition. This is optional because it is only supported
-/// on 8.1 and up.
+impl WndState {
+    // The DirectComposition state, unless there's none or it's been lost
+    // and couldn't be made again yet.
+    fn dcomp_state(&mut self) -> Option<&mut DCompState> {
+        self.composition.as_mut().and_then(|c| c.current.as_mut())
+    }
+
+    fn has_dcomp(&self) -> bool {
+        self.composition.as_ref().map_or(false, |c| c.current.is_some())
+    }
+}
+
+/// State for DirectComposition: the devices, the visual and the swap chain
+/// it shows.
 struct DCompState {
+    swap_chain: *mut IDXGISwapChain1,
     dcomp_device: DCompositionDevice,
     dcomp_target: DCompositionTarget,
     swapchain_visual: DCompositionVisual,
@@ -264,6 +386,133 @@ struct DCompState {
     sizing: bool,
 }
 
+impl Drop for DCompState {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.swap_chain).Release();
+        }
+    }
+}
+
+/// Makes what a window draws with. A trait so that tests can stand in a
+/// factory that fails, as making a device does while the GPU is reset.
+trait CompositionFactory {
+    type State;
+    fn create(&self) -> Result<Self::State, Error>;
+}
+
+// What a window draws with, made again after its device is lost, as on a
+// driver reset or reconnecting over RDP. Until it can be, there's none,
+// and the window draws to its hwnd.
+struct Composition<F: CompositionFactory> {
+    factory: F,
+    current: Option<F::State>,
+}
+
+impl<F: CompositionFactory> Composition<F> {
+    fn new(factory: F) -> Result<Composition<F>, Error> {
+        let current = Some(factory.create()?);
+        Ok(Composition { factory, current })
+    }
+
+    // Drop what was made with a lost device.
+    fn lose(&mut self) {
+        self.current = None;
+    }
+
+    // Make it again after the device was lost. Returns whether that
+    // worked.
+    fn recreate(&mut self) -> bool {
+        // The old one goes first: a window can only have one target.
+        self.current = None;
+        match self.factory.create() {
+            Ok(state) => {
+                self.current = Some(state);
+                true
+            }
+            Err(e) => {
+                println!("can't recreate the device: {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn is_lost(&self) -> bool {
+        self.current.is_none()
+    }
+}
+
+// Makes the DirectComposition state for a window.
+struct DCompFactory {
+    hwnd: HWND,
+    present_strategy: PresentStrategy,
+}
+
+impl CompositionFactory for DCompFactory {
+    type State = DCompState;
+
+    fn create(&self) -> Result<DCompState, Error> {
+        unsafe {
+            // Checked before making a factory.
+            let create_dxgi_factory2 = OPTIONAL_FUNCTIONS.CreateDXGIFactory2
+                .ok_or(Error::OldWindows)?;
+            let mut d3d11_device = D3D11Device::new_simple()?;
+            let mut d2d1_device = d3d11_device.create_d2d1_device()?;
+            let mut dcomp_device = d2d1_device.create_composition_device()?;
+            let dcomp_target = dcomp_device.create_target_for_hwnd(self.hwnd, true)?;
+            let swapchain_visual = dcomp_device.create_visual()?;
+
+            let mut factory: *mut IDXGIFactory2 = null_mut();
+            as_result(create_dxgi_factory2(0, &IID_IDXGIFactory2,
+                &mut factory as *mut *mut IDXGIFactory2 as *mut *mut c_void))?;
+            println!("dxgi factory pointer = {:?}", factory);
+            let adapter = choose_adapter(factory);
+            println!("adapter = {:?}", adapter);
+            let (swap_effect, bufs) = match self.present_strategy {
+                PresentStrategy::Sequential => (DXGI_SWAP_EFFECT_SEQUENTIAL, 1),
+                PresentStrategy::Flip | PresentStrategy::FlipRedirect =>
+                    (DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL, 2),
+            };
+            let desc = DXGI_SWAP_CHAIN_DESC1 {
+                Width: 1024,
+                Height: 768,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                Stereo: FALSE,
+                SampleDesc: DXGI_SAMPLE_DESC { Count: 1, Quality: 0},
+                BufferUsage: DXGI_USAGE_RENDER_TARGET_OUTPUT,
+                BufferCount: bufs,
+                Scaling: DXGI_SCALING_STRETCH,
+                SwapEffect: swap_effect,
+                AlphaMode: DXGI_ALPHA_MODE_IGNORE,
+                Flags: 0,
+            };
+            let mut swap_chain: *mut IDXGISwapChain1 = null_mut();
+            let res = (*factory).CreateSwapChainForComposition(
+                d3d11_device.raw_ptr() as *mut IUnknown, &desc, null_mut(), &mut swap_chain);
+            (*factory).Release();
+            println!("swap chain res = 0x{:x}, pointer = {:?}", res, swap_chain);
+            as_result(res)?;
+
+            // Made before anything else can fail, so the swap chain is
+            // released if it does.
+            let mut state = DCompState {
+                swap_chain, dcomp_device, dcomp_target, swapchain_visual,
+                sizing: false,
+            };
+            state.swapchain_visual.set_content_raw(swap_chain as *mut IUnknown)?;
+            state.dcomp_target.set_root(&mut state.swapchain_visual)?;
+            Ok(state)
+        }
+    }
+}
+
+// Whether a failed draw or present means the device is gone, and has to be
+// made again along with everything made with it.
+fn device_lost(hr: HRESULT) -> bool {
+    hr == DXGI_ERROR_DEVICE_REMOVED || hr == DXGI_ERROR_DEVICE_RESET
+        || hr == D2DERR_RECREATE_TARGET
+}
+
 /// Message indicating there are idle tasks to run.
 const XI_RUN_IDLE: UINT = WM_USER;
 
@@ -275,40 +524,150 @@ impl Default for PresentStrategy {
     }
 }
 
-fn get_mod_state(lparam: LPARAM) -> u32 {
+// Modifier state for keyboard messages. Alt is asked for rather than taken
+// from the context bit, which is only set when Ctrl isn't down, so that
+// AltGr, which comes as Ctrl+Alt, has both.
+fn get_mod_state() -> u32 {
     unsafe {
         let mut mod_state = 0;
-        if (lparam & (1 << 29)) != 0 { mod_state |= MOD_ALT as u32; }
+        if GetKeyState(VK_MENU) < 0 { mod_state |= MOD_ALT as u32; }
         if GetKeyState(VK_CONTROL) < 0 { mod_state |= MOD_CONTROL as u32; }
         if GetKeyState(VK_SHIFT) < 0 { mod_state |= MOD_SHIFT as u32; }
         mod_state
     }
 }
 
+// The character a keyboard message gives the handler's char, if any. A
+// dead key, like the acute accent on some layouts, gives none: it waits
+// for the next key, TranslateMessage keeps track of it, and the WM_CHAR
+// after has the accented character, or the accent on its own after a
+// space, so its own message is left to the default.
+fn typed_char(msg: UINT, wparam: WPARAM) -> Option<u32> {
+    match msg {
+        WM_CHAR => Some(wparam as u32),
+        _ => None,
+    }
+}
+
+// Modifier state for mouse messages, which carry shift and control in
+// wparam, but not alt.
+fn get_mouse_mod_state(wparam: WPARAM) -> u32 {
+    let mut mod_state = 0;
+    if (wparam & MK_SHIFT) != 0 { mod_state |= M_SHIFT; }
+    if (wparam & MK_CONTROL) != 0 { mod_state |= M_CTRL; }
+    if unsafe { GetKeyState(VK_MENU) } < 0 { mod_state |= M_ALT; }
+    mod_state
+}
+
 impl MyWndProc {
     fn rebuild_render_target(&self) {
         unsafe {
             let mut state = self.state.borrow_mut();
             let s = state.as_mut().unwrap();
-            let rt = paint::create_render_target_dxgi(&self.d2d_factory, s.swap_chain, s.dpi).ok();
+            let dpi = s.dpi;
+            let rt = match s.dcomp_state() {
+                Some(ds) => paint::create_render_target_dxgi(&self.d2d_factory, ds.swap_chain,
+                    dpi).ok(),
+                None => None,
+            };
             s.render_target = rt;
         }
     }
 
-    // Renders but does not present.
+    // Present the swap chain, if there is one. A device lost since the
+    // frame was drawn is made again at the next paint.
+    unsafe fn present(&self, sync_interval: UINT) {
+        let hr = match self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
+            Some(ds) => (*ds.swap_chain).Present(sync_interval, 0),
+            None => return,
+        };
+        if device_lost(hr) {
+            println!("Present error: 0x{:x}", hr);
+            self.lose_device();
+        }
+    }
+
+    // Resize the swap chain's buffers. Returns whether that worked; a lost
+    // device is made again at the next paint.
+    unsafe fn resize_swap_chain(&self, buffers: UINT, width: u32, height: u32) -> bool {
+        let hr = match self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
+            Some(ds) => (*ds.swap_chain).ResizeBuffers(buffers, width, height,
+                DXGI_FORMAT_UNKNOWN, 0),
+            None => return false,
+        };
+        if !SUCCEEDED(hr) {
+            println!("ResizeBuffers failed: 0x{:x}", hr);
+            if device_lost(hr) {
+                self.lose_device();
+            }
+        }
+        SUCCEEDED(hr)
+    }
+
+    // The device is gone, as on a driver reset or reconnecting over RDP:
+    // drop the render target and the composition, and have the handler
+    // drop what it made with them. They're made again at the next paint.
+    fn lose_device(&self) {
+        {
+            let mut state = self.state.borrow_mut();
+            let s = state.as_mut().unwrap();
+            s.render_target = None;
+            if let Some(ref mut composition) = s.composition {
+                composition.lose();
+            }
+        }
+        self.handler.rebuild_resources();
+        self.handle.borrow().invalidate();
+    }
+
+    // Make the composition again after its device was lost: the D3D and
+    // DirectComposition devices, the visual and the swap chain, sized to
+    // the window, with a render target on it. Until that works, the
+    // window draws to its hwnd, and the next paint tries again.
+    unsafe fn restore_device(&self, hwnd: HWND) {
+        let restored = match self.state.borrow_mut().as_mut().unwrap().composition {
+            Some(ref mut composition) => composition.is_lost() && composition.recreate(),
+            None => false,
+        };
+        if !restored {
+            return;
+        }
+        let mut rect: RECT = mem::uninitialized();
+        GetClientRect(hwnd, &mut rect);
+        let width = (rect.right - rect.left) as u32;
+        let height = (rect.bottom - rect.top) as u32;
+        if self.resize_swap_chain(0, width, height) {
+            self.rebuild_render_target();
+            self.handler.rebuild_resources();
+        }
+    }
+
+    // Renders but does not present. Without a render target, as when
+    // creating one failed, there's nothing to render to.
     fn render(&self) {
-        let mut state = self.state.borrow_mut();
-        let s = state.as_mut().unwrap();
-        let rt = s.render_target.as_mut().unwrap();
-        rt.begin_draw();
-        let anim = self.handler.paint(&mut PaintCtx {
-            d2d_factory: &self.d2d_factory,
-            render_target: rt,
-        });
-        // Maybe should deal with lost device here...
-        let res = rt.end_draw();
-        if let Err(e) = res {
-            println!("EndDraw error: {:?}", e);
+        let (anim, lost) = {
+            let mut state = self.state.borrow_mut();
+            let s = state.as_mut().unwrap();
+            let rt = match s.render_target.as_mut() {
+                Some(rt) => rt,
+                None => return,
+            };
+            rt.begin_draw();
+            let anim = self.handler.paint(&mut PaintCtx {
+                d2d_factory: &self.d2d_factory,
+                render_target: rt,
+            });
+            let res = rt.end_draw();
+            if let Err(ref e) = res {
+                println!("EndDraw error: {:?}", e);
+            }
+            (anim, res.is_err())
+        };
+        if lost {
+            // EndDraw fails with D2DERR_RECREATE_TARGET when the device is
+            // lost. Other failures are taken the same way, as making
+            // everything again is no worse than retrying.
+            self.lose_device();
         }
         if anim {
             let handle = self.handle.borrow().get_idle_handle().unwrap();
@@ -335,16 +694,18 @@ impl WndProc for MyWndProc {
                 Some(0)
             }
             WM_PAINT => unsafe {
+                self.restore_device(hwnd);
                 if self.state.borrow().as_ref().unwrap().render_target.is_none() {
-                    let rt = paint::create_render_target(&self.d2d_factory, hwnd);
+                    let dpi = self.state.borrow().as_ref().unwrap().dpi;
+                    let rt = paint::create_render_target(&self.d2d_factory, hwnd, dpi);
                     self.state.borrow_mut().as_mut().unwrap().render_target = rt.ok();
                 }
                 self.render();
-                let mut state = self.state.borrow_mut();
-                let s = state.as_mut().unwrap();
-                if let Some(ref mut ds) = s.dcomp_state {
-                    if !ds.sizing {
-                        (*s.swap_chain).Present(1, 0);
+                let sizing = self.state.borrow_mut().as_mut().unwrap().dcomp_state()
+                    .map(|ds| ds.sizing);
+                if sizing == Some(false) {
+                    self.present(1);
+                    if let Some(ds) = self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
                         let _ = ds.dcomp_device.commit();
                     }
                 }
@@ -352,15 +713,14 @@ impl WndProc for MyWndProc {
                 Some(0)
             },
             WM_ENTERSIZEMOVE => unsafe {
-                if self.state.borrow().as_ref().unwrap().dcomp_state.is_some() {
-                    let rt = paint::create_render_target(&self.d2d_factory, hwnd);
+                if self.state.borrow().as_ref().unwrap().has_dcomp() {
+                    let dpi = self.state.borrow().as_ref().unwrap().dpi;
+                    let rt = paint::create_render_target(&self.d2d_factory, hwnd, dpi);
                     self.state.borrow_mut().as_mut().unwrap().render_target = rt.ok();
                     self.handler.rebuild_resources();
                     self.render();
 
-                    let mut state = self.state.borrow_mut();
-                    let s = state.as_mut().unwrap();
-                    if let Some(ref mut ds) = s.dcomp_state {
+                    if let Some(ds) = self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
                         let _ = ds.dcomp_target.clear_root();
                         let _ = ds.dcomp_device.commit();
                         ds.sizing = true;
@@ -369,31 +729,23 @@ impl WndProc for MyWndProc {
                 None
             }
             WM_EXITSIZEMOVE => unsafe {
-                if self.state.borrow().as_ref().unwrap().dcomp_state.is_some() {
+                if self.state.borrow().as_ref().unwrap().has_dcomp() {
                     let mut rect: RECT = mem::uninitialized();
                     GetClientRect(hwnd, &mut rect);
                     let width = (rect.right - rect.left) as u32;
                     let height = (rect.bottom - rect.top) as u32;
-                    let res = (*self.state.borrow_mut().as_mut().unwrap().swap_chain)
-                        .ResizeBuffers(2, width, height, DXGI_FORMAT_UNKNOWN, 0);
-                    if SUCCEEDED(res) {
+                    if self.resize_swap_chain(2, width, height) {
                         self.handler.rebuild_resources();
                         self.rebuild_render_target();
                         self.render();
-                        let mut state = self.state.borrow_mut();
-                        let mut s = state.as_mut().unwrap();
-                        (*s.swap_chain).Present(0, 0);
-                    } else {
-                        println!("ResizeBuffers failed: 0x{:x}", res);
+                        self.present(0);
                     }
 
                     // Flush to present flicker artifact (old swapchain composited)
                     // It might actually be better to create a new swapchain here.
                     DwmFlush();
 
-                    let mut state = self.state.borrow_mut();
-                    let mut s = state.as_mut().unwrap();
-                    if let Some(ref mut ds) = s.dcomp_state {
+                    if let Some(ds) = self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
                         let _ = ds.dcomp_target.set_root(&mut ds.swapchain_visual);
                         let _ = ds.dcomp_device.commit();
                         ds.sizing = false;
@@ -405,12 +757,9 @@ impl WndProc for MyWndProc {
                 let width = LOWORD(lparam as u32) as u32;
                 let height = HIWORD(lparam as u32) as u32;
                 self.handler.size(width, height);
-                let use_hwnd = if let Some(ref dcomp_state) =
-                    self.state.borrow().as_ref().unwrap().dcomp_state
-                {
-                    dcomp_state.sizing
-                } else {
-                    true
+                let use_hwnd = match self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
+                    Some(dcomp_state) => dcomp_state.sizing,
+                    None => true,
                 };
                 if use_hwnd {
                     let mut state = self.state.borrow_mut();
@@ -425,35 +774,60 @@ impl WndProc for MyWndProc {
                     InvalidateRect(hwnd, null_mut(), FALSE);
                 } else {
                     self.state.borrow_mut().as_mut().unwrap().render_target = None;
-                    let res = (*self.state.borrow_mut().as_mut().unwrap().swap_chain)
-                        .ResizeBuffers(0, width, height, DXGI_FORMAT_UNKNOWN, 0);
-                    if SUCCEEDED(res) {
+                    if self.resize_swap_chain(0, width, height) {
                         self.rebuild_render_target();
                         self.render();
-                        let mut state = self.state.borrow_mut();
-                        let mut s = state.as_mut().unwrap();
-                        (*s.swap_chain).Present(0, 0);
-                        if let Some(ref mut dcomp_state) = s.dcomp_state {
-                            let _ = dcomp_state.dcomp_device.commit();
+                        self.present(0);
+                        if let Some(ds) = self.state.borrow_mut().as_mut().unwrap().dcomp_state() {
+                            let _ = ds.dcomp_device.commit();
                         }
                         ValidateRect(hwnd, null_mut());
-                    } else {
-                        println!("ResizeBuffers failed: 0x{:x}", res);
                     }
                 }
                 Some(0)
             },
+            WM_DPICHANGED => unsafe {
+                // Both halves of wparam carry the same dpi.
+                let dpi = HIWORD(wparam as u32) as f32;
+                self.handle.borrow().set_dpi(dpi);
+                let use_swap_chain = {
+                    let mut state = self.state.borrow_mut();
+                    let s = state.as_mut().unwrap();
+                    s.dpi = dpi;
+                    s.render_target = None;
+                    s.dcomp_state().map_or(false, |ds| !ds.sizing)
+                };
+                if use_swap_chain {
+                    self.rebuild_render_target();
+                }
+                // Otherwise the hwnd render target is recreated with the new
+                // dpi at the next paint.
+                self.handler.rebuild_resources();
+                let rect = &*(lparam as *const RECT);
+                SetWindowPos(hwnd, null_mut(), rect.left, rect.top,
+                    rect.right - rect.left, rect.bottom - rect.top,
+                    SWP_NOZORDER | SWP_NOACTIVATE);
+                // The size in px units changes even if the pixel size
+                // doesn't, in which case there's no WM_SIZE.
+                let mut client: RECT = mem::uninitialized();
+                GetClientRect(hwnd, &mut client);
+                self.handler.size((client.right - client.left) as u32,
+                    (client.bottom - client.top) as u32);
+                InvalidateRect(hwnd, null_mut(), FALSE);
+                Some(0)
+            },
             WM_COMMAND => {
                 self.handler.command(LOWORD(wparam as u32) as u32);
                 Some(0)
             }
-            WM_CHAR => {
-                let mods = get_mod_state(lparam);
-                self.handler.char(wparam as u32, mods);
+            WM_CHAR | WM_DEADCHAR | WM_SYSDEADCHAR => {
+                let ch = typed_char(msg, wparam)?;
+                let mods = get_mod_state();
+                self.handler.char(ch, mods);
                 Some(0)
             }
             WM_KEYDOWN | WM_SYSKEYDOWN => {
-                let mods = get_mod_state(lparam);
+                let mods = get_mod_state();
                 let handled = self.handler.keydown(wparam as i32, mods);
                 if handled {
                     Some(0)
@@ -461,6 +835,16 @@ impl WndProc for MyWndProc {
                     None
                 }
             }
+            WM_KEYUP | WM_SYSKEYUP => {
+                let mods = get_mod_state();
+                // Unhandled, releasing Alt has to get to the default
+                // handling, which activates the menu bar.
+                if self.handler.keyup(wparam as i32, mods) {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
             WM_MOUSEWHEEL => {
                 let delta = HIWORD(wparam as u32) as i16 as i32;
                 let mods = LOWORD(wparam as u32) as u32;
@@ -473,10 +857,39 @@ impl WndProc for MyWndProc {
                 self.handler.mouse_hwheel(delta, mods);
                 Some(0)
             }
+            WM_POINTERDOWN | WM_POINTERUPDATE | WM_POINTERUP => {
+                let get_pointer_type = match OPTIONAL_FUNCTIONS.GetPointerType {
+                    Some(func) => func,
+                    None => return None,
+                };
+                let id = LOWORD(wparam as u32) as u32;
+                let mut pointer_type = 0;
+                if unsafe { get_pointer_type(id, &mut pointer_type) } == 0
+                    || pointer_type != PT_TOUCH
+                {
+                    // The default turns the rest into mouse messages.
+                    return None;
+                }
+                let mut point = POINT {
+                    x: LOWORD(lparam as u32) as i16 as i32,
+                    y: HIWORD(lparam as u32) as i16 as i32,
+                };
+                unsafe { ScreenToClient(hwnd, &mut point) };
+                let ty = match msg {
+                    WM_POINTERDOWN => TouchType::Down,
+                    WM_POINTERUP => TouchType::Up,
+                    _ => TouchType::Move,
+                };
+                if self.handler.touch(id, point.x, point.y, ty) {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
             WM_MOUSEMOVE => {
                 let x = LOWORD(lparam as u32) as i16 as i32;
-                let y = LOWORD(lparam as u32) as i16 as i32;
-                let mods = LOWORD(wparam as u32) as u32;
+                let y = HIWORD(lparam as u32) as i16 as i32;
+                let mods = get_mouse_mod_state(wparam);
                 self.handler.mouse_move(x, y, mods);
                 Some(0)
             }
@@ -512,11 +925,59 @@ impl WndProc for MyWndProc {
                     _ => unreachable!(),
                 };
                 let x = LOWORD(lparam as u32) as i16 as i32;
-                let y = LOWORD(lparam as u32) as i16 as i32;
-                let mods = LOWORD(wparam as u32) as u32;
+                let y = HIWORD(lparam as u32) as i16 as i32;
+                let mods = get_mouse_mod_state(wparam);
+                unsafe {
+                    if ty == MouseType::Up {
+                        let buttons = MK_LBUTTON | MK_MBUTTON | MK_RBUTTON | MK_XBUTTON1 |
+                            MK_XBUTTON2;
+                        if (wparam & buttons) == 0 {
+                            ReleaseCapture();
+                        }
+                    } else {
+                        SetCapture(hwnd);
+                    }
+                }
                 self.handler.mouse(x, y, mods, button, ty);
                 Some(0)
             }
+            // Outside the client area, the frame's cursors are left to
+            // the default processing.
+            WM_SETCURSOR if LOWORD(lparam as u32) as LRESULT == HTCLIENT => {
+                match self.handle.borrow().cursor() {
+                    Some(cursor) => {
+                        unsafe { SetCursor(cursor); }
+                        Some(TRUE as LRESULT)
+                    }
+                    None => None,
+                }
+            }
+            WM_TIMER => {
+                self.handler.timer(wparam as usize);
+                Some(0)
+            }
+            WM_CAPTURECHANGED => {
+                self.handler.capture_lost();
+                Some(0)
+            }
+            WM_SETFOCUS => {
+                self.handler.got_focus();
+                Some(0)
+            }
+            WM_KILLFOCUS => {
+                self.handler.lost_focus();
+                Some(0)
+            }
+            WM_GETOBJECT => {
+                self.handler.get_object(wparam, lparam)
+            }
+            WM_CLOSE => {
+                if self.handler.request_close() {
+                    None
+                } else {
+                    Some(0)
+                }
+            }
             WM_DESTROY => {
                 self.handler.destroy();
                 None
@@ -612,6 +1073,8 @@ impl WindowBuilder {
                 dpi: Cell::new(0.0),
                 wndproc: Box::new(wndproc),
                 idle_queue: Default::default(),
+                fullscreen: Default::default(),
+                cursor: Cell::new(cursor),
             };
             let win = Rc::new(window);
             let handle = WindowHandle(Rc::downgrade(&win));
@@ -644,49 +1107,25 @@ impl WindowBuilder {
             if hwnd.is_null() {
                 return Err(Error::Null);
             }
+            // The monitor the window landed on may not be at the system dpi.
+            let mut dpi = dpi;
+            if let Some(func) = OPTIONAL_FUNCTIONS.GetDpiForWindow {
+                let window_dpi = func(hwnd) as f32;
+                if window_dpi != dpi && window_dpi > 0.0 {
+                    let scale = window_dpi / dpi;
+                    dpi = window_dpi;
+                    win.dpi.set(dpi);
+                    SetWindowPos(hwnd, null_mut(), 0, 0,
+                        (width as f32 * scale) as i32, (height as f32 * scale) as i32,
+                        SWP_NOMOVE | SWP_NOZORDER | SWP_NOACTIVATE);
+                }
+            }
 
-            let mut swap_chain: *mut IDXGISwapChain1 = null_mut();
-            let dcomp_state = if let Some(create_dxgi_factory2) = OPTIONAL_FUNCTIONS.CreateDXGIFactory2 {
-                let mut d3d11_device = D3D11Device::new_simple()?;
-                let mut d2d1_device = d3d11_device.create_d2d1_device()?;
-                let mut dcomp_device = d2d1_device.create_composition_device()?;
-                let mut dcomp_target = dcomp_device.create_target_for_hwnd(hwnd, true)?;
-
-                let mut factory: *mut IDXGIFactory2 = null_mut();
-                as_result(create_dxgi_factory2(0, &IID_IDXGIFactory2,
-                    &mut factory as *mut *mut IDXGIFactory2 as *mut *mut c_void))?;
-                println!("dxgi factory pointer = {:?}", factory);
-                let adapter = choose_adapter(factory);
-                println!("adapter = {:?}", adapter);
-                let (swap_effect, bufs) = match self.present_strategy {
-                    PresentStrategy::Sequential => (DXGI_SWAP_EFFECT_SEQUENTIAL, 1),
-                    PresentStrategy::Flip | PresentStrategy::FlipRedirect =>
-                        (DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL, 2),
-                };
-                let desc = DXGI_SWAP_CHAIN_DESC1 {
-                    Width: 1024,
-                    Height: 768,
-                    Format: DXGI_FORMAT_B8G8R8A8_UNORM,
-                    Stereo: FALSE,
-                    SampleDesc: DXGI_SAMPLE_DESC { Count: 1, Quality: 0},
-                    BufferUsage: DXGI_USAGE_RENDER_TARGET_OUTPUT,
-                    BufferCount: bufs,
-                    Scaling: DXGI_SCALING_STRETCH,
-                    SwapEffect: swap_effect,
-                    AlphaMode: DXGI_ALPHA_MODE_IGNORE,
-                    Flags: 0,
-                };
-                let res = (*factory).CreateSwapChainForComposition(d3d11_device.raw_ptr() as *mut IUnknown,
-                    &desc, null_mut(), &mut swap_chain);
-                println!("swap chain res = 0x{:x}, pointer = {:?}", res, swap_chain);
-
-                let mut swapchain_visual = dcomp_device.create_visual()?;
-                swapchain_visual.set_content_raw(swap_chain as *mut IUnknown)?;
-                dcomp_target.set_root(&mut swapchain_visual)?;
-                Some(DCompState {
-                    dcomp_device, dcomp_target, swapchain_visual,
-                    sizing: false,
-                })
+            let composition = if OPTIONAL_FUNCTIONS.CreateDXGIFactory2.is_some() {
+                Some(Composition::new(DCompFactory {
+                    hwnd,
+                    present_strategy: self.present_strategy,
+                })?)
             } else {
                 None
             };
@@ -694,8 +1133,7 @@ impl WindowBuilder {
             win.hwnd.set(hwnd);
             let state = WndState {
                 render_target: None,
-                swap_chain,
-                dcomp_state,
+                composition,
                 dpi,
             };
             win.wndproc.connect(&handle, state);
@@ -705,6 +1143,14 @@ impl WindowBuilder {
     }
 }
 
+// The window's menubar, which is put aside while it's full screen.
+unsafe fn menubar(w: &WindowState, hwnd: HWND) -> HMENU {
+    match *w.fullscreen.borrow() {
+        Some(ref saved) => saved.menu,
+        None => GetMenu(hwnd),
+    }
+}
+
 /// Choose an adapter. Here the heuristic is to choose the adapter with the
 /// largest video memory, which will generally be the discrete adapter. It's
 /// possible that on some systems the integrated adapter might be a better
@@ -784,6 +1230,20 @@ impl WindowHandle {
         }
     }
 
+    /// Bring the window to the front and give it the focus, restoring it
+    /// if it's minimized.
+    pub fn activate(&self) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            unsafe {
+                if IsIconic(hwnd) != 0 {
+                    ShowWindow(hwnd, SW_RESTORE);
+                }
+                SetForegroundWindow(hwnd);
+            }
+        }
+    }
+
     pub fn close(&self) {
         if let Some(w) = self.0.upgrade() {
             let hwnd = w.hwnd.get();
@@ -802,6 +1262,250 @@ impl WindowHandle {
         }
     }
 
+    /// Invalidate a rectangle of the window, given in px units.
+    pub fn invalidate_rect(&self, x0: f32, y0: f32, x1: f32, y1: f32) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            let scale = w.dpi.get() * (1.0 / 96.0);
+            let rect = RECT {
+                left: (x0 * scale).floor() as i32,
+                top: (y0 * scale).floor() as i32,
+                right: (x1 * scale).ceil() as i32,
+                bottom: (y1 * scale).ceil() as i32,
+            };
+            unsafe {
+                InvalidateRect(hwnd, &rect, FALSE);
+            }
+        }
+    }
+
+    /// Start a timer, which calls `WinHandler::timer` with the given token
+    /// every `ms` milliseconds until killed. Setting a timer that is already
+    /// running restarts it with the new interval.
+    pub fn set_timer(&self, token: usize, ms: u32) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            unsafe {
+                SetTimer(hwnd, token as UINT_PTR, ms, None);
+            }
+        }
+    }
+
+    /// Stop a timer started with `set_timer`.
+    pub fn kill_timer(&self, token: usize) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            unsafe {
+                KillTimer(hwnd, token as UINT_PTR);
+            }
+        }
+    }
+
+    /// Show a context menu at the given point, in client coordinates
+    /// (physical pixels, as passed to `WinHandler::mouse`). Selected items
+    /// are delivered through `WinHandler::command`, like the menubar.
+    ///
+    /// This runs a modal loop, so the caller shouldn't hold any borrows
+    /// that the handler needs. A mirrored menu opens to the left of the
+    /// point, laid out right to left.
+    pub fn show_context_menu(&self, menu: Menu, x: i32, y: i32, mirrored: bool) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            unsafe {
+                let mut point = POINT { x, y };
+                ClientToScreen(hwnd, &mut point);
+                let hmenu = menu.into_hmenu();
+                let align = if mirrored { TPM_RIGHTALIGN | TPM_LAYOUTRTL } else { TPM_LEFTALIGN };
+                TrackPopupMenu(hmenu, align | TPM_TOPALIGN | TPM_RIGHTBUTTON,
+                    point.x, point.y, 0, hwnd, null());
+                DestroyMenu(hmenu);
+            }
+        }
+    }
+
+    /// Replace the window's menubar, for menus whose contents change.
+    pub fn set_menu(&self, menu: Menu) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            // Full screen, the menubar goes on when the window comes back.
+            if let Some(ref mut saved) = *w.fullscreen.borrow_mut() {
+                unsafe {
+                    DestroyMenu(saved.menu);
+                }
+                saved.menu = menu.into_hmenu();
+                return;
+            }
+            unsafe {
+                let old_menu = GetMenu(hwnd);
+                SetMenu(hwnd, menu.into_hmenu());
+                if !old_menu.is_null() {
+                    DestroyMenu(old_menu);
+                }
+                DrawMenuBar(hwnd);
+            }
+        }
+    }
+
+    /// Check or uncheck an item in the window's menubar, or one of its
+    /// submenus.
+    pub fn check_menu_item(&self, id: u32, checked: bool) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            let flag = if checked { MF_CHECKED } else { MF_UNCHECKED };
+            unsafe {
+                let hmenu = menubar(&w, hwnd);
+                if !hmenu.is_null() {
+                    CheckMenuItem(hmenu, id, MF_BYCOMMAND | flag);
+                }
+            }
+        }
+    }
+
+    /// Enable or gray out an item in the window's menubar, or one of its
+    /// submenus.
+    pub fn enable_menu_item(&self, id: u32, enable: bool) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            let flag = if enable { MF_ENABLED } else { MF_GRAYED };
+            unsafe {
+                let hmenu = menubar(&w, hwnd);
+                if !hmenu.is_null() {
+                    EnableMenuItem(hmenu, id, MF_BYCOMMAND | flag);
+                }
+            }
+        }
+    }
+
+    /// The current placement of the window, or the one it goes back to if
+    /// it's full screen.
+    pub fn get_placement(&self) -> Option<WindowPlacement> {
+        let w = self.0.upgrade()?;
+        let hwnd = w.hwnd.get();
+        unsafe {
+            let mut wp: WINDOWPLACEMENT = mem::zeroed();
+            wp.length = mem::size_of::<WINDOWPLACEMENT>() as UINT;
+            if let Some(ref saved) = *w.fullscreen.borrow() {
+                wp = saved.placement;
+            } else if GetWindowPlacement(hwnd, &mut wp) == 0 {
+                return None;
+            }
+            let rect = wp.rcNormalPosition;
+            Some(WindowPlacement {
+                left: rect.left,
+                top: rect.top,
+                right: rect.right,
+                bottom: rect.bottom,
+                maximized: wp.showCmd == SW_SHOWMAXIMIZED as UINT,
+            })
+        }
+    }
+
+    /// Move the window to a saved placement and show it, instead of
+    /// `show`. The rect is kept within the work area of the nearest
+    /// monitor, in case the one it was saved on is gone.
+    pub fn show_with_placement(&self, placement: &WindowPlacement) {
+        if let Some(w) = self.0.upgrade() {
+            let hwnd = w.hwnd.get();
+            let mut rect = RECT {
+                left: placement.left,
+                top: placement.top,
+                right: placement.right,
+                bottom: placement.bottom,
+            };
+            unsafe {
+                let monitor = MonitorFromRect(&rect, MONITOR_DEFAULTTONEAREST);
+                let mut info: MONITORINFO = mem::zeroed();
+                info.cbSize = mem::size_of::<MONITORINFO>() as DWORD;
+                if GetMonitorInfoW(monitor, &mut info) != 0 {
+                    let work = info.rcWork;
+                    let width = min(rect.right - rect.left, work.right - work.left);
+                    let height = min(rect.bottom - rect.top, work.bottom - work.top);
+                    let left = max(work.left, min(rect.left, work.right - width));
+                    let top = max(work.top, min(rect.top, work.bottom - height));
+                    rect = RECT { left, top, right: left + width, bottom: top + height };
+                }
+                let show_cmd = if placement.maximized { SW_SHOWMAXIMIZED } else { SW_SHOWNORMAL };
+                let wp = WINDOWPLACEMENT {
+                    length: mem::size_of::<WINDOWPLACEMENT>() as UINT,
+                    flags: 0,
+                    showCmd: show_cmd as UINT,
+                    ptMinPosition: POINT { x: -1, y: -1 },
+                    ptMaxPosition: POINT { x: -1, y: -1 },
+                    rcNormalPosition: rect,
+                };
+                SetWindowPlacement(hwnd, &wp);
+                UpdateWindow(hwnd);
+            }
+        }
+    }
+
+    /// Set the cursor shown over the client area, until it's set again.
+    /// It changes at once if the mouse is there.
+    pub fn set_cursor(&self, cursor: Cursor) {
+        if let Some(w) = self.0.upgrade() {
+            let id = match cursor {
+                Cursor::IBeam => IDC_IBEAM,
+                Cursor::ResizeUpDown => IDC_SIZENS,
+            };
+            unsafe {
+                let cursor = LoadCursorW(0 as HINSTANCE, id);
+                if cursor != w.cursor.get() {
+                    w.cursor.set(cursor);
+                    SetCursor(cursor);
+                }
+            }
+        }
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.0.upgrade().map_or(false, |w| w.fullscreen.borrow().is_some())
+    }
+
+    /// Cover the monitor the window is on, without a frame or menubar, or
+    /// put the window back the way it was before, maximized or not.
+    pub fn set_fullscreen(&self, fullscreen: bool) {
+        let w = match self.0.upgrade() {
+            Some(w) => w,
+            None => return,
+        };
+        let hwnd = w.hwnd.get();
+        if fullscreen == w.fullscreen.borrow().is_some() {
+            return;
+        }
+        unsafe {
+            if fullscreen {
+                let mut placement: WINDOWPLACEMENT = mem::zeroed();
+                placement.length = mem::size_of::<WINDOWPLACEMENT>() as UINT;
+                let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTONEAREST);
+                let mut info: MONITORINFO = mem::zeroed();
+                info.cbSize = mem::size_of::<MONITORINFO>() as DWORD;
+                if GetWindowPlacement(hwnd, &mut placement) == 0 ||
+                    GetMonitorInfoW(monitor, &mut info) == 0
+                {
+                    return;
+                }
+                let style = GetWindowLongPtrW(hwnd, GWL_STYLE);
+                let menu = GetMenu(hwnd);
+                *w.fullscreen.borrow_mut() = Some(SavedWindow { placement, style, menu });
+                // Taking the menu off doesn't destroy it.
+                SetMenu(hwnd, null_mut());
+                SetWindowLongPtrW(hwnd, GWL_STYLE, style & !(WS_OVERLAPPEDWINDOW as LONG_PTR));
+                let rect = info.rcMonitor;
+                SetWindowPos(hwnd, HWND_TOP, rect.left, rect.top, rect.right - rect.left,
+                    rect.bottom - rect.top, SWP_NOOWNERZORDER | SWP_FRAMECHANGED);
+            } else {
+                let saved = w.fullscreen.borrow_mut().take().unwrap();
+                SetWindowLongPtrW(hwnd, GWL_STYLE, saved.style);
+                SetMenu(hwnd, saved.menu);
+                // The saved show state brings back a maximized window
+                // maximized.
+                SetWindowPlacement(hwnd, &saved.placement);
+                SetWindowPos(hwnd, null_mut(), 0, 0, 0, 0, SWP_NOMOVE | SWP_NOSIZE |
+                    SWP_NOZORDER | SWP_NOOWNERZORDER | SWP_FRAMECHANGED);
+            }
+        }
+    }
+
     /// Get the raw HWND handle, for uses that are not wrapped in
     /// xi_win_shell.
     pub fn get_hwnd(&self) -> Option<HWND> {
@@ -827,7 +1531,18 @@ impl WindowHandle {
         }
     }
 
-    /// Get the dpi of the window.
+    fn set_dpi(&self, dpi: f32) {
+        if let Some(w) = self.0.upgrade() {
+            w.dpi.set(dpi);
+        }
+    }
+
+    fn cursor(&self) -> Option<HCURSOR> {
+        self.0.upgrade().map(|w| w.cursor.get())
+    }
+
+    /// Get the dpi of the window. This changes when the window moves to
+    /// a monitor with a different scale factor.
     pub fn get_dpi(&self) -> f32 {
         if let Some(w) = self.0.upgrade() {
             w.dpi.get()
@@ -884,3 +1599,108 @@ impl IdleHandle {
         }        
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A factory that fails while the GPU is "resetting", and counts what
+    // it made that's still around, of which there can only be one.
+    struct Factory {
+        resetting: Rc<Cell<bool>>,
+        live: Rc<Cell<usize>>,
+        made: Cell<usize>,
+    }
+
+    struct Made(Rc<Cell<usize>>);
+
+    impl Drop for Made {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() - 1);
+        }
+    }
+
+    impl CompositionFactory for Factory {
+        type State = Made;
+
+        fn create(&self) -> Result<Made, Error> {
+            assert_eq!(self.live.get(), 0, "made a second target for the window");
+            if self.resetting.get() {
+                return Err(Error::Hr(DXGI_ERROR_DEVICE_REMOVED));
+            }
+            self.made.set(self.made.get() + 1);
+            self.live.set(self.live.get() + 1);
+            Ok(Made(self.live.clone()))
+        }
+    }
+
+    #[test]
+    fn device_loss() {
+        let resetting = Rc::new(Cell::new(false));
+        let live = Rc::new(Cell::new(0));
+        let factory = Factory { resetting: resetting.clone(), live: live.clone(),
+            made: Cell::new(0) };
+        let mut composition = Composition::new(factory).ok().unwrap();
+        assert!(!composition.is_lost());
+
+        // The device goes while the GPU resets, and can't be made again
+        // until it's done.
+        composition.lose();
+        assert!(composition.is_lost());
+        assert_eq!(live.get(), 0);
+        resetting.set(true);
+        assert!(!composition.recreate());
+        assert!(!composition.recreate());
+        assert!(composition.is_lost());
+
+        resetting.set(false);
+        assert!(composition.recreate());
+        assert!(!composition.is_lost());
+        assert_eq!((live.get(), composition.factory.made.get()), (1, 2));
+
+        // Losing it at once again, as Present can, drops the old one
+        // before making the new.
+        assert!(composition.recreate());
+        assert_eq!((live.get(), composition.factory.made.get()), (1, 3));
+    }
+
+    #[test]
+    fn failing_at_first() {
+        let factory = Factory { resetting: Rc::new(Cell::new(true)),
+            live: Rc::new(Cell::new(0)), made: Cell::new(0) };
+        assert!(Composition::new(factory).is_err());
+    }
+
+    #[test]
+    fn lost_devices() {
+        assert!(device_lost(DXGI_ERROR_DEVICE_REMOVED));
+        assert!(device_lost(DXGI_ERROR_DEVICE_RESET));
+        assert!(device_lost(D2DERR_RECREATE_TARGET));
+        assert!(!device_lost(S_OK));
+        assert!(!device_lost(E_INVALIDARG));
+    }
+
+    // The characters the handler gets for a run of messages, as
+    // TranslateMessage posts them.
+    fn chars(msgs: &[(UINT, WPARAM)]) -> Vec<u32> {
+        msgs.iter().filter_map(|&(msg, wparam)| typed_char(msg, wparam)).collect()
+    }
+
+    #[test]
+    fn dead_keys() {
+        // ´ then e, on a German layout: the accent's key gives a
+        // WM_DEADCHAR, and e's the accented character.
+        let acute_e = [(WM_KEYDOWN, 0xdd), (WM_DEADCHAR, 0xb4), (WM_KEYUP, 0xdd),
+            (WM_KEYDOWN, 'E' as WPARAM), (WM_CHAR, 0xe9), (WM_KEYUP, 'E' as WPARAM)];
+        assert_eq!(chars(&acute_e), vec![0xe9]);
+        // ´ then space gives the accent on its own.
+        assert_eq!(chars(&[(WM_KEYDOWN, 0xdd), (WM_DEADCHAR, 0xb4),
+            (WM_KEYDOWN, VK_SPACE as WPARAM), (WM_CHAR, 0xb4)]), vec![0xb4]);
+        // ´ then s, which don't combine, give both, from the one key.
+        assert_eq!(chars(&[(WM_KEYDOWN, 0xdd), (WM_DEADCHAR, 0xb4),
+            (WM_KEYDOWN, 'S' as WPARAM), (WM_CHAR, 0xb4), (WM_CHAR, 's' as WPARAM)]),
+            vec![0xb4, 's' as u32]);
+        // With Alt down the dead key comes as WM_SYSDEADCHAR.
+        assert_eq!(chars(&[(WM_SYSKEYDOWN, 0xdd), (WM_SYSDEADCHAR, 0xb4)]), Vec::<u32>::new());
+    }
+}